@@ -0,0 +1,298 @@
+//! Buddy allocator for physical frames.
+//!
+//! Free memory is kept in blocks of 2^order frames, aligned on their size in physical memory.
+//! Allocating splits a larger block in halves until one has the wanted order, freeing merges a
+//! block with its buddy, the other half of the block they were split from, as long as the
+//! buddy is free too.
+//!
+//! Free blocks are linked through their first bytes, and a byte per frame records which frames
+//! start a free block and of which order. The allocator only sees memory through the offset it
+//! is mapped at, so it can run on a plain buffer on the host.
+#![no_std]
+
+use core::ops::Range;
+
+pub const FRAME_SIZE: u64 = 4096;
+/// Order of the largest blocks, 2^10 frames (4 MiB)
+pub const MAX_ORDER: usize = 10;
+/// Order of a 2 MiB block
+pub const HUGE_ORDER: usize = 9;
+
+/// End of a free list
+const NONE: u64 = u64::MAX;
+
+/// Bytes in a block of `order`.
+pub const fn block_size(order: usize) -> u64 {
+    FRAME_SIZE << order
+}
+
+/// Smallest order holding `frames` frames.
+pub const fn order_for(frames: u64) -> usize {
+    if frames <= 1 {
+        0
+    } else {
+        (u64::BITS - (frames - 1).leading_zeros()) as usize
+    }
+}
+
+/// Links of a free block, stored in the block itself.
+#[repr(C)]
+struct Link {
+    prev: u64,
+    next: u64,
+}
+
+#[derive(Debug)]
+pub struct BuddyAllocator {
+    /// Virtual address of physical address 0
+    phys_offset: u64,
+    /// Physical address of the first frame with a state byte
+    base: u64,
+    /// Frames with a state byte
+    frames: u64,
+    /// Physical address of the state bytes, one per frame: `order + 1` if a free block of that
+    /// order starts there, 0 otherwise
+    state: u64,
+    free_lists: [u64; MAX_ORDER + 1],
+    free_blocks: [usize; MAX_ORDER + 1],
+    free_frames: u64,
+    total_frames: u64,
+}
+
+impl BuddyAllocator {
+    /// Creates an allocator handing out the frames in `regions`, keeping its state at the start
+    /// of the first one large enough. Returns `None` if none is.
+    ///
+    /// Region bounds are rounded inwards to whole frames.
+    ///
+    /// # Safety
+    /// The regions must not overlap, be unused, and be mapped at `phys_offset` for as long as
+    /// the allocator lives.
+    pub unsafe fn new<I>(regions: I, phys_offset: u64) -> Option<Self>
+    where
+        I: IntoIterator<Item = Range<u64>>,
+        I::IntoIter: Clone,
+    {
+        let regions = regions
+            .into_iter()
+            .map(|r| r.start.next_multiple_of(FRAME_SIZE)..r.end / FRAME_SIZE * FRAME_SIZE)
+            .filter(|r| r.start < r.end);
+
+        let base = regions.clone().map(|r| r.start).min()?;
+        let end = regions.clone().map(|r| r.end).max()?;
+        let frames = (end - base) / FRAME_SIZE;
+        let state_len = frames.next_multiple_of(FRAME_SIZE);
+        let state = regions
+            .clone()
+            .find(|r| r.end - r.start >= state_len)?
+            .start;
+
+        let mut allocator = Self {
+            phys_offset,
+            base,
+            frames,
+            state,
+            free_lists: [NONE; MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+            free_frames: 0,
+            total_frames: 0,
+        };
+        // Safety: the state fits in its region, which the caller gave us
+        unsafe {
+            core::ptr::write_bytes(allocator.virt::<u8>(state), 0, frames as usize);
+        }
+
+        for region in regions {
+            let start = if region.start == state {
+                state + state_len
+            } else {
+                region.start
+            };
+            // Safety: the caller gave us the region
+            unsafe { allocator.free_region(start..region.end) };
+        }
+        Some(allocator)
+    }
+
+    /// Hands the frame-aligned `region` over, like the regions the allocator was created with.
+    /// Returns whether it did: there is no state for the frames before the lowest of those
+    /// regions or after the highest, so a region reaching out of them is left alone.
+    ///
+    /// # Safety
+    /// The region must be unused, not handed over already, and mapped at the physical offset for
+    /// as long as the allocator lives.
+    pub unsafe fn add_region(&mut self, region: Range<u64>) -> bool {
+        debug_assert!(
+            region.start.is_multiple_of(FRAME_SIZE) && region.end.is_multiple_of(FRAME_SIZE)
+        );
+        let tracked = self.base..self.base + self.frames * FRAME_SIZE;
+        if region.start < tracked.start || region.end > tracked.end {
+            return false;
+        }
+        unsafe { self.free_region(region) };
+        true
+    }
+
+    /// Frees the frame-aligned `region` in the largest blocks it holds.
+    unsafe fn free_region(&mut self, region: Range<u64>) {
+        let mut addr = region.start;
+        while addr < region.end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&o| addr.is_multiple_of(block_size(o)) && addr + block_size(o) <= region.end)
+                .unwrap_or(0);
+            self.total_frames += 1 << order;
+            self.free_frames += 1 << order;
+            unsafe { self.free_block(addr, order) };
+            addr += block_size(order);
+        }
+    }
+
+    /// Takes a block of 2^`order` frames, returning its physical address.
+    pub fn allocate(&mut self, order: usize) -> Option<u64> {
+        let found = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NONE)?;
+        // Safety: the block is on a free list
+        Some(unsafe { self.take(self.free_lists[found], found, order) })
+    }
+
+    /// Takes a block of 2^`order` frames ending at or below the physical address `limit`, for
+    /// devices that only reach part of the memory.
+    ///
+    /// This walks the free lists, so it is slower than [`Self::allocate`].
+    pub fn allocate_below(&mut self, order: usize, limit: u64) -> Option<u64> {
+        let (found, addr) = (order..=MAX_ORDER).find_map(|o| {
+            self.free_list(o)
+                .find(|&addr| addr + block_size(order) <= limit)
+                .map(|addr| (o, addr))
+        })?;
+        // Safety: the block is on a free list
+        Some(unsafe { self.take(addr, found, order) })
+    }
+
+    /// Gives back a block taken with [`Self::allocate`], merging it with its free buddies.
+    ///
+    /// # Safety
+    /// `addr` must come from `allocate(order)` on this allocator and not have been freed since.
+    pub unsafe fn deallocate(&mut self, addr: u64, order: usize) {
+        debug_assert!(addr.is_multiple_of(block_size(order)), "misaligned block");
+        debug_assert_eq!(self.state_of(addr), 0, "double free of {addr:#x}");
+        self.free_frames += 1 << order;
+        unsafe { self.free_block(addr, order) };
+    }
+
+    /// Frames not allocated.
+    pub const fn free_frames(&self) -> u64 {
+        self.free_frames
+    }
+
+    /// Frames handed to the allocator, without the ones holding its state.
+    pub const fn total_frames(&self) -> u64 {
+        self.total_frames
+    }
+
+    /// Free blocks of each order.
+    pub const fn free_blocks(&self) -> &[usize; MAX_ORDER + 1] {
+        &self.free_blocks
+    }
+
+    /// Physical memory holding the allocator state.
+    pub const fn state_region(&self) -> Range<u64> {
+        self.state..self.state + self.frames.next_multiple_of(FRAME_SIZE)
+    }
+
+    /// Takes the free block at `addr` of order `found`, keeping its first 2^`order` frames.
+    unsafe fn take(&mut self, addr: u64, found: usize, order: usize) -> u64 {
+        unsafe { self.unlink(addr, found) };
+
+        // Give back the upper halves until the block has the wanted size
+        for o in (order..found).rev() {
+            unsafe { self.link(addr + block_size(o), o) };
+        }
+        self.free_frames -= 1 << order;
+        addr
+    }
+
+    /// Addresses of the free blocks of `order`.
+    fn free_list(&self, order: usize) -> impl Iterator<Item = u64> + '_ {
+        let mut addr = self.free_lists[order];
+        core::iter::from_fn(move || {
+            let current = addr;
+            if current == NONE {
+                return None;
+            }
+            // Safety: the block is on a free list
+            addr = unsafe { self.virt::<Link>(current).read() }.next;
+            Some(current)
+        })
+    }
+
+    /// Frees a block, merging it upwards as long as its buddy is free.
+    unsafe fn free_block(&mut self, mut addr: u64, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if self.state_of(buddy) != order as u8 + 1 {
+                break;
+            }
+            unsafe { self.unlink(buddy, order) };
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        unsafe { self.link(addr, order) };
+    }
+
+    /// Pushes a block on its free list.
+    unsafe fn link(&mut self, addr: u64, order: usize) {
+        let head = self.free_lists[order];
+        unsafe {
+            self.virt::<Link>(addr).write(Link {
+                prev: NONE,
+                next: head,
+            });
+            if head != NONE {
+                (*self.virt::<Link>(head)).prev = addr;
+            }
+        }
+        self.free_lists[order] = addr;
+        self.free_blocks[order] += 1;
+        self.set_state(addr, order as u8 + 1);
+    }
+
+    /// Takes a block off its free list.
+    unsafe fn unlink(&mut self, addr: u64, order: usize) {
+        let Link { prev, next } = unsafe { self.virt::<Link>(addr).read() };
+        if prev == NONE {
+            self.free_lists[order] = next;
+        } else {
+            unsafe { (*self.virt::<Link>(prev)).next = next };
+        }
+        if next != NONE {
+            unsafe { (*self.virt::<Link>(next)).prev = prev };
+        }
+        self.free_blocks[order] -= 1;
+        self.set_state(addr, 0);
+    }
+
+    /// State of the frame at `addr`, 0 for frames the allocator doesn't track.
+    fn state_of(&self, addr: u64) -> u8 {
+        match self.index(addr) {
+            // Safety: the index is within the state bytes
+            Some(index) => unsafe { self.virt::<u8>(self.state + index).read() },
+            None => 0,
+        }
+    }
+
+    fn set_state(&mut self, addr: u64, state: u8) {
+        let index = self.index(addr).expect("frame tracked by the allocator");
+        // Safety: the index is within the state bytes
+        unsafe { self.virt::<u8>(self.state + index).write(state) };
+    }
+
+    fn index(&self, addr: u64) -> Option<u64> {
+        let index = addr.checked_sub(self.base)? / FRAME_SIZE;
+        (index < self.frames).then_some(index)
+    }
+
+    fn virt<T>(&self, phys: u64) -> *mut T {
+        core::ptr::with_exposed_provenance_mut(phys.wrapping_add(self.phys_offset) as usize)
+    }
+}
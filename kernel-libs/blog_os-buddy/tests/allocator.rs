@@ -0,0 +1,203 @@
+use blog_os_buddy::{FRAME_SIZE, HUGE_ORDER, MAX_ORDER, block_size, order_for};
+
+use crate::common::{Machine, PHYS_BASE, disjoint};
+
+mod common;
+
+const MIB: u64 = 1024 * 1024;
+
+/// 8 MiB where the first frame holds the state and the upper 4 MiB are a single block
+fn one_block() -> Machine {
+    Machine::new(8 * MIB, &[0..FRAME_SIZE, 4 * MIB..8 * MIB])
+}
+
+/// Allocates frames until there are none left.
+fn drain(machine: &mut Machine) -> Vec<u64> {
+    std::iter::from_fn(|| machine.allocate(0)).collect()
+}
+
+#[test]
+fn orders_round_up() {
+    assert_eq!(order_for(0), 0);
+    assert_eq!(order_for(1), 0);
+    assert_eq!(order_for(2), 1);
+    assert_eq!(order_for(3), 2);
+    assert_eq!(order_for(512), HUGE_ORDER);
+    assert_eq!(order_for(513), MAX_ORDER);
+    assert_eq!(block_size(HUGE_ORDER), 2 * MIB);
+}
+
+#[test]
+fn seeds_every_usable_frame_but_the_state() {
+    let machine = Machine::with_frames(2048);
+    assert_eq!(machine.state_region(), PHYS_BASE..PHYS_BASE + FRAME_SIZE);
+    assert_eq!(machine.total_frames(), 2047);
+    assert_eq!(machine.free_frames(), 2047);
+    // The frames after the state, in the largest aligned blocks
+    assert_eq!(machine.free_blocks(), &[1; MAX_ORDER + 1]);
+}
+
+#[test]
+fn splitting_keeps_the_upper_halves() {
+    let mut machine = one_block();
+    assert_eq!(machine.free_blocks()[MAX_ORDER], 1);
+
+    let frame = machine.allocate(0).unwrap();
+    assert_eq!(frame, PHYS_BASE + 4 * MIB);
+    let mut split = [1; MAX_ORDER + 1];
+    split[MAX_ORDER] = 0;
+    assert_eq!(machine.free_blocks(), &split);
+    assert_eq!(machine.free_frames(), 1023);
+
+    unsafe { machine.deallocate(frame, 0) };
+    let mut merged = [0; MAX_ORDER + 1];
+    merged[MAX_ORDER] = 1;
+    assert_eq!(machine.free_blocks(), &merged);
+    assert_eq!(machine.free_frames(), 1024);
+}
+
+#[test]
+fn huge_blocks_are_aligned() {
+    let mut machine = Machine::with_frames(4096);
+    let blocks: Vec<_> = std::iter::from_fn(|| machine.allocate(HUGE_ORDER))
+        .map(|addr| (addr, HUGE_ORDER))
+        .collect();
+    // All but the first 2 MiB, which hold the state
+    assert_eq!(blocks.len(), 7);
+    assert!(blocks.iter().all(|&(addr, _)| addr % (2 * MIB) == 0));
+    assert!(disjoint(&blocks));
+}
+
+#[test]
+fn exhausted_allocator_fails() {
+    let mut machine = one_block();
+    let block = machine.allocate(MAX_ORDER).unwrap();
+    assert_eq!(machine.free_frames(), 0);
+    assert_eq!(machine.allocate(0), None);
+    assert_eq!(machine.allocate(MAX_ORDER), None);
+
+    unsafe { machine.deallocate(block, MAX_ORDER) };
+    assert_eq!(machine.allocate(MAX_ORDER + 1), None);
+    assert_eq!(machine.allocate(MAX_ORDER), Some(block));
+}
+
+#[test]
+fn freeing_everything_merges_back() {
+    let mut machine = Machine::with_frames(2048);
+    let initial = *machine.free_blocks();
+
+    let mut frames = drain(&mut machine);
+    assert_eq!(frames.len(), 2047);
+    assert_eq!(machine.free_frames(), 0);
+
+    let state = machine.state_region();
+    assert!(frames.iter().all(|frame| !state.contains(frame)));
+    let blocks: Vec<_> = frames.iter().map(|&frame| (frame, 0)).collect();
+    assert!(disjoint(&blocks));
+
+    // Free in an order unrelated to the allocation order
+    frames.sort_by_key(|&frame| (frame / FRAME_SIZE).wrapping_mul(0x9E37_79B9) % 2053);
+    for frame in frames {
+        unsafe { machine.deallocate(frame, 0) };
+    }
+    assert_eq!(machine.free_frames(), 2047);
+    assert_eq!(machine.free_blocks(), &initial);
+}
+
+#[test]
+fn holes_are_never_handed_out() {
+    let usable = [
+        0..FRAME_SIZE,
+        // Rounded down to 3 frames
+        4 * MIB..4 * MIB + 3 * FRAME_SIZE + 100,
+        // Rounded up to start on a frame
+        5 * MIB + 10..6 * MIB,
+    ];
+    let mut machine = Machine::new(8 * MIB, &usable);
+    let expected = 3 + (MIB / FRAME_SIZE - 1);
+    assert_eq!(machine.total_frames(), expected);
+
+    let frames = drain(&mut machine);
+    assert_eq!(frames.len() as u64, expected);
+    let in_region = |frame: u64| {
+        let frame = frame - PHYS_BASE;
+        (4 * MIB..4 * MIB + 3 * FRAME_SIZE).contains(&frame)
+            || (5 * MIB + FRAME_SIZE..6 * MIB).contains(&frame)
+    };
+    assert!(frames.iter().all(|&frame| in_region(frame)));
+
+    for frame in frames {
+        unsafe { machine.deallocate(frame, 0) };
+    }
+    assert_eq!(machine.free_frames(), expected);
+    // Nothing merged across the holes
+    assert_eq!(machine.free_blocks()[MAX_ORDER], 0);
+}
+
+#[test]
+fn regions_are_added_only_within_the_tracked_frames() {
+    let mut machine = Machine::new(8 * MIB, &[MIB..2 * MIB, 4 * MIB..6 * MIB]);
+    let total = machine.total_frames();
+    let frame = |offset: u64| PHYS_BASE + offset..PHYS_BASE + offset + FRAME_SIZE;
+
+    // Below the first usable region and past the last one
+    assert!(!unsafe { machine.add_region(frame(0)) });
+    assert!(!unsafe { machine.add_region(frame(6 * MIB)) });
+    assert!(!unsafe { machine.add_region(frame(6 * MIB - FRAME_SIZE).start..PHYS_BASE + 7 * MIB) });
+    assert_eq!(machine.total_frames(), total);
+
+    // In the hole between them
+    assert!(unsafe { machine.add_region(frame(3 * MIB)) });
+    assert_eq!(machine.total_frames(), total + 1);
+    assert_eq!(machine.free_frames(), total + 1);
+    assert!(drain(&mut machine).contains(&(PHYS_BASE + 3 * MIB)));
+}
+
+#[test]
+fn mixed_orders_are_aligned_and_disjoint() {
+    let mut machine = Machine::with_frames(4096);
+    let orders = [0, 3, HUGE_ORDER, 1, 5, 0, 2, 7];
+    let mut blocks = Vec::new();
+    for order in orders.iter().cycle().copied() {
+        match machine.allocate(order) {
+            Some(addr) => blocks.push((addr, order)),
+            None if order == 0 => break,
+            None => {}
+        }
+    }
+    assert_eq!(machine.free_frames(), 0);
+    assert!(
+        blocks
+            .iter()
+            .all(|&(addr, order)| addr % block_size(order) == 0)
+    );
+    assert!(disjoint(&blocks));
+
+    let total: u64 = blocks.iter().map(|&(_, order)| 1 << order).sum();
+    assert_eq!(total, machine.total_frames());
+    for (addr, order) in blocks.into_iter().rev() {
+        unsafe { machine.deallocate(addr, order) };
+    }
+    assert_eq!(machine.free_frames(), machine.total_frames());
+}
+
+#[test]
+fn allocating_below_a_limit() {
+    let mut machine = Machine::with_frames(4096);
+    let limit = PHYS_BASE + 3 * MIB;
+
+    let mut blocks = Vec::new();
+    while let Some(addr) = machine.allocate_below(2, limit) {
+        assert!(addr + block_size(2) <= limit);
+        blocks.push((addr, 2));
+    }
+    assert!(disjoint(&blocks));
+    // The first 3 MiB, except the 4 frames starting with the state
+    assert_eq!(blocks.len() as u64, 3 * MIB / block_size(2) - 1);
+    assert_eq!(
+        machine.allocate_below(HUGE_ORDER, PHYS_BASE + 4 * MIB),
+        None
+    );
+    // The rest of the memory is still there
+    assert!(machine.allocate(HUGE_ORDER).unwrap() >= limit);
+}
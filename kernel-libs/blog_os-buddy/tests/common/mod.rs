@@ -0,0 +1,63 @@
+//! A buffer standing in for physical memory, with the allocator running on top of it.
+
+use std::ops::{Deref, DerefMut, Range};
+
+use blog_os_buddy::{BuddyAllocator, FRAME_SIZE, block_size};
+
+/// Physical address of the first byte of the buffer, 4 MiB aligned like real memory would be
+pub const PHYS_BASE: u64 = 0x40_0000;
+
+pub struct Machine {
+    /// Kept alive for the allocator, which reaches it through its address
+    _memory: Vec<u64>,
+    allocator: BuddyAllocator,
+}
+
+impl Machine {
+    /// Creates a machine with `bytes` of memory from [`PHYS_BASE`], where `usable` regions,
+    /// relative to [`PHYS_BASE`], go to the allocator.
+    pub fn new(bytes: u64, usable: &[Range<u64>]) -> Self {
+        let mut memory = vec![0u64; (bytes / 8) as usize];
+        let phys_offset = (memory.as_mut_ptr().expose_provenance() as u64).wrapping_sub(PHYS_BASE);
+        let regions = usable
+            .iter()
+            .map(|r| r.start + PHYS_BASE..r.end + PHYS_BASE);
+        // Safety: the regions are within the buffer, which lives as long as the allocator
+        let allocator =
+            unsafe { BuddyAllocator::new(regions, phys_offset) }.expect("room for the state");
+        Self {
+            _memory: memory,
+            allocator,
+        }
+    }
+
+    /// A machine where all of its `frames` are usable.
+    pub fn with_frames(frames: u64) -> Self {
+        let bytes = frames * FRAME_SIZE;
+        Self::new(bytes, std::slice::from_ref(&(0..bytes)))
+    }
+}
+
+impl Deref for Machine {
+    type Target = BuddyAllocator;
+
+    fn deref(&self) -> &Self::Target {
+        &self.allocator
+    }
+}
+
+impl DerefMut for Machine {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.allocator
+    }
+}
+
+/// Whether the blocks, given as address and order, don't overlap.
+pub fn disjoint(blocks: &[(u64, usize)]) -> bool {
+    let mut ranges: Vec<_> = blocks
+        .iter()
+        .map(|&(addr, order)| addr..addr + block_size(order))
+        .collect();
+    ranges.sort_by_key(|r| r.start);
+    ranges.windows(2).all(|w| w[0].end <= w[1].start)
+}
@@ -0,0 +1,234 @@
+//! The scheduling policy of the kernel: earliest eligible virtual deadline first for normal tasks,
+//! with fixed-priority real-time tasks running before them.
+//!
+//! Only the bookkeeping lives here. The kernel keeps the run queues and does the switching, and
+//! hands the tasks in through [`Task`].
+//!
+//! Virtual runtimes and deadlines wrap around, so they are only compared through their
+//! difference, see [`is_before`].
+#![no_std]
+
+extern crate alloc;
+
+use core::num::{NonZeroUsize, Wrapping};
+
+use alloc::sync::Arc;
+use blog_os_syscalls::priority::{NICE_MIN, Priority};
+
+/// Weight of each nice value from -20 to 19, each step giving about 10% less CPU time.
+/// The same table as Linux.
+pub const NICE_TO_WEIGHT: [usize; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+pub const NICE_0_WEIGHT: usize = 1024;
+
+/// Virtual runtime of a nice 0 task for one timer tick
+pub const TICK_VRUNTIME: usize = 1024;
+pub const SLICE: Wrapping<usize> = Wrapping(250 * TICK_VRUNTIME); // 250 timer ticks
+// This constant represents 2^(BITS - 1) for a usize.
+pub const HALF_RANGE: Wrapping<usize> = Wrapping((usize::MAX / 2) + 1);
+
+/// Whether `a` comes strictly before `b`, assuming they are less than half the range apart.
+pub fn is_before(a: Wrapping<usize>, b: Wrapping<usize>) -> bool {
+    a != b && b - a < HALF_RANGE
+}
+
+/// Weight of a normal task. Real-time tasks don't share the CPU, they weigh as nice 0.
+pub fn weight(class: Priority) -> NonZeroUsize {
+    let weight = match class {
+        Priority::Normal(nice) => NICE_TO_WEIGHT[(nice - NICE_MIN) as usize],
+        Priority::RealTime(_) => NICE_0_WEIGHT,
+    };
+    NonZeroUsize::new(weight).unwrap()
+}
+
+/// Scheduling state of a task.
+#[derive(Debug, Clone)]
+pub struct Entity {
+    class: Priority,
+    /// Share of the CPU among the normal tasks, from [`NICE_TO_WEIGHT`]
+    weight: NonZeroUsize,
+    vruntime: Wrapping<usize>,
+    deadline: Wrapping<usize>,
+}
+
+impl Entity {
+    pub fn new(class: Priority) -> Self {
+        Self {
+            class,
+            weight: weight(class),
+            vruntime: Wrapping(0),
+            deadline: Wrapping(0),
+        }
+    }
+
+    pub const fn class(&self) -> Priority {
+        self.class
+    }
+
+    pub fn set_class(&mut self, class: Priority) {
+        self.class = class;
+        self.weight = weight(class);
+    }
+
+    pub const fn weight(&self) -> NonZeroUsize {
+        self.weight
+    }
+
+    pub const fn vruntime(&self) -> Wrapping<usize> {
+        self.vruntime
+    }
+
+    pub const fn deadline(&self) -> Wrapping<usize> {
+        self.deadline
+    }
+
+    /// Real-time priority, `None` for normal tasks.
+    pub const fn realtime(&self) -> Option<u8> {
+        match self.class {
+            Priority::RealTime(prio) => Some(prio),
+            Priority::Normal(_) => None,
+        }
+    }
+
+    /// Moves the task to `vruntime`, when it joins a run queue.
+    pub fn place(&mut self, vruntime: Wrapping<usize>) {
+        self.vruntime = vruntime;
+    }
+
+    /// Accounts a timer tick of running, returning whether the task reached its deadline.
+    ///
+    /// Real-time tasks run until they block or yield, they never reach it.
+    pub fn tick(&mut self) -> bool {
+        if self.realtime().is_some() {
+            return false;
+        }
+        // Heavier tasks age slower, and get to run longer before their deadline
+        self.vruntime += TICK_VRUNTIME * NICE_0_WEIGHT / self.weight.get();
+        !is_before(self.vruntime, self.deadline)
+    }
+
+    /// Sets the virtual deadline to when the task got its share of `SLICE` among tasks
+    /// weighing `total_weight` together.
+    fn compute_deadline(&mut self, total_weight: NonZeroUsize) {
+        let weight = Wrapping(self.weight.get());
+        let share = SLICE * weight / Wrapping(total_weight.get());
+        // The task ages slower than real time if it is heavier than nice 0
+        self.deadline = self.vruntime + share * Wrapping(NICE_0_WEIGHT) / weight;
+    }
+}
+
+/// A task as seen by the policy.
+pub trait Task {
+    fn with_entity<R>(&self, f: impl FnOnce(&mut Entity) -> R) -> R;
+}
+
+impl<T: Task + ?Sized> Task for &T {
+    fn with_entity<R>(&self, f: impl FnOnce(&mut Entity) -> R) -> R {
+        (**self).with_entity(f)
+    }
+}
+
+impl<T: Task + ?Sized> Task for Arc<T> {
+    fn with_entity<R>(&self, f: impl FnOnce(&mut Entity) -> R) -> R {
+        (**self).with_entity(f)
+    }
+}
+
+/// Places the `waking` tasks at the smallest vruntime of the normal tasks in `current` and
+/// `ready`, so that they neither gain nor lose from their time away.
+///
+/// They keep their vruntime if there is no normal task to compare with.
+pub fn place<'a, T: Task + 'a>(
+    current: Option<&'a T>,
+    ready: impl IntoIterator<Item = &'a T>,
+    waking: impl IntoIterator<Item = &'a T>,
+) {
+    let minimum_vruntime = current
+        .into_iter()
+        .chain(ready)
+        .filter_map(|t| t.with_entity(|e| e.realtime().is_none().then_some(e.vruntime)))
+        .reduce(|min, v| if is_before(v, min) { v } else { min });
+
+    let Some(minimum_vruntime) = minimum_vruntime else {
+        return;
+    };
+    for t in waking {
+        t.with_entity(|e| {
+            if e.realtime().is_none() {
+                e.place(minimum_vruntime);
+            }
+        });
+    }
+}
+
+/// Weighted average of the vruntimes of the normal tasks among `tasks`, `None` without any.
+///
+/// A task's lag is how far its vruntime is behind the average: tasks past it ran more than their
+/// share, and aren't eligible until the others catch up.
+pub fn average_vruntime<'a, T: Task + 'a>(
+    tasks: impl IntoIterator<Item = &'a T>,
+) -> Option<Wrapping<usize>> {
+    // Summed as offsets from the first vruntime, which don't wrap
+    let mut origin = None;
+    let mut weighted_offsets = 0i128;
+    let mut total_weight = 0i128;
+    for t in tasks {
+        t.with_entity(|e| {
+            if e.realtime().is_some() {
+                return;
+            }
+            let origin = *origin.get_or_insert(e.vruntime);
+            let offset = (e.vruntime - origin).0 as isize;
+            weighted_offsets += offset as i128 * e.weight.get() as i128;
+            total_weight += e.weight.get() as i128;
+        });
+    }
+    // Rounded toward the origin, which keeps the task with the smallest vruntime eligible
+    let average = (weighted_offsets / total_weight.max(1)) as isize;
+    origin.map(|origin| origin + Wrapping(average as usize))
+}
+
+/// Picks the task to run among `current`, if it can keep running, and `ready`.
+///
+/// The highest real-time priority runs first, with the current task going last on a tie.
+/// Otherwise the eligible normal task, see [`average_vruntime`], with the earliest deadline runs,
+/// the current one on a tie. Deadlines are computed again on the way.
+pub fn pick_next<'a, T: Task>(
+    current: Option<&'a T>,
+    ready: impl IntoIterator<Item = &'a T> + Clone,
+) -> Option<&'a T> {
+    let candidates = || current.into_iter().chain(ready.clone());
+
+    let mut total_weight = 0usize;
+    let mut highest_realtime: Option<(&T, u8)> = None;
+    for t in candidates() {
+        t.with_entity(|e| match e.realtime() {
+            Some(prio) => {
+                if highest_realtime.is_none_or(|(_, highest)| prio >= highest) {
+                    highest_realtime = Some((t, prio));
+                }
+            }
+            None => total_weight = total_weight.saturating_add(e.weight.get()),
+        });
+    }
+    if let Some((t, _)) = highest_realtime {
+        return Some(t);
+    }
+
+    let total_weight = NonZeroUsize::new(total_weight)?;
+    let average = average_vruntime(candidates())?;
+    let mut soonest_deadline: Option<(&T, Wrapping<usize>)> = None;
+    for t in candidates() {
+        let (deadline, eligible) = t.with_entity(|e| {
+            e.compute_deadline(total_weight);
+            (e.deadline, !is_before(average, e.vruntime))
+        });
+        if eligible && soonest_deadline.is_none_or(|(_, soonest)| is_before(deadline, soonest)) {
+            soonest_deadline = Some((t, deadline));
+        }
+    }
+    soonest_deadline.map(|(t, _)| t)
+}
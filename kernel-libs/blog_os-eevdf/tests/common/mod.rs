@@ -0,0 +1,193 @@
+//! A single CPU run queue driven tick by tick, doing what the kernel does around the policy.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, BTreeSet},
+    num::Wrapping,
+};
+
+use blog_os_eevdf::{Entity, Task, average_vruntime, pick_next, place};
+use blog_os_syscalls::priority::Priority;
+
+pub type TaskId = usize;
+
+pub struct SimTask {
+    id: TaskId,
+    entity: RefCell<Entity>,
+    /// Ticks spent running
+    ran: Cell<u64>,
+    /// Tick of the last time it was running
+    last_ran: Cell<Option<u64>>,
+    /// Longest time it waited to run again while runnable
+    longest_wait: Cell<u64>,
+}
+
+impl Task for SimTask {
+    fn with_entity<R>(&self, f: impl FnOnce(&mut Entity) -> R) -> R {
+        f(&mut self.entity.borrow_mut())
+    }
+}
+
+#[derive(Default)]
+pub struct Sim {
+    tasks: BTreeMap<TaskId, SimTask>,
+    current: Option<TaskId>,
+    ready: BTreeSet<TaskId>,
+    waking: Vec<TaskId>,
+    sleeping: BTreeSet<TaskId>,
+    needs_reschedule: bool,
+    now: u64,
+}
+
+impl Sim {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&mut self, class: Priority) -> TaskId {
+        let id = self.tasks.len();
+        self.tasks.insert(
+            id,
+            SimTask {
+                id,
+                entity: RefCell::new(Entity::new(class)),
+                ran: Cell::new(0),
+                last_ran: Cell::new(None),
+                longest_wait: Cell::new(0),
+            },
+        );
+        id
+    }
+
+    /// Adds a task like the kernel creates one, through the waking queue.
+    pub fn spawn(&mut self, class: Priority) -> TaskId {
+        let id = self.insert(class);
+        self.waking.push(id);
+        if self.task(id).entity.borrow().realtime().is_some() {
+            self.needs_reschedule = true;
+        }
+        id
+    }
+
+    /// Adds a ready task at `vruntime`.
+    pub fn spawn_at(&mut self, class: Priority, vruntime: usize) -> TaskId {
+        let id = self.insert(class);
+        self.task(id).entity.borrow_mut().place(Wrapping(vruntime));
+        self.ready.insert(id);
+        id
+    }
+
+    fn task(&self, id: TaskId) -> &SimTask {
+        &self.tasks[&id]
+    }
+
+    pub fn current(&self) -> Option<TaskId> {
+        self.current
+    }
+
+    pub fn ran(&self, id: TaskId) -> u64 {
+        self.task(id).ran.get()
+    }
+
+    /// Longest time `id` waited to run while runnable, up to now.
+    pub fn longest_wait(&self, id: TaskId) -> u64 {
+        let task = self.task(id);
+        let since = task.last_ran.get().map_or(self.now, |x| self.now - x);
+        task.longest_wait.get().max(since)
+    }
+
+    pub fn vruntime(&self, id: TaskId) -> Wrapping<usize> {
+        self.task(id).entity.borrow().vruntime()
+    }
+
+    /// Weighted average vruntime of the current and ready tasks.
+    pub fn average_vruntime(&self) -> Option<Wrapping<usize>> {
+        let current = self.current.iter().chain(&self.ready);
+        average_vruntime(current.map(|id| self.task(*id)))
+    }
+
+    pub fn set_priority(&mut self, id: TaskId, class: Priority) {
+        self.task(id).entity.borrow_mut().set_class(class);
+        self.needs_reschedule = true;
+    }
+
+    fn reschedule(&mut self) {
+        let tasks = &self.tasks;
+        let current = self.current.map(|id| &tasks[&id]);
+        place(
+            current,
+            self.ready.iter().map(|id| &tasks[id]),
+            self.waking.iter().map(|id| &tasks[id]),
+        );
+        self.ready.extend(self.waking.drain(..));
+
+        let next = pick_next(current, self.ready.iter().map(|id| &tasks[id])).map(|t| t.id);
+
+        if next != self.current {
+            if let Some(last) = self.current {
+                self.ready.insert(last);
+            }
+            if let Some(next) = next {
+                self.ready.remove(&next);
+            }
+            self.current = next;
+        }
+        self.needs_reschedule = false;
+    }
+
+    /// One timer tick: the current task runs for it, then a new one is picked if needed.
+    pub fn tick(&mut self) {
+        if self.current.is_none() || self.needs_reschedule {
+            self.reschedule();
+        }
+
+        if let Some(id) = self.current {
+            let task = &self.tasks[&id];
+            if let Some(last) = task.last_ran.get() {
+                let waited = self.now - last - 1;
+                task.longest_wait.set(task.longest_wait.get().max(waited));
+            }
+            task.ran.set(task.ran.get() + 1);
+            task.last_ran.set(Some(self.now));
+            if task.entity.borrow_mut().tick() {
+                self.needs_reschedule = true;
+            }
+        }
+        self.now += 1;
+    }
+
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// Puts the current task to sleep.
+    pub fn sleep(&mut self) -> TaskId {
+        let id = self.current.take().expect("A running task");
+        self.sleeping.insert(id);
+        self.reschedule();
+        id
+    }
+
+    pub fn wake(&mut self, id: TaskId) {
+        assert!(self.sleeping.remove(&id), "Task {id} isn't sleeping");
+        // Waiting while asleep doesn't count
+        self.task(id).last_ran.set(Some(self.now));
+        self.waking.push(id);
+        if self.task(id).entity.borrow().realtime().is_some() {
+            self.needs_reschedule = true;
+        }
+    }
+
+    /// Runs until `id` is the current task, up to `limit` ticks.
+    pub fn run_until_current(&mut self, id: TaskId, limit: u64) {
+        for _ in 0..limit {
+            if self.current == Some(id) {
+                return;
+            }
+            self.tick();
+        }
+        panic!("Task {id} didn't run within {limit} ticks");
+    }
+}
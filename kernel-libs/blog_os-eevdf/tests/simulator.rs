@@ -0,0 +1,193 @@
+use std::num::Wrapping;
+
+use blog_os_eevdf::{NICE_TO_WEIGHT, weight};
+use blog_os_syscalls::priority::{NICE_MIN, Priority};
+
+use crate::common::Sim;
+
+mod common;
+
+const NICE_0: Priority = Priority::Normal(0);
+
+fn assert_share(ran: u64, expected: f64, tolerance: f64) {
+    let ran = ran as f64;
+    assert!(
+        (ran - expected).abs() <= expected * tolerance,
+        "ran {ran} ticks, expected {expected} ± {}%",
+        tolerance * 100.0
+    );
+}
+
+#[test]
+fn weights_follow_nice_values() {
+    assert_eq!(weight(NICE_0).get(), 1024);
+    assert_eq!(weight(Priority::Normal(NICE_MIN)).get(), NICE_TO_WEIGHT[0]);
+    assert_eq!(weight(Priority::RealTime(50)).get(), 1024);
+    assert!(NICE_TO_WEIGHT.windows(2).all(|w| w[0] > w[1]));
+}
+
+#[test]
+fn equal_tasks_share_evenly() {
+    let mut sim = Sim::new();
+    let tasks: Vec<_> = (0..3).map(|_| sim.spawn(NICE_0)).collect();
+
+    sim.run(30_000);
+
+    for id in tasks {
+        assert_share(sim.ran(id), 10_000.0, 0.05);
+    }
+}
+
+#[test]
+fn nice_values_weight_the_share() {
+    let mut sim = Sim::new();
+    let fast = sim.spawn(Priority::Normal(0));
+    let slow = sim.spawn(Priority::Normal(5));
+
+    sim.run(100_000);
+
+    let total = (1024 + 335) as f64;
+    assert_share(sim.ran(fast), 100_000.0 * 1024.0 / total, 0.05);
+    assert_share(sim.ran(slow), 100_000.0 * 335.0 / total, 0.05);
+}
+
+#[test]
+fn lowest_weight_is_not_starved() {
+    let mut sim = Sim::new();
+    let heavy: Vec<_> = (0..9).map(|_| sim.spawn(Priority::Normal(-20))).collect();
+    let light = sim.spawn(Priority::Normal(19));
+
+    sim.run(1_000_000);
+
+    // Its fair share is one tick in about 53000
+    let total = (9 * 88761 + 15) as f64;
+    let period = total / 15.0;
+    assert!(sim.ran(light) > 0);
+    assert!(
+        (sim.longest_wait(light) as f64) < 2.0 * period,
+        "waited {} ticks",
+        sim.longest_wait(light)
+    );
+    for id in heavy {
+        assert!(
+            sim.longest_wait(id) < 2_000,
+            "waited {}",
+            sim.longest_wait(id)
+        );
+    }
+}
+
+#[test]
+fn sleeper_does_not_catch_up() {
+    let mut sim = Sim::new();
+    let sleeper = sim.spawn(NICE_0);
+    let other = sim.spawn(NICE_0);
+
+    sim.run_until_current(sleeper, 1_000);
+    sim.sleep();
+    sim.run(10_000);
+    sim.wake(sleeper);
+
+    // Without placing it at the current vruntime, it would run alone for about 10000 ticks
+    let before = (sim.ran(sleeper), sim.ran(other));
+    sim.run(2_000);
+    assert_share(sim.ran(sleeper) - before.0, 1_000.0, 0.3);
+    assert_share(sim.ran(other) - before.1, 1_000.0, 0.3);
+}
+
+#[test]
+fn vruntime_wraps_around() {
+    let mut sim = Sim::new();
+    let start = usize::MAX - 1_000_000;
+    let tasks: Vec<_> = (0..2).map(|_| sim.spawn_at(NICE_0, start)).collect();
+    let late = sim.spawn_at(NICE_0, start + 10_000);
+
+    sim.run(30_000);
+
+    for &id in &tasks {
+        // 30000 ticks at 1024 each went past the end of the range
+        assert!(sim.vruntime(id).0 < start);
+        assert_share(sim.ran(id), 10_000.0, 0.05);
+    }
+    assert_share(sim.ran(late), 10_000.0, 0.05);
+}
+
+#[test]
+fn wrapped_task_does_not_look_ahead() {
+    let mut sim = Sim::new();
+    // Just past the wrap, it is ahead of the other by 2000 vruntime and runs second
+    let wrapped = sim.spawn_at(NICE_0, 1_000);
+    let before = sim.spawn_at(NICE_0, usize::MAX - 1_000);
+
+    sim.tick();
+    assert_eq!(sim.current(), Some(before));
+    sim.run_until_current(wrapped, 1_000);
+}
+
+#[test]
+fn average_vruntime_is_weighted() {
+    let mut sim = Sim::new();
+    // 3121 and 1024, the heavy task pulls the average toward it
+    sim.spawn_at(Priority::Normal(-5), 0);
+    sim.spawn_at(NICE_0, 4_145);
+    // Real-time tasks have no lag
+    sim.spawn_at(Priority::RealTime(1), 1_000_000);
+    assert_eq!(sim.average_vruntime(), Some(Wrapping(1_024)));
+}
+
+#[test]
+fn average_vruntime_wraps_around() {
+    let mut sim = Sim::new();
+    sim.spawn_at(NICE_0, 1_000);
+    sim.spawn_at(NICE_0, usize::MAX - 1_001);
+    // Halfway through the 2002 between them
+    assert_eq!(sim.average_vruntime(), Some(Wrapping(usize::MAX)));
+}
+
+#[test]
+fn realtime_preempts_normal_tasks() {
+    let mut sim = Sim::new();
+    let normal = sim.spawn(NICE_0);
+    sim.run(100);
+
+    let realtime = sim.spawn(Priority::RealTime(10));
+    let before = sim.ran(normal);
+    sim.run(10_000);
+
+    assert_eq!(sim.ran(normal), before);
+    assert_eq!(sim.ran(realtime), 10_000);
+
+    sim.sleep();
+    sim.run(100);
+    assert_eq!(sim.ran(normal), before + 100);
+}
+
+#[test]
+fn higher_realtime_priority_runs_first() {
+    let mut sim = Sim::new();
+    let low = sim.spawn(Priority::RealTime(10));
+    let high = sim.spawn(Priority::RealTime(50));
+
+    sim.run(1_000);
+    assert_eq!(sim.ran(high), 1_000);
+    assert_eq!(sim.ran(low), 0);
+
+    sim.set_priority(high, NICE_0);
+    sim.run(1_000);
+    assert_eq!(sim.ran(low), 1_000);
+}
+
+#[test]
+fn lowering_to_normal_rejoins_the_share() {
+    let mut sim = Sim::new();
+    let tasks: Vec<_> = (0..2).map(|_| sim.spawn(NICE_0)).collect();
+    let realtime = sim.spawn(Priority::RealTime(1));
+    sim.run(1_000);
+    assert_eq!(sim.ran(realtime), 1_000);
+
+    sim.set_priority(realtime, NICE_0);
+    sim.run(30_000);
+    for id in tasks.into_iter().chain([realtime]) {
+        assert_share(sim.ran(id), 10_000.0, 0.1);
+    }
+}
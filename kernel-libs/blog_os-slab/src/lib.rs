@@ -0,0 +1,325 @@
+//! Slab allocator for fixed-size objects.
+//!
+//! A cache hands out objects of a single layout, carved from slabs: blocks aligned on their size
+//! that start with a header. The free objects of a slab are linked through their first bytes and
+//! the slabs with free objects are linked in the cache, so allocating and freeing take constant
+//! time. The slab of an object is found by aligning its address down to the slab size.
+//!
+//! With poisoning, free objects are filled with [`POISON_FREE`], which is checked when they are
+//! handed out again to catch writes after free. Objects are handed out filled with
+//! [`POISON_ALLOC`], so that reads of uninitialized memory stand out.
+#![no_std]
+
+use core::{
+    alloc::Layout,
+    ptr::{self, NonNull},
+};
+
+/// Filling of free objects, past their link
+pub const POISON_FREE: u8 = 0x6b;
+/// Filling of objects as they are handed out
+pub const POISON_ALLOC: u8 = 0x5a;
+/// Size of the smallest slabs, a page
+pub const MIN_SLAB_SIZE: usize = 4096;
+/// Objects a slab holds at least, slabs growing for large objects
+pub const MIN_OBJECTS: usize = 8;
+
+/// Bytes at the start of a free object linking it to the next one
+const LINK: usize = size_of::<*mut u8>();
+/// Bytes of the slab header
+const HEADER: usize = size_of::<Slab>();
+
+/// Memory the slabs are carved from.
+pub trait SlabSource {
+    /// Allocates `size` bytes aligned on `size`, a power of two.
+    fn allocate_slab(&mut self, size: usize) -> Option<NonNull<u8>>;
+
+    /// Gives back a slab.
+    ///
+    /// # Safety
+    /// `slab` must come from `allocate_slab(size)` on this source.
+    unsafe fn free_slab(&mut self, slab: NonNull<u8>, size: usize);
+}
+
+/// Header at the start of each slab.
+#[repr(C)]
+struct Slab {
+    prev: *mut Slab,
+    next: *mut Slab,
+    /// First free object, null if the slab is full
+    free: *mut u8,
+    /// Objects handed out
+    in_use: usize,
+}
+
+/// Usage of a cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Bytes taken by an object, with padding
+    pub object_size: usize,
+    pub slab_size: usize,
+    pub objects_per_slab: usize,
+    /// Objects handed out
+    pub active_objects: usize,
+    /// Objects in all the slabs, handed out or not
+    pub total_objects: usize,
+    pub slabs: usize,
+    pub allocations: u64,
+    pub frees: u64,
+    /// Free objects found written to when handed out again
+    pub poison_errors: u64,
+}
+
+/// An object handed out by [`SlabCache::allocate`].
+#[derive(Debug)]
+pub struct Allocation {
+    pub ptr: NonNull<u8>,
+    /// Offset of the first byte written while the object was free, if poisoning caught one
+    pub corrupted_at: Option<usize>,
+}
+
+/// Objects of a single layout.
+#[derive(Debug)]
+pub struct SlabCache {
+    name: &'static str,
+    layout: Layout,
+    poison: bool,
+    /// Distance between objects
+    stride: usize,
+    slab_size: usize,
+    /// Offset of the first object in a slab, after the header
+    first_object: usize,
+    objects_per_slab: usize,
+    /// Slabs with free objects
+    partial: *mut Slab,
+    /// Slabs without any object handed out, all on the partial list
+    empty: usize,
+    stats: CacheStats,
+}
+
+// Safety: the slabs are only reached through the cache
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    /// Creates an empty cache for objects of `layout`, poisoning them if `poison` is set.
+    pub const fn new(name: &'static str, layout: Layout, poison: bool) -> Self {
+        let align = if layout.align() > align_of::<*mut u8>() {
+            layout.align()
+        } else {
+            align_of::<*mut u8>()
+        };
+        let size = if layout.size() > LINK {
+            layout.size()
+        } else {
+            LINK
+        };
+        let stride = size.next_multiple_of(align);
+        let first_object = HEADER.next_multiple_of(align);
+        let mut slab_size = MIN_SLAB_SIZE;
+        while slab_size < first_object + MIN_OBJECTS * stride {
+            slab_size *= 2;
+        }
+        let objects_per_slab = (slab_size - first_object) / stride;
+
+        Self {
+            name,
+            layout,
+            poison,
+            stride,
+            slab_size,
+            first_object,
+            objects_per_slab,
+            partial: ptr::null_mut(),
+            empty: 0,
+            stats: CacheStats {
+                object_size: stride,
+                slab_size,
+                objects_per_slab,
+                active_objects: 0,
+                total_objects: 0,
+                slabs: 0,
+                allocations: 0,
+                frees: 0,
+                poison_errors: 0,
+            },
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Layout of the objects the cache is for.
+    pub const fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub const fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Hands out an object, taking a new slab from `source` if every slab is full.
+    pub fn allocate(&mut self, source: &mut impl SlabSource) -> Option<Allocation> {
+        if self.partial.is_null() {
+            self.grow(source)?;
+        }
+
+        let slab = self.partial;
+        // Safety: slabs on the partial list are live and have a free object
+        unsafe {
+            let object = (*slab).free;
+            (*slab).free = object.cast::<*mut u8>().read();
+            if (*slab).in_use == 0 {
+                self.empty -= 1;
+            }
+            (*slab).in_use += 1;
+            if (*slab).free.is_null() {
+                self.unlink(slab);
+            }
+
+            let mut corrupted_at = None;
+            if self.poison {
+                corrupted_at = (LINK..self.stride).find(|&i| object.add(i).read() != POISON_FREE);
+                if corrupted_at.is_some() {
+                    self.stats.poison_errors += 1;
+                }
+                object.write_bytes(POISON_ALLOC, self.stride);
+            }
+
+            self.stats.active_objects += 1;
+            self.stats.allocations += 1;
+            Some(Allocation {
+                ptr: NonNull::new_unchecked(object),
+                corrupted_at,
+            })
+        }
+    }
+
+    /// Takes back an object, giving its slab back to `source` if it was the last object of the
+    /// slab and another slab is empty already.
+    ///
+    /// # Safety
+    /// `ptr` must come from [`Self::allocate`] on this cache and not have been freed since.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, source: &mut impl SlabSource) {
+        let object = ptr.as_ptr();
+        let slab = object
+            .map_addr(|addr| addr & !(self.slab_size - 1))
+            .cast::<Slab>();
+        debug_assert_eq!(
+            (object.addr() - slab.addr() - self.first_object) % self.stride,
+            0,
+            "{:p} is not an object of {}",
+            object,
+            self.name
+        );
+
+        // Safety: the object belongs to a live slab
+        unsafe {
+            if self.poison {
+                object.write_bytes(POISON_FREE, self.stride);
+            }
+            let was_full = (*slab).free.is_null();
+            object.cast::<*mut u8>().write((*slab).free);
+            (*slab).free = object;
+            (*slab).in_use -= 1;
+            if was_full {
+                self.link(slab);
+            }
+            if (*slab).in_use == 0 {
+                if self.empty > 0 {
+                    self.unlink(slab);
+                    self.release(slab, source);
+                } else {
+                    self.empty += 1;
+                }
+            }
+        }
+
+        self.stats.active_objects -= 1;
+        self.stats.frees += 1;
+    }
+
+    /// Gives every empty slab back to `source`.
+    pub fn shrink(&mut self, source: &mut impl SlabSource) {
+        let mut slab = self.partial;
+        while !slab.is_null() {
+            // Safety: the slab is on the partial list
+            unsafe {
+                let next = (*slab).next;
+                if (*slab).in_use == 0 {
+                    self.unlink(slab);
+                    self.release(slab, source);
+                    self.empty -= 1;
+                }
+                slab = next;
+            }
+        }
+    }
+
+    /// Takes a new slab from `source` and puts it on the partial list.
+    fn grow(&mut self, source: &mut impl SlabSource) -> Option<()> {
+        let slab = source.allocate_slab(self.slab_size)?.as_ptr();
+        let objects = (0..self.objects_per_slab)
+            .map(|i| slab.wrapping_add(self.first_object + i * self.stride));
+
+        // Safety: the slab is ours and large enough for the header and objects
+        unsafe {
+            let mut next = ptr::null_mut::<u8>();
+            for object in objects.rev() {
+                if self.poison {
+                    object.write_bytes(POISON_FREE, self.stride);
+                }
+                object.cast::<*mut u8>().write(next);
+                next = object;
+            }
+            let slab = slab.cast::<Slab>();
+            slab.write(Slab {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                free: next,
+                in_use: 0,
+            });
+            self.link(slab);
+        }
+
+        self.empty += 1;
+        self.stats.slabs += 1;
+        self.stats.total_objects += self.objects_per_slab;
+        Some(())
+    }
+
+    /// Gives an empty slab, off the partial list, back to `source`.
+    unsafe fn release(&mut self, slab: *mut Slab, source: &mut impl SlabSource) {
+        // Safety: the slab came from the source
+        unsafe { source.free_slab(NonNull::new_unchecked(slab.cast()), self.slab_size) };
+        self.stats.slabs -= 1;
+        self.stats.total_objects -= self.objects_per_slab;
+    }
+
+    /// Pushes a slab on the partial list.
+    unsafe fn link(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = ptr::null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    /// Takes a slab off the partial list.
+    unsafe fn unlink(&mut self, slab: *mut Slab) {
+        unsafe {
+            let Slab { prev, next, .. } = *slab;
+            if prev.is_null() {
+                self.partial = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::{alloc::Layout, collections::HashSet, ptr::NonNull};
+
+use blog_os_slab::{MIN_OBJECTS, MIN_SLAB_SIZE, POISON_ALLOC, SlabCache};
+
+use crate::common::HostSource;
+
+mod common;
+
+/// Allocates `count` objects, panicking if the source runs out.
+fn take(cache: &mut SlabCache, source: &mut HostSource, count: usize) -> Vec<NonNull<u8>> {
+    (0..count)
+        .map(|_| cache.allocate(source).expect("a slab").ptr)
+        .collect()
+}
+
+fn give_back(cache: &mut SlabCache, source: &mut HostSource, objects: Vec<NonNull<u8>>) {
+    for object in objects {
+        unsafe { cache.deallocate(object, source) };
+    }
+}
+
+#[test]
+fn small_objects_fill_a_page() {
+    let cache = SlabCache::new("small", Layout::new::<u8>(), false);
+    let stats = cache.stats();
+    // Room for the link
+    assert_eq!(stats.object_size, 8);
+    assert_eq!(stats.slab_size, MIN_SLAB_SIZE);
+    assert!(stats.objects_per_slab > 500);
+}
+
+#[test]
+fn large_objects_get_larger_slabs() {
+    let cache = SlabCache::new("large", Layout::from_size_align(1000, 8).unwrap(), false);
+    let stats = cache.stats();
+    assert_eq!(stats.object_size, 1000);
+    assert_eq!(stats.slab_size, 2 * MIN_SLAB_SIZE);
+    assert!(stats.objects_per_slab >= MIN_OBJECTS);
+}
+
+#[test]
+fn objects_are_distinct_and_aligned() {
+    let layout = Layout::from_size_align(48, 64).unwrap();
+    let mut cache = SlabCache::new("aligned", layout, false);
+    let mut source = HostSource::default();
+    let count = cache.stats().objects_per_slab * 3 + 1;
+
+    let objects = take(&mut cache, &mut source, count);
+    assert!(objects.iter().all(|p| p.as_ptr().addr().is_multiple_of(64)));
+    let distinct: HashSet<_> = objects.iter().map(|p| p.as_ptr().addr()).collect();
+    assert_eq!(distinct.len(), count);
+    // The objects don't overlap
+    let mut addrs: Vec<_> = distinct.into_iter().collect();
+    addrs.sort();
+    assert!(addrs.windows(2).all(|w| w[1] - w[0] >= 64));
+
+    let stats = cache.stats();
+    assert_eq!(stats.slabs, 4);
+    assert_eq!(source.live, 4);
+    assert_eq!(stats.active_objects, count);
+    assert_eq!(stats.total_objects, 4 * stats.objects_per_slab);
+
+    give_back(&mut cache, &mut source, objects);
+}
+
+#[test]
+fn freeing_keeps_a_single_empty_slab() {
+    let mut cache = SlabCache::new("keep", Layout::new::<[u64; 32]>(), false);
+    let mut source = HostSource::default();
+    let per_slab = cache.stats().objects_per_slab;
+
+    let objects = take(&mut cache, &mut source, per_slab * 3);
+    assert_eq!(source.live, 3);
+    give_back(&mut cache, &mut source, objects);
+
+    let stats = cache.stats();
+    assert_eq!(stats.active_objects, 0);
+    assert_eq!(stats.slabs, 1);
+    assert_eq!(source.live, 1);
+    assert_eq!(stats.allocations, stats.frees);
+
+    cache.shrink(&mut source);
+    assert_eq!(cache.stats().slabs, 0);
+    assert_eq!(source.live, 0);
+}
+
+#[test]
+fn freed_objects_are_reused_first() {
+    let mut cache = SlabCache::new("reuse", Layout::new::<[u64; 4]>(), false);
+    let mut source = HostSource::default();
+
+    let objects = take(&mut cache, &mut source, 10);
+    let freed = objects[4];
+    unsafe { cache.deallocate(freed, &mut source) };
+    assert_eq!(cache.allocate(&mut source).unwrap().ptr, freed);
+    assert_eq!(source.live, 1);
+
+    give_back(&mut cache, &mut source, objects);
+}
+
+#[test]
+fn full_slabs_come_back_to_the_partial_list() {
+    let mut cache = SlabCache::new("full", Layout::new::<[u64; 64]>(), false);
+    let mut source = HostSource {
+        limit: Some(1),
+        ..Default::default()
+    };
+    let per_slab = cache.stats().objects_per_slab;
+
+    let mut objects = take(&mut cache, &mut source, per_slab);
+    assert!(cache.allocate(&mut source).is_none());
+
+    let freed = objects.pop().unwrap();
+    unsafe { cache.deallocate(freed, &mut source) };
+    assert_eq!(cache.allocate(&mut source).unwrap().ptr, freed);
+    objects.push(freed);
+
+    give_back(&mut cache, &mut source, objects);
+}
+
+#[test]
+fn poisoning_fills_objects() {
+    let mut cache = SlabCache::new("poison", Layout::new::<[u8; 40]>(), true);
+    let mut source = HostSource::default();
+
+    let allocation = cache.allocate(&mut source).unwrap();
+    assert_eq!(allocation.corrupted_at, None);
+    let bytes = unsafe { std::slice::from_raw_parts(allocation.ptr.as_ptr(), 40) };
+    assert!(bytes.iter().all(|&b| b == POISON_ALLOC));
+
+    unsafe { cache.deallocate(allocation.ptr, &mut source) };
+}
+
+#[test]
+fn poisoning_catches_writes_after_free() {
+    let mut cache = SlabCache::new("poison", Layout::new::<[u8; 40]>(), true);
+    let mut source = HostSource::default();
+
+    let object = cache.allocate(&mut source).unwrap().ptr;
+    unsafe { cache.deallocate(object, &mut source) };
+    // Writing to the object after freeing it
+    unsafe { object.as_ptr().add(20).write(1) };
+
+    let allocation = cache.allocate(&mut source).unwrap();
+    assert_eq!(allocation.ptr, object);
+    assert_eq!(allocation.corrupted_at, Some(20));
+    assert_eq!(cache.stats().poison_errors, 1);
+
+    unsafe { cache.deallocate(allocation.ptr, &mut source) };
+}
@@ -0,0 +1,32 @@
+//! Slabs taken from the host allocator.
+
+use std::{
+    alloc::{Layout, alloc, dealloc},
+    ptr::NonNull,
+};
+
+use blog_os_slab::SlabSource;
+
+/// Hands out slabs from the host heap, counting the live ones.
+#[derive(Debug, Default)]
+pub struct HostSource {
+    pub live: usize,
+    /// Refuses new slabs once `live` reaches it
+    pub limit: Option<usize>,
+}
+
+impl SlabSource for HostSource {
+    fn allocate_slab(&mut self, size: usize) -> Option<NonNull<u8>> {
+        if self.limit.is_some_and(|limit| self.live >= limit) {
+            return None;
+        }
+        let slab = NonNull::new(unsafe { alloc(Layout::from_size_align(size, size).unwrap()) })?;
+        self.live += 1;
+        Some(slab)
+    }
+
+    unsafe fn free_slab(&mut self, slab: NonNull<u8>, size: usize) {
+        self.live -= 1;
+        unsafe { dealloc(slab.as_ptr(), Layout::from_size_align(size, size).unwrap()) };
+    }
+}
@@ -0,0 +1,7 @@
+//! Flags of `BRK`, passed as its second argument.
+
+/// Maps the growth with 2 MiB pages where they fit, rounding the break up to 2 MiB.
+///
+/// Large heaps then take fewer TLB entries and page tables, at the cost of memory for small
+/// ones.
+pub const HUGE_PAGES: u64 = 1 << 0;
@@ -4,6 +4,9 @@ use core::num::TryFromIntError;
 
 pub use num_enum::{IntoPrimitive, TryFromPrimitive, TryFromPrimitiveError};
 
+pub mod brk;
+pub mod priority;
+
 macro_rules! enum_with_max {
     (
         $(#[$meta:meta])*
@@ -63,7 +66,21 @@ enum_with_max! {
         INIT_DRIVER,
         DELETE_DRIVER,
         STAT,
-        NEXT_DIRENTRY
+        NEXT_DIRENTRY,
+        TRUNCATE,
+        FTRUNCATE,
+        PREAD,
+        PWRITE,
+        READV,
+        WRITEV,
+        IOCTL,
+        OPENAT,
+        STATAT,
+        MKDIRAT,
+        SET_PRIORITY,
+        GET_PRIORITY,
+        PIPE,
+        CHDIR,
     }
 }
 
@@ -0,0 +1,148 @@
+//! Scheduling priorities, as passed to `SET_PRIORITY` and returned by `GET_PRIORITY`.
+
+use core::{fmt, str::FromStr};
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+pub const RT_PRIORITY_MIN: u8 = 1;
+pub const RT_PRIORITY_MAX: u8 = 99;
+
+const CLASS_NORMAL: u64 = 0;
+const CLASS_REALTIME: u64 = 1;
+
+/// Scheduling class and priority of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Shares the CPU with the other normal tasks, weighted by its nice value.
+    /// Lower values get more CPU time.
+    Normal(i8),
+    /// Runs before every normal task, and before real-time tasks with a lower priority.
+    RealTime(u8),
+}
+
+impl Priority {
+    pub const DEFAULT: Self = Self::Normal(0);
+
+    pub const fn is_valid(self) -> bool {
+        match self {
+            Self::Normal(nice) => nice >= NICE_MIN && nice <= NICE_MAX,
+            Self::RealTime(prio) => prio >= RT_PRIORITY_MIN && prio <= RT_PRIORITY_MAX,
+        }
+    }
+
+    /// Packs the priority in a syscall argument: the class in bits 8 to 15, the value below.
+    pub const fn encode(self) -> u64 {
+        match self {
+            Self::Normal(nice) => (CLASS_NORMAL << 8) | nice as u8 as u64,
+            Self::RealTime(prio) => (CLASS_REALTIME << 8) | prio as u64,
+        }
+    }
+
+    /// Unpacks a priority packed by [`Self::encode`], rejecting invalid ones.
+    pub const fn decode(value: u64) -> Option<Self> {
+        let priority = match value >> 8 {
+            CLASS_NORMAL => Self::Normal(value as u8 as i8),
+            CLASS_REALTIME => Self::RealTime(value as u8),
+            _ => return None,
+        };
+        if priority.is_valid() {
+            Some(priority)
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Formats as `normal <nice>` or `realtime <priority>`.
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Normal(nice) => write!(f, "normal {nice}"),
+            Self::RealTime(prio) => write!(f, "realtime {prio}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsePriorityError;
+
+impl FromStr for Priority {
+    type Err = ParsePriorityError;
+
+    /// Parses the [`Display`](fmt::Display) form. A lone number is a nice value.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let priority = match (words.next(), words.next(), words.next()) {
+            (Some("normal"), Some(nice), None) => {
+                Self::Normal(nice.parse().map_err(|_| ParsePriorityError)?)
+            }
+            (Some("realtime"), Some(prio), None) => {
+                Self::RealTime(prio.parse().map_err(|_| ParsePriorityError)?)
+            }
+            (Some(nice), None, None) => Self::Normal(nice.parse().map_err(|_| ParsePriorityError)?),
+            _ => return Err(ParsePriorityError),
+        };
+        if priority.is_valid() {
+            Ok(priority)
+        } else {
+            Err(ParsePriorityError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn encode_roundtrip() {
+        for nice in NICE_MIN..=NICE_MAX {
+            let priority = Priority::Normal(nice);
+            assert_eq!(Priority::decode(priority.encode()), Some(priority));
+        }
+        for prio in RT_PRIORITY_MIN..=RT_PRIORITY_MAX {
+            let priority = Priority::RealTime(prio);
+            assert_eq!(Priority::decode(priority.encode()), Some(priority));
+        }
+    }
+
+    #[test]
+    fn decode_rejects_invalid() {
+        assert_eq!(Priority::decode(Priority::Normal(20).encode()), None);
+        assert_eq!(Priority::decode(Priority::RealTime(0).encode()), None);
+        assert_eq!(Priority::decode(Priority::RealTime(100).encode()), None);
+        assert_eq!(Priority::decode(2 << 8), None);
+    }
+
+    #[test]
+    fn text_roundtrip() {
+        for priority in [
+            Priority::Normal(-20),
+            Priority::Normal(5),
+            Priority::RealTime(50),
+        ] {
+            assert_eq!(priority.to_string().parse(), Ok(priority));
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(" normal  10\n".parse(), Ok(Priority::Normal(10)));
+        assert_eq!("-5".parse(), Ok(Priority::Normal(-5)));
+        assert_eq!("realtime 99".parse(), Ok(Priority::RealTime(99)));
+        assert_eq!("realtime".parse::<Priority>(), Err(ParsePriorityError));
+        assert_eq!("normal 1 2".parse::<Priority>(), Err(ParsePriorityError));
+        assert_eq!("batch 1".parse::<Priority>(), Err(ParsePriorityError));
+        assert_eq!("normal -21".parse::<Priority>(), Err(ParsePriorityError));
+    }
+}
@@ -1,3 +1,4 @@
+use alloc::{boxed::Box, vec::Vec};
 use core::num::NonZeroU64;
 
 use kernel_utils::smallmap::SmallBTreeMap;
@@ -25,8 +26,12 @@ impl DEntryStatus {
     }
 }
 
+/// A cached entry, boxed so that entries have their own allocations, which the kernel takes from
+/// an object cache.
+pub type CachedDEntry = (DEntry, DEntryStatus);
+
 pub struct DEntryCache {
-    map: SmallBTreeMap<1, PathBuf, (DEntry, DEntryStatus)>,
+    map: SmallBTreeMap<1, PathBuf, Box<CachedDEntry>>,
     version: NonZeroU64, // TODO implement cleaning
 }
 
@@ -39,7 +44,8 @@ impl DEntryCache {
     }
 
     pub fn get_mut(&mut self, key: &Path) -> Option<&mut DEntry> {
-        self.map.get_mut(key).map(|(x, status)| {
+        self.map.get_mut(key).map(|entry| {
+            let (x, status) = &mut **entry;
             status.set_version(self.version);
             self.version = self.version.saturating_add(1);
             x
@@ -47,17 +53,33 @@ impl DEntryCache {
     }
 
     pub fn add_mountpoint(&mut self, key: PathBuf, entry: DEntry) {
-        self.map.insert(key, (entry, DEntryStatus::MountPoint));
+        self.map
+            .insert(key, Box::new((entry, DEntryStatus::MountPoint)));
     }
 
     pub fn add_cached(&mut self, key: PathBuf, entry: DEntry) {
-        self.map
-            .insert(key, (entry, DEntryStatus::LastAccess(self.version)));
+        self.map.insert(
+            key,
+            Box::new((entry, DEntryStatus::LastAccess(self.version))),
+        );
         self.version = self.version.saturating_add(1);
     }
 
     pub fn remove(&mut self, key: &Path) -> Option<DEntry> {
-        self.map.remove(key).map(|(x, _)| x)
+        self.map.remove(key).map(|entry| entry.0)
+    }
+
+    /// Removes the entry of `key` and those of the paths below it.
+    pub fn remove_below(&mut self, key: &Path) {
+        let below: Vec<PathBuf> = self
+            .map
+            .iter()
+            .filter(|(path, _)| path.relative(key).is_some())
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in below {
+            self.map.remove(&path);
+        }
     }
 
     pub fn find_greatest<'a, 'b>(
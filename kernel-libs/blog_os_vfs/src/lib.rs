@@ -11,7 +11,7 @@ use blog_os_vfs_api::{
     path::ffi::pathbuf_into_ffi_ref,
 };
 
-use alloc::{borrow::ToOwned, collections::btree_map::BTreeMap, string::String};
+use alloc::{borrow::ToOwned, collections::btree_map::BTreeMap, string::String, vec::Vec};
 use log::{debug, warn};
 
 use crate::{
@@ -40,10 +40,20 @@ impl INodeRef {
     }
 }
 
+/// How the mounted filesystems fit together.
+#[derive(Default)]
+struct Mounts {
+    /// Roots of the filesystems mounted over a directory, by the directory
+    covered: BTreeMap<(FsIdx, u64), INodeRef>,
+    /// Where each filesystem is mounted, for `..` to leave its root
+    paths: BTreeMap<FsIdx, PathBuf>,
+}
+
 pub struct VFS {
     filesystems: BTreeMap<String, FilesystemBox<'static>>,
     superblocks: slotmap::SlotMap<FsIdx, SuperblockBox<'static>>,
     dentry_cache: DEntryCache,
+    mounts: Mounts,
 }
 
 #[derive(Debug)]
@@ -55,6 +65,7 @@ impl VFS {
             filesystems: Default::default(),
             superblocks: Default::default(),
             dentry_cache: DEntryCache::new(),
+            mounts: Default::default(),
         }
     }
 
@@ -75,10 +86,13 @@ impl VFS {
         self.filesystems.remove(fs)
     }
 
+    /// Mounts `fs` at `path`, over the directory `dir` if there was one.
     fn mount_fs(
         superblocks: &mut slotmap::SlotMap<FsIdx, SuperblockBox<'static>>,
         dentry_cache: &mut DEntryCache,
+        mounts: &mut Mounts,
         path: PathBuf,
+        dir: Option<INodeRef>,
         dev: Option<&PathBuf>,
         fs: &FilesystemBox<'static>,
     ) -> Option<FsIdx> {
@@ -86,12 +100,12 @@ impl VFS {
 
         let root_inode = superblock.get_root_inode_ref();
         let fs = superblocks.insert(superblock);
-        dentry_cache.add_mountpoint(
-            path,
-            DEntry {
-                inode: INodeRef(fs, root_inode),
-            },
-        );
+        let root = INodeRef(fs, root_inode);
+        dentry_cache.add_mountpoint(path.clone(), DEntry { inode: root });
+        mounts.paths.insert(fs, path);
+        if let Some(INodeRef(dir_fs, dir_inode)) = dir {
+            mounts.covered.insert((dir_fs, dir_inode.0), root);
+        }
         Some(fs)
     }
 
@@ -101,24 +115,152 @@ impl VFS {
         dev: Option<&PathBuf>,
         fs_type: &str,
     ) -> Option<FsIdx> {
+        let path = path.normalize();
+        let dir = self.get_ref(&path);
         let fs = self.filesystems.get(fs_type)?;
 
-        Self::mount_fs(&mut self.superblocks, &mut self.dentry_cache, path, dev, fs)
+        Self::mount_fs(
+            &mut self.superblocks,
+            &mut self.dentry_cache,
+            &mut self.mounts,
+            path,
+            dir,
+            dev,
+            fs,
+        )
     }
 
     pub fn mount(&mut self, path: PathBuf, dev: Option<&PathBuf>) -> Option<FsIdx> {
+        let path = path.normalize();
+        let dir = self.get_ref(&path);
         self.filesystems.values().find_map(|fs| {
             Self::mount_fs(
                 &mut self.superblocks,
                 &mut self.dentry_cache,
+                &mut self.mounts,
                 path.clone(),
+                dir,
                 dev,
                 fs,
             )
         })
     }
 
+    /// Unmounts the filesystem mounted last at `path`, uncovering what it was mounted over.
+    ///
+    /// Fails with `OperationNotPermitted` while another filesystem is mounted inside it, and with
+    /// `InvalidArgument` if nothing is mounted at `path`.
+    pub fn unmount(&mut self, path: &Path) -> Result<(), IOError> {
+        let path = path.normalize();
+        let root = self.get_ref(&path).ok_or(IOError::NotFound)?;
+        let INodeRef(fs, root_inode) = root;
+        let superblock = self.superblocks.get(fs).ok_or(IOError::NotFound)?;
+        if self.mounts.paths.get(&fs) != Some(&path)
+            || root_inode.0 != superblock.get_root_inode_ref().0
+        {
+            return Err(IOError::InvalidArgument);
+        }
+        if self.mounts.covered.keys().any(|&(dir_fs, _)| dir_fs == fs) {
+            return Err(IOError::OperationNotPermitted);
+        }
+
+        let uncovered = self
+            .mounts
+            .covered
+            .iter()
+            .find(|&(_, mounted)| mounted.0 == fs)
+            .map(|(&dir, _)| dir);
+        self.mounts.covered.retain(|_, mounted| mounted.0 != fs);
+        self.mounts.paths.remove(&fs);
+        self.dentry_cache.remove_below(&path);
+        // Stacked over another filesystem's root, which is the mount point again
+        if let Some((dir_fs, dir_inode)) = uncovered
+            && self.mounts.paths.get(&dir_fs) == Some(&path)
+        {
+            let inode = INodeRef(dir_fs, FsINodeRef(dir_inode));
+            self.dentry_cache.add_mountpoint(path, DEntry { inode });
+        }
+
+        if let Some(superblock) = self.superblocks.remove(fs) {
+            superblock.unmount();
+        }
+        Ok(())
+    }
+
+    /// Looks `components` up one by one from the directory `start`, entering the filesystems
+    /// mounted over the directories on the way. `visit` gets each component looked up with its
+    /// inode, but not `.` and `..`.
+    fn walk<'a>(
+        superblocks: &slotmap::SlotMap<FsIdx, SuperblockBox<'static>>,
+        mounts: &Mounts,
+        start: INodeRef,
+        components: impl Iterator<Item = &'a str>,
+        mut visit: impl FnMut(&'a str, INodeRef),
+    ) -> Option<INodeRef> {
+        // The directories walked through, which `..` goes back to
+        let mut parents = Vec::new();
+        let mut current = mounts.enter(start);
+        for (i, c) in components.enumerate() {
+            match c {
+                "." => (),
+                ".." => {
+                    current = match parents.pop() {
+                        Some(parent) => parent,
+                        None => Self::parent(superblocks, mounts, current)?,
+                    }
+                }
+                _ => {
+                    let INodeRef(fs, inode_ref) = current;
+                    let inode = superblocks.get(fs)?.get_inode(inode_ref).transpose()?;
+                    debug!("[{i}] looking up {c:?} in {inode_ref:x?}");
+                    parents.push(current);
+                    current = mounts.enter(INodeRef(fs, inode.lookup(c)?));
+                    visit(c, current);
+                }
+            }
+        }
+
+        Some(current)
+    }
+
+    /// Parent of the directory `dir`, when the walk didn't come through it.
+    ///
+    /// The root of a mounted filesystem has the parent of where it is mounted, and the root of
+    /// them all is its own parent. Other directories have the `..` of their filesystem.
+    fn parent(
+        superblocks: &slotmap::SlotMap<FsIdx, SuperblockBox<'static>>,
+        mounts: &Mounts,
+        dir: INodeRef,
+    ) -> Option<INodeRef> {
+        let INodeRef(fs, inode_ref) = dir;
+        let superblock = superblocks.get(fs)?;
+        if inode_ref.0 != superblock.get_root_inode_ref().0 {
+            let inode = superblock.get_inode(inode_ref).transpose()?;
+            return inode.lookup("..").map(|parent| INodeRef(fs, parent));
+        }
+
+        let Some(parent) = mounts.paths.get(&fs)?.parent() else {
+            return Some(dir);
+        };
+        let (&root_fs, _) = mounts
+            .paths
+            .iter()
+            .find(|(_, path)| **path == PathBuf::root())?;
+        let root = INodeRef(root_fs, superblocks.get(root_fs)?.get_root_inode_ref());
+        // The path is normalized, without any `..` bringing us back here
+        Self::walk(
+            superblocks,
+            mounts,
+            root,
+            parent.components().skip(1),
+            |_, _| (),
+        )
+    }
+
     pub fn get_ref(&mut self, path: &Path) -> Option<INodeRef> {
+        // Cached by their path without the detours
+        let path = path.normalize();
+        let path = path.as_path();
         let (dentry, greatest) = self.dentry_cache.find_greatest(path)?;
         debug!("GCD: {greatest} from {path}");
         if greatest == path {
@@ -130,27 +272,39 @@ impl VFS {
                 .expect("greatest is a prefix of path");
             debug!("Remaining: {remaining}");
             let mut current = greatest.to_owned();
-            let INodeRef(fs, mut inode_ref) = dentry.inode;
-            let superblock = &self.superblocks[fs];
-
-            for (i, c) in remaining.components().enumerate() {
-                let inode = superblock.get_inode(inode_ref).transpose()?;
-                // if i < remaining.len() {
-                debug!("[{i}] looking up {c:?} in {inode_ref:x?}");
-                inode_ref = inode.lookup(c)?;
-                current
-                    .push_component(c)
-                    .expect("component doesnt contain slash");
-                self.dentry_cache.add_cached(
-                    current.clone(),
-                    DEntry {
-                        inode: INodeRef(fs, inode_ref),
-                    },
-                );
-                // }
-            }
+            let start = dentry.inode;
+            let dentry_cache = &mut self.dentry_cache;
+
+            Self::walk(
+                &self.superblocks,
+                &self.mounts,
+                start,
+                remaining.components(),
+                |c, inode| {
+                    current
+                        .push_component(c)
+                        .expect("component doesnt contain slash");
+                    dentry_cache.add_cached(current.clone(), DEntry { inode });
+                },
+            )
+        }
+    }
 
-            Some(INodeRef(fs, inode_ref))
+    /// Resolves `path` starting from the directory `dir`.
+    ///
+    /// Absolute paths ignore `dir`, and relative paths without a directory start at the root.
+    /// Like absolute lookups, relative ones enter the filesystems mounted on their way.
+    pub fn get_ref_at(&mut self, dir: Option<INodeRef>, path: &Path) -> Option<INodeRef> {
+        match dir {
+            _ if path.is_absolute() => self.get_ref(path),
+            Some(dir) => Self::walk(
+                &self.superblocks,
+                &self.mounts,
+                dir,
+                path.components(),
+                |_, _| (),
+            ),
+            None => self.get_ref(&PathBuf::root().join(path)),
         }
     }
 
@@ -166,7 +320,18 @@ impl VFS {
         make_subdirs: bool,
         ignore_exists: bool,
     ) -> Result<INodeRef, IOError> {
-        let current = self.get_ref(path);
+        self.mkdir_at(None, path, make_subdirs, ignore_exists)
+    }
+
+    /// Like [`Self::mkdir`], with `path` resolved as in [`Self::get_ref_at`].
+    pub fn mkdir_at(
+        &mut self,
+        dir: Option<INodeRef>,
+        path: &Path,
+        make_subdirs: bool,
+        ignore_exists: bool,
+    ) -> Result<INodeRef, IOError> {
+        let current = self.get_ref_at(dir, path);
         if let Some(current) = current {
             if ignore_exists {
                 Ok(current)
@@ -176,9 +341,11 @@ impl VFS {
             }
         } else {
             let parent = if make_subdirs && let Some(parent) = path.parent() {
-                Some(self.mkdir(parent, true, true)?)
+                Some(self.mkdir_at(dir, parent, true, true)?)
             } else if let Some(parent) = path.parent() {
-                self.get_ref(parent)
+                self.get_ref_at(dir, parent)
+            } else if !path.is_absolute() {
+                dir.or_else(|| self.get_ref(&PathBuf::root()))
             } else {
                 None
             };
@@ -219,6 +386,26 @@ impl VFS {
         let inode = self.get_inode(r).ok_or(IOError::NotFound)?;
         Ok(inode)
     }
+
+    pub fn get_at(
+        &mut self,
+        dir: Option<INodeRef>,
+        path: &Path,
+    ) -> Result<(INodeRef, CArcSome<INodeBox<'static>>), IOError> {
+        let r = self.get_ref_at(dir, path).ok_or(IOError::NotFound)?;
+        let inode = self.get_inode(r).ok_or(IOError::NotFound)?;
+        Ok((r, inode))
+    }
+}
+
+impl Mounts {
+    /// The root of the filesystem mounted over `dir` last, or `dir` if there is none.
+    fn enter(&self, mut dir: INodeRef) -> INodeRef {
+        while let Some(root) = self.covered.get(&(dir.0, dir.1.0)) {
+            dir = *root;
+        }
+        dir
+    }
 }
 
 impl Default for VFS {
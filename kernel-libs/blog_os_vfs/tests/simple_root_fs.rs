@@ -2,6 +2,7 @@ use std::ptr::NonNull;
 
 use blog_os_vfs::{VFS, api::fs::Superblock, api::inode::FsINodeRef, api::path::PathBuf};
 use blog_os_vfs_api::{
+    IOError,
     cglue::{self, arc::CArc},
     fs::{Filesystem, cglue_filesystem::*, cglue_superblock::*},
     inode::cglue_inode::INodeBox,
@@ -87,3 +88,142 @@ pub fn tree_root_fs() {
     assert_eq!(fs, Some(sh.fs()));
     assert!(sh.inode().0 > 0);
 }
+
+#[test]
+pub fn relative_lookup() {
+    let mut vfs = VFS::new();
+
+    vfs.register_fs(cglue::trait_obj!(CustomFs as Filesystem))
+        .unwrap();
+    vfs.mount(PathBuf::root(), None);
+
+    let bin = vfs.get_ref(&PathBuf::parse("/bin")).expect("An inode");
+    let sh = vfs.get_ref(&PathBuf::parse("/bin/sh")).expect("An inode");
+
+    let relative = vfs
+        .get_ref_at(Some(bin), &PathBuf::parse("sh"))
+        .expect("An inode");
+    assert_eq!(sh.inode().0, relative.inode().0);
+
+    // Absolute paths ignore the directory
+    let absolute = vfs
+        .get_ref_at(Some(bin), &PathBuf::parse("/bin/echo"))
+        .expect("An inode");
+    let echo = vfs.get_ref(&PathBuf::parse("/bin/echo")).expect("An inode");
+    assert_eq!(echo.inode().0, absolute.inode().0);
+
+    // Without a directory, relative paths start at the root
+    let from_root = vfs
+        .get_ref_at(None, &PathBuf::parse("bin/sh"))
+        .expect("An inode");
+    assert_eq!(sh.inode().0, from_root.inode().0);
+
+    assert!(vfs.get_ref_at(Some(bin), &PathBuf::parse("bin")).is_none());
+}
+
+#[test]
+pub fn relative_lookup_crosses_mounts() {
+    let mut vfs = VFS::new();
+
+    vfs.register_fs(cglue::trait_obj!(CustomFs as Filesystem))
+        .unwrap();
+    vfs.mount(PathBuf::root(), None);
+    let mounted = vfs.mount(PathBuf::parse("/bin"), None);
+
+    let root = vfs.get_ref(&PathBuf::root()).expect("An inode");
+    let sh = vfs
+        .get_ref(&PathBuf::parse("/bin/bin/sh"))
+        .expect("An inode");
+    assert_eq!(mounted, Some(sh.fs()));
+
+    // `bin` of the root filesystem is covered by the mounted one, whose root holds `bin/sh`
+    let relative = vfs
+        .get_ref_at(Some(root), &PathBuf::parse("bin/bin/sh"))
+        .expect("An inode");
+    assert_eq!(mounted, Some(relative.fs()));
+    assert_eq!(sh.inode().0, relative.inode().0);
+    assert!(
+        vfs.get_ref_at(Some(root), &PathBuf::parse("bin/sh"))
+            .is_none()
+    );
+}
+
+#[test]
+pub fn parent_crosses_mounts() {
+    let mut vfs = VFS::new();
+
+    vfs.register_fs(cglue::trait_obj!(CustomFs as Filesystem))
+        .unwrap();
+    let root_fs = vfs.mount(PathBuf::root(), None);
+    let mounted = vfs.mount(PathBuf::parse("/bin"), None);
+
+    let root = vfs.get_ref(&PathBuf::root()).expect("An inode");
+    let mounted_root = vfs.get_ref(&PathBuf::parse("/bin")).expect("An inode");
+    assert_eq!(mounted, Some(mounted_root.fs()));
+
+    // Out of the mounted filesystem, from its root
+    let parent = vfs
+        .get_ref_at(Some(mounted_root), &PathBuf::parse(".."))
+        .expect("An inode");
+    assert_eq!(root_fs, Some(parent.fs()));
+    assert_eq!(root.inode().0, parent.inode().0);
+    let sh = vfs
+        .get_ref_at(Some(mounted_root), &PathBuf::parse("../bin/bin/sh"))
+        .expect("An inode");
+    assert_eq!(mounted, Some(sh.fs()));
+
+    // Back through the mount point walked into
+    let back = vfs
+        .get_ref_at(Some(root), &PathBuf::parse("bin/bin/../.."))
+        .expect("An inode");
+    assert_eq!(root_fs, Some(back.fs()));
+
+    for (path, fs) in [
+        ("/bin/bin/..", mounted),
+        ("/bin/..", root_fs),
+        ("/..", root_fs),
+    ] {
+        let inode = vfs.get_ref(&PathBuf::parse(path)).expect("An inode");
+        assert_eq!(fs, Some(inode.fs()), "{path}");
+        assert_eq!(0, inode.inode().0, "{path}");
+    }
+}
+
+#[test]
+pub fn unmount_uncovers_the_directory() {
+    let mut vfs = VFS::new();
+
+    vfs.register_fs(cglue::trait_obj!(CustomFs as Filesystem))
+        .unwrap();
+    let root_fs = vfs.mount(PathBuf::root(), None);
+    let mounted = vfs.mount(PathBuf::parse("/bin"), None);
+    let root = vfs.get_ref(&PathBuf::root()).expect("An inode");
+    let sh = vfs
+        .get_ref(&PathBuf::parse("/bin/bin/sh"))
+        .expect("An inode");
+    assert_eq!(mounted, Some(sh.fs()));
+
+    assert!(matches!(
+        vfs.unmount(&PathBuf::root()),
+        Err(IOError::OperationNotPermitted)
+    ));
+    assert!(matches!(
+        vfs.unmount(&PathBuf::parse("/bin/bin")),
+        Err(IOError::InvalidArgument)
+    ));
+    vfs.unmount(&PathBuf::parse("/bin")).expect("Unmounted");
+
+    assert!(vfs.get_ref(&PathBuf::parse("/bin/bin/sh")).is_none());
+    let sh = vfs.get_ref(&PathBuf::parse("/bin/sh")).expect("An inode");
+    assert_eq!(root_fs, Some(sh.fs()));
+    let relative = vfs
+        .get_ref_at(Some(root), &PathBuf::parse("bin/sh"))
+        .expect("An inode");
+    assert_eq!(root_fs, Some(relative.fs()));
+
+    // Over the root, which is found again once unmounted
+    let stacked = vfs.mount(PathBuf::root(), None);
+    assert_eq!(stacked, vfs.get_ref(&PathBuf::root()).map(|x| x.fs()));
+    vfs.unmount(&PathBuf::root()).expect("Unmounted");
+    assert_eq!(root_fs, vfs.get_ref(&PathBuf::root()).map(|x| x.fs()));
+}
@@ -1,5 +1,6 @@
 use api_utils::cglue;
 use blog_os_device_api::DeviceId;
+use shared_fs::ioctl::IoctlRequest;
 
 use crate::{IOError, inode::FsINodeRef};
 
@@ -22,4 +23,36 @@ pub trait File {
     fn mknod(&mut self, name: &str, device: DeviceId) -> Result<FsINodeRef, IOError>;
     fn creat(&mut self, name: &str) -> Result<FsINodeRef, IOError>;
     fn flush(&mut self) -> Result<(), IOError>;
+    fn truncate(&mut self, size: u64) -> Result<(), IOError>;
+
+    /// Reads at `offset` without moving the cursor.
+    ///
+    /// The default implementation saves the cursor, seeks and restores it afterwards,
+    /// so files that can access their data directly should override it.
+    fn pread(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, IOError> {
+        let cursor = self.seek(SeekMode::CURSOR, 0)?;
+        self.seek(SeekMode::START, offset as isize)?;
+        let res = self.read(buf);
+        self.seek(SeekMode::START, cursor as isize)?;
+        res
+    }
+
+    /// Writes at `offset` without moving the cursor.
+    ///
+    /// See [`File::pread`] for the default implementation caveats.
+    fn pwrite(&mut self, buf: &[u8], offset: u64) -> Result<usize, IOError> {
+        let cursor = self.seek(SeekMode::CURSOR, 0)?;
+        self.seek(SeekMode::START, offset as isize)?;
+        let res = self.write(buf);
+        self.seek(SeekMode::START, cursor as isize)?;
+        res
+    }
+
+    /// Device specific control request. `arg` holds the argument, already copied in from
+    /// the caller for write requests, and is copied back out for read requests.
+    ///
+    /// Requests are defined in [`shared_fs::ioctl`]. Returns a request specific value.
+    fn ioctl(&mut self, _request: IoctlRequest, _arg: &mut [u8]) -> Result<u64, IOError> {
+        Err(IOError::NotSupported)
+    }
 }
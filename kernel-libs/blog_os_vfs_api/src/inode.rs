@@ -13,4 +13,5 @@ pub trait INode {
     fn lookup(&self, component: &str) -> Option<FsINodeRef>;
     fn stat(&self) -> Result<Stat, IOError>;
     fn open(&self) -> Result<FileBox<'static>, IOError>;
+    fn truncate(&self, size: u64) -> Result<(), IOError>;
 }
@@ -57,6 +57,18 @@ enum_with_max! {
         #[error("End of file")]
         EOF,
         #[error("Load elf error")]
-        LoadError
+        LoadError,
+        #[error("Invalid argument")]
+        InvalidArgument,
+        #[error("Bad address")]
+        BadAddress,
+        #[error("Operation not supported")]
+        NotSupported,
+        #[error("Not a directory")]
+        NotADirectory,
+        #[error("File too large")]
+        FileTooLarge,
+        #[error("No space left")]
+        NoSpace
     }
 }
@@ -32,6 +32,33 @@ impl TryFrom<CLayout> for Layout {
     }
 }
 
+/// Physically contiguous memory for a device, see [`KernelInterface::dma_alloc`].
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct DmaRegion {
+    /// Kernel address of the memory, null if the allocation failed
+    pub virt: *mut u8,
+    /// Address of the memory for the device
+    pub phys: u64,
+    /// Size, rounded up to whole blocks of frames
+    pub size: usize,
+}
+
+impl DmaRegion {
+    /// The region of a failed allocation.
+    pub const fn null() -> Self {
+        Self {
+            virt: core::ptr::null_mut(),
+            phys: 0,
+            size: 0,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.virt.is_null()
+    }
+}
+
 #[cglue::cglue_trait]
 pub trait KernelInterface {
     fn abort(&self);
@@ -83,5 +110,53 @@ pub trait KernelInterface {
     /// Otherwise the behavior is undefined.
     unsafe fn dealloc(&self, ptr: *mut u8, layout: CLayout);
 
+    /// Allocates at least `size` zeroed bytes of physically contiguous memory, aligned on
+    /// `align` both virtually and physically, for a device to access directly. With
+    /// `below_4g`, the memory ends below 4 GiB, for devices with 32 bit addresses.
+    ///
+    /// Returns a null region on failure. Memory that is never freed is freed when the driver
+    /// is unloaded.
+    fn dma_alloc(&self, size: usize, align: usize, below_4g: bool) -> DmaRegion;
+    /// Frees memory from [`Self::dma_alloc`].
+    ///
+    /// # Safety
+    /// `virt` must be the address of a region from [`Self::dma_alloc`], which neither the
+    /// driver nor the device use anymore.
+    unsafe fn dma_free(&self, virt: *mut u8);
+
     fn register_bus(&self, bus: BusBox<'static>);
+
+    /// Creates a completion, for a task to wait on work finished elsewhere, like in an
+    /// interrupt handler. Returns its handle.
+    fn completion_create(&self) -> u64;
+    /// Signals a completion once, waking a task waiting on it, or letting the next wait
+    /// return right away. Can be called from an interrupt handler.
+    fn completion_signal(&self, completion: u64);
+    /// Sleeps until the completion is signaled. Returns `false` for an unknown handle.
+    fn completion_wait(&self, completion: u64) -> bool;
+    fn completion_destroy(&self, completion: u64);
+
+    /// Runs `entry(arg)` on a new kernel thread, returning its handle.
+    fn thread_spawn(&self, entry: extern "C" fn(*mut ()), arg: *mut (), name: &str) -> u64;
+    /// Sleeps until the thread returned. Returns `false` for an unknown handle.
+    ///
+    /// Threads that are never joined are joined when the driver is unloaded.
+    fn thread_join(&self, thread: u64) -> bool;
+
+    /// Creates a tasklet running `entry(arg)` in an interrupt tail once scheduled, for what
+    /// an interrupt handler can leave for later. It must not sleep. Returns its handle.
+    fn tasklet_create(&self, entry: extern "C" fn(*mut ()), arg: *mut ()) -> u64;
+    /// Schedules a tasklet to run once. Can be called from an interrupt handler.
+    fn tasklet_schedule(&self, tasklet: u64);
+    /// Waits until the tasklet isn't scheduled or running anymore, and frees it.
+    fn tasklet_destroy(&self, tasklet: u64);
+    /// Queues `entry(arg)` for the kernel worker thread, for deferred work that may sleep.
+    /// Work still queued when the driver is unloaded is skipped, calling `cancel(arg)` instead
+    /// to free what `arg` points to.
+    fn queue_work(
+        &self,
+        entry: extern "C" fn(*mut ()),
+        cancel: extern "C" fn(*mut ()),
+        arg: *mut (),
+    );
 }
@@ -2,8 +2,8 @@
 
 extern crate alloc;
 
-use alloc::string::String;
-use kdriver_api::{KernelInterface, cglue_kernelinterface::KernelInterfaceBox};
+use alloc::{boxed::Box, string::String};
+use kdriver_api::{DmaRegion, KernelInterface, cglue_kernelinterface::KernelInterfaceBox};
 
 pub use kdriver_api as api;
 
@@ -38,6 +38,160 @@ pub fn print(s: core::fmt::Arguments) {
     interface().print(&string);
 }
 
+/// Physically contiguous memory a device can access directly, freed on drop.
+pub struct DmaBuffer(DmaRegion);
+
+unsafe impl Send for DmaBuffer {}
+unsafe impl Sync for DmaBuffer {}
+
+impl DmaBuffer {
+    /// Allocates at least `size` zeroed bytes aligned on `align`. With `below_4g`, the memory
+    /// ends below 4 GiB, for devices with 32 bit addresses.
+    pub fn new(size: usize, align: usize, below_4g: bool) -> Option<Self> {
+        let region = interface().dma_alloc(size, align, below_4g);
+        (!region.is_null()).then_some(Self(region))
+    }
+
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.0.virt
+    }
+
+    /// Address of the memory for the device.
+    pub fn phys_addr(&self) -> u64 {
+        self.0.phys
+    }
+
+    /// Size of the memory, at least what was asked for.
+    pub fn size(&self) -> usize {
+        self.0.size
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe { interface().dma_free(self.0.virt) };
+    }
+}
+
+/// Lets a task sleep until work done elsewhere is finished.
+pub struct Completion(u64);
+
+impl Completion {
+    pub fn new() -> Self {
+        Self(interface().completion_create())
+    }
+
+    /// Wakes a task in [`Self::wait`], or lets the next one return right away.
+    pub fn signal(&self) {
+        interface().completion_signal(self.0);
+    }
+
+    /// Sleeps until the completion is signaled. Returns `false` if the kernel didn't know the
+    /// completion, so it returned without waiting.
+    #[must_use]
+    pub fn wait(&self) -> bool {
+        interface().completion_wait(self.0)
+    }
+}
+
+impl Default for Completion {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        interface().completion_destroy(self.0);
+    }
+}
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+/// A background worker, see [`spawn`].
+pub struct JoinHandle(u64);
+
+impl JoinHandle {
+    /// Sleeps until the worker returned.
+    pub fn join(self) {
+        interface().thread_join(self.0);
+    }
+}
+
+/// Runs `f` on a new kernel thread.
+///
+/// The driver is only unloaded once all of its threads returned.
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F, name: &str) -> JoinHandle {
+    extern "C" fn thread_start(arg: *mut ()) {
+        // Safety: `spawn` passes a boxed `ThreadMain`, taken back only here
+        let main = unsafe { Box::from_raw(arg.cast::<ThreadMain>()) };
+        main();
+    }
+
+    let main: ThreadMain = Box::new(f);
+    let arg = Box::into_raw(Box::new(main)).cast();
+    JoinHandle(interface().thread_spawn(thread_start, arg, name))
+}
+
+type TaskletMain = Box<dyn Fn() + Send + Sync>;
+
+/// Work an interrupt handler leaves for the interrupt tail. It must not sleep.
+pub struct Tasklet {
+    handle: u64,
+    main: *mut TaskletMain,
+}
+
+unsafe impl Send for Tasklet {}
+unsafe impl Sync for Tasklet {}
+
+impl Tasklet {
+    pub fn new<F: Fn() + Send + Sync + 'static>(f: F) -> Self {
+        extern "C" fn tasklet_run(arg: *mut ()) {
+            // Safety: the tasklet is destroyed before `main` is freed
+            let main = unsafe { &*arg.cast::<TaskletMain>() };
+            main();
+        }
+
+        let main: TaskletMain = Box::new(f);
+        let main = Box::into_raw(Box::new(main));
+        Self {
+            handle: interface().tasklet_create(tasklet_run, main.cast()),
+            main,
+        }
+    }
+
+    /// Runs the tasklet once in the next interrupt tail. Can be called from an interrupt
+    /// handler.
+    pub fn schedule(&self) {
+        interface().tasklet_schedule(self.handle);
+    }
+}
+
+impl Drop for Tasklet {
+    fn drop(&mut self) {
+        interface().tasklet_destroy(self.handle);
+        drop(unsafe { Box::from_raw(self.main) });
+    }
+}
+
+/// Runs `f` on the kernel worker thread, for deferred work that may sleep.
+///
+/// If the driver is unloaded before it ran, `f` is dropped without running.
+pub fn queue_work<F: FnOnce() + Send + 'static>(f: F) {
+    extern "C" fn work_run(arg: *mut ()) {
+        // Safety: `queue_work` passes a boxed `ThreadMain`, run or cancelled only once
+        let main = unsafe { Box::from_raw(arg.cast::<ThreadMain>()) };
+        main();
+    }
+    extern "C" fn work_cancel(arg: *mut ()) {
+        // Safety: as in `work_run`
+        drop(unsafe { Box::from_raw(arg.cast::<ThreadMain>()) });
+    }
+
+    let main: ThreadMain = Box::new(f);
+    interface().queue_work(work_run, work_cancel, Box::into_raw(Box::new(main)).cast());
+}
+
 // Required panic handler
 #[panic_handler]
 fn panic(_: &core::panic::PanicInfo) -> ! {
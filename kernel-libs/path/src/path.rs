@@ -72,6 +72,31 @@ impl Path {
     pub const fn is_empty(&self) -> bool {
         self.components.is_empty()
     }
+
+    /// Resolves the `.` and `..` components without looking at any filesystem, the parent of the
+    /// root being the root. A relative path keeps the `..` going above where it starts.
+    pub fn normalize(&self) -> PathBuf {
+        let mut normal = PathBuf::new();
+        for c in &self.components {
+            match c.as_ref() {
+                "." => (),
+                ".." if normal.is_absolute() => {
+                    if normal.len() > 1 {
+                        normal.components.pop();
+                    }
+                }
+                ".." if normal
+                    .components
+                    .last()
+                    .is_some_and(|last| last.as_ref() != "..") =>
+                {
+                    normal.components.pop();
+                }
+                _ => normal.components.push(c.clone()),
+            }
+        }
+        normal
+    }
 }
 
 impl ToOwned for Path {
@@ -399,6 +424,22 @@ mod tests {
         assert!(path.parent().is_none());
     }
 
+    #[test]
+    fn normalize_absolute() {
+        let path = PathBuf::parse("/a/./b/../../c/..");
+        assert_eq!("/", format!("{}", path.normalize()));
+        let path = PathBuf::parse("/../a/b/..");
+        assert_eq!("/a", format!("{}", path.normalize()));
+    }
+
+    #[test]
+    fn normalize_relative() {
+        let path = PathBuf::parse("a/../../b/./c/..");
+        assert_eq!("../b", format!("{}", path.normalize()));
+        let path = PathBuf::parse("a/..");
+        assert!(path.normalize().is_empty());
+    }
+
     #[test]
     fn slash_in_slice() {
         let slice = [Box::from(""), Box::from("a/")];
@@ -54,6 +54,10 @@ impl<R: RawRwLock + Send + Sync> INode for DirectoryINode<R> {
             current: None
         } as File))
     }
+
+    fn truncate(&self, _size: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
 }
 
 pub struct DirectoryFile<R: RawRwLock + Send + Sync + 'static> {
@@ -136,8 +140,12 @@ impl<R: RawRwLock + Send + Sync> File for DirectoryFile<R> {
         Err(IOError::OperationNotPermitted)
     }
 
+    fn truncate(&mut self, _size: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
     fn seek(&mut self, _mode: SeekMode, _amount: isize) -> Result<usize, IOError> {
-        todo!()
+        Err(IOError::OperationNotPermitted)
     }
 
     fn next_direntry(&mut self) -> Result<&str, IOError> {
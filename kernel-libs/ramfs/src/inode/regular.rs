@@ -11,6 +11,23 @@ use shared_fs::{FileType, Stat};
 
 use lock_api::{RawRwLock, RwLock};
 
+/// Largest size a file can grow to, the contents live in the kernel heap
+pub const MAX_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Resizes `data` to `size` bytes, zero filling, failing instead of aborting when the heap runs
+/// out.
+fn resize(data: &mut Vec<u8>, size: u64) -> Result<(), IOError> {
+    if size > MAX_FILE_SIZE {
+        return Err(IOError::FileTooLarge);
+    }
+    let size = size as usize;
+    data.try_reserve(size.saturating_sub(data.len()))
+        .map_err(|_| IOError::NoSpace)?;
+    data.resize(size, 0);
+
+    Ok(())
+}
+
 pub struct RegularINode<R: RawRwLock + Send + Sync + 'static> {
     data: Arc<RwLock<R, Vec<u8>>>,
 }
@@ -42,6 +59,10 @@ impl<R: RawRwLock + Send + Sync> INode for RegularINode<R> {
             cursor: 0
         } as File))
     }
+
+    fn truncate(&self, size: u64) -> Result<(), IOError> {
+        resize(&mut self.data.write(), size)
+    }
 }
 
 pub struct RegularFile<R: RawRwLock + Send + Sync + 'static> {
@@ -49,25 +70,20 @@ pub struct RegularFile<R: RawRwLock + Send + Sync + 'static> {
     cursor: usize,
 }
 
-impl<R: RawRwLock + Send + Sync> File for RegularFile<R> {
-    fn close(&mut self) -> Result<(), IOError> {
-        Ok(())
-    }
-
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+impl<R: RawRwLock + Send + Sync> RegularFile<R> {
+    fn read_at(&self, buf: &mut [u8], offset: usize) -> Result<usize, IOError> {
         let lock = self.data.read();
 
-        if self.cursor >= lock.len() {
+        if offset >= lock.len() {
             return Err(IOError::EOF);
         }
 
-        let self_data = &lock[self.cursor..];
+        let self_data = &lock[offset..];
 
         let bytes = self_data.len().min(buf.len());
 
         debug!(
-            "Reading {bytes} bytes from the cursor: {} (len: {})",
-            self.cursor,
+            "Reading {bytes} bytes from offset: {offset} (len: {})",
             lock.len()
         );
 
@@ -75,33 +91,58 @@ impl<R: RawRwLock + Send + Sync> File for RegularFile<R> {
 
         drop(lock);
 
-        self.cursor += bytes;
-
         Ok(bytes)
     }
 
-    fn write(&mut self, data: &[u8]) -> Result<usize, IOError> {
+    fn write_at(&self, data: &[u8], offset: u64) -> Result<usize, IOError> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or(IOError::FileTooLarge)?;
         let mut lock = self.data.write();
 
-        let self_data = &mut lock[self.cursor..];
+        if end > lock.len() as u64 {
+            // Writing past the end (after a truncate or a seek), fill the gap with zeroes
+            resize(&mut lock, end)?;
+        }
 
-        let bytes = if self_data.is_empty() {
-            lock.extend_from_slice(data);
-            data.len()
-        } else {
-            let bytes = self_data.len().min(data.len());
-
-            self_data[..bytes].copy_from_slice(&data[..bytes]);
-            bytes
-        };
+        // `end` is within the file now, which fits in memory
+        lock[offset as usize..end as usize].copy_from_slice(data);
 
         drop(lock);
 
+        Ok(data.len())
+    }
+}
+
+impl<R: RawRwLock + Send + Sync> File for RegularFile<R> {
+    fn close(&mut self) -> Result<(), IOError> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+        let bytes = self.read_at(buf, self.cursor)?;
+
+        self.cursor += bytes;
+
+        Ok(bytes)
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<usize, IOError> {
+        let bytes = self.write_at(data, self.cursor as u64)?;
+
         self.cursor += bytes;
 
         Ok(bytes)
     }
 
+    fn pread(&mut self, buf: &mut [u8], offset: u64) -> Result<usize, IOError> {
+        self.read_at(buf, offset as usize)
+    }
+
+    fn pwrite(&mut self, buf: &[u8], offset: u64) -> Result<usize, IOError> {
+        self.write_at(buf, offset)
+    }
+
     fn mkdir(&mut self, _name: &str) -> Result<FsINodeRef, IOError> {
         Err(IOError::OperationNotPermitted)
     }
@@ -120,8 +161,22 @@ impl<R: RawRwLock + Send + Sync> File for RegularFile<R> {
         Ok(())
     }
 
+    fn truncate(&mut self, size: u64) -> Result<(), IOError> {
+        resize(&mut self.data.write(), size)
+    }
+
     fn seek(&mut self, mode: SeekMode, amount: isize) -> Result<usize, IOError> {
-        todo!()
+        let base = match mode {
+            SeekMode::START => 0,
+            SeekMode::CURSOR => self.cursor,
+            SeekMode::END => self.data.read().len(),
+        };
+
+        self.cursor = base
+            .checked_add_signed(amount)
+            .ok_or(IOError::InvalidArgument)?;
+
+        Ok(self.cursor)
     }
 
     fn next_direntry(&mut self) -> Result<&str, IOError> {
@@ -0,0 +1,121 @@
+use api_utils::cglue::arc::CArcSome;
+use blog_os_vfs_api::{
+    IOError,
+    file::{File, SeekMode},
+    fs::Superblock,
+    inode::{INode, cglue_inode::INodeBox},
+};
+use ramfs::{inode::regular::MAX_FILE_SIZE, superblock::RamFSSuperblock};
+
+type RamSuperblock = RamFSSuperblock<spin::RwLock<()>>;
+
+fn create_file(sb: &RamSuperblock, name: &str) -> CArcSome<INodeBox<'static>> {
+    let root = sb.get_inode(sb.get_root_inode_ref()).transpose().unwrap();
+    let mut dir = root.open().unwrap();
+    let created = dir.creat(name).unwrap();
+    dir.close().unwrap();
+
+    sb.get_inode(created).transpose().unwrap()
+}
+
+#[test]
+pub fn truncate_shrinks_and_extends() {
+    let sb = RamSuperblock::default();
+    let inode = create_file(&sb, "log");
+
+    let mut file = inode.open().unwrap();
+    assert_eq!(file.write(b"hello world").unwrap(), 11);
+
+    inode.truncate(5).unwrap();
+    assert_eq!(inode.stat().unwrap().size, 5);
+
+    file.truncate(8).unwrap();
+    assert_eq!(inode.stat().unwrap().size, 8);
+
+    let mut reader = inode.open().unwrap();
+    let mut buf = [0xffu8; 16];
+    assert_eq!(reader.read(&mut buf).unwrap(), 8);
+    assert_eq!(&buf[..8], b"hello\0\0\0");
+    assert!(matches!(reader.read(&mut buf), Err(IOError::EOF)));
+}
+
+#[test]
+pub fn truncate_past_the_size_limit_fails() {
+    let sb = RamSuperblock::default();
+    let inode = create_file(&sb, "huge");
+
+    let mut file = inode.open().unwrap();
+    file.write(b"kept").unwrap();
+
+    assert!(matches!(
+        inode.truncate(MAX_FILE_SIZE + 1),
+        Err(IOError::FileTooLarge)
+    ));
+    assert!(matches!(
+        file.truncate(u64::MAX),
+        Err(IOError::FileTooLarge)
+    ));
+    assert_eq!(inode.stat().unwrap().size, 4);
+}
+
+#[test]
+pub fn write_past_the_size_limit_fails() {
+    let sb = RamSuperblock::default();
+    let inode = create_file(&sb, "sparse");
+
+    let mut file = inode.open().unwrap();
+    assert!(matches!(
+        file.pwrite(b"x", MAX_FILE_SIZE),
+        Err(IOError::FileTooLarge)
+    ));
+    assert!(matches!(
+        file.pwrite(b"x", u64::MAX),
+        Err(IOError::FileTooLarge)
+    ));
+    file.seek(SeekMode::START, MAX_FILE_SIZE as isize).unwrap();
+    assert!(matches!(file.write(b"x"), Err(IOError::FileTooLarge)));
+    assert_eq!(inode.stat().unwrap().size, 0);
+}
+
+#[test]
+pub fn write_after_truncate_fills_gap() {
+    let sb = RamSuperblock::default();
+    let inode = create_file(&sb, "data");
+
+    let mut file = inode.open().unwrap();
+    file.write(b"abcdef").unwrap();
+
+    inode.truncate(0).unwrap();
+    assert_eq!(file.write(b"g").unwrap(), 1);
+
+    let mut buf = [0xffu8; 16];
+    let mut reader = inode.open().unwrap();
+    assert_eq!(reader.read(&mut buf).unwrap(), 7);
+    assert_eq!(&buf[..7], b"\0\0\0\0\0\0g");
+}
+
+#[test]
+pub fn positional_io_keeps_cursor() {
+    let sb = RamSuperblock::default();
+    let inode = create_file(&sb, "archive");
+
+    let mut file = inode.open().unwrap();
+    file.write(b"0123456789").unwrap();
+    assert_eq!(file.seek(SeekMode::START, 2).unwrap(), 2);
+
+    let mut buf = [0u8; 3];
+    assert_eq!(file.pread(&mut buf, 6).unwrap(), 3);
+    assert_eq!(&buf, b"678");
+
+    assert_eq!(file.pwrite(b"ab", 12).unwrap(), 2);
+    assert_eq!(inode.stat().unwrap().size, 14);
+
+    assert_eq!(file.read(&mut buf).unwrap(), 3);
+    assert_eq!(&buf, b"234");
+
+    assert_eq!(file.seek(SeekMode::END, -2).unwrap(), 12);
+    assert!(matches!(
+        file.seek(SeekMode::CURSOR, -13),
+        Err(IOError::InvalidArgument)
+    ));
+}
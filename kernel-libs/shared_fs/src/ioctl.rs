@@ -0,0 +1,232 @@
+//! Device control requests.
+//!
+//! A request number packs, from the low bits up: the command number (8 bits), the
+//! [`IoctlSubsystem`] (8 bits), the argument size (14 bits) and the [`IoctlDirection`]
+//! (2 bits), like the Linux `_IOC` encoding. The argument itself is copied in and out of
+//! the caller's buffer by the kernel, so files only ever see a kernel slice.
+
+use core::marker::PhantomData;
+
+const NR_BITS: u32 = 8;
+const SUBSYSTEM_BITS: u32 = 8;
+const SIZE_BITS: u32 = 14;
+
+const SUBSYSTEM_SHIFT: u32 = NR_BITS;
+const SIZE_SHIFT: u32 = SUBSYSTEM_SHIFT + SUBSYSTEM_BITS;
+const DIRECTION_SHIFT: u32 = SIZE_SHIFT + SIZE_BITS;
+
+pub const MAX_ARG_SIZE: usize = (1 << SIZE_BITS) - 1;
+
+/// Direction of the argument transfer, seen from the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IoctlDirection {
+    /// No argument
+    None = 0,
+    /// The caller passes the argument to the device
+    Write = 1,
+    /// The device fills the argument for the caller
+    Read = 2,
+    ReadWrite = 3,
+}
+
+impl IoctlDirection {
+    pub const fn copies_in(self) -> bool {
+        matches!(self, Self::Write | Self::ReadWrite)
+    }
+
+    pub const fn copies_out(self) -> bool {
+        matches!(self, Self::Read | Self::ReadWrite)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum IoctlSubsystem {
+    Terminal = b'T',
+    Framebuffer = b'F',
+    Block = b'B',
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct IoctlRequest(pub u32);
+
+impl IoctlRequest {
+    pub const fn new(direction: IoctlDirection, subsystem: u8, nr: u8, size: usize) -> Self {
+        assert!(size <= MAX_ARG_SIZE, "ioctl argument too big");
+
+        Self(
+            ((direction as u32) << DIRECTION_SHIFT)
+                | ((size as u32) << SIZE_SHIFT)
+                | ((subsystem as u32) << SUBSYSTEM_SHIFT)
+                | nr as u32,
+        )
+    }
+
+    pub const fn direction(self) -> IoctlDirection {
+        match self.0 >> DIRECTION_SHIFT {
+            0 => IoctlDirection::None,
+            1 => IoctlDirection::Write,
+            2 => IoctlDirection::Read,
+            _ => IoctlDirection::ReadWrite,
+        }
+    }
+
+    pub const fn subsystem(self) -> u8 {
+        (self.0 >> SUBSYSTEM_SHIFT) as u8
+    }
+
+    pub const fn nr(self) -> u8 {
+        self.0 as u8
+    }
+
+    pub const fn size(self) -> usize {
+        ((self.0 >> SIZE_SHIFT) & ((1 << SIZE_BITS) - 1)) as usize
+    }
+}
+
+/// Types that can be passed as an ioctl argument.
+///
+/// # Safety
+/// The type must be `repr(C)` (or a primitive) and every bit pattern must be a valid value,
+/// as arguments are built from untrusted bytes.
+pub unsafe trait IoctlArg: Copy {}
+
+unsafe impl IoctlArg for () {}
+unsafe impl IoctlArg for u32 {}
+unsafe impl IoctlArg for u64 {}
+
+/// A request number tied to the type of its argument.
+#[derive(Debug)]
+pub struct Ioctl<T: IoctlArg> {
+    request: IoctlRequest,
+    _marker: PhantomData<T>,
+}
+
+impl<T: IoctlArg> Clone for Ioctl<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: IoctlArg> Copy for Ioctl<T> {}
+
+impl<T: IoctlArg> Ioctl<T> {
+    const fn new(direction: IoctlDirection, subsystem: IoctlSubsystem, nr: u8) -> Self {
+        Self {
+            request: IoctlRequest::new(direction, subsystem as u8, nr, size_of::<T>()),
+            _marker: PhantomData,
+        }
+    }
+
+    pub const fn read(subsystem: IoctlSubsystem, nr: u8) -> Self {
+        Self::new(IoctlDirection::Read, subsystem, nr)
+    }
+
+    pub const fn write(subsystem: IoctlSubsystem, nr: u8) -> Self {
+        Self::new(IoctlDirection::Write, subsystem, nr)
+    }
+
+    pub const fn read_write(subsystem: IoctlSubsystem, nr: u8) -> Self {
+        Self::new(IoctlDirection::ReadWrite, subsystem, nr)
+    }
+
+    pub const fn request(self) -> IoctlRequest {
+        self.request
+    }
+
+    /// Decodes the argument, if the buffer has the right size for it.
+    pub fn arg(self, buf: &[u8]) -> Option<T> {
+        (buf.len() == size_of::<T>()).then(|| unsafe { buf.as_ptr().cast::<T>().read_unaligned() })
+    }
+
+    /// Encodes the argument. Returns false if the buffer has the wrong size.
+    pub fn set_arg(self, buf: &mut [u8], value: T) -> bool {
+        let fits = buf.len() == size_of::<T>();
+        if fits {
+            unsafe { buf.as_mut_ptr().cast::<T>().write_unaligned(value) };
+        }
+        fits
+    }
+}
+
+impl Ioctl<()> {
+    pub const fn none(subsystem: IoctlSubsystem, nr: u8) -> Self {
+        Self::new(IoctlDirection::None, subsystem, nr)
+    }
+}
+
+impl<T: IoctlArg> PartialEq<IoctlRequest> for Ioctl<T> {
+    fn eq(&self, other: &IoctlRequest) -> bool {
+        self.request == *other
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct TermMode {
+    pub flags: u32,
+}
+
+impl TermMode {
+    /// Echo typed characters back to the console
+    pub const ECHO: u32 = 1 << 0;
+    /// Reads only return complete lines
+    pub const CANONICAL: u32 = 1 << 1;
+
+    pub const fn contains(self, flag: u32) -> bool {
+        self.flags & flag == flag
+    }
+}
+
+unsafe impl IoctlArg for TermMode {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(C)]
+pub struct FbGeometry {
+    pub width: u32,
+    pub height: u32,
+    /// Pixels per row, may be larger than `width`
+    pub stride: u32,
+    pub bytes_per_pixel: u32,
+}
+
+unsafe impl IoctlArg for FbGeometry {}
+
+pub const TERM_GET_MODE: Ioctl<TermMode> = Ioctl::read(IoctlSubsystem::Terminal, 0);
+pub const TERM_SET_MODE: Ioctl<TermMode> = Ioctl::write(IoctlSubsystem::Terminal, 1);
+
+pub const FB_GET_GEOMETRY: Ioctl<FbGeometry> = Ioctl::read(IoctlSubsystem::Framebuffer, 0);
+
+/// Size of the device in bytes
+pub const BLK_GET_SIZE: Ioctl<u64> = Ioctl::read(IoctlSubsystem::Block, 0);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_roundtrip() {
+        let req = FB_GET_GEOMETRY.request();
+
+        assert_eq!(req.direction(), IoctlDirection::Read);
+        assert_eq!(req.subsystem(), IoctlSubsystem::Framebuffer as u8);
+        assert_eq!(req.nr(), 0);
+        assert_eq!(req.size(), size_of::<FbGeometry>());
+    }
+
+    #[test]
+    fn requests_are_distinct() {
+        assert_ne!(TERM_GET_MODE.request(), TERM_SET_MODE.request());
+        assert_ne!(TERM_GET_MODE.request().0, BLK_GET_SIZE.request().0);
+    }
+
+    #[test]
+    fn arg_checks_size() {
+        let mut buf = [0u8; 8];
+        assert!(BLK_GET_SIZE.set_arg(&mut buf, 0x1234));
+        assert_eq!(BLK_GET_SIZE.arg(&buf), Some(0x1234));
+        assert_eq!(TERM_GET_MODE.arg(&buf), None);
+    }
+}
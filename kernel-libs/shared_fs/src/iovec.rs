@@ -0,0 +1,35 @@
+/// One buffer of a vectored read or write, laid out like the POSIX `struct iovec`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct IoVec {
+    pub base: *mut u8,
+    pub len: usize,
+}
+
+impl IoVec {
+    pub const fn new(buf: &[u8]) -> Self {
+        Self {
+            base: buf.as_ptr().cast_mut(),
+            len: buf.len(),
+        }
+    }
+
+    pub const fn new_mut(buf: &mut [u8]) -> Self {
+        Self {
+            base: buf.as_mut_ptr(),
+            len: buf.len(),
+        }
+    }
+
+    /// # Safety
+    /// `base` must point to `len` readable bytes that outlive `'a`.
+    pub const unsafe fn as_slice<'a>(&self) -> &'a [u8] {
+        unsafe { core::slice::from_raw_parts(self.base, self.len) }
+    }
+
+    /// # Safety
+    /// `base` must point to `len` writable bytes that outlive `'a` and are not aliased.
+    pub const unsafe fn as_mut_slice<'a>(&self) -> &'a mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.base, self.len) }
+    }
+}
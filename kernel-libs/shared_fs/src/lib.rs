@@ -6,6 +6,11 @@ extern crate alloc;
 pub use blog_os_device_api::DeviceId;
 
 pub mod dirent;
+pub mod ioctl;
+pub mod iovec;
+
+/// Directory fd for the `*AT` syscalls that resolves relative paths from the working directory.
+pub const AT_FDCWD: u64 = u64::MAX;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
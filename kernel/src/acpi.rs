@@ -0,0 +1,238 @@
+//! Minimal ACPI table parsing, enough to find the interrupt controllers and processors
+//! described by the MADT.
+//!
+//! Tables are read through the bootloader's physical memory mapping.
+
+use alloc::vec::Vec;
+use log::{debug, info};
+use spin::Once;
+use thiserror::Error;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::setup::KERNEL_INFO;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+const SDT_HEADER_LEN: usize = 36;
+
+#[derive(Debug, Error)]
+pub enum AcpiError {
+    #[error("The bootloader didn't report an RSDP")]
+    NoRsdp,
+    #[error("Bad signature for {0}")]
+    BadSignature(&'static str),
+    #[error("Bad checksum for {0}")]
+    BadChecksum(&'static str),
+    #[error("Table {0} not found")]
+    TableNotFound(&'static str),
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0+
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+/// A processor and its local APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct MadtProcessor {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MadtIoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC
+    pub gsi_base: u32,
+}
+
+/// Legacy ISA IRQ that isn't identity mapped to a global system interrupt.
+#[derive(Debug, Clone, Copy)]
+pub struct MadtOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+#[derive(Debug)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The machine also has the legacy 8259 PICs
+    pub has_legacy_pics: bool,
+    pub processors: Vec<MadtProcessor>,
+    pub io_apics: Vec<MadtIoApic>,
+    pub overrides: Vec<MadtOverride>,
+}
+
+impl Madt {
+    /// Global system interrupt and trigger mode of a legacy ISA IRQ.
+    pub fn legacy_irq(&self, irq: u8) -> MadtOverride {
+        self.overrides
+            .iter()
+            .find(|o| o.irq == irq)
+            .copied()
+            .unwrap_or(MadtOverride {
+                irq,
+                gsi: u32::from(irq),
+                active_low: false,
+                level_triggered: false,
+            })
+    }
+}
+
+fn phys_to_virt(addr: u64) -> VirtAddr {
+    KERNEL_INFO.get().unwrap().physical_memory_offset + addr
+}
+
+/// # Safety
+/// `addr` must point to `size_of::<T>()` bytes of physical memory
+unsafe fn read_phys<T: Copy>(addr: u64) -> T {
+    unsafe { phys_to_virt(addr).as_ptr::<T>().read_unaligned() }
+}
+
+/// # Safety
+/// `addr` must point to `len` bytes of physical memory
+unsafe fn phys_bytes<'a>(addr: u64, len: usize) -> &'a [u8] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(addr).as_ptr::<u8>(), len) }
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) == 0
+}
+
+/// Reads and validates the table header at `addr`, returning the whole table.
+fn read_table<'a>(addr: u64, name: &'static str) -> Result<(SdtHeader, &'a [u8]), AcpiError> {
+    let header = unsafe { read_phys::<SdtHeader>(addr) };
+    let bytes = unsafe { phys_bytes(addr, header.length as usize) };
+    if checksum_ok(bytes) {
+        Ok((header, bytes))
+    } else {
+        Err(AcpiError::BadChecksum(name))
+    }
+}
+
+/// Finds a table by its signature through the XSDT, or the RSDT on ACPI 1.0 machines.
+fn find_table(signature: &[u8; 4], name: &'static str) -> Result<u64, AcpiError> {
+    let rsdp_addr = KERNEL_INFO
+        .get()
+        .unwrap()
+        .rsdp_addr
+        .ok_or(AcpiError::NoRsdp)?;
+    let rsdp = unsafe { read_phys::<Rsdp>(rsdp_addr) };
+    if &rsdp.signature != RSDP_SIGNATURE {
+        return Err(AcpiError::BadSignature("RSDP"));
+    }
+    if !checksum_ok(unsafe { phys_bytes(rsdp_addr, 20) }) {
+        return Err(AcpiError::BadChecksum("RSDP"));
+    }
+
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (u64::from(rsdp.rsdt_address), 4)
+    };
+    let (_, root) = read_table(root, "RSDT")?;
+
+    root[SDT_HEADER_LEN..]
+        .chunks_exact(entry_size)
+        .map(|entry| {
+            let mut addr = [0; 8];
+            addr[..entry_size].copy_from_slice(entry);
+            u64::from_le_bytes(addr)
+        })
+        .find(|&addr| &unsafe { read_phys::<SdtHeader>(addr) }.signature == signature)
+        .ok_or(AcpiError::TableNotFound(name))
+}
+
+fn parse_madt() -> Result<Madt, AcpiError> {
+    let (_, table) = read_table(find_table(MADT_SIGNATURE, "MADT")?, "MADT")?;
+
+    let u16_at = |i: usize| u16::from_le_bytes([table[i], table[i + 1]]);
+    let u32_at = |i: usize| u32::from_le_bytes(table[i..i + 4].try_into().unwrap());
+    let u64_at = |i: usize| u64::from_le_bytes(table[i..i + 8].try_into().unwrap());
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(u64::from(u32_at(SDT_HEADER_LEN))),
+        has_legacy_pics: u32_at(SDT_HEADER_LEN + 4) & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let mut offset = SDT_HEADER_LEN + 8;
+    while offset + 2 <= table.len() {
+        let kind = table[offset];
+        let len = table[offset + 1] as usize;
+        if len < 2 || offset + len > table.len() {
+            break;
+        }
+
+        match kind {
+            0 => madt.processors.push(MadtProcessor {
+                processor_id: table[offset + 2],
+                apic_id: table[offset + 3],
+                enabled: u32_at(offset + 4) & 0b11 != 0,
+            }),
+            1 => madt.io_apics.push(MadtIoApic {
+                id: table[offset + 2],
+                address: PhysAddr::new(u64::from(u32_at(offset + 4))),
+                gsi_base: u32_at(offset + 8),
+            }),
+            2 => {
+                let flags = u16_at(offset + 8);
+                madt.overrides.push(MadtOverride {
+                    irq: table[offset + 3],
+                    gsi: u32_at(offset + 4),
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                });
+            }
+            5 => madt.local_apic_address = PhysAddr::new(u64_at(offset + 4)),
+            _ => debug!("Skipping MADT entry of type {kind}"),
+        }
+
+        offset += len;
+    }
+
+    Ok(madt)
+}
+
+static MADT: Once<Option<Madt>> = Once::new();
+
+/// The parsed MADT, if the firmware provides one.
+pub fn madt() -> Option<&'static Madt> {
+    MADT.call_once(|| {
+        parse_madt()
+            .inspect(|madt| info!("MADT: {madt:x?}"))
+            .inspect_err(|e| info!("No usable MADT: {e}"))
+            .ok()
+    })
+    .as_ref()
+}
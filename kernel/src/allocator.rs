@@ -1,15 +1,31 @@
-use core::ops::DerefMut;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ops::DerefMut,
+    ptr::NonNull,
+};
+
+use blog_os_slab::SlabSource;
 use log::{debug, error, info};
 use spin::Mutex;
 use talc::{OomHandler, Span, Talc, Talck};
 use x86_64::{
     VirtAddr,
-    structures::paging::{
-        FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB, mapper::MapToError,
-    },
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB, mapper::MapToError},
+};
+
+use crate::{
+    lock_class,
+    lockdep::{Class, Fixed, FixedClass},
+    memory::mapping,
+    setup::AllocKernelInfoMutex,
+    slab,
 };
 
-use crate::{multitask::lock::ReentrantMutex, setup::AllocKernelInfo};
+#[cfg(feature = "heap-debug")]
+mod debug;
+
+#[cfg(feature = "heap-debug")]
+pub use debug::dump_live_allocations;
 
 // pub const HEAP_START: u64 = 0x_4444_4444_0000;
 pub const HEAP_PAGES: u64 = 1024;
@@ -17,45 +33,31 @@ pub const HEAP_SIZE: u64 = HEAP_PAGES * Size4KiB::SIZE; // 16 MiB
 
 // TODO grow on oom
 
-pub fn init_heap(
-    mutable_inf: &'static ReentrantMutex<AllocKernelInfo>,
-) -> Result<(), MapToError<Size4KiB>> {
+pub fn init_heap(mutable_inf: &'static AllocKernelInfoMutex) -> Result<(), MapToError<Size4KiB>> {
     debug!("Locking alloc_inf");
     let mut lock = mutable_inf.lock();
     let locked = lock.deref_mut();
 
     debug!("Getting heap_start");
+    // Aligned so that the heap is mapped with huge pages
     let heap_start = VirtAddr::new_truncate(
         locked
             .virt_region_allocator
-            .allocate_range(HEAP_PAGES)
+            .allocate_aligned_range(HEAP_PAGES, Size2MiB::SIZE)
             .expect("Heap region")
             .start,
     );
     // let heap_sheap_starttart = VirtAddr::new(HEAP_START);
 
-    debug!("Getting page range");
-    let page_range = {
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
     debug!("Mapping pages");
-    for page in page_range {
-        let frame = locked
-            .frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            locked
-                .page_table
-                .map_to(page, frame, flags, &mut locked.frame_allocator)?
-                .flush()
-        };
-    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    mapping::map_range(
+        locked,
+        heap_start..heap_start + HEAP_SIZE,
+        flags,
+        true,
+        |_| (),
+    )?;
     drop(lock);
 
     let span = Span::from_base_size(heap_start.as_mut_ptr(), HEAP_SIZE as usize);
@@ -75,11 +77,80 @@ pub fn init_heap(
     Ok(())
 }
 
+#[cfg(not(feature = "heap-debug"))]
+#[global_allocator]
+static KERNEL_ALLOCATOR: KernelAllocator = KernelAllocator;
+
+/// With redzones, the layouts seldom match an object cache, so most allocations go to the
+/// general heap.
+#[cfg(feature = "heap-debug")]
 #[global_allocator]
-static ALLOCATOR: Talck<Mutex<()>, OomGrow> = Talc::new(OomGrow { mutable_inf: None }).lock();
+static KERNEL_ALLOCATOR: debug::DebugAllocator<KernelAllocator> =
+    debug::DebugAllocator::new(KernelAllocator);
+
+/// The class of the lock of the general heap, which talc creates itself
+struct HeapClass;
+
+impl FixedClass for HeapClass {
+    const CLASS: &'static Class = lock_class!();
+}
+
+/// The general heap
+static ALLOCATOR: Talck<Fixed<Mutex<()>, HeapClass>, OomGrow> =
+    Talc::new(OomGrow { mutable_inf: None }).lock();
+
+/// Serves the allocations with the layout of an [object cache](slab) from it, and the others
+/// from the general heap.
+struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match slab::cache_for(layout) {
+            Some(cache) => cache.allocate(&mut HeapSlabs),
+            None => unsafe { ALLOCATOR.alloc(layout) },
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match slab::cache_for(layout) {
+            Some(cache) => unsafe { cache.deallocate(ptr, &mut HeapSlabs) },
+            None => unsafe { ALLOCATOR.dealloc(ptr, layout) },
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        if slab::cache_for(layout).is_none() && slab::cache_for(new_layout).is_none() {
+            return unsafe { ALLOCATOR.realloc(ptr, layout, new_size) };
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
+}
+
+/// Takes the slabs of the object caches from the general heap.
+struct HeapSlabs;
+
+impl SlabSource for HeapSlabs {
+    fn allocate_slab(&mut self, size: usize) -> Option<NonNull<u8>> {
+        let layout = Layout::from_size_align(size, size).ok()?;
+        NonNull::new(unsafe { ALLOCATOR.alloc(layout) })
+    }
+
+    unsafe fn free_slab(&mut self, slab: NonNull<u8>, size: usize) {
+        unsafe { ALLOCATOR.dealloc(slab.as_ptr(), Layout::from_size_align_unchecked(size, size)) };
+    }
+}
 
 struct OomGrow {
-    mutable_inf: Option<&'static ReentrantMutex<AllocKernelInfo>>,
+    mutable_inf: Option<&'static AllocKernelInfoMutex>,
 }
 
 const GROW_PAGES: u64 = 1024;
@@ -113,7 +184,7 @@ impl OomHandler for OomGrow {
         // NOTE: pass page count, not bytes
         let region = kinf
             .virt_region_allocator
-            .allocate_range(grow_pages) // pages
+            .allocate_aligned_range(grow_pages, Size2MiB::SIZE) // pages
             .expect("Heap region");
         let heap_start = VirtAddr::new_truncate(region.start);
 
@@ -122,25 +193,15 @@ impl OomHandler for OomGrow {
             layout.size()
         );
 
-        let page_range = {
-            let heap_end = heap_start + size_bytes - 1u64;
-            let heap_start_page = Page::containing_address(heap_start);
-            let heap_end_page = Page::containing_address(heap_end);
-            Page::range_inclusive(heap_start_page, heap_end_page)
-        };
-
-        debug!("Page range: {page_range:?}");
-
-        for page in page_range {
-            let frame = kinf.frame_allocator.allocate_frame().ok_or(())?;
-            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-            unsafe {
-                kinf.page_table
-                    .map_to(page, frame, flags, &mut kinf.frame_allocator)
-                    .map_err(|_| ())?
-                    .flush()
-            };
-        }
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        mapping::map_range(
+            kinf,
+            heap_start..heap_start + size_bytes,
+            flags,
+            true,
+            |_| (),
+        )
+        .map_err(|_| ())?;
         drop(lock);
 
         let span = Span::from_base_size(heap_start.as_mut_ptr::<u8>(), size_bytes as usize);
@@ -183,4 +244,21 @@ mod test {
             assert_eq!(*x, i);
         }
     }
+
+    #[cfg(feature = "heap-debug")]
+    #[test_case]
+    fn freed_memory_is_poisoned() {
+        use core::alloc::{GlobalAlloc, Layout};
+
+        let layout = Layout::from_size_align(100, 32).unwrap();
+        unsafe {
+            let ptr = super::KERNEL_ALLOCATOR.alloc(layout);
+            assert!(!ptr.is_null());
+            assert!((ptr as usize).is_multiple_of(32));
+            ptr.write_bytes(0, layout.size());
+            super::KERNEL_ALLOCATOR.dealloc(ptr, layout);
+            // The heap reuses a few bytes of a free chunk for itself, the middle stays poisoned
+            assert_eq!(ptr.add(50).read_volatile(), super::debug::POISON_BYTE);
+        }
+    }
 }
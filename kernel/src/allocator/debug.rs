@@ -0,0 +1,385 @@
+//! Heap debugging, with the `heap-debug` feature.
+//!
+//! Every allocation is surrounded by redzones, checked when it is freed, and freed memory is
+//! poisoned. In front of the lower redzone, a header records the size of the allocation and the
+//! stack it was allocated from, and links it into the list of live allocations, which
+//! [`dump_live_allocations`] reports grouped by allocation site.
+//!
+//! The sites are unwound with [`unwind::capture`], which is slow, and allocations made before the
+//! kernel info is set up or while the same CPU is unwinding have none.
+
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cmp::Reverse,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+use alloc::vec::Vec;
+use log::{error, info};
+use x86_64::instructions::interrupts;
+
+use crate::{
+    lock_class,
+    multitask::lock::{SpinMutex, spin_mutex},
+    setup::KERNEL_INFO,
+    smp::{self, PerCpu},
+    unwind,
+};
+
+/// Bytes on each side of an allocation that must not be written
+const REDZONE: usize = 16;
+const REDZONE_BYTE: u8 = 0xcc;
+/// Fills freed memory
+pub const POISON_BYTE: u8 = 0x6b;
+/// Frames recorded per allocation site
+const SITE_FRAMES: usize = 16;
+
+/// Where and how much was allocated, in front of the lower redzone.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct Header {
+    prev: *mut Header,
+    next: *mut Header,
+    size: usize,
+    site: Site,
+}
+
+/// The stack an allocation was made from, innermost frame first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Site {
+    frames: usize,
+    pcs: [u64; SITE_FRAMES],
+}
+
+impl Site {
+    fn pcs(&self) -> &[u64] {
+        &self.pcs[..self.frames]
+    }
+}
+
+/// The live allocations, linked through their headers.
+struct LiveList {
+    head: *mut Header,
+}
+
+// Safety: the headers are only reached through the list lock
+unsafe impl Send for LiveList {}
+
+static LIVE: SpinMutex<LiveList> = spin_mutex(
+    lock_class!(),
+    LiveList {
+        head: ptr::null_mut(),
+    },
+);
+static LIVE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// CPUs recording an allocation site, whose own allocations are not recorded
+static UNWINDING: [AtomicBool; smp::MAX_CPUS] = [const { AtomicBool::new(false) }; smp::MAX_CPUS];
+
+/// Wraps the allocations of `A` with redzones, poisons them once freed and records their sites.
+pub struct DebugAllocator<A> {
+    inner: A,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+/// The layout asked from the inner allocator for `layout`, with the offset of the allocation in
+/// it.
+fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+    let align = layout.align().max(align_of::<Header>());
+    let front = (size_of::<Header>() + REDZONE).next_multiple_of(align);
+    let size = front.checked_add(layout.size())?.checked_add(REDZONE)?;
+    Some((Layout::from_size_align(size, align).ok()?, front))
+}
+
+/// The header of the allocation at `ptr`.
+fn header_of(ptr: *mut u8) -> *mut Header {
+    ptr.wrapping_sub(REDZONE + size_of::<Header>()).cast()
+}
+
+fn record_site() -> Site {
+    let mut site = Site {
+        frames: 0,
+        pcs: [0; SITE_FRAMES],
+    };
+    let cpu = smp::percpu::current().map_or(0, PerCpu::index);
+    if KERNEL_INFO.get().is_none() || UNWINDING[cpu].swap(true, Ordering::Acquire) {
+        return site;
+    }
+    site.frames = unwind::capture(&mut site.pcs);
+    UNWINDING[cpu].store(false, Ordering::Release);
+    site
+}
+
+fn log_site(site: &Site) {
+    if site.frames == 0 {
+        info!("  <unknown site>");
+    }
+    for pc in site.pcs() {
+        unwind::log_kernel_frame(*pc);
+    }
+}
+
+/// Offsets of the first overwritten bytes of the redzones below and above an allocation.
+#[derive(Clone, Copy)]
+struct Damage {
+    below: Option<usize>,
+    above: Option<usize>,
+}
+
+impl Damage {
+    /// # Safety
+    /// `ptr` must be a live allocation of this allocator, of `size` bytes.
+    unsafe fn of(ptr: *const u8, size: usize) -> Self {
+        let overwritten = |redzone: *const u8| {
+            let redzone = unsafe { core::slice::from_raw_parts(redzone, REDZONE) };
+            redzone.iter().position(|b| *b != REDZONE_BYTE)
+        };
+        Self {
+            below: overwritten(unsafe { ptr.sub(REDZONE) }),
+            above: overwritten(unsafe { ptr.add(size) }),
+        }
+    }
+
+    const fn is_none(&self) -> bool {
+        self.below.is_none() && self.above.is_none()
+    }
+
+    fn report(&self, ptr: *const u8, size: usize) {
+        if let Some(offset) = self.below {
+            error!(
+                "heap: {ptr:p} ({size} bytes) was written {} bytes before its start",
+                REDZONE - offset
+            );
+        }
+        if let Some(offset) = self.above {
+            error!("heap: {ptr:p} ({size} bytes) was written {offset} bytes after its end");
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((outer, front)) = outer_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let site = record_site();
+        let base = unsafe { self.inner.alloc(outer) };
+        if base.is_null() {
+            return base;
+        }
+
+        let ptr = unsafe { base.add(front) };
+        let header = header_of(ptr);
+        unsafe {
+            header.write(Header {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                size: layout.size(),
+                site,
+            });
+            ptr.sub(REDZONE).write_bytes(REDZONE_BYTE, REDZONE);
+            ptr.add(layout.size()).write_bytes(REDZONE_BYTE, REDZONE);
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut live = LIVE.lock();
+            unsafe {
+                (*header).next = live.head;
+                if let Some(next) = live.head.as_mut() {
+                    next.prev = header;
+                }
+            }
+            live.head = header;
+        });
+        LIVE_COUNT.fetch_add(1, Ordering::Relaxed);
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (outer, front) = outer_layout(layout).expect("the layout of a live allocation");
+        let header = header_of(ptr);
+
+        interrupts::without_interrupts(|| {
+            let mut live = LIVE.lock();
+            let Header { prev, next, .. } = unsafe { *header };
+            match unsafe { prev.as_mut() } {
+                Some(prev) => prev.next = next,
+                None => live.head = next,
+            }
+            if let Some(next) = unsafe { next.as_mut() } {
+                next.prev = prev;
+            }
+        });
+        LIVE_COUNT.fetch_sub(1, Ordering::Relaxed);
+
+        let header = unsafe { *header };
+        let damage = unsafe { Damage::of(ptr, header.size) };
+        damage.report(ptr, header.size);
+        if header.size != layout.size() {
+            error!(
+                "heap: {ptr:p} freed with {} bytes, allocated with {}",
+                layout.size(),
+                header.size
+            );
+        }
+        if !damage.is_none() || header.size != layout.size() {
+            info!("heap: {ptr:p} was allocated at:");
+            log_site(&header.site);
+        }
+
+        unsafe {
+            let base = ptr.sub(front);
+            base.write_bytes(POISON_BYTE, outer.size());
+            self.inner.dealloc(base, outer);
+        }
+    }
+}
+
+/// The live allocations at one point.
+struct Snapshot {
+    /// Allocation sites, with their count of allocations and bytes, the most bytes first
+    sites: Vec<(Site, usize, usize)>,
+    /// Allocations whose redzones were overwritten, with their site and size
+    damaged: Vec<(Site, usize, *const u8, Damage)>,
+    /// Allocations made while the snapshot was taken, left out
+    skipped: usize,
+}
+
+/// Takes a snapshot of the live allocations, checking their redzones.
+fn snapshot() -> Snapshot {
+    // Allocating or logging with the list locked would deadlock, so the copy is made in place
+    let mut live: Vec<(Site, usize, *const u8, Damage)> =
+        Vec::with_capacity(LIVE_COUNT.load(Ordering::Relaxed) + 64);
+    let mut skipped = 0;
+    interrupts::without_interrupts(|| {
+        let list = LIVE.lock();
+        let mut header = list.head;
+        while let Some(current) = unsafe { header.as_ref() } {
+            if live.len() < live.capacity() {
+                let ptr = header
+                    .cast::<u8>()
+                    .wrapping_add(size_of::<Header>() + REDZONE);
+                let damage = unsafe { Damage::of(ptr, current.size) };
+                live.push((current.site, current.size, ptr, damage));
+            } else {
+                skipped += 1;
+            }
+            header = current.next;
+        }
+    });
+
+    let damaged = live
+        .iter()
+        .filter(|(.., damage)| !damage.is_none())
+        .copied()
+        .collect();
+
+    live.sort_unstable_by_key(|(site, ..)| *site);
+    let mut sites: Vec<(Site, usize, usize)> = Vec::new();
+    for (site, size, ..) in live {
+        match sites.last_mut() {
+            Some((last, count, bytes)) if *last == site => {
+                *count += 1;
+                *bytes += size;
+            }
+            _ => sites.push((site, 1, size)),
+        }
+    }
+    sites.sort_unstable_by_key(|(_, _, bytes)| Reverse(*bytes));
+
+    Snapshot {
+        sites,
+        damaged,
+        skipped,
+    }
+}
+
+/// Logs the live allocations grouped by allocation site, the sites holding the most bytes first,
+/// and checks their redzones.
+pub fn dump_live_allocations() {
+    let Snapshot {
+        sites,
+        damaged,
+        skipped,
+    } = snapshot();
+
+    for (site, size, ptr, damage) in &damaged {
+        damage.report(*ptr, *size);
+        info!("heap: {ptr:p} was allocated at:");
+        log_site(site);
+    }
+
+    let total: usize = sites.iter().map(|(_, _, bytes)| bytes).sum();
+    info!(
+        "heap: {} live allocations, {total} bytes, from {} sites",
+        sites.iter().map(|(_, count, _)| count).sum::<usize>(),
+        sites.len()
+    );
+    if skipped > 0 {
+        info!("heap: {skipped} allocations made during the dump are left out");
+    }
+    for (site, count, bytes) in &sites {
+        info!("heap: {count} allocations, {bytes} bytes, at:");
+        log_site(site);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::vec::Vec;
+    use core::alloc::{GlobalAlloc, Layout};
+
+    use super::{REDZONE_BYTE, header_of, snapshot};
+    use crate::allocator::KERNEL_ALLOCATOR;
+
+    #[test_case]
+    fn overwritten_redzone_is_reported() {
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        unsafe {
+            let ptr = KERNEL_ALLOCATOR.alloc(layout);
+            assert!(!ptr.is_null());
+            // One byte past the end
+            ptr.add(layout.size()).write(0);
+
+            let damage = snapshot()
+                .damaged
+                .iter()
+                .find(|(_, _, damaged, _)| damaged.cast_mut() == ptr)
+                .map(|(.., damage)| *damage);
+            assert!(damage.is_some_and(|damage| damage.below.is_none() && damage.above == Some(0)));
+
+            ptr.add(layout.size()).write(REDZONE_BYTE);
+            KERNEL_ALLOCATOR.dealloc(ptr, layout);
+        }
+    }
+
+    #[test_case]
+    fn allocations_are_grouped_by_site() {
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+        // Both made from the same call site
+        let ptrs: Vec<*mut u8> = (0..2)
+            .map(|_| unsafe { KERNEL_ALLOCATOR.alloc(layout) })
+            .collect();
+        assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+        let site = unsafe { (*header_of(ptrs[0])).site };
+        assert!(site == unsafe { (*header_of(ptrs[1])).site });
+
+        let group = snapshot()
+            .sites
+            .into_iter()
+            .find(|(other, ..)| *other == site)
+            .map(|(_, count, bytes)| (count, bytes));
+        assert_eq!(group, Some((2, 2 * layout.size())));
+
+        for ptr in ptrs {
+            unsafe { KERNEL_ALLOCATOR.dealloc(ptr, layout) };
+        }
+    }
+}
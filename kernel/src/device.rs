@@ -1,5 +1,10 @@
 use blog_os_device::bus::BusRegistry;
-use spin::{Lazy, RwLock};
+use spin::Lazy;
 
-pub static BUS_REGISTRY: Lazy<RwLock<BusRegistry>> =
-    Lazy::new(|| RwLock::new(BusRegistry::default()));
+use crate::{
+    lock_class,
+    multitask::lock::{SpinRwLock, spin_rwlock},
+};
+
+pub static BUS_REGISTRY: Lazy<SpinRwLock<BusRegistry>> =
+    Lazy::new(|| spin_rwlock(lock_class!(), BusRegistry::default()));
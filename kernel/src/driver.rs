@@ -1,19 +1,27 @@
-use core::{alloc::Layout, ffi::CStr};
+use core::{
+    alloc::Layout,
+    ffi::CStr,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+};
 
 use alloc::{
+    boxed::Box,
     collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    format,
     string::{String, ToString},
     sync::Arc,
 };
+use blog_os_buddy::{MAX_ORDER, block_size, order_for};
 use blog_os_device::api::bus::{Bus, cglue_bus::BusBox};
-use kdriver_api::{CLayout, KernelInterface};
+use kdriver_api::{CLayout, DmaRegion, KernelInterface};
 use log::{debug, info};
 use object::{Object, ObjectSymbol};
-use spin::{Once, RwLock};
+use spin::Once;
 use thiserror::Error;
 use x86_64::{
-    VirtAddr,
-    structures::paging::{PageSize, Size4KiB},
+    PhysAddr, VirtAddr,
+    instructions::interrupts::without_interrupts,
+    structures::paging::{PageSize, PhysFrame, Size2MiB, Size4KiB},
 };
 
 use crate::{
@@ -22,12 +30,24 @@ use crate::{
         EType, ElfLoadError, LoadedElf, load_elf,
         symbol::{InterfaceKey, KDriverResolver},
     },
+    interrupts::softirq::Tasklet,
+    lock_class,
     memory::range_alloc::FreeOnDrop,
+    multitask::{
+        kthread::{self, JoinHandle},
+        lock::{SpinRwLock, spin_rwlock},
+        sync::Semaphore,
+        wait::WaitQueue,
+        workqueue,
+    },
     setup::KERNEL_INFO,
 };
 
 pub mod registry;
 
+/// End of the memory devices with 32 bit addresses reach
+const DMA_32_LIMIT: u64 = 1 << 32;
+
 struct InterfaceData {
     id: InterfaceKey,
     name: String,
@@ -36,16 +56,65 @@ struct InterfaceData {
 
 pub struct Interface {
     data: Arc<Once<InterfaceData>>,
-    allocs: Arc<RwLock<BTreeMap<VirtAddr, Layout>>>,
-    registered_buses: Arc<RwLock<BTreeSet<String>>>,
+    allocs: Arc<SpinRwLock<BTreeMap<VirtAddr, Layout>>>,
+    /// DMA memory, with its first frame and order
+    dma: Arc<SpinRwLock<BTreeMap<VirtAddr, (PhysFrame, usize)>>>,
+    registered_buses: Arc<SpinRwLock<BTreeSet<String>>>,
+    /// Signaled from interrupt handlers, so only locked with them disabled
+    completions: Arc<SpinRwLock<BTreeMap<u64, Arc<Semaphore>>>>,
+    threads: Arc<SpinRwLock<BTreeMap<u64, JoinHandle<()>>>>,
+    /// Scheduled from interrupt handlers, so only locked with them disabled
+    tasklets: Arc<SpinRwLock<BTreeMap<u64, &'static Tasklet>>>,
+    work: Arc<DriverWork>,
+    /// Handles of completions and threads
+    next_handle: Arc<AtomicU64>,
+}
+
+/// Work the driver queued, which must be over before the driver goes away.
+///
+/// Waiting for it from the worker thread would never end, so drivers are not dropped there.
+#[derive(Default)]
+struct DriverWork {
+    /// Queued or running
+    outstanding: AtomicUsize,
+    /// Set once the driver is going away, the work still queued is cancelled
+    cancelled: AtomicBool,
+    idle: WaitQueue,
+}
+
+/// A function of the driver with its argument, which the driver made to be called from
+/// anywhere.
+struct DriverCall {
+    entry: extern "C" fn(*mut ()),
+    arg: *mut (),
+}
+
+unsafe impl Send for DriverCall {}
+unsafe impl Sync for DriverCall {}
+
+impl DriverCall {
+    fn call(&self) {
+        (self.entry)(self.arg);
+    }
+}
+
+/// Runs the [`DriverCall`] a driver tasklet points to.
+fn run_driver_tasklet(call: usize) {
+    unsafe { &*(call as *const DriverCall) }.call();
 }
 
 impl Interface {
     pub fn new() -> Self {
         Self {
             data: Arc::new(Once::new()),
-            allocs: Arc::new(RwLock::new(BTreeMap::new())),
-            registered_buses: Default::default(),
+            allocs: Arc::new(spin_rwlock(lock_class!(), BTreeMap::new())),
+            dma: Arc::new(spin_rwlock(lock_class!(), BTreeMap::new())),
+            registered_buses: Arc::new(spin_rwlock(lock_class!(), BTreeSet::new())),
+            completions: Arc::new(spin_rwlock(lock_class!(), BTreeMap::new())),
+            threads: Arc::new(spin_rwlock(lock_class!(), BTreeMap::new())),
+            tasklets: Arc::new(spin_rwlock(lock_class!(), BTreeMap::new())),
+            work: Default::default(),
+            next_handle: Default::default(),
         }
     }
 }
@@ -90,16 +159,156 @@ impl KernelInterface for Interface {
         }
     }
 
+    fn dma_alloc(&self, size: usize, align: usize, below_4g: bool) -> DmaRegion {
+        let frames = (size as u64).div_ceil(Size4KiB::SIZE);
+        let order = order_for(frames).max(order_for((align as u64).div_ceil(Size4KiB::SIZE)));
+        if order > MAX_ORDER {
+            return DmaRegion::null();
+        }
+
+        let kinf = KERNEL_INFO.get().unwrap();
+        let mut lock = kinf.alloc_kinf.lock();
+        let frame = if below_4g {
+            lock.frame_allocator
+                .allocate_contiguous_below(order, PhysAddr::new(DMA_32_LIMIT))
+        } else {
+            lock.frame_allocator.allocate_contiguous(order)
+        };
+        drop(lock);
+        let Some(frame) = frame else {
+            return DmaRegion::null();
+        };
+
+        let virt = kinf.physical_memory_offset + frame.start_address().as_u64();
+        let size = block_size(order) as usize;
+        unsafe { core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, size) };
+        self.dma.write().insert(virt, (frame, order));
+
+        DmaRegion {
+            virt: virt.as_mut_ptr(),
+            phys: frame.start_address().as_u64(),
+            size,
+        }
+    }
+
+    unsafe fn dma_free(&self, virt: *mut u8) {
+        let Some((frame, order)) = self.dma.write().remove(&VirtAddr::from_ptr(virt)) else {
+            panic!("freeing DMA memory that was not allocated")
+        };
+        let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
+        unsafe { lock.frame_allocator.deallocate_contiguous(frame, order) };
+    }
+
     fn register_bus(&self, bus: BusBox<'static>) {
         let name = bus.name().to_string();
         debug!("Registering bus {name:?}");
         self.registered_buses.write().insert(name);
         BUS_REGISTRY.write().register(bus);
     }
+
+    fn completion_create(&self) -> u64 {
+        let id = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        without_interrupts(|| {
+            self.completions
+                .write()
+                .insert(id, Arc::new(Semaphore::new(0)));
+        });
+        id
+    }
+
+    fn completion_signal(&self, completion: u64) {
+        let semaphore = without_interrupts(|| self.completions.read().get(&completion).cloned());
+        if let Some(semaphore) = semaphore {
+            semaphore.release();
+        }
+    }
+
+    fn completion_wait(&self, completion: u64) -> bool {
+        // Not holding the map while sleeping
+        let semaphore = without_interrupts(|| self.completions.read().get(&completion).cloned());
+        semaphore.inspect(|x| x.acquire()).is_some()
+    }
+
+    fn completion_destroy(&self, completion: u64) {
+        without_interrupts(|| self.completions.write().remove(&completion));
+    }
+
+    fn thread_spawn(&self, entry: extern "C" fn(*mut ()), arg: *mut (), name: &str) -> u64 {
+        let data = self.data.get().unwrap();
+        let call = DriverCall { entry, arg };
+        let handle = kthread::spawn(move || call.call(), format!("{}/{name}", data.name));
+
+        let id = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        self.threads.write().insert(id, handle);
+        id
+    }
+
+    fn thread_join(&self, thread: u64) -> bool {
+        let handle = self.threads.write().remove(&thread);
+        handle.map(JoinHandle::join).is_some()
+    }
+
+    fn tasklet_create(&self, entry: extern "C" fn(*mut ()), arg: *mut ()) -> u64 {
+        let call = Box::into_raw(Box::new(DriverCall { entry, arg }));
+        let tasklet = Box::leak(Box::new(Tasklet::new(run_driver_tasklet, call as usize)));
+
+        let id = self.next_handle.fetch_add(1, Ordering::Relaxed);
+        without_interrupts(|| self.tasklets.write().insert(id, tasklet));
+        id
+    }
+
+    fn tasklet_schedule(&self, tasklet: u64) {
+        if let Some(tasklet) = without_interrupts(|| self.tasklets.read().get(&tasklet).copied()) {
+            tasklet.schedule();
+        }
+    }
+
+    fn tasklet_destroy(&self, tasklet: u64) {
+        if let Some(tasklet) = without_interrupts(|| self.tasklets.write().remove(&tasklet)) {
+            unsafe { free_driver_tasklet(tasklet) };
+        }
+    }
+
+    fn queue_work(
+        &self,
+        entry: extern "C" fn(*mut ()),
+        cancel: extern "C" fn(*mut ()),
+        arg: *mut (),
+    ) {
+        let call = DriverCall { entry, arg };
+        let cancel = DriverCall { entry: cancel, arg };
+        let work = self.work.clone();
+        work.outstanding.fetch_add(1, Ordering::AcqRel);
+        workqueue::queue_work(move || {
+            if work.cancelled.load(Ordering::Acquire) {
+                cancel.call();
+            } else {
+                call.call();
+            }
+            if work.outstanding.fetch_sub(1, Ordering::AcqRel) == 1 {
+                work.idle.wake_all();
+            }
+        });
+    }
 }
 
 impl Drop for Interface {
     fn drop(&mut self) {
+        // They still run the driver's code and use its allocations
+        let threads = core::mem::take(&mut *self.threads.write());
+        for handle in threads.into_values() {
+            handle.join();
+        }
+        let tasklets = without_interrupts(|| core::mem::take(&mut *self.tasklets.write()));
+        for tasklet in tasklets.into_values() {
+            unsafe { free_driver_tasklet(tasklet) };
+        }
+        // Queued work is cancelled, running work finishes
+        self.work.cancelled.store(true, Ordering::Release);
+        self.work
+            .idle
+            .wait_until(|| self.work.outstanding.load(Ordering::Acquire) == 0);
+
         for bus in self.registered_buses.read().iter() {
             BUS_REGISTRY.write().unregister(bus);
         }
@@ -107,9 +316,28 @@ impl Drop for Interface {
         for (&ptr, &layout) in self.allocs.read().iter() {
             unsafe { alloc::alloc::dealloc(ptr.as_mut_ptr::<u8>(), layout) };
         }
+
+        let dma = core::mem::take(&mut *self.dma.write());
+        if !dma.is_empty() {
+            let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
+            for (frame, order) in dma.into_values() {
+                unsafe { lock.frame_allocator.deallocate_contiguous(frame, order) };
+            }
+        }
     }
 }
 
+/// Frees a tasklet made by [`Interface::tasklet_create`], once it is idle.
+///
+/// # Safety
+/// The tasklet must come from [`Interface::tasklet_create`], and be out of its map so that it
+/// can't be scheduled again.
+unsafe fn free_driver_tasklet(tasklet: &'static Tasklet) {
+    tasklet.wait_idle();
+    let tasklet = unsafe { Box::from_raw(core::ptr::from_ref(tasklet).cast_mut()) };
+    drop(unsafe { Box::from_raw(tasklet.data() as *mut DriverCall) });
+}
+
 pub struct KDriver {
     _elf: LoadedElf<KDriverResolver>,
     name: String,
@@ -162,10 +390,14 @@ impl KDriver {
 
                     let pages = size.div_ceil(Size4KiB::SIZE);
                     debug!("Requesting {pages} pages for driver (0x{size:X} bytes)");
-                    let alloc_reg = lock
-                        .virt_region_allocator
-                        .allocate_range(pages)
-                        .map_err(|_| ElfLoadError::MemAllocError)?;
+                    // Large images are aligned so that their segments get huge pages
+                    let alloc_reg = if size >= Size2MiB::SIZE {
+                        lock.virt_region_allocator
+                            .allocate_aligned_range(pages, Size2MiB::SIZE)
+                    } else {
+                        lock.virt_region_allocator.allocate_range(pages)
+                    }
+                    .map_err(|_| ElfLoadError::MemAllocError)?;
                     let base_addr = VirtAddr::new_truncate(alloc_reg.start);
                     region = Some(alloc_reg);
 
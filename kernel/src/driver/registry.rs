@@ -1,7 +1,9 @@
+use crate::{
+    driver::KDriver,
+    lock_class,
+    multitask::lock::{SpinRwLock, spin_rwlock},
+};
 use alloc::{collections::btree_map::BTreeMap, string::String};
-use spin::lock_api::RwLock;
-
-use crate::driver::KDriver;
 
 pub struct KDriverRegistry {
     drivers: BTreeMap<String, KDriver>,
@@ -33,5 +35,5 @@ impl Default for KDriverRegistry {
     }
 }
 
-pub static DRIVER_REGISTRY: RwLock<KDriverRegistry> =
-    RwLock::const_new(spin::RwLock::new(()), KDriverRegistry::new());
+pub static DRIVER_REGISTRY: SpinRwLock<KDriverRegistry> =
+    spin_rwlock(lock_class!(), KDriverRegistry::new());
@@ -1,6 +1,6 @@
 use core::{
     mem::ManuallyDrop,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, RangeInclusive},
 };
 
 use addr2line::Context;
@@ -17,17 +17,20 @@ use thiserror::Error;
 use x86_64::{
     VirtAddr,
     structures::paging::{
-        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
-        mapper::CleanUp,
+        FrameAllocator, Mapper, Page, PageSize, PageTableFlags, Size2MiB, Size4KiB, mapper::CleanUp,
     },
 };
 
 use crate::{
     dwarf::{EndianSlice, load_dwarf},
     elf::symbol::SymbolResolver,
-    multitask::lock::ReentrantMutex,
+    lock_class,
+    memory::{
+        mapping::{self, MappedPage},
+        vma::{Access, AddressSpace, Backing, FaultError, ImageSegment, UserStack},
+    },
+    multitask::lock::{ReentrantMutex, reentrant_mutex},
     setup::KERNEL_INFO,
-    stack::{self, GeneralStack},
     unwind::eh::EhInfo,
 };
 
@@ -135,19 +138,21 @@ pub struct ElfWithDataAndDwarf {
 pub struct UserHeap {
     size: u64,
     brk: VirtAddr,
-    mapped_pages: BTreeMap<Page, PageTableFlags>,
 }
 
 impl UserHeap {
     pub const fn new(brk: VirtAddr) -> Self {
-        Self {
-            size: 0,
-            brk,
-            mapped_pages: BTreeMap::new(),
-        }
+        Self { size: 0, brk }
     }
 
-    pub fn change_brk(&mut self, stack: &GeneralStack, offset: i64) -> Option<VirtAddr> {
+    /// Moves the break by `offset`, adding the growth to `space` as an area mapped on first
+    /// touch, with 2 MiB pages where they fit if `huge` is set.
+    pub fn change_brk(
+        &mut self,
+        space: &mut AddressSpace,
+        offset: i64,
+        huge: bool,
+    ) -> Option<VirtAddr> {
         if offset == 0 {
             Some(self.brk)
         } else if offset < 0 {
@@ -158,39 +163,23 @@ impl UserHeap {
                 todo!("implement brk shinking (0x{offset:x} - {offset})")
             }
         } else {
-            let new_brk = (self.brk + offset.unsigned_abs()).align_up(Size4KiB::SIZE);
-            let new_pages = Page::<Size4KiB>::range(
-                Page::containing_address(self.brk),
-                Page::containing_address(new_brk),
-            );
-            if new_pages.end >= Page::containing_address(stack.bottom()) {
-                panic!("Memory overflow, cannot allocate more heap: {new_pages:?} -> {stack:?}")
-            }
-
-            let info = KERNEL_INFO.get().unwrap();
-            let mut info_lock = info.alloc_kinf.lock();
-            let info = info_lock.deref_mut();
-
-            let page_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE; // For now with one page table we should be able to write to it
-
-            for p in new_pages {
-                let frame = info.frame_allocator.allocate_frame().expect("A frame");
-                unsafe {
-                    info.page_table.map_to(
-                        p,
-                        frame,
-                        PageTableFlags::PRESENT
-                            | PageTableFlags::WRITABLE
-                            | PageTableFlags::USER_ACCESSIBLE,
-                        &mut info.frame_allocator,
-                    )
-                }
-                .unwrap()
-                .flush();
-                self.mapped_pages.insert(p, page_flags);
+            let align = if huge { Size2MiB::SIZE } else { Size4KiB::SIZE };
+            let new_brk = (self.brk + offset.unsigned_abs()).align_up(align);
+            // Up to the guard gap of the stack
+            if new_brk > space.heap_limit() {
+                warn!(
+                    "Memory overflow, cannot allocate more heap: {:p}-{new_brk:p} (limit: {:p})",
+                    self.brk,
+                    space.heap_limit()
+                );
+                return None;
             }
 
-            drop(info_lock);
+            let page_flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE
+                | PageTableFlags::NO_EXECUTE;
+            space.add_area(self.brk..new_brk, page_flags, Backing::Zero { huge });
 
             let growth = new_brk - self.brk;
             self.size += growth;
@@ -201,44 +190,15 @@ impl UserHeap {
     }
 }
 
-impl Drop for UserHeap {
-    fn drop(&mut self) {
-        // Unload the stack
-        let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
-        let mem = &mut *lock;
-
-        let mut min_max = None;
-        for page in self.mapped_pages.keys() {
-            let (frame, flush) = mem.page_table.unmap(*page).expect("Mapped page");
-            flush.flush();
-            unsafe { mem.frame_allocator.deallocate_frame(frame) };
-            if let Some((min, max)) = &mut min_max {
-                if *min > *page {
-                    *min = *page
-                } else if *max < *page {
-                    *max = *page
-                }
-            } else {
-                min_max = Some((*page, *page));
-            }
-        }
-
-        if let Some((min, max)) = min_max {
-            unsafe {
-                mem.page_table
-                    .clean_up_addr_range(Page::range_inclusive(min, max), &mut mem.frame_allocator)
-            };
-        }
-
-        drop(lock);
-    }
-}
-
 pub struct LoadedElf<S: SymbolResolver> {
     _symbol_resolver: ManuallyDrop<S>,
     load_offset: u64,
     elf: ElfWithDataAndDwarf,
     mapped_pages: BTreeMap<Page, ElfPhSegmentFlags>,
+    /// 2 MiB pages mapping the large segments of kernel ELFs
+    huge_pages: BTreeMap<Page<Size2MiB>, ElfPhSegmentFlags>,
+    /// Areas of a user ELF, whose pages are only mapped on first touch
+    address_space: Option<AddressSpace>,
     highest_page: Option<Page>,
 }
 
@@ -266,7 +226,7 @@ impl<S: SymbolResolver> LoadedElf<S> {
                     .inspect(|_| debug!("Built context"))
                     .inspect_err(|e| warn!("{e:?}"))
                     .ok()
-                    .map(ReentrantMutex::new)
+                    .map(|c| reentrant_mutex(lock_class!(), c))
                     .map(Arc::new);
 
                 info!("Loaded addr2line context for process");
@@ -316,19 +276,17 @@ impl<S: SymbolResolver> Drop for LoadedElf<S> {
         let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
         let mem = &mut *lock;
 
+        let pages = (self.mapped_pages.keys().copied().map(MappedPage::Small))
+            .chain(self.huge_pages.keys().copied().map(MappedPage::Huge));
         let mut min_max = None;
-        for page in self.mapped_pages.keys() {
-            let (frame, flush) = mem.page_table.unmap(*page).expect("Mapped page");
-            flush.flush();
-            unsafe { mem.frame_allocator.deallocate_frame(frame) };
+        for page in pages {
+            mapping::unmap(mem, page).expect("Mapped page");
+            let covered = page.small_pages();
             if let Some((min, max)) = &mut min_max {
-                if *min > *page {
-                    *min = *page
-                } else if *max < *page {
-                    *max = *page
-                }
+                *min = covered.start.min(*min);
+                *max = covered.end.max(*max);
             } else {
-                min_max = Some((*page, *page));
+                min_max = Some((covered.start, covered.end));
             }
         }
 
@@ -345,9 +303,10 @@ impl<S: SymbolResolver> Drop for LoadedElf<S> {
 
 pub struct LoadedProgram {
     elf: LoadedElf<()>,
-    stack: ManuallyDrop<GeneralStack>,
+    stack: UserStack,
     entry: VirtAddr,
     heap: ReentrantMutex<UserHeap>,
+    address_space: ReentrantMutex<AddressSpace>,
 }
 
 impl core::fmt::Debug for LoadedProgram {
@@ -365,25 +324,23 @@ impl LoadedProgram {
         self.entry
     }
 
-    pub fn stack(&self) -> &GeneralStack {
+    pub const fn stack(&self) -> &UserStack {
         &self.stack
     }
 
     pub const fn heap(&self) -> &ReentrantMutex<UserHeap> {
         &self.heap
     }
-}
-
-impl Drop for LoadedProgram {
-    fn drop(&mut self) {
-        let stack = unsafe { ManuallyDrop::take(&mut self.stack) };
 
-        // Unload the stack
-        let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
-        let mem = &mut *lock;
-        unsafe { stack::clear_stack(stack, &mut mem.page_table, &mut mem.frame_allocator) };
+    pub const fn address_space(&self) -> &ReentrantMutex<AddressSpace> {
+        &self.address_space
+    }
 
-        drop(lock);
+    /// Maps the page of `addr` after an `access` to it faulted.
+    pub fn handle_fault(&self, addr: VirtAddr, access: Access) -> Result<(), FaultError> {
+        self.address_space
+            .lock()
+            .handle_fault(addr, access, self.elf.elf.borrow_data())
     }
 }
 
@@ -407,10 +364,17 @@ pub enum ElfLoadError {
     InvalidType(EType),
     #[error("Unable to allocate memory region for loading this ELF")]
     MemAllocError,
+    #[error("Segment at 0x{0:x} would map pages writable and executable")]
+    WritableExecutable(u64),
 }
 
 pub type ElfHeader = object::elf::FileHeader64<object::endian::LittleEndian>;
 
+/// Numbers of the 4 KiB pages covered by `memsz` bytes from `vaddr`.
+fn page_span(vaddr: u64, memsz: u64) -> RangeInclusive<u64> {
+    vaddr / Size4KiB::SIZE..=(vaddr + memsz.max(1) - 1) / Size4KiB::SIZE
+}
+
 pub const ELF_ALIGN: usize = core::mem::align_of::<ElfHeader>();
 
 pub fn load_elf<S: SymbolResolver>(
@@ -460,6 +424,19 @@ pub fn load_elf<S: SymbolResolver>(
             p.p_align(LittleEndian)
         );
         if p_type == PHType::Load {
+            // Pages shared with an earlier segment get the flags of both
+            let pages = page_span(vaddr, memsz);
+            let shared = loads
+                .iter()
+                .filter(|(_, (v, m), _)| {
+                    let other = page_span(*v, *m);
+                    other.start() <= pages.end() && pages.start() <= other.end()
+                })
+                .fold(flags, |acc, (_, _, f)| acc | *f);
+            if shared.contains(ElfPhSegmentFlags::W | ElfPhSegmentFlags::E) {
+                return Err(ElfLoadError::WritableExecutable(vaddr));
+            }
+
             highest_end = highest_end.max(vaddr + memsz);
             loads.push(((offset, filesz), (vaddr, memsz), flags));
         }
@@ -489,6 +466,10 @@ pub fn load_elf<S: SymbolResolver>(
     let info = info_lock.deref_mut();
     let mut highest_page: Option<Page> = None;
     let mut mapped_pages = BTreeMap::new();
+    let mut huge_pages = BTreeMap::new();
+    let mut user_pages = BTreeMap::<Page, ElfPhSegmentFlags>::new();
+    let mut segments = Vec::new();
+    let mut fixups = BTreeMap::new();
     for ((offset, filesz), (vaddr_offset, memsz), flags) in loads {
         let vaddr = base_addr + vaddr_offset;
         debug!(
@@ -498,9 +479,56 @@ pub fn load_elf<S: SymbolResolver>(
             Page::containing_address(vaddr),
             Page::containing_address(vaddr + memsz - 1),
         );
-        for p in pages {
+        highest_page = Some(highest_page.map_or(pages.end, |old| {
+            if old.start_address() > pages.end.start_address() {
+                old
+            } else {
+                pages.end
+            }
+        }));
+
+        if user {
+            // Filled from the image on first touch
+            for p in pages {
+                *user_pages.entry(p).or_insert(ElfPhSegmentFlags::empty()) |= flags;
+            }
+            segments.push(ImageSegment {
+                vaddr,
+                offset,
+                filesz,
+            });
+            continue;
+        }
+
+        let mut remaining = pages;
+        while let Some(p) = remaining.next() {
             let mut page_flags = base_setup_flags;
 
+            // Kernel segments get 2 MiB pages for the aligned blocks they cover whole
+            let huge_end = p.start_address() + Size2MiB::SIZE;
+            if !user
+                && p.start_address().is_aligned(Size2MiB::SIZE)
+                && huge_end <= vaddr + memsz
+                && mapped_pages
+                    .range(p..Page::containing_address(huge_end))
+                    .next()
+                    .is_none()
+            {
+                get_flags(&mut page_flags, flags);
+                if let Some(huge) = mapping::map_huge(
+                    info,
+                    Page::containing_address(p.start_address()),
+                    page_flags,
+                )
+                .expect("Unmapped page")
+                {
+                    huge_pages.insert(huge, flags);
+                    remaining.start = Page::containing_address(huge_end);
+                    continue;
+                }
+                page_flags = base_setup_flags;
+            }
+
             if let Some(x) = mapped_pages.get(&p) {
                 debug!("Page {p:?} already mapped with flags {x:?} (new: {flags:?})");
                 let new_flags = flags | *x;
@@ -556,14 +584,6 @@ pub fn load_elf<S: SymbolResolver>(
                 (memsz - filesz) as usize,
             )
         };
-
-        highest_page = Some(highest_page.map_or(pages.end, |old| {
-            if old.start_address() > pages.end.start_address() {
-                old
-            } else {
-                pages.end
-            }
-        }))
     }
 
     // Remove writable flag for non writable pages
@@ -575,6 +595,14 @@ pub fn load_elf<S: SymbolResolver>(
             .unwrap()
             .flush();
     }
+    for (page, flags) in huge_pages.iter() {
+        let mut page_flags = base_flags;
+        get_flags(&mut page_flags, *flags);
+
+        unsafe { info.page_table.update_flags(*page, page_flags) }
+            .unwrap()
+            .flush();
+    }
 
     info!("Loaded segments");
 
@@ -606,8 +634,12 @@ pub fn load_elf<S: SymbolResolver>(
                     let addr = base_addr + addr;
 
                     // unsafe { core::ptr::copy(value.data.as_ptr(), addr.as_mut_ptr::<u8>(), value.data.len()) };
-                    unsafe {
-                        addr.as_mut_ptr::<u64>().write_volatile(value.data.as_u64());
+                    if user {
+                        fixups.insert(addr, value.data.as_u64());
+                    } else {
+                        unsafe {
+                            addr.as_mut_ptr::<u64>().write_volatile(value.data.as_u64());
+                        }
                     }
 
                     debug!(
@@ -624,7 +656,11 @@ pub fn load_elf<S: SymbolResolver>(
                     let value = base_addr.as_u64().saturating_add_signed(reloc.addend());
 
                     // debug!("Setting {addr:p} to {value}");
-                    unsafe { addr.as_mut_ptr::<u64>().write(value) };
+                    if user {
+                        fixups.insert(addr, value);
+                    } else {
+                        unsafe { addr.as_mut_ptr::<u64>().write(value) };
+                    }
                 }
                 x => unimplemented!("{x:?}"),
             }
@@ -633,17 +669,30 @@ pub fn load_elf<S: SymbolResolver>(
 
     drop(info_lock);
 
+    let address_space = user.then(|| {
+        let mut space = AddressSpace::new(segments, fixups);
+        for (page, flags) in user_pages {
+            let mut page_flags = base_flags;
+            get_flags(&mut page_flags, flags);
+            let start = page.start_address();
+            space.add_area(start..start + Size4KiB::SIZE, page_flags, Backing::Image);
+        }
+        space
+    });
+
     Ok(LoadedElf {
         load_offset: base_addr.as_u64(),
         elf: elf_contained,
         mapped_pages,
+        huge_pages,
+        address_space,
         highest_page,
         _symbol_resolver: ManuallyDrop::new(resolver),
     })
 }
 
 pub fn load_user_program(bytes: &[u8]) -> Result<LoadedProgram, ElfLoadError> {
-    let loaded_elf = load_elf(
+    let mut loaded_elf = load_elf(
         bytes,
         |e_type, _size| {
             if *e_type == EType::ET_DYN {
@@ -659,24 +708,12 @@ pub fn load_user_program(bytes: &[u8]) -> Result<LoadedProgram, ElfLoadError> {
         (),
     )?;
 
-    let info = KERNEL_INFO.get().unwrap();
-    let mut info_lock = info.alloc_kinf.lock();
-    let info = info_lock.deref_mut();
-
     let highest_page = loaded_elf.highest_page.unwrap();
     let brk = highest_page.start_address() + Size4KiB::SIZE;
 
-    let stack_top = qemu_common::KERNEL_START;
-    let stack = unsafe {
-        stack::create_stack_from_top(
-            stack_top,
-            &mut info.page_table,
-            &mut info.frame_allocator,
-            PageTableFlags::USER_ACCESSIBLE,
-        )
-    };
-
-    drop(info_lock);
+    let mut address_space = loaded_elf.address_space.take().expect("user ELF areas");
+    // Mapped on first touch like the rest, growing down as needed
+    let stack = address_space.add_stack(qemu_common::KERNEL_START);
 
     debug!("Setup stack {stack:?}");
 
@@ -685,9 +722,10 @@ pub fn load_user_program(bytes: &[u8]) -> Result<LoadedProgram, ElfLoadError> {
     info!("ELF loaded with entry point {:p}", entry);
 
     Ok(LoadedProgram {
-        stack: ManuallyDrop::new(stack),
+        stack,
         entry,
         elf: loaded_elf,
-        heap: ReentrantMutex::new(UserHeap::new(brk)),
+        heap: reentrant_mutex(lock_class!(), UserHeap::new(brk)),
+        address_space: reentrant_mutex(lock_class!(), address_space),
     })
 }
@@ -6,10 +6,15 @@ use kdriver_api::cglue_kernelinterface::*;
 use log::debug;
 use object::{Object, ObjectSymbol};
 use slotmap::{KeyData, SlotMap};
-use spin::{Lazy, RwLock};
+use spin::Lazy;
 use x86_64::VirtAddr;
 
-use crate::{driver::Interface, setup::KERNEL_INFO};
+use crate::{
+    driver::Interface,
+    lock_class,
+    multitask::lock::{SpinRwLock, spin_rwlock},
+    setup::KERNEL_INFO,
+};
 
 #[derive(Debug)]
 pub struct SymbolData<'a> {
@@ -66,8 +71,8 @@ impl SymbolResolver for () {
 
 slotmap::new_key_type! { pub struct InterfaceKey; }
 
-static INTERFACES: Lazy<RwLock<SlotMap<InterfaceKey, KernelInterfaceBox<'static>>>> =
-    Lazy::new(|| RwLock::new(SlotMap::with_key()));
+static INTERFACES: Lazy<SpinRwLock<SlotMap<InterfaceKey, KernelInterfaceBox<'static>>>> =
+    Lazy::new(|| spin_rwlock(lock_class!(), SlotMap::with_key()));
 
 extern "C" fn get_interface(id: u64) -> *const KernelInterfaceBox<'static> {
     let key = InterfaceKey::from(KeyData::from_ffi(id));
@@ -5,20 +5,35 @@ use blog_os_vfs::{
 };
 use log::debug;
 use ramfs::fs::{RAMFS_TYPE, RamFS};
-use spin::{Lazy, lock_api::RwLock};
+use spin::Lazy;
 use x86_64::{
     VirtAddr,
     structures::paging::{FrameDeallocator, Mapper, Page, Size4KiB},
 };
 
-use crate::setup::KERNEL_INFO;
+use crate::{
+    lock_class,
+    lockdep::{Class, Fixed, FixedClass},
+    multitask::lock::{SpinRwLock, spin_rwlock},
+    setup::KERNEL_INFO,
+};
 
 pub mod sysfs;
 
-pub static VFS: Lazy<RwLock<VFS>> = Lazy::new(|| RwLock::new(VFS::new()));
+pub static VFS: Lazy<SpinRwLock<VFS>> = Lazy::new(|| spin_rwlock(lock_class!(), VFS::new()));
+
+/// The class of the locks of the ramfs mounted by the kernel, which creates them itself
+pub struct RamFsClass;
+
+impl FixedClass for RamFsClass {
+    const CLASS: &'static Class = lock_class!();
+}
+
+/// Lock of the ramfs mounted by the kernel
+pub type RamFsLock = Fixed<spin::RwLock<()>, RamFsClass>;
 
 pub fn init_ramfs() {
-    let ramfs = RamFS::<spin::RwLock<()>>::default();
+    let ramfs = RamFS::<RamFsLock>::default();
 
     let mut lock = VFS.write();
 
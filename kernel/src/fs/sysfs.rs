@@ -8,15 +8,24 @@ use blog_os_vfs::api::{
     path::ffi::PathBufOpaqueRef,
 };
 use slotmap::{Key, KeyData};
-use spin::lock_api::RwLock;
 
-use crate::fs::sysfs::root::RootINode;
+use crate::{
+    fs::sysfs::root::RootINode,
+    lock_class,
+    multitask::lock::{SpinRwLock, spin_rwlock},
+};
 
+mod const_dir;
 mod device;
 mod driver;
+mod heap;
 mod proc;
 mod root;
-mod const_dir;
+mod sched;
+mod slab;
+mod text;
+
+pub use sched::task_exited;
 
 pub struct SysFs;
 
@@ -36,7 +45,7 @@ impl Filesystem for SysFs {
 
 slotmap::new_key_type! {struct SysFsINode;}
 
-type INodes = Arc<RwLock<slotmap::SlotMap<SysFsINode, Arc<INodeBox<'static>>>>>;
+type INodes = Arc<SpinRwLock<slotmap::SlotMap<SysFsINode, Arc<INodeBox<'static>>>>>;
 
 pub struct SysFsSuperblock {
     root_inode: SysFsINode,
@@ -45,7 +54,7 @@ pub struct SysFsSuperblock {
 
 impl SysFsSuperblock {
     pub fn new() -> Self {
-        let inodes: INodes = Default::default();
+        let inodes: INodes = Arc::new(spin_rwlock(lock_class!(), slotmap::SlotMap::default()));
         let root_inode =
             inodes.write().insert(Arc::new(cglue::trait_obj!(
                 RootINode::new(inodes.clone()) as INode
@@ -76,4 +85,3 @@ impl Superblock for SysFsSuperblock {
 }
 
 const A: usize = 3;
-
@@ -67,6 +67,10 @@ impl<const N: usize, C: ConstDir<N>> blog_os_vfs::api::inode::INode for ConstDir
         // let base: FileBaseBox<'static, ConstDirFile::<N, C>> = From2::from2(f);
         // Ok(base.into_opaque())
     }
+
+    fn truncate(&self, _: u64) -> Result<(), blog_os_vfs::api::IOError> {
+        Err(blog_os_vfs::api::IOError::OperationNotPermitted)
+    }
 }
 
 pub struct ConstDirFile<const N: usize, C: ConstDir<N>> {
@@ -130,6 +134,10 @@ impl<const N: usize, C: ConstDir<N>> File for ConstDirFile<N, C> {
     fn flush(&mut self) -> Result<(), blog_os_vfs::api::IOError> {
         Err(blog_os_vfs::api::IOError::OperationNotPermitted)
     }
+
+    fn truncate(&mut self, _: u64) -> Result<(), blog_os_vfs::api::IOError> {
+        Err(blog_os_vfs::api::IOError::OperationNotPermitted)
+    }
 }
 
 #[macro_export]
@@ -177,6 +185,7 @@ macro_rules! const_dir {
                             ));
                             idx += 1;
                         )+
+                        debug_assert_eq!(idx, N_NAMES);
 
                         drop(lock);
 
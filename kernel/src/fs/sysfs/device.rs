@@ -50,6 +50,10 @@ impl INode for DevicesINode {
     fn open(&self) -> Result<FileBox<'static>, IOError> {
         Ok(cglue::trait_obj!(DevicesFile { idx: 0 } as File))
     }
+
+    fn truncate(&self, _: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
 }
 
 struct DevicesFile {
@@ -92,4 +96,8 @@ impl File for DevicesFile {
     fn flush(&mut self) -> Result<(), IOError> {
         Err(IOError::OperationNotPermitted)
     }
+
+    fn truncate(&mut self, _: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
 }
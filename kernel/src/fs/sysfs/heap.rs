@@ -0,0 +1,67 @@
+//! `/sys/heap`: `live`, which logs the live allocations of the heap, grouped by allocation site,
+//! when written to. Only the `heap-debug` feature records them, other kernels refuse the write.
+
+use alloc::string::String;
+use api_utils::cglue;
+use blog_os_vfs::api::{
+    IOError,
+    file::cglue_file::*,
+    inode::{FsINodeRef, INode},
+};
+use shared_fs::Stat;
+
+use crate::{
+    const_dir,
+    fs::sysfs::{INodes, text::TextFile},
+};
+
+const_dir! {
+    pub struct HeapINode {
+
+        dirs = [
+            { name: "live", inode: LiveINode },
+        ];
+    }
+}
+
+pub struct LiveINode;
+
+impl LiveINode {
+    pub fn new(_: INodes) -> Self {
+        Self
+    }
+}
+
+impl INode for LiveINode {
+    fn lookup(&self, _: &str) -> Option<FsINodeRef> {
+        None
+    }
+
+    fn stat(&self) -> Result<Stat, IOError> {
+        Ok(Stat {
+            device: None,
+            size: 0,
+            file_type: shared_fs::FileType::RegularFile,
+        })
+    }
+
+    fn open(&self) -> Result<FileBox<'static>, IOError> {
+        #[cfg(feature = "heap-debug")]
+        let file = TextFile::writable(String::new(), |_| {
+            crate::allocator::dump_live_allocations();
+            Ok(())
+        });
+        #[cfg(not(feature = "heap-debug"))]
+        let file = TextFile::new(String::new());
+        Ok(cglue::trait_obj!(file as File))
+    }
+
+    /// Writes only trigger the dump, so truncating is accepted and ignored
+    fn truncate(&self, _: u64) -> Result<(), IOError> {
+        if cfg!(feature = "heap-debug") {
+            Ok(())
+        } else {
+            Err(IOError::OperationNotPermitted)
+        }
+    }
+}
@@ -50,6 +50,10 @@ impl INode for ProcsINode {
     fn open(&self) -> Result<FileBox<'static>, IOError> {
         Ok(cglue::trait_obj!(ProcsFile { idx: 0 } as File))
     }
+
+    fn truncate(&self, _: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
 }
 
 struct ProcsFile {
@@ -92,4 +96,8 @@ impl File for ProcsFile {
     fn flush(&mut self) -> Result<(), IOError> {
         Err(IOError::OperationNotPermitted)
     }
+
+    fn truncate(&mut self, _: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
 }
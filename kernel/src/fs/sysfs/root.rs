@@ -1,5 +1,6 @@
 use crate::fs::sysfs::{
-    device::DevicesINode, driver::DriversINode, proc::ProcsINode,
+    device::DevicesINode, driver::DriversINode, heap::HeapINode, proc::ProcsINode,
+    sched::SchedINode, slab::SlabINode,
 };
 
 use crate::const_dir;
@@ -11,6 +12,9 @@ const_dir!{
             { name: "proc",    inode: ProcsINode },
             { name: "devices", inode: DevicesINode },
             { name: "drivers", inode: DriversINode },
+            { name: "sched",   inode: SchedINode },
+            { name: "slab",    inode: SlabINode },
+            { name: "heap",    inode: HeapINode },
         ];
     }
 }
@@ -0,0 +1,211 @@
+//! `/sys/sched`: one file per task, named by its ID, holding its scheduling priority.
+//!
+//! Reading gives `normal <nice>` or `realtime <priority>`, writing the same text changes it.
+//! Only kernel tasks may make a task real-time, and processes may only change their own tasks.
+
+use alloc::{
+    collections::btree_map::BTreeMap,
+    string::String,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use api_utils::cglue;
+use blog_os_syscalls::priority::Priority;
+use blog_os_vfs::api::{
+    IOError,
+    file::{File, SeekMode, cglue_file::*},
+    inode::{FsINodeRef, INode, cglue_inode::*},
+};
+use shared_fs::{DeviceId, Stat};
+use slotmap::Key;
+use uuid::Uuid;
+
+use crate::{
+    fs::sysfs::{INodes, SysFsINode, text::TextFile},
+    lock_class,
+    multitask::{
+        self,
+        lock::{SpinMutex, SpinRwLock, spin_mutex, spin_rwlock},
+    },
+};
+
+/// The task inodes of every mounted sysfs
+static MOUNTED: SpinMutex<Vec<Weak<TaskINodes>>> = spin_mutex(lock_class!(), Vec::new());
+
+/// Inodes of the tasks looked up so far and still running
+struct TaskINodes {
+    inodes: INodes,
+    by_id: SpinRwLock<BTreeMap<Uuid, SysFsINode>>,
+}
+
+impl TaskINodes {
+    fn remove(&self, id: &Uuid) {
+        if let Some(inode) = self.by_id.write().remove(id) {
+            self.inodes.write().remove(inode);
+        }
+    }
+}
+
+/// Drops the inode of the task `id`, which is exiting.
+pub fn task_exited(id: &Uuid) {
+    MOUNTED.lock().retain(|tasks| {
+        let Some(tasks) = tasks.upgrade() else {
+            return false;
+        };
+        tasks.remove(id);
+        true
+    });
+}
+
+pub struct SchedINode {
+    tasks: Arc<TaskINodes>,
+}
+
+impl SchedINode {
+    pub fn new(inodes: INodes) -> Self {
+        let tasks = Arc::new(TaskINodes {
+            inodes,
+            by_id: spin_rwlock(lock_class!(), BTreeMap::new()),
+        });
+        MOUNTED.lock().push(Arc::downgrade(&tasks));
+        Self { tasks }
+    }
+}
+
+impl INode for SchedINode {
+    fn lookup(&self, component: &str) -> Option<FsINodeRef> {
+        let id = Uuid::try_parse(component).ok()?;
+        multitask::get_priority(&id)?;
+
+        let mut by_id = self.tasks.by_id.write();
+        let inode = *by_id.entry(id).or_insert_with(|| {
+            self.tasks
+                .inodes
+                .write()
+                .insert(Arc::new(cglue::trait_obj!(TaskINode { id } as INode)))
+        });
+        drop(by_id);
+
+        // Looked up while exiting, after its inode was dropped
+        if multitask::get_priority(&id).is_none() {
+            self.tasks.remove(&id);
+            return None;
+        }
+
+        Some(FsINodeRef(inode.data().as_ffi()))
+    }
+
+    fn stat(&self) -> Result<Stat, IOError> {
+        Ok(Stat {
+            device: None,
+            size: 0,
+            file_type: shared_fs::FileType::Directory,
+        })
+    }
+
+    fn open(&self) -> Result<FileBox<'static>, IOError> {
+        let ids = multitask::tasks().into_iter().map(|(id, _)| id).collect();
+        Ok(cglue::trait_obj!(SchedFile {
+            ids,
+            idx: 0,
+            current: String::new(),
+        } as File))
+    }
+
+    fn truncate(&self, _: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+}
+
+/// Lists the tasks running when the directory was opened.
+struct SchedFile {
+    ids: Vec<Uuid>,
+    idx: usize,
+    current: String,
+}
+
+impl File for SchedFile {
+    fn close(&mut self) -> Result<(), IOError> {
+        Ok(())
+    }
+
+    fn read(&mut self, _: &mut [u8]) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn write(&mut self, _: &[u8]) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn seek(&mut self, _: SeekMode, _: isize) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn next_direntry(&mut self) -> Result<&str, IOError> {
+        let id = self.ids.get(self.idx).ok_or(IOError::EOF)?;
+        self.idx += 1;
+
+        self.current.clear();
+        self.current
+            .push_str(id.hyphenated().encode_lower(&mut Uuid::encode_buffer()));
+        Ok(&self.current)
+    }
+
+    fn mkdir(&mut self, _: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn mknod(&mut self, _: &str, _: DeviceId) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn creat(&mut self, _: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn truncate(&mut self, _: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+}
+
+struct TaskINode {
+    id: Uuid,
+}
+
+/// The file contents for `id`, or `NotFound` once the task ended.
+fn priority_text(id: &Uuid) -> Result<String, IOError> {
+    let priority = multitask::get_priority(id).ok_or(IOError::NotFound)?;
+    Ok(alloc::format!("{priority}\n"))
+}
+
+impl INode for TaskINode {
+    fn lookup(&self, _: &str) -> Option<FsINodeRef> {
+        None
+    }
+
+    fn stat(&self) -> Result<Stat, IOError> {
+        Ok(Stat {
+            device: None,
+            size: priority_text(&self.id)?.len() as u64,
+            file_type: shared_fs::FileType::RegularFile,
+        })
+    }
+
+    fn open(&self) -> Result<FileBox<'static>, IOError> {
+        let id = self.id;
+        let file = TextFile::writable(priority_text(&id)?, move |text| {
+            let priority: Priority = text.parse().map_err(|_| IOError::InvalidArgument)?;
+            multitask::request_priority(&id, priority)
+        });
+        Ok(cglue::trait_obj!(file as File))
+    }
+
+    /// Writes replace the whole priority, so truncating is accepted and ignored
+    fn truncate(&self, _: u64) -> Result<(), IOError> {
+        Ok(())
+    }
+}
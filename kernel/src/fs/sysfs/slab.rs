@@ -0,0 +1,169 @@
+//! `/sys/slab`: one read-only file per [object cache](crate::slab), named after it, holding its
+//! usage as `<stat> <value>` lines.
+
+use alloc::{collections::btree_map::BTreeMap, string::String, sync::Arc};
+use api_utils::cglue;
+use blog_os_vfs::api::{
+    IOError,
+    file::{File, SeekMode, cglue_file::*},
+    inode::{FsINodeRef, INode, cglue_inode::*},
+};
+use shared_fs::{DeviceId, Stat};
+use slotmap::Key;
+
+use crate::{
+    fs::sysfs::{INodes, SysFsINode, text::TextFile},
+    lock_class,
+    multitask::lock::{SpinRwLock, spin_rwlock},
+    slab::{CACHES, ObjectCache},
+};
+
+pub struct SlabINode {
+    inodes: INodes,
+    /// Inodes of the caches looked up so far, by index in [`CACHES`]
+    caches: Arc<SpinRwLock<BTreeMap<usize, SysFsINode>>>,
+}
+
+impl SlabINode {
+    pub fn new(inodes: INodes) -> Self {
+        Self {
+            inodes,
+            caches: Arc::new(spin_rwlock(lock_class!(), BTreeMap::new())),
+        }
+    }
+}
+
+impl INode for SlabINode {
+    fn lookup(&self, component: &str) -> Option<FsINodeRef> {
+        let index = CACHES.iter().position(|cache| cache.name() == component)?;
+
+        let mut caches = self.caches.write();
+        let inode = *caches.entry(index).or_insert_with(|| {
+            let cache = &CACHES[index];
+            self.inodes
+                .write()
+                .insert(Arc::new(cglue::trait_obj!(CacheINode { cache } as INode)))
+        });
+        drop(caches);
+
+        Some(FsINodeRef(inode.data().as_ffi()))
+    }
+
+    fn stat(&self) -> Result<Stat, IOError> {
+        Ok(Stat {
+            device: None,
+            size: CACHES.len() as u64,
+            file_type: shared_fs::FileType::Directory,
+        })
+    }
+
+    fn open(&self) -> Result<FileBox<'static>, IOError> {
+        Ok(cglue::trait_obj!(SlabFile { idx: 0 } as File))
+    }
+
+    fn truncate(&self, _: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+}
+
+/// Lists the caches.
+struct SlabFile {
+    idx: usize,
+}
+
+impl File for SlabFile {
+    fn close(&mut self) -> Result<(), IOError> {
+        Ok(())
+    }
+
+    fn read(&mut self, _: &mut [u8]) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn write(&mut self, _: &[u8]) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn seek(&mut self, _: SeekMode, _: isize) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn next_direntry(&mut self) -> Result<&str, IOError> {
+        let cache = CACHES.get(self.idx).ok_or(IOError::EOF)?;
+        self.idx += 1;
+        Ok(cache.name())
+    }
+
+    fn mkdir(&mut self, _: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn mknod(&mut self, _: &str, _: DeviceId) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn creat(&mut self, _: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn truncate(&mut self, _: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+}
+
+struct CacheINode {
+    cache: &'static ObjectCache,
+}
+
+/// The current usage of `cache`.
+fn stats_text(cache: &ObjectCache) -> String {
+    let stats = cache.stats();
+    alloc::format!(
+        "object_size {}\n\
+         slab_size {}\n\
+         objects_per_slab {}\n\
+         active_objects {}\n\
+         total_objects {}\n\
+         slabs {}\n\
+         allocations {}\n\
+         frees {}\n\
+         poison_errors {}\n",
+        stats.object_size,
+        stats.slab_size,
+        stats.objects_per_slab,
+        stats.active_objects,
+        stats.total_objects,
+        stats.slabs,
+        stats.allocations,
+        stats.frees,
+        stats.poison_errors,
+    )
+}
+
+impl INode for CacheINode {
+    fn lookup(&self, _: &str) -> Option<FsINodeRef> {
+        None
+    }
+
+    fn stat(&self) -> Result<Stat, IOError> {
+        Ok(Stat {
+            device: None,
+            size: stats_text(self.cache).len() as u64,
+            file_type: shared_fs::FileType::RegularFile,
+        })
+    }
+
+    fn open(&self) -> Result<FileBox<'static>, IOError> {
+        Ok(cglue::trait_obj!(
+            TextFile::new(stats_text(self.cache)) as File
+        ))
+    }
+
+    fn truncate(&self, _: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+}
@@ -0,0 +1,107 @@
+//! Files reading a snapshot of their text, taken when they are opened so that the values stay
+//! consistent across reads.
+
+use alloc::{boxed::Box, string::String};
+use blog_os_vfs::api::{
+    IOError,
+    file::{File, SeekMode},
+    inode::FsINodeRef,
+};
+use shared_fs::DeviceId;
+
+/// Parses and applies the whole text of a write
+type WriteHandler = Box<dyn Fn(&str) -> Result<(), IOError> + Send + Sync>;
+
+pub struct TextFile {
+    text: String,
+    cursor: usize,
+    write: Option<WriteHandler>,
+}
+
+impl TextFile {
+    /// A read-only file holding `text`.
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            cursor: 0,
+            write: None,
+        }
+    }
+
+    /// A file holding `text`, whose writes are handed to `write` in one piece.
+    ///
+    /// Writes replace the whole value, so truncating is accepted and ignored.
+    pub fn writable(
+        text: String,
+        write: impl Fn(&str) -> Result<(), IOError> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            write: Some(Box::new(write)),
+            ..Self::new(text)
+        }
+    }
+}
+
+impl File for TextFile {
+    fn close(&mut self) -> Result<(), IOError> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+        let rest = self.text.as_bytes().get(self.cursor..).unwrap_or_default();
+        if rest.is_empty() {
+            return Err(IOError::EOF);
+        }
+        let len = rest.len().min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        self.cursor += len;
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+        let write = self.write.as_ref().ok_or(IOError::OperationNotPermitted)?;
+        write(core::str::from_utf8(buf).map_err(|_| IOError::InvalidArgument)?)?;
+        Ok(buf.len())
+    }
+
+    fn seek(&mut self, mode: SeekMode, amount: isize) -> Result<usize, IOError> {
+        let base = match mode {
+            SeekMode::START => 0,
+            SeekMode::CURSOR => self.cursor,
+            SeekMode::END => self.text.len(),
+        };
+
+        self.cursor = base
+            .checked_add_signed(amount)
+            .ok_or(IOError::InvalidArgument)?;
+
+        Ok(self.cursor)
+    }
+
+    fn next_direntry(&mut self) -> Result<&str, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn mkdir(&mut self, _: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn mknod(&mut self, _: &str, _: DeviceId) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn creat(&mut self, _: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _: u64) -> Result<(), IOError> {
+        match self.write {
+            Some(_) => Ok(()),
+            None => Err(IOError::OperationNotPermitted),
+        }
+    }
+}
@@ -29,13 +29,16 @@ static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
     tss
 });
 
-static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
+static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| new_gdt(&TSS));
+
+/// Builds a GDT using `tss`. Every CPU gets the same selectors.
+pub fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
     let mut gdt = GlobalDescriptorTable::new();
     let kernel_code_selector = gdt.append(Descriptor::kernel_code_segment());
     let kernel_data_selector = gdt.append(Descriptor::kernel_data_segment());
     let user_code_selector = gdt.append(Descriptor::user_code_segment());
     let user_data_selector = gdt.append(Descriptor::user_data_segment());
-    let tss_selector = gdt.append(Descriptor::tss_segment(&TSS));
+    let tss_selector = gdt.append(Descriptor::tss_segment(tss));
     (
         gdt,
         Selectors {
@@ -46,7 +49,15 @@ static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
             tss_selector,
         },
     )
-});
+}
+
+/// Builds the TSS of an application processor.
+pub fn new_tss(esp0: &SlabStack, ist_df: &SlabStack) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    tss.privilege_stack_table[0] = esp0.top();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist_df.top();
+    tss
+}
 
 pub struct Selectors {
     pub kernel_code_selector: SegmentSelector,
@@ -57,20 +68,38 @@ pub struct Selectors {
 }
 
 pub fn init() {
+    load(&GDT);
+}
+
+/// Loads `gdt` on the running CPU, reloading the segment registers and the task register.
+///
+/// Loading GS clears the GS base, so this must happen before the per-CPU area is set up.
+pub fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::segmentation::{CS, Segment};
     use x86_64::instructions::tables::load_tss;
-    GDT.0.load();
+    let (gdt, selectors) = gdt;
+    gdt.load();
     unsafe {
-        CS::set_reg(GDT.1.kernel_code_selector);
-        DS::set_reg(GDT.1.kernel_data_selector);
-        ES::set_reg(GDT.1.kernel_data_selector);
-        FS::set_reg(GDT.1.kernel_data_selector);
-        GS::set_reg(GDT.1.kernel_data_selector);
-        SS::set_reg(GDT.1.kernel_data_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.kernel_code_selector);
+        DS::set_reg(selectors.kernel_data_selector);
+        ES::set_reg(selectors.kernel_data_selector);
+        FS::set_reg(selectors.kernel_data_selector);
+        GS::set_reg(selectors.kernel_data_selector);
+        SS::set_reg(selectors.kernel_data_selector);
+        load_tss(selectors.tss_selector);
     }
 }
 
+/// The GDT of the bootstrap processor.
+pub fn bsp_gdt() -> &'static (GlobalDescriptorTable, Selectors) {
+    &GDT
+}
+
+/// The TSS of the bootstrap processor.
+pub fn bsp_tss() -> &'static TaskStateSegment {
+    &TSS
+}
+
 pub fn selectors() -> &'static Selectors {
     &GDT.1
 }
@@ -1,5 +1,9 @@
-use log::{debug, error};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use log::{debug, error, info, warn};
 use pic8259::ChainedPics;
+use qemu_common::KERNEL_START;
+use shared_fs::ioctl::TermMode;
 use spin::Lazy;
 use x86_64::{
     instructions::port::Port,
@@ -7,26 +11,41 @@ use x86_64::{
 };
 
 use crate::{
-    _print, STDIN, gdt, hlt_loop,
-    interrupts::stub::InterruptContext,
-    multitask,
+    _print, STDIN, STDIN_READABLE, gdt, hlt_loop,
+    interrupts::{softirq::Tasklet, stub::InterruptContext},
+    lock_class,
+    memory::{
+        user,
+        vma::{self, Access},
+    },
+    multitask::{
+        self,
+        lock::{SpinMutex, spin_mutex},
+    },
+    process::{self, ProcessStatus, Signal},
     setup::KERNEL_INFO,
+    smp,
     unwind::{backtrace, backtrace_sp_ip},
+    watchdog,
 };
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+pub mod apic;
 pub mod info;
+pub mod softirq;
 #[macro_use]
 pub mod stub;
 mod syscalls;
+pub mod timer;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: SpinMutex<ChainedPics> = spin_mutex(lock_class!(), unsafe {
+    ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
+});
 
 pub fn init_pics() {
-    // init_timer(1);
+    timer::set_frequency(timer::DEFAULT_FREQUENCY_HZ);
     unsafe {
         PICS.lock().initialize();
     }
@@ -38,47 +57,65 @@ pub fn init_pics() {
         // Read the current mask
         let current_mask = master_mask_port.read();
 
-        // Clear the lowest bit (IRQ 0/Timer)
-        // 0b11111110 (0xFE) ensures only IRQ 0 is unmasked, keeping others as they were.
-        master_mask_port.write(current_mask & 0b11111100);
+        // Clear the bits of IRQ 0 (Timer), IRQ 1 (Keyboard) and IRQ 4 (COM1),
+        // keeping others as they were.
+        master_mask_port.write(current_mask & 0b11101100);
     }
 
     x86_64::instructions::interrupts::enable();
 }
-// Function to initialize the PIT to generate interrupts at a specific frequency
-// pub fn init_timer(frequency_hz: u32) {
-//     // The PIT base frequency is 1,193,182 Hz
-//     const PIT_BASE_FREQ: u32 = 1193182;
-
-//     // Calculate divisor
-//     let divisor: u16 = (PIT_BASE_FREQ / frequency_hz) as u16;
 
-//     // PIT Ports
-//     const PIT_CMD_PORT: u16 = 0x43;
-//     const PIT_DATA_PORT: u16 = 0x40; // Channel 0 (Timer)
+/// Which controller delivers the hardware interrupts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptController {
+    Pic,
+    Apic,
+}
 
-//     // Command: Channel 0, LOBYTE/HIBYTE, Mode 3 (Square Wave Generator)
-//     const COMMAND: u8 = 0b00110110;
+/// Moves interrupt delivery to the APICs if the machine has them, the PICs stay in use
+/// otherwise or when built with the `legacy-pic` feature.
+pub fn init_controller() -> InterruptController {
+    if cfg!(feature = "legacy-pic") {
+        info!("Using the legacy PICs (legacy-pic feature)");
+        return InterruptController::Pic;
+    }
 
-//     // Create mutable port instances
-//     let mut cmd_port = Port::<u8>::new(PIT_CMD_PORT);
-//     let mut data_port = Port::<u8>::new(PIT_DATA_PORT);
+    match apic::init() {
+        Ok(()) => InterruptController::Apic,
+        Err(e) => {
+            warn!("Falling back to the legacy PICs: {e}");
+            InterruptController::Pic
+        }
+    }
+}
 
-//     unsafe {
-//         // 1. Send the command byte
-//         cmd_port.write(COMMAND);
+pub fn controller() -> InterruptController {
+    if apic::local_apic().is_some() {
+        InterruptController::Apic
+    } else {
+        InterruptController::Pic
+    }
+}
 
-//         // 2. Send divisor (Low byte then High byte)
-//         data_port.write((divisor & 0xFF) as u8);
-//         data_port.write((divisor >> 8) as u8);
-//     }
-// }
+/// Acknowledges a hardware interrupt to whichever controller delivered it.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if let Some(local_apic) = apic::local_apic() {
+        local_apic.end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index.as_u8());
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial = PIC_1_OFFSET + 4,
+    ApicError = 0xFE,
+    Spurious = 0xFF,
 }
 
 impl InterruptIndex {
@@ -90,6 +127,7 @@ impl InterruptIndex {
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
     idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.non_maskable_interrupt.set_handler_fn(nmi_handler);
     idt.page_fault.set_handler_fn(page_fault_handler);
     unsafe {
         idt.general_protection_fault
@@ -115,10 +153,15 @@ static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
             .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
     }
     idt[InterruptIndex::Timer.as_u8()].set_handler_fn(naked_timer_interrupt_handler);
-    idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
+    idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(naked_keyboard_interrupt_handler);
+    idt[InterruptIndex::Serial.as_u8()].set_handler_fn(naked_serial_interrupt_handler);
+    idt[InterruptIndex::ApicError.as_u8()].set_handler_fn(apic_error_handler);
+    idt[InterruptIndex::Spurious.as_u8()].set_handler_fn(spurious_interrupt_handler);
     idt[0x80]
         .set_handler_fn(naked_int_80_handler)
         .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+    #[cfg(feature = "kpti")]
+    stub::route_through_kpti_stubs(&mut idt);
     idt
 });
 
@@ -126,6 +169,11 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// The IDT of the bootstrap processor, which application processors copy.
+pub fn idt() -> &'static InterruptDescriptorTable {
+    &IDT
+}
+
 interrupt_with_tail!(extern "x86-interrupt" fn naked_int_80_handler(InterruptStackFrame) => int_80_handler);
 
 extern "C" fn int_80_handler(ctx: &mut InterruptContext) {
@@ -142,22 +190,46 @@ extern "C" fn int_80_handler(ctx: &mut InterruptContext) {
 
 interrupt_with_tail!(extern "x86-interrupt" fn naked_timer_interrupt_handler(InterruptStackFrame) => timer_interrupt_handler);
 
-extern "C" fn timer_interrupt_handler(_context: &mut InterruptContext) {
-    _print!(".");
-    // TODO advance a number
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+/// The switch happens in the interrupt tail, once the tick flagged a reschedule
+extern "C" fn timer_interrupt_handler(context: &mut InterruptContext) {
+    timer::on_tick();
+    multitask::tick();
+    watchdog::on_tick(context);
+    report_unexpected_nmis();
+    end_of_interrupt(InterruptIndex::Timer);
+}
+
+/// NMIs nobody sent since the last tick, and where the last one hit
+static UNEXPECTED_NMIS: AtomicU64 = AtomicU64::new(0);
+static UNEXPECTED_NMI_IP: AtomicU64 = AtomicU64::new(0);
+
+/// Logging isn't allowed in the NMI handler, as it may have interrupted the logger
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    user::clac();
+    // NMIs sent together arrive as one, so both senders are checked
+    let shootdown = smp::sync_tlb();
+    if !watchdog::on_nmi(&stack_frame) && !shootdown {
+        UNEXPECTED_NMI_IP.store(stack_frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+        UNEXPECTED_NMIS.fetch_add(1, Ordering::Release);
+    }
+}
+
+fn report_unexpected_nmis() {
+    let count = UNEXPECTED_NMIS.swap(0, Ordering::Acquire);
+    if count != 0 {
+        let ip = UNEXPECTED_NMI_IP.load(Ordering::Relaxed);
+        warn!("{count} unexpected NMI(s), the last one at {ip:#x}");
     }
-    // println!("Finished timer, waiting");
-    // hlt_loop();
 }
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    user::clac();
+    debug_assert!(!user::user_access_open(), "Entered with user access open");
     log::info!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 extern "x86-interrupt" fn align_check(stack_frame: InterruptStackFrame, error_code: u64) {
+    user::clac();
     panic!(
         "EXCEPTION: ALIGN FAULT ({error_code} - 0x{error_code:x})\n{:#?}",
         stack_frame
@@ -165,6 +237,7 @@ extern "x86-interrupt" fn align_check(stack_frame: InterruptStackFrame, error_co
 }
 
 extern "x86-interrupt" fn stack_segment(stack_frame: InterruptStackFrame, error_code: u64) {
+    user::clac();
     panic!(
         "EXCEPTION: STACK SEGMENT FAULT ({error_code} - 0x{error_code:x})\n{:#?}",
         stack_frame
@@ -172,6 +245,7 @@ extern "x86-interrupt" fn stack_segment(stack_frame: InterruptStackFrame, error_
 }
 
 extern "x86-interrupt" fn segment_not_present(stack_frame: InterruptStackFrame, error_code: u64) {
+    user::clac();
     panic!(
         "EXCEPTION: SEGMENT NOT PRESENT FAULT ({error_code} - 0x{error_code:x})\n{:#?}",
         stack_frame
@@ -179,6 +253,7 @@ extern "x86-interrupt" fn segment_not_present(stack_frame: InterruptStackFrame,
 }
 
 extern "x86-interrupt" fn invalid_tss(stack_frame: InterruptStackFrame, error_code: u64) {
+    user::clac();
     panic!(
         "EXCEPTION: INVALID TSS FAULT ({error_code} - 0x{error_code:x})\n{:#?}",
         stack_frame
@@ -186,6 +261,7 @@ extern "x86-interrupt" fn invalid_tss(stack_frame: InterruptStackFrame, error_co
 }
 
 extern "x86-interrupt" fn invalid_opcode(stack_frame: InterruptStackFrame) {
+    user::clac();
     panic!("EXCEPTION: OPCODE FAULT\n{:#?}", stack_frame);
 }
 
@@ -193,6 +269,7 @@ extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) -> ! {
+    user::clac();
     panic!(
         "EXCEPTION: DOUBLE FAULT ({error_code} - 0x{error_code:x})\n{:#?}",
         stack_frame
@@ -203,8 +280,30 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    user::clac();
     use x86_64::registers::control::Cr2;
 
+    // Pages of the process are mapped on first touch by the process. Syscalls map them before
+    // copying, so the kernel itself never faults on a user address.
+    if let Some(addr) = Cr2::read().ok().filter(|addr| *addr < KERNEL_START) {
+        if !error_code.contains(PageFaultErrorCode::USER_MODE) {
+            error!("Kernel access to user address {addr:p} ({error_code:?})");
+        } else if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            match vma::handle_user_fault(addr, Access::from_error_code(error_code)) {
+                Ok(()) => return,
+                Err(e) => warn!("Unresolved page fault: {e}"),
+            }
+        }
+    }
+
+    if error_code.contains(PageFaultErrorCode::USER_MODE) {
+        warn!(
+            "Killing the current process after a page fault at {:p} ({error_code:?})",
+            stack_frame.instruction_pointer
+        );
+        kill_current_process(Signal::Segv);
+    }
+
     error!("EXCEPTION: PAGE FAULT");
 
     backtrace();
@@ -285,6 +384,14 @@ extern "x86-interrupt" fn page_fault_handler(
     hlt_loop();
 }
 
+/// Ends the process of the current task after it caused an exception.
+pub(crate) fn kill_current_process(signal: Signal) -> ! {
+    process::end_current_process(ProcessStatus::Killed(signal));
+    // Ends the task along with the process
+    syscalls::syscall_tail();
+    unreachable!("The killed process is still running");
+}
+
 #[repr(u8)]
 #[derive(Debug)]
 enum SelectorTableCode {
@@ -324,6 +431,7 @@ extern "x86-interrupt" fn general_protection_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    user::clac();
     let code = if error_code == 0 {
         None
     } else {
@@ -340,41 +448,135 @@ extern "x86-interrupt" fn general_protection_fault_handler(
     panic!()
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+/// Bytes read by an interrupt handler, waiting for its bottom half. Dropped once full.
+struct RawInput {
+    bytes: [u8; 64],
+    len: usize,
+}
+
+impl RawInput {
+    const fn new() -> Self {
+        Self {
+            bytes: [0; 64],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        if let Some(slot) = self.bytes.get_mut(self.len) {
+            *slot = byte;
+            self.len += 1;
+        }
+    }
+
+    fn take(&mut self) -> ([u8; 64], usize) {
+        (self.bytes, core::mem::take(&mut self.len))
+    }
+}
+
+static SCANCODES: SpinMutex<RawInput> = spin_mutex(lock_class!(), RawInput::new());
+static KEYBOARD_TASKLET: Tasklet = Tasklet::new(decode_scancodes, 0);
+
+interrupt_with_tail!(extern "x86-interrupt" fn naked_keyboard_interrupt_handler(InterruptStackFrame) => keyboard_interrupt_handler);
+
+/// Only reads the scancode, [`decode_scancodes`] does the rest.
+extern "C" fn keyboard_interrupt_handler(_context: &mut InterruptContext) {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+    SCANCODES.lock().push(scancode);
+    KEYBOARD_TASKLET.schedule();
+
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+fn decode_scancodes(_: usize) {
     use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    static KEYBOARD: Lazy<Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>>> = Lazy::new(|| {
-        Mutex::new(Keyboard::new(
-            ScancodeSet1::new(),
-            layouts::Us104Key,
-            HandleControl::Ignore,
-        ))
+
+    static KEYBOARD: Lazy<SpinMutex<Keyboard<layouts::Us104Key, ScancodeSet1>>> = Lazy::new(|| {
+        spin_mutex(
+            lock_class!(),
+            Keyboard::new(
+                ScancodeSet1::new(),
+                layouts::Us104Key,
+                HandleControl::Ignore,
+            ),
+        )
     });
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
+    let (scancodes, len) = SCANCODES.lock().take();
 
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode)
-        && let Some(key) = keyboard.process_keyevent(key_event)
-    {
-        match key {
-            DecodedKey::Unicode(character) => {
-                debug!("CHAR: {}", character);
-                let mut buf = [0; 4];
-                let s: &str = character.encode_utf8(&mut buf);
-                STDIN.write().buffer_mut().extend_from_slice(s.as_bytes());
+    let mut keyboard = KEYBOARD.lock();
+    for &scancode in &scancodes[..len] {
+        if let Ok(Some(key_event)) = keyboard.add_byte(scancode)
+            && let Some(key) = keyboard.process_keyevent(key_event)
+        {
+            match key {
+                DecodedKey::Unicode(character) => {
+                    debug!("CHAR: {}", character);
+                    let mut buf = [0; 4];
+                    push_input(character.encode_utf8(&mut buf));
+                }
+                DecodedKey::RawKey(key) => debug!("KEY: {:?}", key),
             }
-            DecodedKey::RawKey(key) => debug!("KEY: {:?}", key),
         }
     }
+    drop(keyboard);
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+static SERIAL_INPUT: SpinMutex<RawInput> = spin_mutex(lock_class!(), RawInput::new());
+static SERIAL_TASKLET: Tasklet = Tasklet::new(decode_serial_input, 0);
+
+interrupt_with_tail!(extern "x86-interrupt" fn naked_serial_interrupt_handler(InterruptStackFrame) => serial_interrupt_handler);
+
+/// Only drains the receive FIFO, [`decode_serial_input`] does the rest.
+extern "C" fn serial_interrupt_handler(_context: &mut InterruptContext) {
+    const COM1: u16 = 0x3F8;
+    let mut data_port = Port::<u8>::new(COM1);
+    let mut line_status_port = Port::<u8>::new(COM1 + 5);
+
+    let mut input = SERIAL_INPUT.lock();
+    while unsafe { line_status_port.read() } & 1 != 0 {
+        input.push(unsafe { data_port.read() });
     }
+    drop(input);
+    SERIAL_TASKLET.schedule();
+
+    end_of_interrupt(InterruptIndex::Serial);
+}
+
+fn decode_serial_input(_: usize) {
+    let (bytes, len) = SERIAL_INPUT.lock().take();
+    for &byte in &bytes[..len] {
+        debug!("SERIAL: {byte:x}");
+        match byte {
+            b'\r' => push_input("\n"),
+            0..=0x7F => push_input(char::from(byte).encode_utf8(&mut [0; 4])),
+            _ => (),
+        }
+    }
+}
+
+/// Queues typed input for stdin, echoing it if the terminal mode asks for it, and wakes the
+/// tasks reading it.
+fn push_input(s: &str) {
+    let mut stdin = STDIN.write();
+    stdin.buffer_mut().extend_from_slice(s.as_bytes());
+    if stdin.mode().contains(TermMode::ECHO) {
+        _print!("{s}");
+    }
+    drop(stdin);
+    STDIN_READABLE.wake_all();
+}
+
+extern "x86-interrupt" fn apic_error_handler(_stack_frame: InterruptStackFrame) {
+    user::clac();
+    error!("APIC error");
+    end_of_interrupt(InterruptIndex::ApicError);
+}
+
+/// Spurious interrupts must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    user::clac();
 }
 
 // pub struct WithoutInterruptGuard<T> {
@@ -421,3 +623,23 @@ fn test_breakpoint_exception() {
     // invoke a breakpoint exception
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn entries_close_user_access() {
+    use x86_64::registers::rflags::{self, RFlags};
+
+    // Like userspace, which can set AC with popf, the handlers check it is cleared on entry
+    let flags = rflags::read();
+    unsafe { rflags::write(flags | RFlags::ALIGNMENT_CHECK) };
+    x86_64::instructions::interrupts::int3();
+    let after_exception = rflags::read();
+    unsafe {
+        core::arch::asm!("int 0x80", inout("rax") 0u64 => _, in("rdi") 0u64);
+    }
+    let after_syscall = rflags::read();
+    unsafe { rflags::write(flags) };
+
+    // Returning restores the interrupted flags
+    assert!(after_exception.contains(RFlags::ALIGNMENT_CHECK));
+    assert!(after_syscall.contains(RFlags::ALIGNMENT_CHECK));
+}
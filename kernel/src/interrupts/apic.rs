@@ -0,0 +1,346 @@
+//! Local APIC and I/O APIC, replacing the legacy PICs when the MADT describes them.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::vec::Vec;
+use log::{debug, info};
+use spin::Once;
+use thiserror::Error;
+use x86_64::{VirtAddr, registers::model_specific::Msr};
+
+use crate::{
+    acpi::{self, Madt},
+    interrupts::{InterruptIndex, timer},
+    setup::KERNEL_INFO,
+};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// Divide the bus clock by 16 for the timer
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_NMI: u32 = 0b100 << 8;
+
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_NMI: u32 = 0b100 << 8;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+/// How long the PIT runs to measure the local APIC timer
+const CALIBRATION_MS: u32 = 10;
+
+#[derive(Debug, Error)]
+pub enum ApicError {
+    #[error("The CPU has no local APIC")]
+    Unsupported,
+    #[error("No MADT was found")]
+    NoMadt,
+    #[error("The MADT lists no I/O APIC")]
+    NoIoApic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum TimerMode {
+    OneShot = 0,
+    Periodic = 1 << 17,
+}
+
+/// The local APIC of the running CPU. Every CPU sees its own at the same address.
+#[derive(Debug)]
+pub struct LocalApic {
+    base: VirtAddr,
+    timer_ticks_per_ms: AtomicU32,
+}
+
+impl LocalApic {
+    const ID: usize = 0x20;
+    const TPR: usize = 0x80;
+    const EOI: usize = 0xB0;
+    const SVR: usize = 0xF0;
+    const ESR: usize = 0x280;
+    const ICR_LOW: usize = 0x300;
+    const ICR_HIGH: usize = 0x310;
+    const LVT_TIMER: usize = 0x320;
+    const LVT_LINT0: usize = 0x350;
+    const LVT_LINT1: usize = 0x360;
+    const LVT_ERROR: usize = 0x370;
+    const TIMER_INITIAL: usize = 0x380;
+    const TIMER_CURRENT: usize = 0x390;
+    const TIMER_DIVIDE: usize = 0x3E0;
+
+    fn read(&self, reg: usize) -> u32 {
+        unsafe { (self.base + reg as u64).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(&self, reg: usize, value: u32) {
+        unsafe {
+            (self.base + reg as u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        }
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(Self::ID) >> 24) as u8
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(Self::EOI, 0);
+    }
+
+    /// Software-enables this CPU's local APIC, with the PIC inputs masked.
+    fn enable(&self) {
+        unsafe {
+            let mut base = Msr::new(IA32_APIC_BASE);
+            let value = base.read();
+            base.write(value | APIC_BASE_ENABLE);
+        }
+
+        self.write(Self::TPR, 0);
+        self.write(Self::LVT_LINT0, LVT_MASKED);
+        self.write(Self::LVT_LINT1, LVT_NMI);
+        self.write(
+            Self::LVT_ERROR,
+            u32::from(InterruptIndex::ApicError.as_u8()),
+        );
+        // Reading the error status needs a write first
+        self.write(Self::ESR, 0);
+        self.write(Self::ESR, 0);
+        self.write(
+            Self::SVR,
+            0x100 | u32::from(InterruptIndex::Spurious.as_u8()),
+        );
+        self.end_of_interrupt();
+    }
+
+    /// Measures the timer speed against the PIT.
+    fn calibrate_timer(&self) {
+        self.write(Self::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(Self::LVT_TIMER, LVT_MASKED);
+        self.write(Self::TIMER_INITIAL, u32::MAX);
+
+        timer::pit_wait_ms(CALIBRATION_MS);
+
+        let elapsed = u32::MAX - self.read(Self::TIMER_CURRENT);
+        self.write(Self::TIMER_INITIAL, 0);
+
+        let per_ms = (elapsed / CALIBRATION_MS).max(1);
+        debug!("Local APIC timer: {per_ms} ticks per ms");
+        self.timer_ticks_per_ms.store(per_ms, Ordering::Relaxed);
+    }
+
+    /// Sends an inter-processor interrupt, waiting until the local APIC accepted it.
+    fn send_ipi(&self, apic_id: u8, command: u32) {
+        self.write(Self::ICR_HIGH, u32::from(apic_id) << 24);
+        self.write(Self::ICR_LOW, command);
+        while self.read(Self::ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Sends a non-maskable interrupt to the processor `apic_id`.
+    pub fn send_nmi(&self, apic_id: u8) {
+        self.send_ipi(apic_id, ICR_NMI | ICR_ASSERT);
+    }
+
+    /// Starts the processor `apic_id` with INIT-SIPI-SIPI, at real mode address
+    /// `start_page * 4096`.
+    pub fn start_processor(&self, apic_id: u8, start_page: u8) {
+        self.send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+        timer::pit_wait_ms(10);
+        for _ in 0..2 {
+            self.send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | u32::from(start_page));
+            timer::pit_wait_ms(1);
+        }
+    }
+
+    /// Starts the timer, firing the timer vector after `initial_count` timer ticks.
+    pub fn set_timer(&self, mode: TimerMode, initial_count: u32) {
+        self.write(Self::TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(
+            Self::LVT_TIMER,
+            mode as u32 | u32::from(InterruptIndex::Timer.as_u8()),
+        );
+        self.write(Self::TIMER_INITIAL, initial_count);
+    }
+
+    pub fn stop_timer(&self) {
+        self.write(Self::LVT_TIMER, LVT_MASKED);
+        self.write(Self::TIMER_INITIAL, 0);
+    }
+
+    /// Fires the timer vector `frequency_hz` times per second.
+    pub fn start_periodic(&self, frequency_hz: u32) {
+        let per_ms = self.timer_ticks_per_ms.load(Ordering::Relaxed);
+        let count = u64::from(per_ms) * 1000 / u64::from(frequency_hz.max(1));
+        self.set_timer(
+            TimerMode::Periodic,
+            count.clamp(1, u64::from(u32::MAX)) as u32,
+        );
+    }
+
+    /// Fires the timer vector once, after `micros` microseconds.
+    pub fn start_one_shot(&self, micros: u64) {
+        let per_ms = self.timer_ticks_per_ms.load(Ordering::Relaxed);
+        let count = u64::from(per_ms) * micros / 1000;
+        self.set_timer(
+            TimerMode::OneShot,
+            count.clamp(1, u64::from(u32::MAX)) as u32,
+        );
+    }
+}
+
+#[derive(Debug)]
+pub struct IoApic {
+    base: VirtAddr,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    const VERSION: u32 = 0x01;
+    const REDIRECTION_TABLE: u32 = 0x10;
+
+    const ACTIVE_LOW: u64 = 1 << 13;
+    const LEVEL_TRIGGERED: u64 = 1 << 15;
+    const MASKED: u64 = 1 << 16;
+
+    fn new(base: VirtAddr, gsi_base: u32) -> Self {
+        let mut io_apic = Self {
+            base,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(Self::VERSION) >> 16) & 0xFF) + 1;
+        io_apic
+    }
+
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            self.base.as_mut_ptr::<u32>().write_volatile(reg);
+            (self.base + 0x10u64).as_ptr::<u32>().read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            self.base.as_mut_ptr::<u32>().write_volatile(reg);
+            (self.base + 0x10u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let reg = Self::REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        // Mask while the entry is half written
+        self.write(reg, Self::MASKED as u32);
+        self.write(reg + 1, (entry >> 32) as u32);
+        self.write(reg, entry as u32);
+    }
+
+    fn mask_all(&self) {
+        for gsi in self.gsi_base..self.gsi_base + self.entries {
+            self.set_redirection(gsi, Self::MASKED);
+        }
+    }
+}
+
+static LOCAL_APIC: Once<LocalApic> = Once::new();
+static IO_APICS: Once<Vec<IoApic>> = Once::new();
+
+/// The local APIC, once it replaced the PICs.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
+
+/// Delivers a legacy ISA IRQ to `vector` on the bootstrap processor.
+fn route_legacy_irq(madt: &Madt, io_apics: &[IoApic], irq: u8, vector: u8, dest: u8) {
+    let route = madt.legacy_irq(irq);
+    let Some(io_apic) = io_apics.iter().find(|x| x.handles(route.gsi)) else {
+        info!("No I/O APIC handles IRQ {irq} (GSI {})", route.gsi);
+        return;
+    };
+
+    let mut entry = u64::from(vector) | (u64::from(dest) << 56);
+    if route.active_low {
+        entry |= IoApic::ACTIVE_LOW;
+    }
+    if route.level_triggered {
+        entry |= IoApic::LEVEL_TRIGGERED;
+    }
+    debug!(
+        "Routing IRQ {irq} through GSI {} to vector {vector}",
+        route.gsi
+    );
+    io_apic.set_redirection(route.gsi, entry);
+}
+
+/// Switches interrupt delivery from the PICs to the APICs.
+///
+/// The PICs must already be remapped, so that spurious interrupts from them don't look like
+/// exceptions.
+pub fn init() -> Result<(), ApicError> {
+    if core::arch::x86_64::__cpuid(1).edx & (1 << 9) == 0 {
+        return Err(ApicError::Unsupported);
+    }
+    let madt = acpi::madt().ok_or(ApicError::NoMadt)?;
+    if madt.io_apics.is_empty() {
+        return Err(ApicError::NoIoApic);
+    }
+
+    let phys_offset = KERNEL_INFO.get().unwrap().physical_memory_offset;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let local = LocalApic {
+            base: phys_offset + madt.local_apic_address.as_u64(),
+            timer_ticks_per_ms: AtomicU32::new(0),
+        };
+        local.enable();
+        local.calibrate_timer();
+
+        let io_apics: Vec<_> = madt
+            .io_apics
+            .iter()
+            .map(|x| IoApic::new(phys_offset + x.address.as_u64(), x.gsi_base))
+            .collect();
+        for io_apic in &io_apics {
+            io_apic.mask_all();
+        }
+
+        unsafe { super::PICS.lock().disable() };
+
+        let dest = local.id();
+        route_legacy_irq(madt, &io_apics, 1, InterruptIndex::Keyboard.as_u8(), dest);
+        route_legacy_irq(madt, &io_apics, 4, InterruptIndex::Serial.as_u8(), dest);
+
+        info!(
+            "Using the local APIC {} and {} I/O APIC(s)",
+            local.id(),
+            io_apics.len()
+        );
+        IO_APICS.call_once(|| io_apics);
+        LOCAL_APIC.call_once(|| local);
+        timer::set_frequency(timer::frequency());
+    });
+
+    Ok(())
+}
+
+/// Enables the local APIC of an application processor and starts its timer.
+///
+/// The timer calibration of the bootstrap processor is reused.
+pub fn init_application_processor() {
+    let local = LOCAL_APIC
+        .get()
+        .expect("APs are only started with the local APIC");
+    local.enable();
+    local.start_periodic(timer::frequency());
+}
@@ -0,0 +1,140 @@
+//! Bottom halves of interrupt handlers.
+//!
+//! A handler only does what can't wait, like taking the data off the device, and schedules a
+//! [`Tasklet`] for the rest. Pending tasklets run in the interrupt tail, after the handler
+//! acknowledged the interrupt but still with interrupts disabled, so they must not sleep.
+//! Longer work goes to the [workqueue](crate::multitask::workqueue).
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+
+/// Tasklets scheduled since the last tail, most recent first
+static PENDING: AtomicPtr<Tasklet> = AtomicPtr::new(ptr::null_mut());
+
+/// A function to run once in the interrupt tail, however many times it was scheduled.
+///
+/// Scheduling doesn't allocate or lock, so handlers can do it with anything held. A tasklet
+/// never runs on two CPUs at once, one scheduled while it runs elsewhere waits for the next tail.
+#[derive(Debug)]
+pub struct Tasklet {
+    func: fn(usize),
+    data: usize,
+    scheduled: AtomicBool,
+    running: AtomicBool,
+    /// Next in [`PENDING`] while scheduled
+    next: AtomicPtr<Self>,
+}
+
+impl Tasklet {
+    pub const fn new(func: fn(usize), data: usize) -> Self {
+        Self {
+            func,
+            data,
+            scheduled: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Queues the tasklet for the next interrupt tail, unless it is already queued.
+    pub fn schedule(&'static self) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            self.push();
+        }
+    }
+
+    /// Adds the tasklet to [`PENDING`], which only its scheduler may do.
+    fn push(&'static self) {
+        let this = ptr::from_ref(self).cast_mut();
+        let mut head = PENDING.load(Ordering::Acquire);
+        loop {
+            self.next.store(head, Ordering::Relaxed);
+            match PENDING.compare_exchange_weak(head, this, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    pub const fn data(&self) -> usize {
+        self.data
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.scheduled.load(Ordering::Acquire)
+    }
+
+    /// Spins until the tasklet is neither scheduled nor running, before freeing it.
+    ///
+    /// Tasklets only run in interrupt tails, so interrupts must be enabled.
+    pub fn wait_idle(&self) {
+        while self.is_scheduled() || self.running.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Runs the tasklets scheduled so far, in the order they were scheduled.
+///
+/// Tasklets scheduled meanwhile, even the running ones again, wait for the next tail.
+pub fn run_pending() {
+    let mut list = PENDING.swap(ptr::null_mut(), Ordering::AcqRel);
+
+    // Reverse the list, so that the first scheduled runs first
+    let mut ordered: *mut Tasklet = ptr::null_mut();
+    // Safety: tasklets are 'static, and only this function touches `next` until they are
+    // marked as not scheduled
+    while let Some(tasklet) = unsafe { list.as_ref() } {
+        let next = tasklet.next.load(Ordering::Relaxed);
+        tasklet.next.store(ordered, Ordering::Relaxed);
+        ordered = list;
+        list = next;
+    }
+
+    while let Some(tasklet) = unsafe { ordered.as_ref() } {
+        ordered = tasklet.next.load(Ordering::Relaxed);
+        // Marked as running before it stops being scheduled, so that it is never seen idle
+        if tasklet.running.swap(true, Ordering::AcqRel) {
+            // Running on another CPU, it stays scheduled for the next tail
+            tasklet.push();
+            continue;
+        }
+        tasklet.scheduled.store(false, Ordering::Release);
+        (tasklet.func)(tasklet.data);
+        tasklet.running.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{Tasklet, run_pending};
+
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+    static TASKLET: Tasklet = Tasklet::new(count_run, 0);
+
+    fn count_run(_: usize) {
+        RUNS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn running_tasklets_wait_for_the_next_tail() {
+        let runs = RUNS.load(Ordering::Relaxed);
+        // As if another CPU was running it
+        TASKLET.running.store(true, Ordering::Release);
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            TASKLET.schedule();
+            run_pending();
+        });
+        assert_eq!(RUNS.load(Ordering::Relaxed), runs);
+        assert!(TASKLET.is_scheduled());
+
+        TASKLET.running.store(false, Ordering::Release);
+        x86_64::instructions::interrupts::without_interrupts(run_pending);
+        assert_eq!(RUNS.load(Ordering::Relaxed), runs + 1);
+        TASKLET.wait_idle();
+    }
+}
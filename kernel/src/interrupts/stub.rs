@@ -1,3 +1,11 @@
+#[cfg(feature = "kpti")]
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[cfg(feature = "kpti")]
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrameValue};
 use x86_64::{
     VirtAddr,
     registers::{
@@ -6,7 +14,10 @@ use x86_64::{
     },
 };
 
-use crate::interrupts::syscalls::syscall_tail;
+use crate::{
+    interrupts::{softirq, syscalls::syscall_tail},
+    memory::user,
+};
 
 // const SAVED_REG_COUNT: u64 = 10; // RBP RCX, RDX, RSI, RDI, R8, R9, R10, R11, RAX
 // const SAVED_BYTES: u64 = SAVED_REG_COUNT * core::mem::size_of::<u64>() as u64;
@@ -48,6 +59,44 @@ pub struct InterruptContext {
     pub frame: x86_64::structures::idt::InterruptStackFrame,
 }
 
+/// Entry code of the tail stubs switching to the kernel page table when they interrupted
+/// userspace, see [`crate::memory::kpti`].
+#[cfg(feature = "kpti")]
+macro_rules! kpti_enter {
+    () => {
+        "
+        test byte ptr [rsp + 8], 3  // RPL of the interrupted CS
+        jz 3f
+        push rax
+        mov rax, cr3
+        btr rax, 12
+        mov cr3, rax
+        pop rax
+    3:
+        "
+    };
+}
+
+#[cfg(not(feature = "kpti"))]
+macro_rules! kpti_enter {
+    () => {
+        ""
+    };
+}
+
+/// Entry code of the tail stubs clearing RFLAGS.AC when SMAP is on, see
+/// [`crate::memory::user::clac`]. Expects the `smap` operand.
+macro_rules! clac_enter {
+    () => {
+        "
+        cmp byte ptr [rip + {smap}], 0
+        je 6f
+        clac
+    6:
+        "
+    };
+}
+
 macro_rules! interrupt_with_tail {
 	($vis:vis extern "x86-interrupt" fn $name:ident(InterruptStackFrame) => $implementation:path) => {
 		paste::paste! {
@@ -57,8 +106,11 @@ macro_rules! interrupt_with_tail {
 				const [<_handler_ $name>]: $crate::interrupts::stub::ContextHandler = $implementation;
 
 				#[unsafe(naked)]
+				#[cfg_attr(feature = "kpti", unsafe(link_section = "kpti_entry"))]
 				pub extern "x86-interrupt" fn $name(_stack_frame: x86_64::structures::idt::InterruptStackFrame) {
 					core::arch::naked_asm!(
+						kpti_enter!(),
+						clac_enter!(),
 						"
 						.cfi_startproc              // Start DWARF frame info
 						// Save caller-saved registers
@@ -106,8 +158,9 @@ macro_rules! interrupt_with_tail {
 
 
 						// if RAX is 0, no stack switch must happen, jump to the handler
+						// (a local label, the stub is instantiated for several handlers)
 						test rax, rax
-						jz  .no_stack_switch
+						jz  2f
 						
 						// RAX now holds the Task Kernel Stack Top address. 
 
@@ -126,7 +179,7 @@ macro_rules! interrupt_with_tail {
 						// Perform the copy (13 QWords = 104 bytes)
 						rep movsq             // Copy the entire stack frame and saved regs to the new stack
 
-					.no_stack_switch:
+					2:
 						
 						mov rbp,rsp
 						add rbp,{saved_bytes}
@@ -142,6 +195,7 @@ macro_rules! interrupt_with_tail {
 						
 						.cfi_endproc
 						",
+						smap = sym $crate::memory::user::SMAP,
 						interrupt = sym $crate::interrupts::stub::interrupt_handle,
 						handler = sym $implementation,
 						get_stack_top = sym $crate::process::get_task_kernel_stack_top,
@@ -261,6 +315,7 @@ pub extern "C" fn interrupt_handle(ctx: &mut InterruptContext, handle: ContextHa
     unsafe {
         core::arch::asm!("mov {reg},rsp", reg = lateout(reg) rsp);
     }
+    debug_assert!(!user::user_access_open(), "Entered with user access open");
     handle(ctx);
     let cs = CS::get_reg();
     let ss = SS::get_reg();
@@ -300,6 +355,8 @@ pub extern "C" fn interrupt_handle(ctx: &mut InterruptContext, handle: ContextHa
 }
 
 pub extern "C" fn interrupt_tail(ctx: &mut InterruptContext) -> ! {
+    softirq::run_pending();
+
     if !ctx.registers.stack_top.is_null() {
         // If we didnt change stacks, there was no task stack - no multitasking possible
         if !syscall_tail() {
@@ -308,6 +365,11 @@ pub extern "C" fn interrupt_tail(ctx: &mut InterruptContext) -> ! {
         }
     }
 
+    #[cfg(feature = "kpti")]
+    if ctx.frame.code_segment.rpl() == x86_64::PrivilegeLevel::Ring3 {
+        unsafe { return_to_user(&ctx.registers, &ctx.frame) };
+    }
+
     // Restore registers
     unsafe {
         core::arch::asm!(
@@ -346,3 +408,247 @@ pub extern "C" fn interrupt_tail(ctx: &mut InterruptContext) -> ! {
         );
     }
 }
+
+/// Handlers of the vectors routed through [`kpti_vector_stubs`], by vector
+#[cfg(feature = "kpti")]
+static KPTI_HANDLERS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+/// Vectors for which the CPU pushes an error code
+#[cfg(feature = "kpti")]
+const ERROR_CODE_VECTORS: u32 = 1 << 8
+    | 1 << 10
+    | 1 << 11
+    | 1 << 12
+    | 1 << 13
+    | 1 << 14
+    | 1 << 17
+    | 1 << 21
+    | 1 << 29
+    | 1 << 30;
+
+// The entry and exit code mapped in the user page tables.
+//
+// `kpti_vector_stubs` holds a 16 byte stub per vector, pushing the vector before jumping to
+// `kpti_vector_common`. Coming from the kernel page table, the common code jumps to the handler
+// on the frame the CPU pushed. Coming from userspace, or from the exit code, it switches to the
+// kernel page table first and calls the handler on a frame returning to itself, then restores
+// the page table and returns on the frame the CPU pushed. Both paths clear RFLAGS.AC once the
+// SMAP flag is mapped.
+//
+// `kpti_exit_to_user` loads the user page table and pops the registers then the iret frame
+// [`return_to_user`] put on the top of the entry stack.
+#[cfg(feature = "kpti")]
+core::arch::global_asm!(
+    "
+    .pushsection kpti_entry, \"ax\", @progbits
+    .balign 4096
+    .global kpti_vector_stubs
+kpti_vector_stubs:
+    .set kpti_vector, 0
+    .rept 256
+    .balign 16
+    .byte 0x68                      // push imm32
+    .long kpti_vector
+    jmp kpti_vector_common
+    .set kpti_vector, kpti_vector + 1
+    .endr
+
+kpti_vector_common:
+    push rax
+    push rcx
+    push rdx
+    // +0 rdx, +8 rcx, +16 rax, +24 vector, +32 error code or CPU frame
+    mov rcx, [rsp + 24]
+    xor edx, edx
+    cmp ecx, 32
+    jae 1f
+    mov edx, {error_code_vectors}
+    shr edx, cl
+    and edx, 1
+    shl edx, 3
+1:  // rdx = size of the error code, the CPU frame is at rsp + 32 + rdx
+    lea rax, [rsp + rdx + 32]
+    test byte ptr [rax + 8], 3      // RPL of the interrupted CS
+    jnz 2f
+    mov rax, [rax]                  // interrupted RIP
+    lea rcx, [rip + kpti_exit_to_user]
+    cmp rax, rcx
+    jb 5f
+    lea rcx, [rip + kpti_exit_end]
+    cmp rax, rcx
+    jae 5f
+
+2:  mov rax, cr3
+    push rax                        // page table to return to
+    btr rax, 12
+    mov cr3, rax
+    cmp byte ptr [rip + {smap}], 0  // SMAP is only readable from here on
+    je 6f
+    clac
+6:
+    // +0 cr3, +8 rdx, +16 rcx, +24 rax, +32 vector, +40 error code or CPU frame
+    mov rcx, [rsp + 32]
+    lea rax, [rip + {handlers}]
+    mov rax, [rax + rcx * 8]
+    mov [rsp + 32], rdx             // the vector slot now holds the error code size
+    mov rcx, rsp
+    and rsp, -16
+    sub rsp, 40
+    mov [rsp + 24], rcx             // RSP
+    mov rcx, ss
+    mov [rsp + 32], rcx             // SS
+    pushfq
+    pop rcx
+    mov [rsp + 16], rcx             // RFLAGS
+    mov rcx, cs
+    mov [rsp + 8], rcx              // CS
+    lea rcx, [rip + 4f]
+    mov [rsp], rcx                  // RIP
+    test edx, edx
+    jz 3f
+    mov rcx, [rsp + 24]
+    push qword ptr [rcx + 40]       // the handler pops its copy of the error code
+3:  jmp rax
+
+4:  // +0 cr3, +8 rdx, +16 rcx, +24 rax, +32 error code size, +40 error code or CPU frame
+    mov rcx, [rsp + 32]
+    lea rcx, [rsp + rcx + 32]       // slot right below the CPU frame
+    mov rax, [rsp + 24]
+    mov [rcx], rax
+    mov rax, [rsp]
+    mov cr3, rax
+    mov rdx, [rsp + 8]
+    mov rax, rcx
+    mov rcx, [rsp + 16]
+    mov rsp, rax
+    pop rax
+    iretq
+
+5:  // Already on the kernel page table: the handler replaces the vector
+    cmp byte ptr [rip + {smap}], 0
+    je 6f
+    clac
+6:
+    mov rcx, [rsp + 24]
+    lea rax, [rip + {handlers}]
+    mov rax, [rax + rcx * 8]
+    mov [rsp + 24], rax
+    pop rdx
+    pop rcx
+    pop rax
+    ret
+
+    .global kpti_exit_to_user
+kpti_exit_to_user:
+    mov rax, cr3
+    bts rax, 12
+    mov cr3, rax
+    pop rax
+    pop rdi
+    pop rbx
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbp
+    iretq
+kpti_exit_end:
+    .popsection
+    ",
+    handlers = sym KPTI_HANDLERS,
+    smap = sym user::SMAP,
+    error_code_vectors = const ERROR_CODE_VECTORS,
+);
+
+#[cfg(feature = "kpti")]
+unsafe extern "C" {
+    fn kpti_vector_stubs();
+    fn kpti_exit_to_user() -> !;
+    static __start_kpti_entry: u8;
+    static __stop_kpti_entry: u8;
+}
+
+/// The code mapped in the user page tables: the tail stubs and the KPTI entry and exit code.
+#[cfg(feature = "kpti")]
+pub fn kpti_entry_section() -> Range<VirtAddr> {
+    VirtAddr::from_ptr(&raw const __start_kpti_entry)
+        ..VirtAddr::from_ptr(&raw const __stop_kpti_entry)
+}
+
+/// Points the present vectors of `idt` that are not tail stubs at [`kpti_vector_stubs`], which
+/// switch to the kernel page table before running their handler.
+///
+/// Interrupting userspace, these handlers see a frame returning into the entry code, the frame
+/// pushed by the CPU sits above it.
+#[cfg(feature = "kpti")]
+pub fn route_through_kpti_stubs(idt: &mut InterruptDescriptorTable) {
+    /// A gate as the CPU reads it
+    #[repr(C)]
+    struct Gate {
+        offset_low: u16,
+        selector: u16,
+        options: u16,
+        offset_middle: u16,
+        offset_high: u32,
+        reserved: u32,
+    }
+    const PRESENT: u16 = 1 << 15;
+    const _: () = assert!(size_of::<InterruptDescriptorTable>() == 256 * size_of::<Gate>());
+
+    let entry = kpti_entry_section();
+    let stubs = VirtAddr::from_ptr(kpti_vector_stubs as *const ()).as_u64();
+    let gates = unsafe { &mut *core::ptr::from_mut(idt).cast::<[Gate; 256]>() };
+    for (vector, gate) in gates.iter_mut().enumerate() {
+        let handler = u64::from(gate.offset_low)
+            | u64::from(gate.offset_middle) << 16
+            | u64::from(gate.offset_high) << 32;
+        if gate.options & PRESENT == 0 || entry.contains(&VirtAddr::new(handler)) {
+            continue;
+        }
+        KPTI_HANDLERS[vector].store(handler, Ordering::Relaxed);
+        let stub = stubs + 16 * vector as u64;
+        gate.offset_low = stub as u16;
+        gate.offset_middle = (stub >> 16) as u16;
+        gate.offset_high = (stub >> 32) as u32;
+    }
+}
+
+/// Returns to userspace with `registers` and `frame` through the user page table.
+///
+/// The entry stack of the CPU is the only stack mapped there, so they are copied to its top
+/// before switching.
+///
+/// # Safety
+/// `frame` must return to ring 3, in the address space loaded on this CPU.
+#[cfg(feature = "kpti")]
+pub unsafe fn return_to_user(registers: &SavedRegisters, frame: &InterruptStackFrameValue) -> ! {
+    x86_64::instructions::interrupts::disable();
+    let top = crate::smp::percpu::current()
+        .map_or_else(crate::gdt::bsp_tss, crate::smp::PerCpu::tss)
+        .privilege_stack_table[0];
+    // Everything but `stack_top`, popped by `kpti_exit_to_user`
+    let saved = size_of::<SavedRegisters>() - size_of::<VirtAddr>();
+    let dst = (top - (saved + size_of::<InterruptStackFrameValue>()) as u64).as_mut_ptr::<u8>();
+    unsafe {
+        core::ptr::copy_nonoverlapping((&raw const registers.rax).cast::<u8>(), dst, saved);
+        core::ptr::copy_nonoverlapping(
+            core::ptr::from_ref(frame).cast::<u8>(),
+            dst.add(saved),
+            size_of::<InterruptStackFrameValue>(),
+        );
+        core::arch::asm!(
+            "mov rsp, {dst}",
+            "jmp {exit}",
+            dst = in(reg) dst,
+            exit = sym kpti_exit_to_user,
+            options(noreturn)
+        );
+    }
+}
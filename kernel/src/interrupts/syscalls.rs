@@ -1,28 +1,39 @@
 use core::ops::{Index, IndexMut};
 
-use alloc::sync::Arc;
 use blog_os_syscalls::SyscallNumber;
-use log::{debug, info, warn};
+use log::{debug, warn};
 use spin::Lazy;
 
 use crate::{
-    multitask::{
-        change_current_process_info, task_exit, task_switch, try_get_current_process_info,
-    },
-    process::ProcessStatus,
+    multitask::{task_exit, task_switch},
+    process,
 };
 
 mod brk;
+mod chdir;
 mod close;
 mod exit;
 mod flush;
+mod ftruncate;
+mod get_priority;
 mod init_driver;
+mod ioctl;
+mod mkdirat;
 mod next_direntry;
 mod nop;
 mod open;
+mod openat;
+mod pipe;
+mod pread;
+mod pwrite;
 mod read;
+mod readv;
+mod set_priority;
 mod stat;
+mod statat;
+mod truncate;
 mod write;
+mod writev;
 mod yield_syscall;
 
 type SyscallHandler = fn(u64, u64, u64, u64, u64, u64) -> u64;
@@ -58,6 +69,20 @@ static SYSCALL_HANDLERS: Lazy<SyscallHandlers> = Lazy::new(|| {
     nums[SyscallNumber::STAT] = stat::stat;
     nums[SyscallNumber::NEXT_DIRENTRY] = next_direntry::next_direntry;
     nums[SyscallNumber::INIT_DRIVER] = init_driver::init_driver;
+    nums[SyscallNumber::TRUNCATE] = truncate::truncate;
+    nums[SyscallNumber::FTRUNCATE] = ftruncate::ftruncate;
+    nums[SyscallNumber::PREAD] = pread::pread;
+    nums[SyscallNumber::PWRITE] = pwrite::pwrite;
+    nums[SyscallNumber::READV] = readv::readv;
+    nums[SyscallNumber::WRITEV] = writev::writev;
+    nums[SyscallNumber::IOCTL] = ioctl::ioctl;
+    nums[SyscallNumber::OPENAT] = openat::openat;
+    nums[SyscallNumber::STATAT] = statat::statat;
+    nums[SyscallNumber::MKDIRAT] = mkdirat::mkdirat;
+    nums[SyscallNumber::SET_PRIORITY] = set_priority::set_priority;
+    nums[SyscallNumber::GET_PRIORITY] = get_priority::get_priority;
+    nums[SyscallNumber::PIPE] = pipe::pipe;
+    nums[SyscallNumber::CHDIR] = chdir::chdir;
 
     nums
 });
@@ -92,25 +117,8 @@ pub extern "C" fn syscall_tail() -> bool {
     x86_64::instructions::interrupts::without_interrupts(|| {
         // debug!("SYSCALL TAIL");
 
-        if let Some(current_pinf) = try_get_current_process_info() {
-            // Process info must be there if a syscall was made.
-
-            if let ProcessStatus::Ending(code) = current_pinf.status() {
-                info!("Process ending with code: {code}");
-                change_current_process_info(|p| p.take()); // This process is no longer associated with the task
-                let program = current_pinf.program().clone();
-                drop(current_pinf);
-
-                debug!("Strong count: {}", Arc::strong_count(&program));
-                debug!("Weak count: {}", Arc::weak_count(&program));
-
-                let program = Arc::into_inner(program).expect("No more than one ref");
-                drop(program);
-
-                task_exit();
-            }
-
-            drop(current_pinf);
+        if process::reap_exited() {
+            task_exit();
         }
         task_switch()
     })
@@ -1,9 +1,10 @@
+use blog_os_syscalls::brk::HUGE_PAGES;
 use log::debug;
 
 use crate::multitask::get_current_process_info;
 
-pub fn brk(offset: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
-    debug!("BRK SYSCALL ({offset})");
+pub fn brk(offset: u64, flags: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    debug!("BRK SYSCALL ({offset}, flags: {flags:x})");
     let offset = offset as i64;
     let Some(pinf) = get_current_process_info() else {
         return 0;
@@ -13,6 +14,10 @@ pub fn brk(offset: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
 
     prog.heap()
         .lock()
-        .change_brk(prog.stack(), offset)
+        .change_brk(
+            &mut prog.address_space().lock(),
+            offset,
+            flags & HUGE_PAGES != 0,
+        )
         .map_or(-1i64 as u64, |addr| addr.as_u64())
 }
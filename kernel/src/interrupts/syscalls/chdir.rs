@@ -0,0 +1,29 @@
+use blog_os_vfs::api::{IOError, inode::INode};
+use log::debug;
+use shared_fs::{AT_FDCWD, FileType};
+
+use super::openat::resolve_at;
+use crate::{fs::VFS, memory::user::string_from_user, multitask::get_current_process_info};
+
+fn chdir_high_level(path: &str) -> Result<u64, IOError> {
+    debug!("Changing directory to {path}");
+    let pinf = get_current_process_info().ok_or(IOError::NotFound)?;
+    let (dir, path) = resolve_at(AT_FDCWD, path)?;
+
+    let (_, inode) = VFS.write().get_at(dir, &path)?;
+    if inode.stat()?.file_type != FileType::Directory {
+        return Err(IOError::NotADirectory);
+    }
+
+    *pinf.cwd().write() = path.normalize();
+    Ok(0)
+}
+
+/// Changes the working directory of the process, where relative paths given with `AT_FDCWD`
+/// start
+pub fn chdir(path: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    string_from_user(path, len as usize)
+        .map_err(IOError::from)
+        .and_then(|path| chdir_high_level(&path))
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
+}
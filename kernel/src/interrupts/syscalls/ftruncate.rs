@@ -0,0 +1,18 @@
+use blog_os_vfs::api::{IOError, file::File};
+use log::debug;
+
+use crate::multitask::get_current_process_info;
+
+fn ftruncate_high_level(fd: u64, size: u64) -> Result<u64, IOError> {
+    debug!("Truncating fd {fd} to {size} bytes");
+    let file = get_current_process_info()
+        .and_then(|pinf| pinf.files().read().get(fd as usize).cloned())
+        .ok_or(IOError::NotFound)?;
+
+    file.write().truncate(size)?;
+    Ok(0)
+}
+
+pub fn ftruncate(fd: u64, size: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    ftruncate_high_level(fd, size).unwrap_or_else(|e| (-(e as i64)) as u64)
+}
@@ -0,0 +1,9 @@
+use crate::multitask::{get_current_task_id, get_priority as get_task_priority};
+
+/// Scheduling priority of the calling task, as encoded by [`Priority::encode`](blog_os_syscalls::priority::Priority::encode)
+pub fn get_priority(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    get_current_task_id()
+        .and_then(|id| get_task_priority(&id))
+        .unwrap_or_default()
+        .encode()
+}
@@ -0,0 +1,35 @@
+use alloc::vec;
+use blog_os_vfs::api::{IOError, file::File};
+use log::debug;
+use shared_fs::ioctl::IoctlRequest;
+
+use crate::{
+    memory::user::{copy_from_user, copy_to_user},
+    multitask::get_current_process_info,
+};
+
+fn ioctl_high_level(fd: u64, request: IoctlRequest, arg: u64) -> Result<u64, IOError> {
+    debug!("ioctl {request:x?} on fd {fd} ({:?})", request.direction());
+    let file = get_current_process_info()
+        .and_then(|pinf| pinf.files().read().get(fd as usize).cloned())
+        .ok_or(IOError::NotFound)?;
+
+    let direction = request.direction();
+    let mut buf = vec![0u8; request.size()];
+
+    if direction.copies_in() {
+        copy_from_user(&mut buf, arg)?;
+    }
+
+    let res = file.write().ioctl(request, &mut buf)?;
+
+    if direction.copies_out() {
+        copy_to_user(arg, &buf)?;
+    }
+
+    Ok(res)
+}
+
+pub fn ioctl(fd: u64, request: u64, arg: u64, _: u64, _: u64, _: u64) -> u64 {
+    ioctl_high_level(fd, IoctlRequest(request as u32), arg).unwrap_or_else(|e| (-(e as i64)) as u64)
+}
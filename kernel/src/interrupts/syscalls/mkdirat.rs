@@ -0,0 +1,20 @@
+use blog_os_vfs::api::IOError;
+use log::debug;
+
+use super::openat::resolve_at;
+use crate::{fs::VFS, memory::user::string_from_user};
+
+fn mkdirat_high_level(dirfd: u64, path: &str) -> Result<u64, IOError> {
+    debug!("Creating directory {path} at fd {dirfd:x}");
+    let (dir, path) = resolve_at(dirfd, path)?;
+
+    VFS.write().mkdir_at(dir, &path, false, false)?;
+    Ok(0)
+}
+
+pub fn mkdirat(dirfd: u64, path: u64, len: u64, _: u64, _: u64, _: u64) -> u64 {
+    string_from_user(path, len as usize)
+        .map_err(IOError::from)
+        .and_then(|path| mkdirat_high_level(dirfd, &path))
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
+}
@@ -1,10 +1,20 @@
+use alloc::vec;
 use blog_os_vfs::api::{IOError, file::File};
-use shared_fs::dirent::{DirEntry, DirEntryHeader};
-use x86_64::VirtAddr;
+use shared_fs::dirent::DirEntryHeader;
 
-use crate::multitask::get_current_process_info;
+use crate::{
+    memory::user::{copy_from_user, copy_to_user},
+    multitask::get_current_process_info,
+};
+
+fn next_direntry_high_level(fd: u64, dirent: u64) -> Result<u64, IOError> {
+    // The header only holds the size of the whole entry, the name follows it
+    let mut record_len = [0; size_of::<DirEntryHeader>()];
+    copy_from_user(&mut record_len, dirent)?;
+    let name_len = usize::from_ne_bytes(record_len)
+        .checked_sub(size_of::<DirEntryHeader>())
+        .ok_or(IOError::InvalidArgument)?;
 
-fn next_direntry_high_level(fd: u64, res: &mut DirEntry) -> Result<u64, IOError> {
     let file = get_current_process_info()
         .and_then(|pinf| pinf.files().read().get(fd as usize).cloned())
         .ok_or(IOError::NotFound)?;
@@ -12,7 +22,7 @@ fn next_direntry_high_level(fd: u64, res: &mut DirEntry) -> Result<u64, IOError>
     let mut lock = file.write();
     let name = lock.next_direntry()?;
 
-    let entry_bytes = res.name_buf_mut();
+    let mut entry_bytes = vec![0; name_len];
 
     let name: &[u8] = name.as_bytes();
 
@@ -26,13 +36,11 @@ fn next_direntry_high_level(fd: u64, res: &mut DirEntry) -> Result<u64, IOError>
         entry_bytes[maxlen] = 0; // NULL termination
     }
 
+    copy_to_user(dirent + size_of::<DirEntryHeader>() as u64, &entry_bytes)?;
+
     Ok(0)
 }
 
 pub fn next_direntry(fd: u64, dirent: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
-    let ptr = VirtAddr::new_truncate(dirent).as_mut_ptr::<DirEntryHeader>();
-
-    let entry = unsafe { DirEntry::from_thin(ptr) };
-
-    next_direntry_high_level(fd, entry).unwrap_or_else(|e| (-(e as i64)) as u64)
+    next_direntry_high_level(fd, dirent).unwrap_or_else(|e| (-(e as i64)) as u64)
 }
@@ -1,29 +1,12 @@
-use alloc::{string::String, sync::Arc};
-use blog_os_vfs::api::{IOError, inode::INode, path::PathBuf};
-use log::debug;
-use spin::lock_api::RwLock;
-use x86_64::VirtAddr;
+use blog_os_vfs::api::IOError;
+use shared_fs::AT_FDCWD;
 
-use crate::{fs::VFS, multitask::get_current_process_info, process::OpenFile};
-
-fn open_high_level(path: &str) -> Result<u64, IOError> {
-    debug!("Opening: {path}");
-    let path = PathBuf::parse(path);
-    get_current_process_info()
-        .ok_or(IOError::NotFound)
-        .and_then(|pinf| {
-            let inode = VFS.write().get(&path)?;
-            let file = inode.open()?;
-            let fd = Arc::new(RwLock::new(OpenFile::new(inode, file)));
-            Ok(pinf.files().write().insert(fd) as u64)
-        })
-        .inspect(|fd| debug!("Opened with fd {fd}"))
-}
+use super::openat::openat_high_level;
+use crate::memory::user::string_from_user;
 
 pub fn open(path: u64, len: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
-    let buf = String::from_utf8_lossy(unsafe {
-        core::slice::from_raw_parts(VirtAddr::new(path).as_ptr::<u8>(), len as usize)
-    });
-
-    open_high_level(&buf).unwrap_or_else(|e| (-(e as i64)) as u64)
+    string_from_user(path, len as usize)
+        .map_err(IOError::from)
+        .and_then(|path| openat_high_level(AT_FDCWD, &path))
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
 }
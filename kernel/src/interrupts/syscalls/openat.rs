@@ -0,0 +1,60 @@
+use alloc::sync::Arc;
+use blog_os_vfs::{
+    INodeRef,
+    api::{IOError, inode::INode, path::PathBuf},
+};
+use log::debug;
+use shared_fs::{AT_FDCWD, FileType};
+
+use crate::{
+    fs::VFS, memory::user::string_from_user, multitask::get_current_process_info, process::OpenFile,
+};
+
+/// Parses a path given to an `*AT` syscall, with the directory it starts from if it is relative.
+///
+/// Paths relative to `AT_FDCWD` are made absolute from the working directory of the process.
+pub(super) fn resolve_at(dirfd: u64, path: &str) -> Result<(Option<INodeRef>, PathBuf), IOError> {
+    let path = PathBuf::parse(path);
+    if path.is_absolute() {
+        return Ok((None, path));
+    }
+    if dirfd == AT_FDCWD {
+        let pinf = get_current_process_info().ok_or(IOError::NotFound)?;
+        let cwd = pinf.cwd().read().clone();
+        return Ok((None, cwd.join(&path)));
+    }
+
+    let file = get_current_process_info()
+        .and_then(|pinf| pinf.files().read().get(dirfd as usize).cloned())
+        .ok_or(IOError::NotFound)?;
+    let file = file.read();
+
+    let inode = file.inode().ok_or(IOError::NotADirectory)?;
+    if inode.stat()?.file_type != FileType::Directory {
+        return Err(IOError::NotADirectory);
+    }
+
+    let dir = file.inode_ref().ok_or(IOError::NotADirectory)?;
+    Ok((Some(dir), path))
+}
+
+pub(super) fn openat_high_level(dirfd: u64, path: &str) -> Result<u64, IOError> {
+    debug!("Opening: {path} at fd {dirfd:x}");
+    let (dir, path) = resolve_at(dirfd, path)?;
+    get_current_process_info()
+        .ok_or(IOError::NotFound)
+        .and_then(|pinf| {
+            let (inode_ref, inode) = VFS.write().get_at(dir, &path)?;
+            let file = inode.open()?;
+            let fd = Arc::new(OpenFile::new(inode_ref, inode, file).into_lock());
+            Ok(pinf.files().write().insert(fd) as u64)
+        })
+        .inspect(|fd| debug!("Opened with fd {fd}"))
+}
+
+pub fn openat(dirfd: u64, path: u64, len: u64, _: u64, _: u64, _: u64) -> u64 {
+    string_from_user(path, len as usize)
+        .map_err(IOError::from)
+        .and_then(|path| openat_high_level(dirfd, &path))
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
+}
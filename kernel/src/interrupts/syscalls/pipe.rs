@@ -0,0 +1,42 @@
+use alloc::sync::Arc;
+use api_utils::cglue;
+use blog_os_vfs::api::{IOError, file::cglue_file::*};
+use log::debug;
+
+use crate::{
+    memory::user::copy_to_user,
+    multitask::get_current_process_info,
+    process::{OpenFile, pipe},
+};
+
+/// Opens both ends of a new pipe, storing their fds at `fds` as `[read, write]`.
+fn pipe_high_level(fds: u64) -> Result<u64, IOError> {
+    let pinf = get_current_process_info().ok_or(IOError::NotFound)?;
+    let (reader, writer) = pipe::pipe();
+
+    let mut files = pinf.files().write();
+    let read_fd = files.insert(Arc::new(
+        OpenFile::new_no_inode(cglue::trait_obj!(reader as File)).into_lock(),
+    ));
+    let write_fd = files.insert(Arc::new(
+        OpenFile::new_no_inode(cglue::trait_obj!(writer as File)).into_lock(),
+    ));
+    drop(files);
+
+    let mut out = [0; 16];
+    out[..8].copy_from_slice(&(read_fd as u64).to_ne_bytes());
+    out[8..].copy_from_slice(&(write_fd as u64).to_ne_bytes());
+    if let Err(e) = copy_to_user(fds, &out) {
+        let mut files = pinf.files().write();
+        files.remove(read_fd);
+        files.remove(write_fd);
+        return Err(e.into());
+    }
+
+    debug!("Opened pipe with fds {read_fd} and {write_fd}");
+    Ok(0)
+}
+
+pub fn pipe(fds: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    pipe_high_level(fds).unwrap_or_else(|e| (-(e as i64)) as u64)
+}
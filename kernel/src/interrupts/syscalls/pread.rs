@@ -0,0 +1,27 @@
+use alloc::vec;
+use blog_os_vfs::api::{IOError, file::File};
+
+use crate::{
+    memory::user::{check_user_range, copy_to_user},
+    multitask::get_current_process_info,
+};
+
+fn pread_high_level(fd: u64, buf: &mut [u8], offset: u64) -> Result<u64, IOError> {
+    let file = get_current_process_info()
+        .and_then(|pinf| pinf.files().read().get(fd as usize).cloned())
+        .ok_or(IOError::NotFound)?;
+
+    file.write().pread(buf, offset).map(|x| x as u64)
+}
+
+pub fn pread(fd: u64, buf: u64, len: u64, offset: u64, _: u64, _: u64) -> u64 {
+    check_user_range(buf, len as usize, true)
+        .map_err(IOError::from)
+        .and_then(|()| {
+            let mut kbuf = vec![0; len as usize];
+            let bytes = pread_high_level(fd, &mut kbuf, offset)?;
+            copy_to_user(buf, &kbuf[..bytes as usize])?;
+            Ok(bytes)
+        })
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
+}
@@ -0,0 +1,18 @@
+use blog_os_vfs::api::{IOError, file::File};
+
+use crate::{memory::user::vec_from_user, multitask::get_current_process_info};
+
+fn pwrite_high_level(fd: u64, buf: &[u8], offset: u64) -> Result<u64, IOError> {
+    let file = get_current_process_info()
+        .and_then(|pinf| pinf.files().read().get(fd as usize).cloned())
+        .ok_or(IOError::NotFound)?;
+
+    file.write().pwrite(buf, offset).map(|x| x as u64)
+}
+
+pub fn pwrite(fd: u64, buf: u64, len: u64, offset: u64, _: u64, _: u64) -> u64 {
+    vec_from_user(buf, len as usize)
+        .map_err(IOError::from)
+        .and_then(|buf| pwrite_high_level(fd, &buf, offset))
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
+}
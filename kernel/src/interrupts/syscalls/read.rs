@@ -1,7 +1,10 @@
+use alloc::vec;
 use blog_os_vfs::api::{IOError, file::File};
-use x86_64::VirtAddr;
 
-use crate::multitask::get_current_process_info;
+use crate::{
+    memory::user::{check_user_range, copy_to_user},
+    multitask::get_current_process_info,
+};
 
 fn read_high_level(fd: u64, buf: &mut [u8]) -> Result<u64, IOError> {
     let file = get_current_process_info()
@@ -12,9 +15,13 @@ fn read_high_level(fd: u64, buf: &mut [u8]) -> Result<u64, IOError> {
 }
 
 pub fn read(fd: u64, buf: u64, len: u64, _: u64, _: u64, _: u64) -> u64 {
-    let buf = unsafe {
-        core::slice::from_raw_parts_mut(VirtAddr::new(buf).as_mut_ptr::<u8>(), len as usize)
-    };
-
-    read_high_level(fd, buf).unwrap_or_else(|e| (-(e as i64)) as u64)
+    check_user_range(buf, len as usize, true)
+        .map_err(IOError::from)
+        .and_then(|()| {
+            let mut kbuf = vec![0; len as usize];
+            let bytes = read_high_level(fd, &mut kbuf)?;
+            copy_to_user(buf, &kbuf[..bytes as usize])?;
+            Ok(bytes)
+        })
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
 }
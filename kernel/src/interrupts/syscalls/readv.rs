@@ -0,0 +1,45 @@
+use alloc::vec;
+use blog_os_vfs::api::{IOError, file::File};
+use shared_fs::iovec::IoVec;
+
+use super::writev::iovecs_from_user;
+use crate::{
+    memory::user::{check_user_range, copy_to_user},
+    multitask::get_current_process_info,
+};
+
+fn readv_high_level(fd: u64, iovs: &[IoVec]) -> Result<u64, IOError> {
+    let file = get_current_process_info()
+        .and_then(|pinf| pinf.files().read().get(fd as usize).cloned())
+        .ok_or(IOError::NotFound)?;
+
+    let mut lock = file.write();
+    let mut total = 0;
+
+    for iov in iovs {
+        check_user_range(iov.base as u64, iov.len, true)?;
+        let mut buf = vec![0; iov.len];
+        let bytes = match lock.read(&mut buf) {
+            // Report what was read so far, the next call will get the EOF
+            Err(IOError::EOF) if total > 0 => break,
+            x => x?,
+        };
+        copy_to_user(iov.base as u64, &buf[..bytes])?;
+
+        total += bytes;
+
+        if bytes < buf.len() {
+            break;
+        }
+    }
+
+    drop(lock);
+
+    Ok(total as u64)
+}
+
+pub fn readv(fd: u64, iov: u64, count: u64, _: u64, _: u64, _: u64) -> u64 {
+    iovecs_from_user(iov, count)
+        .and_then(|iovs| readv_high_level(fd, &iovs))
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
+}
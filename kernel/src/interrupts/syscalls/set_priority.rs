@@ -0,0 +1,16 @@
+use blog_os_syscalls::priority::Priority;
+use blog_os_vfs::api::IOError;
+
+use crate::multitask::{get_current_task_id, request_priority};
+
+fn set_priority_high_level(priority: u64) -> Result<u64, IOError> {
+    let priority = Priority::decode(priority).ok_or(IOError::InvalidArgument)?;
+    let id = get_current_task_id().ok_or(IOError::NotFound)?;
+    request_priority(&id, priority)?;
+    Ok(0)
+}
+
+/// Changes the scheduling priority of the calling task, which can't make itself real-time
+pub fn set_priority(priority: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 {
+    set_priority_high_level(priority).unwrap_or_else(|e| (-(e as i64)) as u64)
+}
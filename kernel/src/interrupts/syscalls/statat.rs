@@ -0,0 +1,24 @@
+use blog_os_vfs::api::{IOError, inode::INode};
+use shared_fs::Stat;
+
+use super::openat::resolve_at;
+use crate::{
+    fs::VFS,
+    memory::user::{string_from_user, write_to_user},
+};
+
+fn statat_high_level(dirfd: u64, path: &str) -> Result<Stat, IOError> {
+    let (dir, path) = resolve_at(dirfd, path)?;
+
+    let (_, inode) = VFS.write().get_at(dir, &path)?;
+    inode.stat()
+}
+
+pub fn statat(dirfd: u64, path: u64, len: u64, stat: u64, _: u64, _: u64) -> u64 {
+    string_from_user(path, len as usize)
+        .map_err(IOError::from)
+        .and_then(|path| statat_high_level(dirfd, &path))
+        .and_then(|res| Ok(write_to_user(stat, &res)?))
+        .map(|()| 0)
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
+}
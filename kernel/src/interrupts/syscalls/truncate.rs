@@ -0,0 +1,20 @@
+use blog_os_vfs::api::{IOError, inode::INode, path::PathBuf};
+use log::debug;
+
+use crate::{fs::VFS, memory::user::string_from_user};
+
+fn truncate_high_level(path: &str, size: u64) -> Result<u64, IOError> {
+    debug!("Truncating {path} to {size} bytes");
+    let path = PathBuf::parse(path);
+
+    VFS.write().get(&path)?.truncate(size)?;
+
+    Ok(0)
+}
+
+pub fn truncate(path: u64, len: u64, size: u64, _: u64, _: u64, _: u64) -> u64 {
+    string_from_user(path, len as usize)
+        .map_err(IOError::from)
+        .and_then(|path| truncate_high_level(&path, size))
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
+}
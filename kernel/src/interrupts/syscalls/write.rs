@@ -1,8 +1,7 @@
 use blog_os_vfs::api::{IOError, file::File};
 use log::debug;
-use x86_64::VirtAddr;
 
-use crate::multitask::get_current_process_info;
+use crate::{memory::user::vec_from_user, multitask::get_current_process_info};
 
 fn write_high_level(fd: u64, buf: &[u8]) -> Result<u64, IOError> {
     debug!("Writing buffer to fd {fd}");
@@ -17,9 +16,8 @@ fn write_high_level(fd: u64, buf: &[u8]) -> Result<u64, IOError> {
 
 pub fn write(fd: u64, buf: u64, len: u64, _: u64, _: u64, _: u64) -> u64 {
     // debug!("Loading buffer for writing (@ 0x{buf:x} with len {len})");
-    let buf =
-        unsafe { core::slice::from_raw_parts(VirtAddr::new(buf).as_ptr::<u8>(), len as usize) };
-    // debug!("Loaded buffer for writing");
-
-    write_high_level(fd, buf).unwrap_or_else(|e| (-(e as i64)) as u64)
+    vec_from_user(buf, len as usize)
+        .map_err(IOError::from)
+        .and_then(|buf| write_high_level(fd, &buf))
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
 }
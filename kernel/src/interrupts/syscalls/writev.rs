@@ -0,0 +1,49 @@
+use alloc::vec::Vec;
+use blog_os_vfs::api::{IOError, file::File};
+use shared_fs::iovec::IoVec;
+
+use crate::{memory::user::vec_from_user, multitask::get_current_process_info};
+
+fn writev_high_level(fd: u64, iovs: &[IoVec]) -> Result<u64, IOError> {
+    let file = get_current_process_info()
+        .and_then(|pinf| pinf.files().read().get(fd as usize).cloned())
+        .ok_or(IOError::NotFound)?;
+
+    // Hold the lock for every buffer, so the gathered write is not interleaved with others
+    let mut lock = file.write();
+    let mut total = 0;
+
+    for iov in iovs {
+        let buf = vec_from_user(iov.base as u64, iov.len)?;
+        let bytes = lock.write(&buf)?;
+
+        total += bytes;
+
+        if bytes < buf.len() {
+            break;
+        }
+    }
+
+    drop(lock);
+
+    Ok(total as u64)
+}
+
+/// Reads the `count` buffer descriptors at `iov` from userspace.
+pub(super) fn iovecs_from_user(iov: u64, count: u64) -> Result<Vec<IoVec>, IOError> {
+    let len = (count as usize)
+        .checked_mul(size_of::<IoVec>())
+        .ok_or(IOError::BadAddress)?;
+    let bytes = vec_from_user(iov, len)?;
+
+    Ok(bytes
+        .chunks_exact(size_of::<IoVec>())
+        .map(|chunk| unsafe { chunk.as_ptr().cast::<IoVec>().read_unaligned() })
+        .collect())
+}
+
+pub fn writev(fd: u64, iov: u64, count: u64, _: u64, _: u64, _: u64) -> u64 {
+    iovecs_from_user(iov, count)
+        .and_then(|iovs| writev_high_level(fd, &iovs))
+        .unwrap_or_else(|e| (-(e as i64)) as u64)
+}
@@ -0,0 +1,104 @@
+//! Timer ticks for the scheduler, from the local APIC timer if it is in use, or from the
+//! programmable interval timer otherwise.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use x86_64::instructions::port::Port;
+
+use crate::{interrupts::apic, smp};
+
+/// Tick frequency used at boot.
+pub const DEFAULT_FREQUENCY_HZ: u32 = 1000;
+
+/// The PIT base frequency
+const PIT_BASE_FREQ: u32 = 1_193_182;
+
+// PIT Ports
+const PIT_CMD_PORT: u16 = 0x43;
+const PIT_DATA_PORT: u16 = 0x40; // Channel 0 (Timer)
+const PIT_CHANNEL_2_PORT: u16 = 0x42;
+const PIT_GATE_PORT: u16 = 0x61;
+
+// Command: Channel 0, LOBYTE/HIBYTE, Mode 3 (Square Wave Generator)
+const COMMAND: u8 = 0b0011_0110;
+
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets how many timer interrupts happen per second.
+///
+/// With the PIT, the frequency is clamped to what the 16 bit divisor can express
+/// (19 Hz to ~1.19 MHz). With the local APIC, only the running CPU's timer is reprogrammed,
+/// application processors use the frequency that was set when they started.
+pub fn set_frequency(frequency_hz: u32) {
+    if let Some(local_apic) = apic::local_apic() {
+        local_apic.start_periodic(frequency_hz);
+        FREQUENCY.store(frequency_hz.max(1), Ordering::Relaxed);
+        return;
+    }
+
+    let divisor = (PIT_BASE_FREQ / frequency_hz.max(1)).clamp(1, u32::from(u16::MAX)) as u16;
+
+    let mut cmd_port = Port::<u8>::new(PIT_CMD_PORT);
+    let mut data_port = Port::<u8>::new(PIT_DATA_PORT);
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        cmd_port.write(COMMAND);
+        data_port.write((divisor & 0xFF) as u8);
+        data_port.write((divisor >> 8) as u8);
+    });
+
+    FREQUENCY.store(PIT_BASE_FREQ / u32::from(divisor), Ordering::Relaxed);
+}
+
+/// The actual tick frequency, after rounding to the PIT divisor.
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Number of timer interrupts since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since the timer was started.
+pub fn uptime_ms() -> u64 {
+    match u64::from(frequency()) {
+        0 => 0,
+        freq => ticks() * 1000 / freq,
+    }
+}
+
+/// Busy waits `ms` milliseconds (at most 54) on PIT channel 2, without interrupts.
+pub(crate) fn pit_wait_ms(ms: u32) {
+    let count = (PIT_BASE_FREQ / 1000 * ms).min(u32::from(u16::MAX)) as u16;
+
+    let mut cmd_port = Port::<u8>::new(PIT_CMD_PORT);
+    let mut data_port = Port::<u8>::new(PIT_CHANNEL_2_PORT);
+    let mut gate_port = Port::<u8>::new(PIT_GATE_PORT);
+
+    unsafe {
+        // Gate off and speaker off while programming
+        let gate = gate_port.read() & !0b11;
+        gate_port.write(gate);
+
+        // Channel 2, LOBYTE/HIBYTE, Mode 0 (Interrupt On Terminal Count)
+        cmd_port.write(0b1011_0000);
+        data_port.write((count & 0xFF) as u8);
+        data_port.write((count >> 8) as u8);
+
+        // Start counting, the output goes high once the count reaches 0
+        gate_port.write(gate | 1);
+        while gate_port.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        gate_port.write(gate);
+    }
+}
+
+pub(super) fn on_tick() {
+    // Every CPU runs its own timer, only the bootstrap processor keeps time
+    if smp::cpu_index() == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -1,9 +1,11 @@
 use core::{fmt::Write, ops::DerefMut};
 
+use crate::{
+    io::serial::SerialPort,
+    lock_class,
+    multitask::lock::{SpinMutex, spin_mutex},
+};
 use bootloader_x86_64_common::framebuffer::FrameBufferWriter;
-use spin::Mutex;
-
-use crate::io::serial::SerialPort;
 
 pub mod framebuffer;
 pub mod logger;
@@ -121,7 +123,7 @@ impl<'a> core::fmt::Write for StackWriter<'a> {
     }
 }
 
-static STACK: Mutex<WriteStack> = Mutex::new(WriteStack::new());
+static STACK: SpinMutex<WriteStack> = spin_mutex(lock_class!(), WriteStack::new());
 
 pub fn print(args: core::fmt::Arguments) {
     writer(|mut w| w.write_fmt(args).expect("write"));
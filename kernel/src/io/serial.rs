@@ -1,10 +1,14 @@
 use core::{fmt::Write, sync::atomic::AtomicBool};
 
-use spin::{Lazy, Mutex};
+use spin::Lazy;
 
-use crate::io::{
-    STACK,
-    logger::{RecordData, structured::RecordSval},
+use crate::{
+    io::{
+        STACK,
+        logger::{RecordData, structured::RecordSval},
+    },
+    lock_class,
+    multitask::lock::{SpinMutex, spin_mutex},
 };
 
 use core::fmt;
@@ -52,11 +56,11 @@ pub fn init() {
     }
 }
 
-static JSON_SINK: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
+static JSON_SINK: Lazy<SpinMutex<SerialPort>> = Lazy::new(|| {
     let mut serial = unsafe { SerialPort::new(0x2F8) };
     serial.write_str("===\n\n\n=== START ===\n\n\n").unwrap();
 
-    Mutex::new(serial)
+    spin_mutex(lock_class!(), serial)
 });
 
 pub fn print_json<'a, 'b>(record: &'a log::Record<'b>, data: RecordData) {
@@ -15,9 +15,10 @@ use api_utils::cglue;
 use blog_os_vfs::api::{file::cglue_file::*, path::PathBuf};
 use log::{debug, info};
 use qemu_common::QemuExitCode;
-use spin::{Lazy, lock_api::RwLock};
+use spin::Lazy;
 
 use crate::{
+    multitask::{lock::SpinRwLock, wait::WaitQueue},
     process::{
         OpenFile, load,
         stdio::{StdIn, StdInData, stderr, stdout},
@@ -25,6 +26,7 @@ use crate::{
     setup::KERNEL_INFO,
 };
 
+pub mod acpi;
 pub mod allocator;
 pub mod config;
 pub mod dwarf;
@@ -37,17 +39,22 @@ pub mod fs;
 pub mod gdt;
 pub mod interrupts;
 pub mod io;
+pub mod lockdep;
 pub mod memory;
 pub mod multitask;
 pub mod priviledge;
 pub mod process;
 pub mod rand;
 pub mod setup;
+pub mod slab;
+pub mod smp;
 pub mod stack;
 pub mod unwind;
+pub mod watchdog;
 
-static STDIN: Lazy<Arc<RwLock<StdInData>>> =
-    Lazy::new(|| Arc::new(RwLock::new(StdInData::default())));
+static STDIN: Lazy<Arc<SpinRwLock<StdInData>>> =
+    Lazy::new(|| Arc::new(StdInData::default().into_lock()));
+static STDIN_READABLE: WaitQueue = WaitQueue::unbounded();
 
 pub fn kernel_main() -> ! {
     // let addresses = [
@@ -136,21 +143,18 @@ pub fn kernel_main() -> ! {
 
     let p = load(&PathBuf::parse("/init")).unwrap();
 
-    p.files()
-        .write()
-        .insert(Arc::new(RwLock::new(OpenFile::new_no_inode(
-            cglue::trait_obj!(StdIn::new(STDIN.clone()) as File),
-        ))));
-    p.files()
-        .write()
-        .insert(Arc::new(RwLock::new(OpenFile::new_no_inode(
-            cglue::trait_obj!(stdout() as File),
-        ))));
-    p.files()
-        .write()
-        .insert(Arc::new(RwLock::new(OpenFile::new_no_inode(
-            cglue::trait_obj!(stderr() as File),
-        ))));
+    p.files().write().insert(Arc::new(
+        OpenFile::new_no_inode(cglue::trait_obj!(
+            StdIn::new(STDIN.clone(), &STDIN_READABLE) as File
+        ))
+        .into_lock(),
+    ));
+    p.files().write().insert(Arc::new(
+        OpenFile::new_no_inode(cglue::trait_obj!(stdout() as File)).into_lock(),
+    ));
+    p.files().write().insert(Arc::new(
+        OpenFile::new_no_inode(cglue::trait_obj!(stderr() as File)).into_lock(),
+    ));
 
     p.start();
 
@@ -0,0 +1,634 @@
+//! Lock dependency checker, compiled in with the `lockdep` feature.
+//!
+//! Locks are checked by class: every lock created at the same place in the code, declared with
+//! [`lock_class!`](crate::lock_class), shares one. The checker keeps the locks every task holds,
+//! and records an edge from each of their classes to the class of every lock taken afterwards.
+//! Waiting for a lock whose class reaches one already held means two paths take the same locks
+//! in opposite orders, and is reported as a possible deadlock. Switching away from a task that
+//! still holds a spinning lock is reported too. Every report comes with a backtrace, and each
+//! pair of classes is only reported once.
+//!
+//! Every kernel lock is tracked, except the checker's own and the state lock inside a
+//! [`ReentrantRawMutex`](crate::multitask::lock::ReentrantRawMutex), which is only held around
+//! updating the owner. Locks that generic code creates itself, like the heap's or the ramfs',
+//! are [`Fixed`] to the class of their type.
+//!
+//! Without the feature, the hooks do nothing and [`Tracked`] is just the lock it wraps.
+
+use core::marker::PhantomData;
+
+use lock_api::{RawMutex, RawRwLock};
+
+/// Declares the lock class of the locks created where it's expanded, as a `&'static Class`.
+#[macro_export]
+macro_rules! lock_class {
+    () => {{
+        static CLASS: $crate::lockdep::Class =
+            $crate::lockdep::Class::new(concat!(file!(), ":", line!()));
+        &CLASS
+    }};
+}
+
+/// Locks of one class a task can hold at once, see [`Class::nested`]
+#[cfg(feature = "lockdep")]
+const NESTING: usize = 2;
+
+/// A class of locks, checked as one.
+#[derive(Debug)]
+pub struct Class {
+    /// Where the class was declared
+    name: &'static str,
+    /// One byte per nesting level, so that each has its own address to key it
+    #[cfg(feature = "lockdep")]
+    levels: [u8; NESTING],
+}
+
+impl Class {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            #[cfg(feature = "lockdep")]
+            levels: [0; NESTING],
+        }
+    }
+
+    /// The key of the locks of this class.
+    pub const fn key(&'static self) -> Key {
+        Key {
+            class: self,
+            level: 0,
+        }
+    }
+
+    /// The key of a lock of this class taken while another one is held, as when switching
+    /// between two tasks.
+    pub const fn nested(&'static self) -> Key {
+        Key {
+            class: self,
+            level: 1,
+        }
+    }
+}
+
+/// A lock class at a nesting level.
+#[derive(Debug, Clone, Copy)]
+pub struct Key {
+    class: &'static Class,
+    level: usize,
+}
+
+impl Key {
+    #[cfg(feature = "lockdep")]
+    fn id(self) -> usize {
+        core::ptr::from_ref(&self.class.levels[self.level]).addr()
+    }
+
+    #[cfg(feature = "lockdep")]
+    fn same_class(self, other: Self) -> bool {
+        core::ptr::eq(self.class, other.class)
+    }
+}
+
+impl core::fmt::Display for Key {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.level {
+            0 => f.write_str(self.class.name),
+            level => write!(f, "{} (nested {level})", self.class.name),
+        }
+    }
+}
+
+/// How a lock is being taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// Spinning until the lock is free
+    Spin,
+    /// Sleeping until the lock is free, so it may be held across a task switch
+    Sleep,
+    /// Succeeded without waiting, so it can't take part in a deadlock
+    Try,
+    /// Shared with other readers, only waiting for a writer
+    Shared,
+}
+
+impl Kind {
+    /// Whether taking the lock this way can wait for another holder.
+    #[cfg(feature = "lockdep")]
+    const fn waits(self) -> bool {
+        matches!(self, Self::Spin | Self::Sleep | Self::Shared)
+    }
+}
+
+/// Locks held by a task, stored in its control block.
+#[derive(Debug, Default)]
+pub struct HeldLocks {
+    #[cfg(feature = "lockdep")]
+    inner: spin::Mutex<imp::Stack>,
+}
+
+impl HeldLocks {
+    pub const fn new() -> Self {
+        Self {
+            #[cfg(feature = "lockdep")]
+            inner: spin::Mutex::new(imp::Stack::new()),
+        }
+    }
+}
+
+/// Checks the order in which the running task is about to take a lock of `key`, before waiting
+/// for it.
+#[inline]
+pub fn check(key: Key, kind: Kind) {
+    #[cfg(feature = "lockdep")]
+    imp::check(key, kind);
+    #[cfg(not(feature = "lockdep"))]
+    let _ = (key, kind);
+}
+
+/// Records that the running task took a lock of `key`.
+#[inline]
+pub fn acquire(key: Key, kind: Kind) {
+    #[cfg(feature = "lockdep")]
+    imp::acquire(key, kind);
+    #[cfg(not(feature = "lockdep"))]
+    let _ = (key, kind);
+}
+
+/// Records that the running task released a lock of the class of `key`, at any nesting level.
+#[inline]
+pub fn release(key: Key) {
+    #[cfg(feature = "lockdep")]
+    imp::release(key);
+    #[cfg(not(feature = "lockdep"))]
+    let _ = key;
+}
+
+/// Checks the locks `current` holds before switching to the task holding `next`.
+///
+/// Interrupts must be disabled until the switch.
+#[inline]
+pub fn switch_to(current: &HeldLocks, next: &HeldLocks) {
+    #[cfg(feature = "lockdep")]
+    imp::switch_to(current, next);
+    #[cfg(not(feature = "lockdep"))]
+    let _ = (current, next);
+}
+
+/// A raw lock whose acquisitions are checked by the lock dependency checker.
+#[derive(Debug)]
+pub struct Tracked<R> {
+    inner: R,
+    class: &'static Class,
+}
+
+impl<R> Tracked<R> {
+    pub const fn new(inner: R, class: &'static Class) -> Self {
+        Self { inner, class }
+    }
+}
+
+impl<R: RawMutex> Tracked<R> {
+    /// Takes the lock while another one of its class is held, see [`Class::nested`].
+    pub fn lock_nested(&self) {
+        check(self.class.nested(), Kind::Spin);
+        self.inner.lock();
+        acquire(self.class.nested(), Kind::Spin);
+    }
+}
+
+unsafe impl<R: RawMutex> RawMutex for Tracked<R> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self =
+        panic!("tracked locks have a class, create them with `spin_mutex` or `spin_rwlock`");
+
+    type GuardMarker = R::GuardMarker;
+
+    fn lock(&self) {
+        check(self.class.key(), Kind::Spin);
+        self.inner.lock();
+        acquire(self.class.key(), Kind::Spin);
+    }
+
+    fn try_lock(&self) -> bool {
+        let locked = self.inner.try_lock();
+        if locked {
+            acquire(self.class.key(), Kind::Try);
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        release(self.class.key());
+        unsafe { self.inner.unlock() }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+}
+
+unsafe impl<R: RawRwLock> RawRwLock for Tracked<R> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self =
+        panic!("tracked locks have a class, create them with `spin_mutex` or `spin_rwlock`");
+
+    type GuardMarker = R::GuardMarker;
+
+    fn lock_shared(&self) {
+        check(self.class.key(), Kind::Shared);
+        self.inner.lock_shared();
+        acquire(self.class.key(), Kind::Shared);
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        let locked = self.inner.try_lock_shared();
+        if locked {
+            acquire(self.class.key(), Kind::Try);
+        }
+        locked
+    }
+
+    unsafe fn unlock_shared(&self) {
+        release(self.class.key());
+        unsafe { self.inner.unlock_shared() }
+    }
+
+    fn lock_exclusive(&self) {
+        check(self.class.key(), Kind::Spin);
+        self.inner.lock_exclusive();
+        acquire(self.class.key(), Kind::Spin);
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        let locked = self.inner.try_lock_exclusive();
+        if locked {
+            acquire(self.class.key(), Kind::Try);
+        }
+        locked
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        release(self.class.key());
+        unsafe { self.inner.unlock_exclusive() }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    fn is_locked_exclusive(&self) -> bool {
+        self.inner.is_locked_exclusive()
+    }
+}
+
+/// Names the class of the locks of a [`Fixed`] type.
+pub trait FixedClass {
+    const CLASS: &'static Class;
+}
+
+/// A [`Tracked`] lock whose class `C` comes with its type, for the locks generic code creates
+/// through `INIT`, which can't be given a class.
+#[derive(Debug)]
+pub struct Fixed<R, C> {
+    inner: Tracked<R>,
+    class: PhantomData<C>,
+}
+
+impl<R: Default, C: FixedClass> Default for Fixed<R, C> {
+    fn default() -> Self {
+        Self {
+            inner: Tracked::new(R::default(), C::CLASS),
+            class: PhantomData,
+        }
+    }
+}
+
+unsafe impl<R: RawMutex, C: FixedClass> RawMutex for Fixed<R, C> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        inner: Tracked::new(R::INIT, C::CLASS),
+        class: PhantomData,
+    };
+
+    type GuardMarker = R::GuardMarker;
+
+    fn lock(&self) {
+        self.inner.lock();
+    }
+
+    fn try_lock(&self) -> bool {
+        self.inner.try_lock()
+    }
+
+    unsafe fn unlock(&self) {
+        unsafe { self.inner.unlock() }
+    }
+
+    fn is_locked(&self) -> bool {
+        RawMutex::is_locked(&self.inner)
+    }
+}
+
+unsafe impl<R: RawRwLock, C: FixedClass> RawRwLock for Fixed<R, C> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self {
+        inner: Tracked::new(R::INIT, C::CLASS),
+        class: PhantomData,
+    };
+
+    type GuardMarker = R::GuardMarker;
+
+    fn lock_shared(&self) {
+        self.inner.lock_shared();
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.inner.try_lock_shared()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        unsafe { self.inner.unlock_shared() }
+    }
+
+    fn lock_exclusive(&self) {
+        self.inner.lock_exclusive();
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.inner.try_lock_exclusive()
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        unsafe { self.inner.unlock_exclusive() }
+    }
+
+    fn is_locked(&self) -> bool {
+        RawRwLock::is_locked(&self.inner)
+    }
+
+    fn is_locked_exclusive(&self) -> bool {
+        self.inner.is_locked_exclusive()
+    }
+}
+
+#[cfg(feature = "lockdep")]
+mod imp {
+    use alloc::{
+        collections::{BTreeMap, BTreeSet},
+        vec::Vec,
+    };
+    use core::{
+        array, ptr,
+        sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+    };
+
+    use log::error;
+    use spin::Mutex;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    use super::{HeldLocks, Key, Kind};
+    use crate::{smp, unwind};
+
+    /// Locks a task can hold at once before the checker gives up on it
+    const MAX_HELD: usize = 32;
+
+    #[derive(Debug, Clone, Copy)]
+    struct Held {
+        key: Key,
+        kind: Kind,
+    }
+
+    #[derive(Debug)]
+    pub(super) struct Stack {
+        held: [Option<Held>; MAX_HELD],
+        len: usize,
+        overflowed: bool,
+    }
+
+    impl Stack {
+        pub(super) const fn new() -> Self {
+            Self {
+                held: [None; MAX_HELD],
+                len: 0,
+                overflowed: false,
+            }
+        }
+
+        fn iter(&self) -> impl Iterator<Item = Held> + '_ {
+            self.held[..self.len].iter().flatten().copied()
+        }
+    }
+
+    impl Default for Stack {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[derive(Default)]
+    struct Graph {
+        /// Keys of the locks taken while holding one of the key
+        after: BTreeMap<usize, BTreeSet<usize>>,
+        /// Pairs of keys already reported
+        reported: BTreeSet<(usize, usize)>,
+    }
+
+    impl Graph {
+        /// Whether `to` was taken after `from`, directly or through other locks.
+        fn reaches(&self, from: usize, to: usize) -> bool {
+            let mut seen = BTreeSet::new();
+            let mut stack = Vec::from([from]);
+            while let Some(key) = stack.pop() {
+                if key == to {
+                    return true;
+                }
+                if seen.insert(key)
+                    && let Some(next) = self.after.get(&key)
+                {
+                    stack.extend(next.iter().copied());
+                }
+            }
+            false
+        }
+    }
+
+    static GRAPH: Mutex<Option<Graph>> = Mutex::new(None);
+
+    /// Held locks of the task running on each CPU
+    static CURRENT: [AtomicPtr<HeldLocks>; smp::MAX_CPUS] =
+        [const { AtomicPtr::new(ptr::null_mut()) }; smp::MAX_CPUS];
+    /// Stands for the task a CPU runs before its first switch
+    static BOOT: [HeldLocks; smp::MAX_CPUS] = [const { HeldLocks::new() }; smp::MAX_CPUS];
+    /// Set while the checker runs on a CPU, so that the locks it takes itself, through the
+    /// allocator, the logger or the unwinder, aren't checked
+    static BUSY: [AtomicBool; smp::MAX_CPUS] = [const { AtomicBool::new(false) }; smp::MAX_CPUS];
+
+    /// Runs `f` with the held locks of the running task, unless the checker is already running.
+    fn enter<R>(f: impl FnOnce(&HeldLocks) -> R) -> Option<R> {
+        without_interrupts(|| {
+            let cpu = smp::cpu_index();
+            if BUSY[cpu].swap(true, Ordering::Acquire) {
+                return None;
+            }
+            let held = CURRENT[cpu].load(Ordering::Acquire);
+            // Safety: the pointer comes from the control block of the running task, which
+            // outlives its run
+            let held = unsafe { held.as_ref() }.unwrap_or(&BOOT[cpu]);
+            let result = f(held);
+            BUSY[cpu].store(false, Ordering::Release);
+            Some(result)
+        })
+    }
+
+    pub(super) fn check(key: Key, kind: Kind) {
+        if !kind.waits() {
+            return;
+        }
+        enter(|held| {
+            let mut inversions = Vec::new();
+            {
+                let stack = held.inner.lock();
+                let mut guard = GRAPH.lock();
+                let graph = guard.get_or_insert_with(Graph::default);
+                for before in stack.iter() {
+                    let (from, to) = (before.key.id(), key.id());
+                    if from == to {
+                        // Readers don't wait for each other
+                        let shared = before.kind == Kind::Shared && kind == Kind::Shared;
+                        if before.kind.waits() && !shared && graph.reported.insert((from, to)) {
+                            inversions.push((key, key));
+                        }
+                    } else if graph.reaches(to, from) {
+                        if graph.reported.insert((from, to)) {
+                            inversions.push((before.key, key));
+                        }
+                    } else {
+                        graph.after.entry(from).or_default().insert(to);
+                    }
+                }
+            }
+
+            for (before, after) in inversions {
+                if before.id() == after.id() {
+                    error!("lockdep: taking {after} again while holding it");
+                } else {
+                    error!(
+                        "lockdep: possible deadlock, taking {after} while holding {before}, \
+                         which was taken while holding it before"
+                    );
+                }
+                unwind::backtrace();
+            }
+        });
+    }
+
+    pub(super) fn acquire(key: Key, kind: Kind) {
+        enter(|held| {
+            let mut stack = held.inner.lock();
+            if stack.len < MAX_HELD {
+                let len = stack.len;
+                stack.held[len] = Some(Held { key, kind });
+                stack.len += 1;
+            } else if !stack.overflowed {
+                stack.overflowed = true;
+                drop(stack);
+                error!("lockdep: more than {MAX_HELD} locks held, not tracking {key}");
+            }
+        });
+    }
+
+    pub(super) fn release(key: Key) {
+        enter(|held| {
+            let mut stack = held.inner.lock();
+            let len = stack.len;
+            if let Some(index) = stack.held[..len]
+                .iter()
+                .rposition(|held| held.is_some_and(|held| held.key.same_class(key)))
+            {
+                stack.held.copy_within(index + 1..len, index);
+                stack.held[len - 1] = None;
+                stack.len -= 1;
+            }
+        });
+    }
+
+    /// Whether taking a lock of `taken` while holding one of `held` was reported.
+    #[cfg(test)]
+    pub(super) fn reported(held: Key, taken: Key) -> bool {
+        GRAPH
+            .lock()
+            .as_ref()
+            .is_some_and(|graph| graph.reported.contains(&(held.id(), taken.id())))
+    }
+
+    pub(super) fn switch_to(current: &HeldLocks, next: &HeldLocks) {
+        enter(|running| {
+            // Before the first switch, the boot stand-in holds the locks
+            let current = if CURRENT[smp::cpu_index()].load(Ordering::Acquire).is_null() {
+                running
+            } else {
+                current
+            };
+            let spinning: [Option<Key>; MAX_HELD] = {
+                let stack = current.inner.lock();
+                let mut keys = stack.iter().filter(|held| held.kind != Kind::Sleep);
+                array::from_fn(|_| keys.next().map(|held| held.key))
+            };
+            if spinning[0].is_some() {
+                error!("lockdep: switching tasks while holding:");
+                for key in spinning.iter().flatten() {
+                    error!("lockdep:     {key}");
+                }
+                unwind::backtrace();
+            }
+        });
+        CURRENT[smp::cpu_index()].store(ptr::from_ref(next).cast_mut(), Ordering::Release);
+    }
+}
+
+#[cfg(all(test, feature = "lockdep"))]
+mod test {
+    use super::imp;
+    use crate::multitask::lock::{spin_mutex, spin_rwlock};
+
+    #[test_case]
+    fn read_after_write_inversion_is_reported() {
+        let (first_class, second_class) = (lock_class!(), lock_class!());
+        let first = spin_rwlock(first_class, ());
+        let second = spin_mutex(second_class, ());
+
+        // Records first -> second
+        drop((first.write(), second.lock()));
+        assert!(!imp::reported(second_class.key(), first_class.key()));
+
+        // A reader waits for the writer holding first while waiting for second
+        let guard = second.lock();
+        drop(first.read());
+        drop(guard);
+        assert!(imp::reported(second_class.key(), first_class.key()));
+    }
+
+    #[test_case]
+    fn write_after_read_inversion_is_reported() {
+        let (first_class, second_class) = (lock_class!(), lock_class!());
+        let first = spin_rwlock(first_class, ());
+        let second = spin_mutex(second_class, ());
+
+        // Records first -> second, though first was only read
+        drop((first.read(), second.lock()));
+
+        let guard = second.lock();
+        drop(first.write());
+        drop(guard);
+        assert!(imp::reported(second_class.key(), first_class.key()));
+    }
+
+    #[test_case]
+    fn recursive_reads_are_not_reported() {
+        let class = lock_class!();
+        let lock = spin_rwlock(class, ());
+
+        drop((lock.read(), lock.read()));
+        assert!(!imp::reported(class.key(), class.key()));
+    }
+}
@@ -1,21 +1,23 @@
-use core::ops::Range;
-use core::slice::Iter;
-
-use alloc::collections::vec_deque::VecDeque;
-use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
+use blog_os_buddy::{BuddyAllocator, HUGE_ORDER, MAX_ORDER};
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 use humansize::DECIMAL;
-use log::{debug, warn};
+use log::{debug, info};
 use x86_64::PhysAddr;
 use x86_64::{VirtAddr, structures::paging::PageTable};
 
 use x86_64::structures::paging::{
-    FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PhysFrame, Size4KiB,
+    FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PhysFrame, Size2MiB, Size4KiB,
 };
 
 pub mod multi_l4_paging;
 // pub mod pages;
 pub mod free_tables;
+#[cfg(feature = "kpti")]
+pub mod kpti;
+pub mod mapping;
 pub mod range_alloc;
+pub mod user;
+pub mod vma;
 
 /// Initialize a new OffsetPageTable.
 ///
@@ -51,128 +53,157 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     unsafe { &mut *page_table_ptr }
 }
 
-type UnusedFramesIter = core::iter::Map<
-    core::iter::FlatMap<
-        core::iter::Map<
-            core::iter::Filter<
-                core::slice::Iter<'static, MemoryRegion>,
-                fn(&&MemoryRegion) -> bool,
-            >,
-            fn(&MemoryRegion) -> Range<u64>,
-        >,
-        core::iter::StepBy<Range<u64>>,
-        fn(Range<u64>) -> core::iter::StepBy<Range<u64>>,
-    >,
-    fn(u64) -> PhysFrame,
->;
-
-pub struct BootInfoFrameAllocator {
-    unused_unalloc: UnusedFramesIter,
-    // Only initialized once the heap is up
-    dealloc: Option<VecDeque<PhysFrame>>,
-    free_frames: usize,
+/// Turns on the paging protections the kernel relies on for userspace.
+///
+/// NXE makes `NO_EXECUTE` pages fault on instruction fetches. SMEP and SMAP, where the CPU has
+/// them, make the kernel fault on executing or touching user pages outside of the
+/// [`user`] copy helpers. The APs copy these registers from the BSP, so this runs before they
+/// are started.
+pub fn enable_protection() {
+    use core::arch::x86_64::{__cpuid, __cpuid_count};
+    use x86_64::registers::{
+        control::{Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    };
+
+    unsafe { Efer::update(|efer| efer.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+
+    let features = if __cpuid(0).eax >= 7 {
+        __cpuid_count(7, 0).ebx
+    } else {
+        0
+    };
+    let smep = features & (1 << 7) != 0;
+    let smap = features & (1 << 20) != 0;
+
+    let mut cr4 = Cr4Flags::empty();
+    cr4.set(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION, smep);
+    cr4.set(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION, smap);
+    unsafe { Cr4::update(|flags| flags.insert(cr4)) };
+    user::set_smap(smap);
+
+    info!("Paging protection: NXE, SMEP: {smep}, SMAP: {smap}");
+}
+
+const LOG_RATE: usize = 100;
+
+/// Physical frame allocator, a [buddy allocator](blog_os_buddy) seeded with the usable memory
+/// regions.
+pub struct BuddyFrameAllocator {
+    buddy: BuddyAllocator,
     log_count: usize,
 }
 
-impl BootInfoFrameAllocator {
+impl BuddyFrameAllocator {
     /// Create a FrameAllocator from the passed memory map.
     ///
     /// # Safety
     /// This function is unsafe because the caller must guarantee that the passed
     /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
-        // get usable regions from memory map
-        let regions: Iter<'static, MemoryRegion> = memory_map.iter();
-        let usable_regions =
-            regions.filter::<fn(&&MemoryRegion) -> bool>(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges =
-            usable_regions.map::<_, fn(&MemoryRegion) -> Range<u64>>(|r| r.start..r.end);
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges
-            .flat_map::<_, fn(Range<u64>) -> core::iter::StepBy<Range<u64>>>(|r| {
-                r.step_by(Size4KiB::SIZE as usize)
-            });
-        // create `PhysFrame` types from the start addresses
-        let unused_unalloc: UnusedFramesIter =
-            frame_addresses.map::<_, fn(u64) -> PhysFrame>(|addr| {
-                PhysFrame::containing_address(PhysAddr::new(addr))
-            });
-        let free_frames = unused_unalloc.clone().count();
+    /// as `USABLE` in it are really unused, and mapped at `physical_memory_offset`.
+    pub unsafe fn init(memory_map: &MemoryRegions, physical_memory_offset: VirtAddr) -> Self {
+        let usable = memory_map
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .map(|r| r.start..r.end);
+        let buddy = unsafe { BuddyAllocator::new(usable, physical_memory_offset.as_u64()) }
+            .expect("a usable region large enough for the frame allocator");
+        info!(
+            "Frame allocator: {} frames, state at {:x?}",
+            buddy.total_frames(),
+            buddy.state_region()
+        );
 
         Self {
-            unused_unalloc,
-            dealloc: None,
-            free_frames,
+            buddy,
             log_count: 0,
         }
     }
-}
 
-impl BootInfoFrameAllocator {
-    // /// Returns an iterator over the usable frames specified in the memory map.
-    // fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-    //     // get usable regions from memory map
-    //     let regions: Iter<'static, MemoryRegion> = self.memory_map.iter();
-    //     let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-    //     // map each region to its address range
-    //     let addr_ranges = usable_regions.map(|r| r.start..r.end);
-    //     // transform to an iterator of frame start addresses
-    //     let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-    //     // create `PhysFrame` types from the start addresses
-    //     frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    // }
-
-    /// Called when the heap is usable
-    pub fn heap_init(&mut self) {
-        self.dealloc = Some(VecDeque::new())
+    /// Allocates `2^order` physically contiguous frames, aligned on their size.
+    pub fn allocate_contiguous(&mut self, order: usize) -> Option<PhysFrame> {
+        let addr = self.buddy.allocate(order)?;
+        self.log("Alloc");
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
     }
-}
 
-const LOG_RATE: usize = 100;
+    /// Allocates `2^order` physically contiguous frames ending at or below `limit`.
+    pub fn allocate_contiguous_below(
+        &mut self,
+        order: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrame> {
+        let addr = self.buddy.allocate_below(order, limit.as_u64())?;
+        self.log("Alloc");
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Frees frames taken with [`Self::allocate_contiguous`].
+    ///
+    /// # Safety
+    /// `start` must come from `allocate_contiguous(order)` and not be used anymore.
+    pub unsafe fn deallocate_contiguous(&mut self, start: PhysFrame, order: usize) {
+        unsafe { self.buddy.deallocate(start.start_address().as_u64(), order) };
+        self.log("Dealloc");
+    }
+
+    /// Hands a frame the memory map didn't list as usable over to the allocator. Returns `false`,
+    /// leaving it unused, if it is outside of the frames the allocator keeps state for.
+    ///
+    /// # Safety
+    /// The frame must be unused and mapped at the physical memory offset.
+    pub unsafe fn add_frame(&mut self, frame: PhysFrame) -> bool {
+        let start = frame.start_address().as_u64();
+        unsafe { self.buddy.add_region(start..start + Size4KiB::SIZE) }
+    }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    pub const fn free_frames(&self) -> u64 {
+        self.buddy.free_frames()
+    }
+
+    pub const fn total_frames(&self) -> u64 {
+        self.buddy.total_frames()
+    }
+
+    /// Free blocks of each order.
+    pub const fn free_blocks(&self) -> &[usize; MAX_ORDER + 1] {
+        self.buddy.free_blocks()
+    }
+
+    fn log(&mut self, action: &str) {
+        if self.log_count == 0 {
+            let free = self.buddy.free_frames();
+            debug!(
+                "{action} frame. Free frames: {free} frames ({})",
+                humansize::SizeFormatter::new(free * Size4KiB::SIZE, DECIMAL)
+            )
+        }
+        self.log_count = (self.log_count + 1) % LOG_RATE;
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        self.dealloc
-            .as_mut()
-            .and_then(VecDeque::pop_front)
-            .or_else(|| self.unused_unalloc.next())
-            .inspect(|_| {
-                self.free_frames -= 1;
-                if self.log_count == 0 {
-                    debug!(
-                        "Alloc frame. Free frames: {} frames ({})",
-                        self.free_frames,
-                        humansize::SizeFormatter::new(
-                            self.free_frames as u64 * Size4KiB::SIZE,
-                            DECIMAL
-                        )
-                    )
-                }
-                self.log_count = (self.log_count + 1) % LOG_RATE;
-            })
+        self.allocate_contiguous(0)
     }
 }
 
-impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
     unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
-        if let Some(dealloc) = self.dealloc.as_mut() {
-            dealloc.push_back(frame);
-            self.free_frames += 1;
-            if self.log_count == 0 {
-                debug!(
-                    "Dealloc frame. Free frames: {} frames ({})",
-                    self.free_frames,
-                    humansize::SizeFormatter::new(
-                        self.free_frames as u64 * Size4KiB::SIZE,
-                        DECIMAL
-                    )
-                )
-            }
-            self.log_count = (self.log_count + 1) % LOG_RATE;
-        } else {
-            warn!("Dealloc without heap, lost frame");
-        }
+        unsafe { self.deallocate_contiguous(frame, 0) };
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate_contiguous(HUGE_ORDER)?;
+        Some(PhysFrame::from_start_address(frame.start_address()).expect("2 MiB aligned block"))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        unsafe { self.deallocate_contiguous(start, HUGE_ORDER) };
     }
 }
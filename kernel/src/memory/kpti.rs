@@ -0,0 +1,188 @@
+//! Kernel page-table isolation, with the `kpti` feature.
+//!
+//! Each process L4 is followed by its user twin in the next frame, which CR3 holds while the
+//! process runs in ring 3. The twin gets each user entry of the process L4 once a mapping creates
+//! it, sharing the tables below, while its kernel half only maps what the CPU and the entry code
+//! touch before switching back: the code of the `kpti_entry` section, the GDTs, TSSs and IDTs,
+//! and the top of the stacks the CPU switches to on an interrupt. Entering the kernel from
+//! userspace clears bit 12 of CR3, returning to it sets the bit again, see
+//! [`crate::interrupts::stub`].
+
+use core::{mem::size_of_val, ops::Range};
+
+use log::info;
+use spin::Once;
+use x86_64::{
+    VirtAddr,
+    structures::{
+        gdt::GlobalDescriptorTable,
+        idt::InterruptDescriptorTable,
+        paging::{
+            FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+            PhysFrame, Size4KiB, Translate,
+            mapper::{MapToError, TranslateResult},
+        },
+        tss::TaskStateSegment,
+    },
+};
+
+use crate::{
+    gdt::{self, DOUBLE_FAULT_IST_INDEX},
+    interrupts::{self, stub},
+    setup::{AllocKernelInfo, KERNEL_INFO},
+};
+
+/// L4 table whose kernel half every user twin shares
+static ENTRY_P4: Once<PhysFrame> = Once::new();
+
+/// Bytes mapped below the top of each stack the CPU switches to
+const ENTRY_STACK_BYTES: u64 = 4096;
+
+/// Builds the entry tables with the entry code and the tables of the bootstrap processor.
+///
+/// Runs before any process page table is created and before the APs are started.
+pub fn init() {
+    let kernel_info = KERNEL_INFO.get().unwrap();
+    let mut lock = kernel_info.alloc_kinf.lock();
+    let mem = &mut *lock;
+    mem.page_table
+        .move_kernel_p4_to_even_frame(&mut mem.frame_allocator);
+
+    let frame = mem
+        .frame_allocator
+        .allocate_frame()
+        .expect("A frame for the entry l4 table");
+    let table = (kernel_info.physical_memory_offset + frame.start_address().as_u64())
+        .as_mut_ptr::<PageTable>();
+    unsafe { table.write(PageTable::new()) };
+    ENTRY_P4.call_once(|| frame);
+
+    let code = stub::kpti_entry_section();
+    map_range(mem, code.clone(), PageTableFlags::PRESENT);
+    drop(lock);
+
+    map_cpu_tables(&gdt::bsp_gdt().0, gdt::bsp_tss(), interrupts::idt());
+    info!(
+        "KPTI: user page tables map the entry code at {:p}..{:p}",
+        code.start, code.end
+    );
+}
+
+/// Maps the descriptor tables and the entry stacks of a CPU in the user twins.
+pub fn map_cpu_tables(
+    gdt: &GlobalDescriptorTable,
+    tss: &TaskStateSegment,
+    idt: &InterruptDescriptorTable,
+) {
+    let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
+    let mem = &mut *lock;
+    let read_only = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    let writable = read_only | PageTableFlags::WRITABLE;
+
+    map_range(mem, span(idt), read_only);
+    // The CPU sets the busy flag of the TSS descriptor
+    map_range(mem, span(gdt), writable);
+    map_range(mem, span(tss), read_only);
+    for top in [
+        tss.privilege_stack_table[0],
+        tss.interrupt_stack_table[usize::from(DOUBLE_FAULT_IST_INDEX)],
+    ] {
+        map_range(mem, top - ENTRY_STACK_BYTES..top, writable);
+    }
+}
+
+/// Addresses taken by `object`.
+fn span<T: ?Sized>(object: &T) -> Range<VirtAddr> {
+    let start = VirtAddr::from_ptr(object);
+    start..start + size_of_val(object) as u64
+}
+
+/// Maps the kernel pages holding `range` in the entry tables, to the same frames.
+fn map_range(mem: &mut AllocKernelInfo, range: Range<VirtAddr>, flags: PageTableFlags) {
+    let offset = KERNEL_INFO.get().unwrap().physical_memory_offset;
+    let entry_p4 = *ENTRY_P4.get().expect("KPTI to be initialized");
+    let table = unsafe {
+        (offset + entry_p4.start_address().as_u64())
+            .as_mut_ptr::<PageTable>()
+            .as_mut()
+    }
+    .unwrap();
+    let mut entry_tables = unsafe { OffsetPageTable::new(table, offset) };
+
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(range.start),
+        Page::containing_address(range.end - 1u64),
+    );
+    for page in pages {
+        let addr = mem
+            .page_table
+            .translate_addr(page.start_address())
+            .expect("Mapped kernel page");
+        let frame = PhysFrame::containing_address(addr);
+        let result = unsafe {
+            entry_tables.map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                &mut mem.frame_allocator,
+            )
+        };
+        match result {
+            // The user twins are never loaded in kernel mode, no flush needed
+            Ok(flush) => flush.ignore(),
+            // Tables sharing a page, which gets the access of both
+            Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {
+                let TranslateResult::Mapped { flags: old, .. } =
+                    entry_tables.translate(page.start_address())
+                else {
+                    unreachable!("{page:?} is mapped");
+                };
+                let mut merged = old | flags;
+                merged.set(
+                    PageTableFlags::NO_EXECUTE,
+                    old.contains(PageTableFlags::NO_EXECUTE)
+                        && flags.contains(PageTableFlags::NO_EXECUTE),
+                );
+                unsafe { entry_tables.update_flags(page, merged) }
+                    .expect("Mapped page")
+                    .ignore();
+            }
+            Err(e) => panic!("Failed to map {page:?} in the entry tables: {e:?}"),
+        }
+    }
+
+    mem.page_table.refresh_user_twins();
+}
+
+/// Gives the new process L4 at `frame` its user twin in the next frame.
+///
+/// The user half starts out empty in both, [`PageTables`](super::multi_l4_paging::PageTables)
+/// copies its entries into the twin as mappings create them.
+pub(super) fn init_user_twin(frame: PhysFrame, kernel_start: VirtAddr, offset: VirtAddr) {
+    let twin = unsafe {
+        (offset + frame.start_address().as_u64() + Size4KiB::SIZE)
+            .as_mut_ptr::<PageTable>()
+            .as_mut()
+    }
+    .unwrap();
+    *twin = PageTable::new();
+    share_entry_tables(twin, kernel_start, offset);
+}
+
+/// Copies the kernel half of the entry tables into the user twin `twin`.
+pub(super) fn share_entry_tables(twin: &mut PageTable, kernel_start: VirtAddr, offset: VirtAddr) {
+    let Some(entry_p4) = ENTRY_P4.get() else {
+        return;
+    };
+    let entry_p4 = unsafe {
+        (offset + entry_p4.start_address().as_u64())
+            .as_ptr::<PageTable>()
+            .as_ref()
+    }
+    .unwrap();
+    let kernel_entries = usize::from(kernel_start.p4_index());
+    for (twin_entry, entry) in twin.iter_mut().zip(entry_p4.iter()).skip(kernel_entries) {
+        twin_entry.clone_from(entry);
+    }
+}
@@ -0,0 +1,126 @@
+//! Mapping ranges of fresh memory, with 2 MiB pages where they fit.
+//!
+//! A 2 MiB page takes a single entry in a level 2 table instead of a whole level 1 table, and a
+//! single TLB entry instead of 512. Both its address and its frame must be aligned on 2 MiB, so a
+//! range gets huge pages for the aligned blocks it covers and 4 KiB pages at its edges, or once
+//! no free 2 MiB block of physical memory is left.
+
+use core::ops::Range;
+
+use x86_64::{
+    VirtAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+        Size2MiB, Size4KiB,
+        mapper::{MapToError, UnmapError},
+        page::PageRangeInclusive,
+    },
+};
+
+use crate::setup::AllocKernelInfo;
+
+/// A page mapped by [`map_range`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MappedPage {
+    Small(Page<Size4KiB>),
+    Huge(Page<Size2MiB>),
+}
+
+impl MappedPage {
+    /// The 4 KiB pages this page covers.
+    pub fn small_pages(self) -> PageRangeInclusive<Size4KiB> {
+        match self {
+            Self::Small(page) => Page::range_inclusive(page, page),
+            Self::Huge(page) => Page::range_inclusive(
+                Page::containing_address(page.start_address()),
+                Page::containing_address(page.start_address() + (Size2MiB::SIZE - 1)),
+            ),
+        }
+    }
+}
+
+/// Maps the pages of `range` to fresh frames with `flags`, calling `mapped` for each of them.
+///
+/// With `huge`, 2 MiB pages are used wherever they fit.
+pub fn map_range(
+    mem: &mut AllocKernelInfo,
+    range: Range<VirtAddr>,
+    flags: PageTableFlags,
+    huge: bool,
+    mut mapped: impl FnMut(MappedPage),
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut addr = range.start.align_down(Size4KiB::SIZE);
+    let end = range.end.align_up(Size4KiB::SIZE);
+    while addr < end {
+        if huge
+            && addr.is_aligned(Size2MiB::SIZE)
+            && end - addr >= Size2MiB::SIZE
+            && let Some(page) = map_huge(mem, Page::containing_address(addr), flags)?
+        {
+            mapped(MappedPage::Huge(page));
+            addr += Size2MiB::SIZE;
+            continue;
+        }
+
+        let page = Page::containing_address(addr);
+        let frame = mem
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        unsafe {
+            mem.page_table
+                .map_to(page, frame, flags, &mut mem.frame_allocator)?
+                .flush()
+        };
+        mapped(MappedPage::Small(page));
+        addr += Size4KiB::SIZE;
+    }
+    Ok(())
+}
+
+/// Maps `page` to a fresh 2 MiB frame, returning `None` if there is no such frame left.
+pub fn map_huge(
+    mem: &mut AllocKernelInfo,
+    page: Page<Size2MiB>,
+    flags: PageTableFlags,
+) -> Result<Option<Page<Size2MiB>>, MapToError<Size4KiB>> {
+    let Some(frame) = FrameAllocator::<Size2MiB>::allocate_frame(&mut mem.frame_allocator) else {
+        return Ok(None);
+    };
+    match unsafe {
+        mem.page_table
+            .map_to(page, frame, flags, &mut mem.frame_allocator)
+    } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(Some(page))
+        }
+        Err(e) => {
+            unsafe { mem.frame_allocator.deallocate_frame(frame) };
+            Err(match e {
+                MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+                MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+                MapToError::PageAlreadyMapped(frame) => MapToError::PageAlreadyMapped(
+                    PhysFrame::containing_address(frame.start_address()),
+                ),
+            })
+        }
+    }
+}
+
+/// Unmaps `page` and frees its frame.
+pub fn unmap(mem: &mut AllocKernelInfo, page: MappedPage) -> Result<(), UnmapError> {
+    match page {
+        MappedPage::Small(page) => {
+            let (frame, flush) = mem.page_table.unmap(page)?;
+            flush.flush();
+            unsafe { mem.frame_allocator.deallocate_frame(frame) };
+        }
+        MappedPage::Huge(page) => {
+            let (frame, flush) = mem.page_table.unmap(page)?;
+            flush.flush();
+            unsafe { mem.frame_allocator.deallocate_frame(frame) };
+        }
+    }
+    Ok(())
+}
@@ -4,14 +4,27 @@ use log::{debug, info, trace, warn};
 use x86_64::{
     VirtAddr,
     structures::paging::{
-        Mapper, OffsetPageTable, Page, PageTable, PageTableIndex, PhysFrame, Size4KiB, Translate,
-        mapper::CleanUp,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PageTableIndex, PhysFrame, Size2MiB, Size4KiB, Translate,
+        mapper::{
+            CleanUp, FlagUpdateError, MapToError, MapperFlush, MapperFlushAll, TranslateError,
+            UnmapError,
+        },
         page::PageRangeInclusive,
         page_table::{PageTableEntry, PageTableLevel},
     },
 };
 
-use crate::memory::free_tables::{FreeEntry, FreeTables};
+#[cfg(feature = "kpti")]
+use x86_64::structures::paging::PageSize;
+
+use crate::memory::{
+    BuddyFrameAllocator,
+    free_tables::{FreeEntry, FreeTables},
+};
+
+/// Order of the block holding a process L4: the table, followed by its user twin with KPTI
+const P4_ORDER: usize = if cfg!(feature = "kpti") { 1 } else { 0 };
 
 #[derive(Debug)]
 pub struct PageTableToken {
@@ -28,6 +41,8 @@ pub struct PageTables {
     current: OffsetPageTable<'static>,
     current_frame: PhysFrame,
     l4_tables: SmallBTreeMap<1, PhysFrame, PageTableInfo>,
+    /// The page table the kernel booted on, never released
+    kernel_frame: PhysFrame,
     kernel_start: VirtAddr,
 }
 
@@ -36,7 +51,10 @@ fn filter_entries<'a, 'b>(
     iter: impl Iterator<Item = &'a PageTableEntry>,
 ) -> impl Iterator<Item = (usize, &'a PageTableEntry, VirtAddr, &'a PageTable)> {
     iter.enumerate()
-        .filter(|(_, entry)| !entry.is_unused())
+        // Huge pages have no table below them
+        .filter(|(_, entry)| {
+            !entry.is_unused() && !entry.flags().contains(PageTableFlags::HUGE_PAGE)
+        })
         .map(|(idx, entry)| {
             let table_virt = current.phys_offset() + entry.addr().as_u64();
             (idx, entry, table_virt)
@@ -101,6 +119,7 @@ impl PageTables {
                 map
             },
             current_frame: phys_f,
+            kernel_frame: phys_f,
             current,
             kernel_start,
         }
@@ -190,42 +209,128 @@ impl PageTables {
             })
     }
 
-    pub fn set_current_page_table<A>(&mut self, frame_alloc: &mut A)
-    where
-        A: x86_64::structures::paging::FrameDeallocator<Size4KiB> + ?Sized,
-    {
+    /// Points the mapper at the page table loaded on the running CPU.
+    ///
+    /// Every CPU can run on a different page table, so this has to be done whenever the page
+    /// tables are locked.
+    pub fn sync_current_page_table(&mut self) {
         let (frame, _) = x86_64::registers::control::Cr3::read();
-        self.set_current_page_table_frame(&frame, frame_alloc);
+        if frame != self.current_frame {
+            self.set_current_page_table_frame(&frame);
+        }
     }
 
-    fn set_current_page_table_frame<A>(&mut self, frame: &PhysFrame, frame_alloc: &mut A)
+    fn set_current_page_table_frame(&mut self, frame: &PhysFrame) {
+        let old_frame = self.current_frame;
+        let addr = self
+            .l4_tables
+            .get(frame)
+            .expect("the CR3 page table to be registered");
+        self.current_frame = *frame;
+        info!(event = "frame_switch", subevent = "after_switch", old_frame:?, new_frame:? = frame; "Switching to page table with frame {frame:?}");
+        self.current = unsafe {
+            OffsetPageTable::<'static>::new(
+                addr.addr.as_mut_ptr::<PageTable>().as_mut().unwrap(),
+                self.current.phys_offset(),
+            )
+        };
+    }
+
+    /// The page table the kernel booted on, which kernel tasks run on.
+    pub const fn kernel_p4_frame(&self) -> PhysFrame {
+        self.kernel_frame
+    }
+
+    /// Tears the address space of the page table `frame` down once no process holds a token for
+    /// it anymore: the frames still mapped in its user half, the tables of that half, then the
+    /// table itself go back to `frame_alloc`.
+    ///
+    /// The page table must not be loaded on any CPU.
+    pub fn release_page_table(&mut self, frame: PhysFrame, frame_alloc: &mut BuddyFrameAllocator) {
+        let Some(old) = self.l4_tables.get(&frame) else {
+            return;
+        };
+        let old_refs = old.token.as_ref().map(Arc::strong_count);
+        info!(event = "frame_switch", subevent = "before_switch", old_frame:? = frame, old_refs:?; "Old frame ({frame:?}) refs: {old_refs:?}");
+        if old_refs == Some(1) && frame != self.current_frame {
+            // old frame is unused and we switched to something else
+            info!(event = "frame_switch", subevent = "cleanup", old_frame:? = frame; "Old CR3 is unused, cleaning up");
+            let old = self.l4_tables.remove(&frame).unwrap();
+            let p4 = unsafe { old.addr.as_ptr::<PageTable>().as_ref() }.unwrap();
+            let leftover = unsafe { self.free_user_half(p4, frame_alloc) };
+            debug!(event = "frame_switch", subevent = "cleanup", old_frame:? = frame, leftover; "Freed {leftover} pages left mapped in the user half");
+            // No need to unmap the page as we're accessing the frame through the memory mapping
+            unsafe { frame_alloc.deallocate_contiguous(frame, P4_ORDER) };
+        }
+    }
+
+    /// Frees the user half of `p4`, returning how many pages were still mapped there.
+    ///
+    /// # Safety
+    /// `p4` must be a process page table that is not loaded anywhere, and the frames mapped in its
+    /// user half must belong to it alone.
+    unsafe fn free_user_half<A>(&self, p4: &PageTable, frame_alloc: &mut A) -> usize
     where
-        A: x86_64::structures::paging::FrameDeallocator<Size4KiB> + ?Sized,
+        A: FrameDeallocator<Size4KiB> + FrameDeallocator<Size2MiB> + ?Sized,
     {
+        let offset = self.current.phys_offset();
+        let table = |entry: &PageTableEntry| {
+            unsafe {
+                (offset + entry.addr().as_u64())
+                    .as_ptr::<PageTable>()
+                    .as_ref()
+            }
+            .unwrap()
+        };
+        let used = |entry: &&PageTableEntry| !entry.is_unused();
+        let mut leftover = 0;
+
+        for p4_entry in p4
+            .iter()
+            .take(self.kernel_start.p4_index().into())
+            .filter(used)
+        {
+            for p3_entry in table(p4_entry).iter().filter(used) {
+                debug_assert!(
+                    !p3_entry.flags().contains(PageTableFlags::HUGE_PAGE),
+                    "1 GiB pages are never mapped"
+                );
+                for p2_entry in table(p3_entry).iter().filter(used) {
+                    if p2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                        leftover += 1;
+                        let frame = PhysFrame::<Size2MiB>::containing_address(p2_entry.addr());
+                        unsafe { frame_alloc.deallocate_frame(frame) };
+                        continue;
+                    }
+                    for p1_entry in table(p2_entry).iter().filter(used) {
+                        leftover += 1;
+                        let frame = PhysFrame::<Size4KiB>::containing_address(p1_entry.addr());
+                        unsafe { frame_alloc.deallocate_frame(frame) };
+                    }
+                    unsafe { frame_alloc.deallocate_frame(Self::table_frame(p2_entry)) };
+                }
+                unsafe { frame_alloc.deallocate_frame(Self::table_frame(p3_entry)) };
+            }
+            unsafe { frame_alloc.deallocate_frame(Self::table_frame(p4_entry)) };
+        }
+
+        leftover
+    }
+
+    /// Frame of the table `entry` points to.
+    fn table_frame(entry: &PageTableEntry) -> PhysFrame<Size4KiB> {
+        PhysFrame::containing_address(entry.addr())
+    }
+
+    /// Loads the page table `frame` on this CPU, then releases the page table it replaces.
+    pub fn switch_and_release(&mut self, frame: PhysFrame, frame_alloc: &mut BuddyFrameAllocator) {
         x86_64::instructions::interrupts::without_interrupts(|| {
-            let old = self.l4_tables.get(&self.current_frame).unwrap();
             let old_frame = self.current_frame;
-            let old_refs = old.token.as_ref().map(Arc::strong_count);
-            info!(event = "frame_switch", subevent = "before_switch", old_frame:?, new_frame:? = frame, old_refs:?; "Old frame ({:?}) refs: {:?}", self.current_frame, old_refs);
-            let addr = self
-                .l4_tables
-                .get(frame)
-                .expect("the CR3 page table to be registered");
-            self.current_frame = *frame;
-            info!(event = "frame_switch", subevent = "after_switch", old_frame:?, new_frame:? = frame; "Switching to page table with frame {frame:?}");
-            self.current = unsafe {
-                OffsetPageTable::<'static>::new(
-                    addr.addr.as_mut_ptr::<PageTable>().as_mut().unwrap(),
-                    self.current.phys_offset(),
-                )
-            };
-            if old_refs == Some(1) && frame.start_address() != old_frame.start_address() {
-                // old frame is unused and we switched to something else
-                info!(event = "frame_switch", subevent = "cleanup", old_frame:?, new_frame:? = frame; "Old CR3 is unused, cleaning up");
-                let _ = self.l4_tables.remove(&old_frame).unwrap();
-                // No need to unmap the page as we're accessing the frame through the memory mapping
-                unsafe { frame_alloc.deallocate_frame(old_frame) };
+            self.set_current_page_table_frame(&frame);
+            unsafe {
+                Self::switch_to_frame(frame);
             }
+            self.release_page_table(old_frame, frame_alloc);
         });
     }
 
@@ -236,12 +341,10 @@ impl PageTables {
         }
     }
 
-    pub fn create_process_p4_and_switch<A>(&mut self, frame_alloc: &mut A) -> Arc<PageTableToken>
-    where
-        A: x86_64::structures::paging::FrameAllocator<Size4KiB>
-            + x86_64::structures::paging::FrameDeallocator<Size4KiB>
-            + ?Sized,
-    {
+    pub fn create_process_p4_and_switch(
+        &mut self,
+        frame_alloc: &mut BuddyFrameAllocator,
+    ) -> Arc<PageTableToken> {
         let sp: u64;
         unsafe {
             core::arch::asm!("mov {0},rsp", lateout(reg) sp);
@@ -251,24 +354,16 @@ impl PageTables {
             .create_process_p4(frame_alloc)
             .expect("A frame for the l4 table");
         debug!(event = "create_p4", subevent = "after_create", sp, frame:?; "Created: {frame:?}");
-        x86_64::instructions::interrupts::without_interrupts(|| {
-            self.set_current_page_table_frame(&frame, frame_alloc);
-            unsafe {
-                Self::switch_to_frame(frame);
-            }
-        });
+        self.switch_and_release(frame, frame_alloc);
         token
     }
 
-    fn create_process_p4<A>(
+    fn create_process_p4(
         &mut self,
-        frame_alloc: &mut A,
-    ) -> Option<(PhysFrame, Arc<PageTableToken>)>
-    where
-        A: x86_64::structures::paging::FrameAllocator<Size4KiB> + ?Sized,
-    {
+        frame_alloc: &mut BuddyFrameAllocator,
+    ) -> Option<(PhysFrame, Arc<PageTableToken>)> {
         debug!(event = "create_p4_internal", subevent = "before_p4_frame"; "Allocating frame for new p4");
-        let frame = frame_alloc.allocate_frame()?;
+        let frame = frame_alloc.allocate_contiguous(P4_ORDER)?;
         debug!(event = "create_p4_internal", subevent = "after_p4_frame", frame:?; "Allocated frame: {frame:?}");
 
         let offset = self.current.phys_offset();
@@ -286,6 +381,8 @@ impl PageTables {
             *a = b.clone();
         }
         info!(event = "create_p4_internal", subevent = "copy_p4", frame:?, page_addr:?; "Copied current p4 table here -> virtaddr({page_addr:p})");
+        #[cfg(feature = "kpti")]
+        super::kpti::init_user_twin(frame, self.kernel_start, offset);
         let token = Arc::new(PageTableToken { inner: frame });
 
         self.l4_tables.insert(
@@ -299,6 +396,65 @@ impl PageTables {
         Some((frame, token))
     }
 
+    /// Points the kernel half of every user twin at the entry tables again, after a mapping may
+    /// have added an entry to them.
+    #[cfg(feature = "kpti")]
+    pub(super) fn refresh_user_twins(&self) {
+        let offset = self.current.phys_offset();
+        for (frame, _) in self.l4_tables.iter().filter(|(_, i)| i.token.is_some()) {
+            let twin = offset + frame.start_address().as_u64() + Size4KiB::SIZE;
+            let twin = unsafe { twin.as_mut_ptr::<PageTable>().as_mut() }.unwrap();
+            super::kpti::share_entry_tables(twin, self.kernel_start, offset);
+        }
+    }
+
+    /// Copies the user entry `p4_index` of the current table into its user twin, with KPTI, after
+    /// a mapping may have created it or a clean up removed it.
+    fn share_user_entry(&self, p4_index: PageTableIndex) {
+        #[cfg(feature = "kpti")]
+        if let Some(info) = self
+            .l4_tables
+            .get(&self.current_frame)
+            .filter(|info| info.token.is_some())
+        {
+            let twin = info.addr + Size4KiB::SIZE;
+            let twin = unsafe { twin.as_mut_ptr::<PageTable>().as_mut() }.unwrap();
+            twin[p4_index].clone_from(&self.current.level_4_table()[p4_index]);
+        }
+        #[cfg(not(feature = "kpti"))]
+        let _ = p4_index;
+    }
+
+    /// Moves the kernel page table to a frame with bit 12 of its address clear, which KPTI
+    /// reserves for the user twins.
+    ///
+    /// Only the booting CPU may run, on the kernel page table, and no process table may exist.
+    #[cfg(feature = "kpti")]
+    pub(super) fn move_kernel_p4_to_even_frame(&mut self, frame_alloc: &mut BuddyFrameAllocator) {
+        if self.kernel_frame.start_address().as_u64() & Size4KiB::SIZE == 0 {
+            return;
+        }
+        // The APs load the kernel page table in real mode, the second frame of the block is
+        // left unused
+        let frame = frame_alloc
+            .allocate_contiguous_below(1, x86_64::PhysAddr::new(u64::from(u32::MAX) + 1))
+            .expect("A frame for the kernel l4 table");
+        let addr = self.current.phys_offset() + frame.start_address().as_u64();
+        let table = unsafe { addr.as_mut_ptr::<PageTable>().as_mut() }.unwrap();
+        table.clone_from(self.current.level_4_table());
+        self.l4_tables
+            .insert(frame, PageTableInfo { addr, token: None });
+        info!(
+            "Moving the kernel page table from {:?} to {frame:?}",
+            self.kernel_frame
+        );
+        self.kernel_frame = frame;
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.set_current_page_table_frame(&frame);
+            unsafe { Self::switch_to_frame(frame) };
+        });
+    }
+
     fn all_but_current_internal<'a>(
         tables: impl Iterator<Item = (&'a PhysFrame, &'a PageTableInfo)>,
         frame: &'a PhysFrame,
@@ -308,6 +464,15 @@ impl PageTables {
             .map(|(_, x)| unsafe { x.addr.as_mut_ptr::<PageTable>().as_mut() }.unwrap())
     }
 
+    /// Copies the kernel entry `p4_index` of the current table into the other tables, after a
+    /// mapping may have created it.
+    fn share_kernel_entry(&self, p4_index: PageTableIndex) {
+        let current_e = &self.current.level_4_table()[p4_index];
+        for e in Self::all_but_current_internal(self.l4_tables.iter(), &self.current_frame) {
+            e[p4_index].clone_from(current_e);
+        }
+    }
+
     #[allow(clippy::needless_pass_by_ref_mut)]
     fn all_but_current(&mut self) -> impl Iterator<Item = &mut PageTable> {
         Self::all_but_current_internal(self.l4_tables.iter(), &self.current_frame)
@@ -340,6 +505,9 @@ impl CleanUp for PageTables {
         unsafe {
             self.current.clean_up_addr_range(range, frame_deallocator);
         }
+        for p4_index in 0..self.kernel_start.p4_index().into() {
+            self.share_user_entry(PageTableIndex::new(p4_index));
+        }
         let current = self
             .current
             .level_4_table()
@@ -395,16 +563,13 @@ impl Mapper<Size4KiB> for PageTables {
 
         if p4_index >= self.kernel_start.p4_index() {
             // println!("Created mapping in kernelspace (P4 idx: {p4_index:?} - {page:?})");
-            let current_e = &self.current.level_4_table()[p4_index];
-            // Copy kernel tables
-            for e in Self::all_but_current_internal(self.l4_tables.iter(), &self.current_frame) {
-                e[p4_index].clone_from(current_e);
-            }
+            self.share_kernel_entry(p4_index);
             // trace!(event = "map_page", subevent = "map_kernel", current_frame:? = self.current_frame, frame:?, page:?;
             //     "Created mapping in kernelspace (Current frame: {:?} / P4 idx: {p4_index:?} - {page:?}) to frame {frame:?}",
             //     self.current_frame
             // )
         } else {
+            self.share_user_entry(p4_index);
             trace!(event = "map_page", subevent = "map_user", current_frame:? = self.current_frame, frame:?, page:?;
                 "Created mapping in userspace (Current frame: {:?} / P4 idx: {p4_index:?} - {page:?}) to frame {frame:?}",
                 self.current_frame
@@ -428,7 +593,14 @@ impl Mapper<Size4KiB> for PageTables {
         //     "Unmapping page: {page:?} ({:?})",
         //     self.current_frame
         // );
-        self.current.unmap(page) // Nothing needs to be done, no cleanup is performed, if at kernel level, no p3 tables are removed, and at user level, it doesnt matter 
+        // Nothing needs to be done, no cleanup is performed, if at kernel level, no p3 tables are removed, and at user level, it doesnt matter
+        let unmapped = self.current.unmap(page);
+        if unmapped.is_ok() && page.p4_index() >= self.kernel_start.p4_index() {
+            // Other CPUs may still cache the kernel mapping, the frame can't be reused before
+            // they dropped it
+            crate::smp::shootdown_kernel_tlb();
+        }
+        unmapped
     }
 
     unsafe fn update_flags(
@@ -460,6 +632,8 @@ impl Mapper<Size4KiB> for PageTables {
 
                 p4_entry.set_flags(flags);
             }
+        } else {
+            self.share_user_entry(p4_index);
         }
 
         Ok(flush)
@@ -498,6 +672,112 @@ impl Mapper<Size4KiB> for PageTables {
     }
 }
 
+/// 2 MiB pages, mapped straight from the level 2 tables. The tables above them are shared the
+/// same way as for [`Size4KiB`] pages.
+impl Mapper<Size2MiB> for PageTables {
+    unsafe fn map_to_with_table_flags<A>(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        parent_table_flags: PageTableFlags,
+        frame_allocator: &mut A,
+    ) -> Result<MapperFlush<Size2MiB>, MapToError<Size2MiB>>
+    where
+        Self: Sized,
+        A: FrameAllocator<Size4KiB> + ?Sized,
+    {
+        let p4_index = page.p4_index();
+        let flush = unsafe {
+            self.current.map_to_with_table_flags(
+                page,
+                frame,
+                flags,
+                parent_table_flags,
+                frame_allocator,
+            )
+        }.inspect_err(|e| {
+            warn!(event = "map_page", subevent = "fail_map", current_frame:? = self.current_frame, frame:?, page:?, error:? = e;
+                "Failed to map huge page (Current frame: {:?} / P4 idx: {p4_index:?} - {page:?}) to frame {frame:?} ({e:?})",
+                self.current_frame
+            )
+        })?;
+
+        if p4_index >= self.kernel_start.p4_index() {
+            self.share_kernel_entry(p4_index);
+        } else {
+            self.share_user_entry(p4_index);
+            trace!(event = "map_page", subevent = "map_user", current_frame:? = self.current_frame, frame:?, page:?;
+                "Created huge mapping in userspace (Current frame: {:?} / P4 idx: {p4_index:?} - {page:?}) to frame {frame:?}",
+                self.current_frame
+            )
+        }
+
+        Ok(flush)
+    }
+
+    fn unmap(
+        &mut self,
+        page: Page<Size2MiB>,
+    ) -> Result<(PhysFrame<Size2MiB>, MapperFlush<Size2MiB>), UnmapError> {
+        let unmapped = self.current.unmap(page);
+        if unmapped.is_ok() && page.p4_index() >= self.kernel_start.p4_index() {
+            // Other CPUs may still cache the kernel mapping, the frame can't be reused before
+            // they dropped it
+            crate::smp::shootdown_kernel_tlb();
+        }
+        unmapped
+    }
+
+    unsafe fn update_flags(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlush<Size2MiB>, FlagUpdateError> {
+        unsafe { self.current.update_flags(page, flags) }
+    }
+
+    unsafe fn set_flags_p4_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        let flush = unsafe { self.current.set_flags_p4_entry(page, flags) }?;
+
+        let p4_index = page.p4_index();
+
+        if p4_index >= self.kernel_start.p4_index() {
+            for p4 in self.all_but_current() {
+                p4[p4_index].set_flags(flags);
+            }
+        } else {
+            self.share_user_entry(p4_index);
+        }
+
+        Ok(flush)
+    }
+
+    unsafe fn set_flags_p3_entry(
+        &mut self,
+        page: Page<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        unsafe { self.current.set_flags_p3_entry(page, flags) }
+    }
+
+    unsafe fn set_flags_p2_entry(
+        &mut self,
+        _page: Page<Size2MiB>,
+        _flags: PageTableFlags,
+    ) -> Result<MapperFlushAll, FlagUpdateError> {
+        Err(FlagUpdateError::ParentEntryHugePage)
+    }
+
+    fn translate_page(&self, page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, TranslateError> {
+        self.current.translate_page(page)
+    }
+}
+
 impl Translate for PageTables {
     fn translate(
         &self,
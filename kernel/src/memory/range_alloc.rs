@@ -222,6 +222,48 @@ impl<const N: usize, T: Copy + Sub<Output = T> + Sum> RangeAllocator<T, N> {
     }
 }
 
+impl<const N: usize> RangeAllocator<u64, N> {
+    /// Allocates `pages` pages starting at a multiple of `align`, so that they can be mapped with
+    /// larger pages. The space skipped before the start stays free.
+    pub fn allocate_aligned_range(
+        &mut self,
+        pages: u64,
+        align: u64,
+    ) -> Result<Range<u64>, RangeAllocationError<u64>> {
+        let length = pages * self.alignment;
+        let found = self
+            .free_ranges
+            .iter()
+            .enumerate()
+            .find_map(|(index, range)| {
+                let start = range.start.next_multiple_of(align);
+                (start.checked_add(length)? <= range.end).then_some((index, start))
+            });
+        let Some((index, start)) = found else {
+            return Err(RangeAllocationError {
+                fragmented_free_length: self.total_available(),
+            });
+        };
+
+        let end = start + length;
+        let free = self.free_ranges[index].clone();
+        match (free.start < start, end < free.end) {
+            (false, false) => {
+                self.free_ranges.remove(index);
+            }
+            (false, true) => self.free_ranges[index].start = end,
+            (true, false) => self.free_ranges[index].end = start,
+            (true, true) => {
+                self.free_ranges[index].end = start;
+                self.free_ranges.insert(index + 1, end..free.end);
+            }
+        }
+
+        debug!("Allocated aligned range at {start:x?}-{end:x?}");
+        Ok(start..end)
+    }
+}
+
 /// Initialize a small region allocator on top of BootInfo-derived free_start.
 ///
 /// This creates a simple 1 GiB virtual window starting at `layout.free_start`.
@@ -0,0 +1,181 @@
+//! Access to userspace memory from syscalls.
+//!
+//! Userspace lives below [`KERNEL_START`], every page of a range is checked in the current
+//! page table before it is touched, so a bad pointer ends up as an error instead of a fault.
+//! Pages of the process that were not touched yet are mapped by the check.
+//!
+//! With SMAP on, the kernel may only touch user pages between `stac` and `clac`, which only the
+//! copy helpers here do. Any other dereference of a user pointer faults.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use alloc::{string::String, vec, vec::Vec};
+use blog_os_vfs::api::IOError;
+use qemu_common::KERNEL_START;
+use thiserror::Error;
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    registers::rflags::{self, RFlags},
+    structures::paging::{Page, PageTableFlags, Size4KiB, Translate, mapper::TranslateResult},
+};
+
+use crate::{
+    memory::vma::{self, Access},
+    setup::KERNEL_INFO,
+};
+
+#[derive(Debug, Error)]
+pub enum UserAccessError {
+    #[error("Address range 0x{0:x} + {1} is not in userspace")]
+    OutOfRange(u64, usize),
+    #[error("Page {0:?} is not mapped for user access")]
+    NotMapped(Page),
+}
+
+/// Whether SMAP is on, `stac` and `clac` are invalid opcodes without it.
+///
+/// The entry stubs read it as a byte, see [`crate::interrupts::stub`].
+pub(crate) static SMAP: AtomicBool = AtomicBool::new(false);
+
+pub(super) fn set_smap(enabled: bool) {
+    SMAP.store(enabled, Ordering::Relaxed);
+}
+
+/// Closes the user access window on kernel entry.
+///
+/// Userspace can set RFLAGS.AC itself, which the CPU keeps when it takes an interrupt, so every
+/// handler starts with this. The interrupted flags come back with `iretq`.
+#[inline(always)]
+pub fn clac() {
+    if SMAP.load(Ordering::Relaxed) {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+}
+
+/// Whether SMAP is on and RFLAGS.AC lifts it, letting the kernel touch user pages.
+pub fn user_access_open() -> bool {
+    SMAP.load(Ordering::Relaxed) && rflags::read().contains(RFlags::ALIGNMENT_CHECK)
+}
+
+impl From<UserAccessError> for IOError {
+    fn from(_: UserAccessError) -> Self {
+        Self::BadAddress
+    }
+}
+
+/// Checks that `len` bytes from `addr` are mapped and accessible from ring 3.
+pub fn check_user_range(addr: u64, len: usize, write: bool) -> Result<(), UserAccessError> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let end = addr
+        .checked_add(len as u64)
+        .filter(|&end| addr != 0 && end <= KERNEL_START.as_u64())
+        .ok_or(UserAccessError::OutOfRange(addr, len))?;
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let access = if write { Access::Write } else { Access::Read };
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(VirtAddr::new(addr)),
+        Page::containing_address(VirtAddr::new(end - 1)),
+    );
+    for page in pages {
+        // Pages of the process not touched yet are mapped here, like on a fault
+        if !is_mapped(page, required)
+            && (vma::handle_user_fault(page.start_address(), access).is_err()
+                || !is_mapped(page, required))
+        {
+            return Err(UserAccessError::NotMapped(page));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_mapped(page: Page, required: PageTableFlags) -> bool {
+    let lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
+    let mapped = matches!(
+        lock.page_table.translate(page.start_address()),
+        TranslateResult::Mapped { flags, .. } if flags.contains(required)
+    );
+    drop(lock);
+    mapped
+}
+
+/// Runs `f` with access to user pages allowed.
+///
+/// Interrupts stay off, so handlers and other tasks never run with the window open.
+fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let smap = SMAP.load(Ordering::Relaxed);
+        if smap {
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        let res = f();
+        if smap {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+        res
+    })
+}
+
+/// Fills `dst` from the userspace buffer at `src`.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), UserAccessError> {
+    check_user_range(src, dst.len(), false)?;
+
+    if !dst.is_empty() {
+        with_user_access(|| {
+            let src = unsafe { core::slice::from_raw_parts(src as *const u8, dst.len()) };
+            dst.copy_from_slice(src);
+        });
+    }
+
+    Ok(())
+}
+
+/// Copies `src` into the userspace buffer at `dst`.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), UserAccessError> {
+    check_user_range(dst, src.len(), true)?;
+
+    if !src.is_empty() {
+        with_user_access(|| {
+            let dst = unsafe { core::slice::from_raw_parts_mut(dst as *mut u8, src.len()) };
+            dst.copy_from_slice(src);
+        });
+    }
+
+    Ok(())
+}
+
+/// Writes `value` to userspace.
+pub fn write_to_user<T>(dst: u64, value: &T) -> Result<(), UserAccessError> {
+    let bytes =
+        unsafe { core::slice::from_raw_parts((&raw const *value).cast::<u8>(), size_of::<T>()) };
+    copy_to_user(dst, bytes)
+}
+
+/// Copies `len` bytes from userspace into a new buffer.
+pub fn vec_from_user(src: u64, len: usize) -> Result<Vec<u8>, UserAccessError> {
+    // Checked before allocating, so a bogus length is an error and not a huge allocation
+    check_user_range(src, len, false)?;
+    let mut buf = vec![0; len];
+    copy_from_user(&mut buf, src)?;
+    Ok(buf)
+}
+
+/// Reads a path or other string of `len` bytes from userspace, replacing invalid UTF-8.
+pub fn string_from_user(src: u64, len: usize) -> Result<String, UserAccessError> {
+    let buf = vec_from_user(src, len)?;
+
+    Ok(String::from_utf8(buf)
+        .unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned()))
+}
@@ -0,0 +1,459 @@
+//! Areas of a user address space, mapped on first touch.
+//!
+//! A process gets no frame for its memory up front: the page fault on an unmapped page of one of
+//! its areas maps a frame there, zeroed or filled from the ELF image, and the faulting access is
+//! retried. Faults outside of every area, or against the permissions of their area, are not
+//! resolved, and end the process when they come from it.
+//!
+//! The stack is an area that grows down on faults anywhere below it, up to [`USER_STACK_MAX`], so
+//! that a function with a large frame can touch its far end first. A gap of
+//! [`USER_STACK_GUARD_GAP`] is kept free below that limit, so that an overflowing stack faults
+//! instead of running into the heap.
+
+use alloc::{
+    collections::{btree_map::BTreeMap, btree_set::BTreeSet},
+    vec::Vec,
+};
+use core::ops::Range;
+
+use thiserror::Error;
+use x86_64::{
+    VirtAddr,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, PhysFrame,
+            Size2MiB, Size4KiB,
+            mapper::{CleanUp, MapToError},
+        },
+    },
+};
+
+use crate::{
+    memory::mapping::{self, MappedPage},
+    process::current_program,
+    setup::{AllocKernelInfo, KERNEL_INFO},
+};
+
+/// Size of the stack of a process when it starts
+pub const USER_STACK_INITIAL: u64 = 64 * 1024;
+/// Size the stack of a process may grow to
+pub const USER_STACK_MAX: u64 = 8 * 1024 * 1024;
+/// Unmapped space kept below the stack limit
+pub const USER_STACK_GUARD_GAP: u64 = 1024 * 1024;
+
+/// What a faulting access tried to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    pub fn from_error_code(code: PageFaultErrorCode) -> Self {
+        if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            Self::Execute
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            Self::Write
+        } else {
+            Self::Read
+        }
+    }
+
+    const fn allowed_by(self, flags: PageTableFlags) -> bool {
+        match self {
+            Self::Read => true,
+            Self::Write => flags.contains(PageTableFlags::WRITABLE),
+            Self::Execute => !flags.contains(PageTableFlags::NO_EXECUTE),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FaultError {
+    #[error("No process to resolve the fault in")]
+    NoProcess,
+    #[error("{0:p} is not in any area of the process")]
+    OutOfArea(VirtAddr),
+    #[error("{1:?} access to {0:p} is not allowed")]
+    Denied(VirtAddr, Access),
+    #[error("Stack overflow, {0:p} is past the stack limit")]
+    StackOverflow(VirtAddr),
+    #[error("Unable to map the page: {0:?}")]
+    Map(MapToError<Size4KiB>),
+}
+
+/// Where the content of the pages of an area comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    /// Zeroed pages, 2 MiB ones where they fit if `huge` is set
+    Zero { huge: bool },
+    /// The [segments](ImageSegment) of the ELF image, zero past them
+    Image,
+}
+
+#[derive(Debug)]
+struct Area {
+    end: VirtAddr,
+    flags: PageTableFlags,
+    backing: Backing,
+}
+
+/// Bytes of the ELF image loaded at `vaddr`.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageSegment {
+    pub vaddr: VirtAddr,
+    pub offset: u64,
+    pub filesz: u64,
+}
+
+/// Where the stack of a process lives.
+#[derive(Debug, Clone, Copy)]
+pub struct UserStack {
+    top: VirtAddr,
+    /// Lowest address the stack may grow to
+    limit: VirtAddr,
+}
+
+impl UserStack {
+    pub const fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    pub const fn limit(&self) -> VirtAddr {
+        self.limit
+    }
+
+    /// Lowest address of the stack reservation, guard gap included.
+    pub fn floor(&self) -> VirtAddr {
+        self.limit - USER_STACK_GUARD_GAP
+    }
+}
+
+/// The areas of a process and the pages mapped in them so far.
+///
+/// Dropping it unmaps those pages, so it must happen on the page table of the process.
+#[derive(Debug, Default)]
+pub struct AddressSpace {
+    /// Areas by start address, page aligned and not overlapping
+    areas: BTreeMap<VirtAddr, Area>,
+    segments: Vec<ImageSegment>,
+    /// Relocated values written over the image, by address
+    fixups: BTreeMap<VirtAddr, u64>,
+    mapped: BTreeSet<MappedPage>,
+    stack: Option<UserStack>,
+}
+
+impl AddressSpace {
+    pub const fn new(segments: Vec<ImageSegment>, fixups: BTreeMap<VirtAddr, u64>) -> Self {
+        Self {
+            areas: BTreeMap::new(),
+            segments,
+            fixups,
+            mapped: BTreeSet::new(),
+            stack: None,
+        }
+    }
+
+    /// Reserves the stack below `top` and adds its initial area.
+    pub fn add_stack(&mut self, top: VirtAddr) -> UserStack {
+        let stack = UserStack {
+            top,
+            limit: top - USER_STACK_MAX,
+        };
+        self.add_area(
+            top - USER_STACK_INITIAL..top,
+            Self::STACK_FLAGS,
+            Backing::Zero { huge: false },
+        );
+        self.stack = Some(stack);
+        stack
+    }
+
+    const STACK_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+        .union(PageTableFlags::WRITABLE)
+        .union(PageTableFlags::USER_ACCESSIBLE)
+        .union(PageTableFlags::NO_EXECUTE);
+
+    /// Lowest address the heap must stay below.
+    pub fn heap_limit(&self) -> VirtAddr {
+        self.stack
+            .as_ref()
+            .map_or(qemu_common::KERNEL_START, UserStack::floor)
+    }
+
+    /// Extends the stack area down to the page of `addr` if the fault on it is one of a growing
+    /// stack, which is any fault between the stack limit and the stack area.
+    fn grow_stack(&mut self, addr: VirtAddr) -> Result<(), FaultError> {
+        let Some(stack) = self.stack else {
+            return Err(FaultError::OutOfArea(addr));
+        };
+        if !(stack.floor()..stack.top).contains(&addr) {
+            return Err(FaultError::OutOfArea(addr));
+        }
+        if addr < stack.limit {
+            return Err(FaultError::StackOverflow(addr));
+        }
+
+        let (bottom, _) = self.area(stack.top - 1u64).expect("the stack area");
+        if addr >= bottom || self.areas.range(addr..bottom).next().is_some() {
+            return Err(FaultError::OutOfArea(addr));
+        }
+
+        let area = self.areas.remove(&bottom).expect("the stack area");
+        self.areas.insert(addr.align_down(Size4KiB::SIZE), area);
+        Ok(())
+    }
+
+    /// Adds an area over the page aligned `range`, merged into the area ending at its start if
+    /// they match.
+    pub fn add_area(&mut self, range: Range<VirtAddr>, flags: PageTableFlags, backing: Backing) {
+        debug_assert!(
+            range.start.is_aligned(Size4KiB::SIZE) && range.end.is_aligned(Size4KiB::SIZE)
+        );
+        debug_assert!(
+            self.areas
+                .range(..range.end)
+                .next_back()
+                .is_none_or(|(_, area)| area.end <= range.start),
+            "{range:?} overlaps an area"
+        );
+
+        if let Some((_, previous)) = self.areas.range_mut(..range.start).next_back()
+            && previous.end == range.start
+            && previous.flags == flags
+            && previous.backing == backing
+        {
+            previous.end = range.end;
+            return;
+        }
+        self.areas.insert(
+            range.start,
+            Area {
+                end: range.end,
+                flags,
+                backing,
+            },
+        );
+    }
+
+    fn area(&self, addr: VirtAddr) -> Option<(VirtAddr, &Area)> {
+        self.areas
+            .range(..=addr)
+            .next_back()
+            .filter(|(_, area)| addr < area.end)
+            .map(|(start, area)| (*start, area))
+    }
+
+    /// Maps the page holding `addr` for an `access` that faulted on it.
+    ///
+    /// `image` is the ELF image the segments are in.
+    pub fn handle_fault(
+        &mut self,
+        addr: VirtAddr,
+        access: Access,
+        image: &[u8],
+    ) -> Result<(), FaultError> {
+        if self.area(addr).is_none() {
+            self.grow_stack(addr)?;
+        }
+        let (start, area) = self.area(addr).ok_or(FaultError::OutOfArea(addr))?;
+        if !access.allowed_by(area.flags) {
+            return Err(FaultError::Denied(addr, access));
+        }
+        let (end, flags, backing) = (area.end, area.flags, area.backing);
+
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let block = Page::<Size2MiB>::containing_address(addr);
+        if self.mapped.contains(&MappedPage::Small(page))
+            || self.mapped.contains(&MappedPage::Huge(block))
+        {
+            // Mapped since the access faulted
+            return Ok(());
+        }
+
+        let kinf = KERNEL_INFO.get().unwrap();
+        let mut lock = kinf.alloc_kinf.lock();
+        let mem = &mut *lock;
+
+        if backing == (Backing::Zero { huge: true })
+            && start <= block.start_address()
+            && block.start_address() + Size2MiB::SIZE <= end
+            && !self.maps_small_pages_in(block)
+            && let Some(frame) =
+                FrameAllocator::<Size2MiB>::allocate_frame(&mut mem.frame_allocator)
+        {
+            let virt = kinf.physical_memory_offset + frame.start_address().as_u64();
+            unsafe {
+                virt.as_mut_ptr::<u8>()
+                    .write_bytes(0, Size2MiB::SIZE as usize)
+            };
+            map_frame(mem, block, frame, flags)?;
+            self.mapped.insert(MappedPage::Huge(block));
+            return Ok(());
+        }
+
+        let frame = mem
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(FaultError::Map(MapToError::FrameAllocationFailed))?;
+        let virt = kinf.physical_memory_offset + frame.start_address().as_u64();
+        let content = unsafe { &mut *virt.as_mut_ptr::<[u8; Size4KiB::SIZE as usize]>() };
+        content.fill(0);
+        if backing == Backing::Image {
+            self.fill_from_image(content, page, image);
+        }
+        map_frame(mem, page, frame, flags)?;
+        self.mapped.insert(MappedPage::Small(page));
+
+        drop(lock);
+        Ok(())
+    }
+
+    fn maps_small_pages_in(&self, block: Page<Size2MiB>) -> bool {
+        let first = Page::containing_address(block.start_address());
+        let last = Page::containing_address(block.start_address() + (Size2MiB::SIZE - 1));
+        self.mapped
+            .range(MappedPage::Small(first)..=MappedPage::Small(last))
+            .next()
+            .is_some()
+    }
+
+    /// Copies the bytes of the segments and fixups falling in `page` to `content`.
+    fn fill_from_image(&self, content: &mut [u8], page: Page, image: &[u8]) {
+        let start = page.start_address();
+        let end = start + Size4KiB::SIZE;
+
+        for segment in &self.segments {
+            let from = start.max(segment.vaddr);
+            let to = end.min(segment.vaddr + segment.filesz);
+            if from < to {
+                let src = (segment.offset + (from - segment.vaddr)) as usize;
+                let len = (to - from) as usize;
+                let dst = (from - start) as usize;
+                content[dst..dst + len].copy_from_slice(&image[src..src + len]);
+            }
+        }
+
+        // A fixup may start on the page before
+        let first = VirtAddr::new(start.as_u64().saturating_sub(size_of::<u64>() as u64 - 1));
+        for (addr, value) in self.fixups.range(first..end) {
+            for (byte_addr, byte) in (0..).map(|i| *addr + i).zip(value.to_le_bytes()) {
+                if (start..end).contains(&byte_addr) {
+                    content[(byte_addr - start) as usize] = byte;
+                }
+            }
+        }
+    }
+}
+
+fn map_frame<S: PageSize>(
+    mem: &mut AllocKernelInfo,
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<(), FaultError>
+where
+    crate::memory::multi_l4_paging::PageTables: Mapper<S>,
+    crate::memory::BuddyFrameAllocator: FrameDeallocator<S>,
+{
+    match unsafe {
+        mem.page_table
+            .map_to(page, frame, flags, &mut mem.frame_allocator)
+    } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(e) => {
+            unsafe { mem.frame_allocator.deallocate_frame(frame) };
+            Err(FaultError::Map(match e {
+                MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+                MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+                MapToError::PageAlreadyMapped(frame) => MapToError::PageAlreadyMapped(
+                    PhysFrame::containing_address(frame.start_address()),
+                ),
+            }))
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
+        let mem = &mut *lock;
+
+        for page in &self.mapped {
+            mapping::unmap(mem, *page).expect("Mapped page");
+        }
+
+        let first = self.areas.keys().next();
+        let last = self.areas.values().next_back().map(|area| area.end);
+        if let (Some(first), Some(last)) = (first, last) {
+            unsafe {
+                mem.page_table.clean_up_addr_range(
+                    Page::range_inclusive(
+                        Page::containing_address(*first),
+                        Page::containing_address(last - 1u64),
+                    ),
+                    &mut mem.frame_allocator,
+                )
+            };
+        }
+
+        drop(lock);
+    }
+}
+
+/// Resolves a fault of the current process on `addr`.
+pub fn handle_user_fault(addr: VirtAddr, access: Access) -> Result<(), FaultError> {
+    let program = current_program().ok_or(FaultError::NoProcess)?;
+    program.handle_fault(addr, access)
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::{collections::btree_map::BTreeMap, vec::Vec};
+    use x86_64::VirtAddr;
+
+    use super::{
+        AddressSpace, FaultError, USER_STACK_GUARD_GAP, USER_STACK_INITIAL, USER_STACK_MAX,
+    };
+
+    const TOP: VirtAddr = VirtAddr::new_truncate(0x7000_0000_0000);
+
+    #[test_case]
+    fn stack_grows_to_a_far_fault() {
+        let mut space = AddressSpace::new(Vec::new(), BTreeMap::new());
+        space.add_stack(TOP);
+
+        // A frame of a few MiB touched at its far end first
+        let far = TOP - USER_STACK_INITIAL - 3 * 1024 * 1024u64;
+        space.grow_stack(far).expect("the stack grows");
+        let (bottom, area) = space.area(TOP - 1u64).expect("the stack area");
+        assert_eq!(bottom, far.align_down(4096u64));
+        assert_eq!(area.end, TOP);
+
+        space
+            .grow_stack(TOP - USER_STACK_MAX)
+            .expect("the stack grows");
+    }
+
+    #[test_case]
+    fn stack_stops_at_its_limit() {
+        let mut space = AddressSpace::new(Vec::new(), BTreeMap::new());
+        let stack = space.add_stack(TOP);
+
+        let in_gap = stack.limit() - 1u64;
+        assert!(matches!(
+            space.grow_stack(in_gap),
+            Err(FaultError::StackOverflow(addr)) if addr == in_gap
+        ));
+        let below = stack.limit() - USER_STACK_GUARD_GAP - 1u64;
+        assert!(matches!(
+            space.grow_stack(below),
+            Err(FaultError::OutOfArea(_))
+        ));
+    }
+}
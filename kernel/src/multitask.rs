@@ -1,35 +1,190 @@
-use crate::multitask::task::TaskId;
+use alloc::{borrow::Cow, sync::Arc, vec::Vec};
+use blog_os_syscalls::priority::Priority;
+use blog_os_vfs::api::IOError;
+use uuid::Uuid;
+use x86_64::VirtAddr;
+
+use crate::multitask::switching::SwitchData;
+use crate::multitask::task::{TaskControlBlock, TaskId};
 use crate::process::ProcessInfo;
 
+pub mod kthread;
 mod switching;
 pub mod task;
+pub mod workqueue;
 
+// Both policies are always compiled, so that the inactive one keeps building
+#[cfg_attr(not(feature = "round-robin"), allow(dead_code))]
 mod round_robin;
+#[cfg_attr(feature = "round-robin", allow(dead_code))]
 mod scheduler;
 
 /// Low-level lock implementation for tasks.
 pub mod lock;
+/// Sleeping locks for tasks.
+pub mod sync;
+pub mod wait;
+
+/// A scheduling policy. The one in use is [`Active`], chosen at build time.
+pub trait Scheduler {
+    const NAME: &'static str;
+    /// Per-task data of the policy
+    type Data: 'static;
+    /// Whether every CPU can run the policy. Otherwise only the bootstrap processor is used.
+    const SMP: bool;
+
+    fn init();
+    /// Picks the task to run next, called with interrupts disabled.
+    fn switch_fn() -> SwitchData<Self::Data>;
+    /// Runs on the new task after every switch, including its first one.
+    extern "C" fn after_switch();
+    /// Creates a task running `entry(arg)`, returning its ID.
+    fn create_task(
+        entry: extern "C" fn(*mut ()),
+        arg: *mut (),
+        name: Cow<'static, str>,
+        priority: Priority,
+    ) -> Uuid;
+    extern "C" fn task_exit() -> !;
+    /// Switches away from the current task until [`Self::wake`] is called for it.
+    fn sleep(kind: SleepKind);
+    /// Wakes a sleeping task. A task that is about to sleep doesn't go to sleep at all.
+    fn wake(id: &Uuid);
+    /// Accounts a timer tick to the current task.
+    fn tick();
+    /// Makes the next task switch pick a new task.
+    fn request_reschedule();
+    fn try_get_current_task() -> Option<Arc<TaskControlBlock<Self::Data>>>;
+    fn locking_get_current_task() -> Option<Arc<TaskControlBlock<Self::Data>>>;
+    /// IDs and names of all tasks.
+    fn tasks() -> Vec<(Uuid, Cow<'static, str>)>;
+    fn find_task(id: &Uuid) -> Option<Arc<TaskControlBlock<Self::Data>>>;
+    fn get_priority(id: &Uuid) -> Option<Priority>;
+    /// Changes the priority of a task, returning whether it was applied.
+    fn set_priority(id: &Uuid, priority: Priority) -> bool;
+    /// Runs `f` on every task in a [`SleepKind::Bounded`] sleep, which can't run meanwhile.
+    fn for_each_sleeping(f: impl FnMut(&SleepingTask));
+}
+
+/// What a task sleeps for, telling the [watchdog](crate::watchdog) whether a long sleep is
+/// suspicious.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SleepKind {
+    /// Waiting for the kernel, like a lock or another thread, which should not take long
+    Bounded,
+    /// Waiting for the outside world, like input, which may take forever
+    Unbounded,
+}
+
+/// A task in a [`SleepKind::Bounded`] sleep.
+#[derive(Debug)]
+pub struct SleepingTask {
+    pub id: Uuid,
+    pub name: Cow<'static, str>,
+    /// Uptime in milliseconds when the task went to sleep
+    pub since_ms: u64,
+    /// Stack pointer saved by the switch away from the task
+    pub stack_pointer: VirtAddr,
+}
+
+/// The EEVDF scheduler, unless built with the `round-robin` feature.
+#[cfg(not(feature = "round-robin"))]
+pub type Active = scheduler::Eevdf;
+#[cfg(feature = "round-robin")]
+pub type Active = round_robin::RoundRobin;
+
+pub fn init() {
+    log::info!("Using the {} scheduler", Active::NAME);
+    Active::init();
+    workqueue::init();
+}
+
+/// Creates a detached task running `entry`, see [`kthread`] for tasks running closures.
+pub fn create_task<S: Into<Cow<'static, str>>>(entry: extern "C" fn(), name: S) {
+    kthread::spawn(move || entry(), name);
+}
+
+/// Ends the current task, which kernel tasks also return into.
+pub extern "C" fn task_exit() -> ! {
+    if let Some(id) = get_current_task_id() {
+        crate::fs::sysfs::task_exited(&id);
+    }
+    Active::task_exit()
+}
+
+pub fn go_to_sleep(kind: SleepKind) {
+    Active::sleep(kind);
+}
 
-// pub use round_robin::create_task;
-use scheduler::after_switch;
-pub use scheduler::create_task;
-pub use scheduler::init;
-pub use scheduler::locking_get_current_task;
-use scheduler::switch_fn;
-pub use scheduler::task_exit;
-pub use scheduler::try_get_current_task;
-pub use scheduler::go_to_sleep;
-pub use scheduler::wake;
+pub fn wake(id: &Uuid) {
+    Active::wake(id);
+}
 
-// pub use round_robin::get_current_task;
-// pub use round_robin::init;
-// use round_robin::switch_fn;
-// pub use round_robin::task_exit;
-// pub use round_robin::try_get_current_task;
+pub fn tick() {
+    Active::tick();
+}
 
+pub fn request_reschedule() {
+    Active::request_reschedule();
+}
+
+pub fn try_get_current_task() -> Option<Arc<TaskControlBlock<<Active as Scheduler>::Data>>> {
+    Active::try_get_current_task()
+}
+
+pub fn locking_get_current_task() -> Option<Arc<TaskControlBlock<<Active as Scheduler>::Data>>> {
+    Active::locking_get_current_task()
+}
+
+pub fn tasks() -> Vec<(Uuid, Cow<'static, str>)> {
+    Active::tasks()
+}
+
+pub fn get_priority(id: &Uuid) -> Option<Priority> {
+    Active::get_priority(id)
+}
+
+/// Runs `f` on every task in a [`SleepKind::Bounded`] sleep, which can't run meanwhile.
+pub fn for_each_sleeping(f: impl FnMut(&SleepingTask)) {
+    Active::for_each_sleeping(f);
+}
+
+/// Changes the priority of a task, failing with `NotSupported` if the scheduler doesn't
+/// support it.
+pub fn set_priority(id: &Uuid, priority: Priority) -> Result<(), IOError> {
+    if Active::set_priority(id, priority) {
+        Ok(())
+    } else if Active::get_priority(id).is_some() {
+        Err(IOError::NotSupported)
+    } else {
+        Err(IOError::NotFound)
+    }
+}
+
+/// Changes the priority of a task on behalf of the current task.
+///
+/// A real-time task runs before every normal one and could starve the system, so only kernel
+/// tasks may make a task real-time. Processes may also only change the tasks of their own
+/// process, not those of others or of the kernel. They fail with `OperationNotPermitted`.
+pub fn request_priority(id: &Uuid, priority: Priority) -> Result<(), IOError> {
+    let process_id = |task: &TaskControlBlock<_>| {
+        let context = task.context.lock();
+        context.process_info.as_ref().map(ProcessInfo::process_id)
+    };
+    if let Some(caller) = locking_get_current_task().and_then(|task| process_id(&task)) {
+        if matches!(priority, Priority::RealTime(_)) {
+            return Err(IOError::OperationNotPermitted);
+        }
+        let target = Active::find_task(id).ok_or(IOError::NotFound)?;
+        if process_id(&target) != Some(caller) {
+            return Err(IOError::OperationNotPermitted);
+        }
+    }
+    set_priority(id, priority)
+}
 
 pub fn task_switch() -> bool {
-    switching::task_switch_safe(switch_fn, after_switch)
+    switching::task_switch_safe(Active::switch_fn, Active::after_switch)
 }
 
 /// Returns the ID of the current task.
@@ -0,0 +1,90 @@
+//! Kernel threads: tasks running a closure, with a handle to wait for what it returns.
+
+use alloc::{borrow::Cow, boxed::Box, sync::Arc};
+use blog_os_syscalls::priority::Priority;
+use uuid::Uuid;
+
+use crate::{
+    lock_class,
+    multitask::{
+        Active, Scheduler,
+        lock::{SpinMutex, spin_mutex},
+        wait::WaitQueue,
+    },
+};
+
+type Main = Box<dyn FnOnce() + Send>;
+
+/// Where a thread leaves its result.
+struct Packet<T> {
+    result: SpinMutex<Option<T>>,
+    finished: WaitQueue,
+}
+
+/// Owned permission to wait for a thread and take its result.
+///
+/// Dropping it detaches the thread.
+pub struct JoinHandle<T> {
+    id: Uuid,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// ID of the task running the thread.
+    pub const fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().is_some()
+    }
+
+    /// Sleeps until the thread returned, and gives back its result.
+    pub fn join(self) -> T {
+        self.packet
+            .finished
+            .wait_for(|| self.packet.result.lock().take())
+    }
+}
+
+/// Spawns a kernel thread running `f`.
+pub fn spawn<F, T, S>(f: F, name: S) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+    S: Into<Cow<'static, str>>,
+{
+    spawn_with_priority(f, name, Priority::DEFAULT)
+}
+
+/// Spawns a kernel thread running `f`, scheduled with `priority`.
+pub fn spawn_with_priority<F, T, S>(f: F, name: S, priority: Priority) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+    S: Into<Cow<'static, str>>,
+{
+    let packet = Arc::new(Packet {
+        result: spin_mutex(lock_class!(), None),
+        finished: WaitQueue::new(),
+    });
+
+    let their_packet = packet.clone();
+    let main: Main = Box::new(move || {
+        let result = f();
+        *their_packet.result.lock() = Some(result);
+        their_packet.finished.wake_all();
+    });
+
+    // Boxed again for a thin pointer, which the new task gets as its argument
+    let arg = Box::into_raw(Box::new(main)).cast();
+    let id = Active::create_task(thread_start, arg, name.into(), priority);
+
+    JoinHandle { id, packet }
+}
+
+extern "C" fn thread_start(arg: *mut ()) {
+    // Safety: `spawn_with_priority` passes a boxed `Main`, which only this task takes back
+    let main = unsafe { Box::from_raw(arg.cast::<Main>()) };
+    main();
+}
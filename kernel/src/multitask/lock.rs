@@ -1,9 +1,13 @@
 use lock_api::{GuardSend, RawMutex};
 
-use crate::multitask::{TaskId, get_current_task_id};
+use crate::{
+    lockdep::{self, Class, Kind, Tracked},
+    multitask::{TaskId, get_current_task_id},
+};
 
 pub struct ReentrantRawMutex {
     inner: spin::Mutex<ReentrantInner>,
+    class: &'static Class,
 }
 
 struct ReentrantInner {
@@ -12,12 +16,13 @@ struct ReentrantInner {
 }
 
 impl ReentrantRawMutex {
-    pub const fn new() -> Self {
+    pub const fn new(class: &'static Class) -> Self {
         Self {
             inner: spin::Mutex::new(ReentrantInner {
                 owner: None,
                 depth: 0,
             }),
+            class,
         }
     }
 
@@ -37,14 +42,8 @@ impl ReentrantRawMutex {
     }
 }
 
-impl Default for ReentrantRawMutex {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 unsafe impl RawMutex for ReentrantRawMutex {
-    const INIT: Self = Self::new();
+    const INIT: Self = panic!("reentrant locks have a class, create them with `reentrant_mutex`");
 
     type GuardMarker = GuardSend;
 
@@ -54,32 +53,36 @@ unsafe impl RawMutex for ReentrantRawMutex {
         let me = get_current_task_id();
         // println!("[INFO][LOCK] For id {me:?}");
 
+        {
+            let mut guard = self.inner.lock();
+            if guard.owner == Some(me) {
+                // Reentrant lock by same thread: increase depth
+                guard.depth = guard
+                    .depth
+                    .checked_add(1)
+                    .expect("reentrant depth overflow");
+                return;
+            }
+        }
+
+        // Only this task could make itself the owner, so it may have to wait from here on
+        lockdep::check(self.class.key(), Kind::Spin);
         loop {
             {
                 let mut guard = self.inner.lock();
-                match guard.owner {
-                    None => {
-                        // nobody holds it -> take ownership
-                        guard.owner = Some(me);
-                        guard.depth = 1;
-                        // return guard (we drop the spin::Mutex guard but keep logical ownership)
-                        return;
-                    }
-                    Some(owner) if owner == me => {
-                        // Reentrant lock by same thread: increase depth
-                        guard.depth = guard
-                            .depth
-                            .checked_add(1)
-                            .expect("reentrant depth overflow");
-                        return;
-                    }
-                    _ => {
-                        // someone else holds it, drop inner guard and spin
-                    }
+                if guard.owner.is_none() {
+                    // nobody holds it -> take ownership
+                    guard.owner = Some(me);
+                    guard.depth = 1;
+                    drop(guard);
+                    lockdep::acquire(self.class.key(), Kind::Spin);
+                    // return guard (we drop the spin::Mutex guard but keep logical ownership)
+                    return;
                 }
+                // someone else holds it, drop inner guard and spin
             }
-            // Optional: call yield or schedule; fallback to spin loop.
-            x86_64::instructions::hlt();
+            // The owner may run on another CPU, so don't wait for an interrupt here
+            core::hint::spin_loop();
         }
     }
 
@@ -93,6 +96,8 @@ unsafe impl RawMutex for ReentrantRawMutex {
             None => {
                 guard.owner = Some(me);
                 guard.depth = 1;
+                drop(guard);
+                lockdep::acquire(self.class.key(), Kind::Try);
                 true
             }
             Some(owner) if owner == me => {
@@ -120,6 +125,8 @@ unsafe impl RawMutex for ReentrantRawMutex {
         guard.depth -= 1;
         if guard.depth == 0 {
             guard.owner = None;
+            drop(guard);
+            lockdep::release(self.class.key());
         }
         // println!("[INFO][LOCK] Unlocked depth: {}", guard.depth);
     }
@@ -159,3 +166,33 @@ unsafe impl RawMutex for ReentrantRawMutex {
 // }
 
 pub type ReentrantMutex<T> = lock_api::Mutex<ReentrantRawMutex, T>;
+
+/// A spinning mutex checked by the [lock dependency checker](crate::lockdep).
+pub type SpinMutex<T> = lock_api::Mutex<Tracked<spin::Mutex<()>>, T>;
+pub type SpinMutexGuard<'a, T> = lock_api::MutexGuard<'a, Tracked<spin::Mutex<()>>, T>;
+/// A spinning reader-writer lock checked by the [lock dependency checker](crate::lockdep).
+pub type SpinRwLock<T> = lock_api::RwLock<Tracked<spin::RwLock<()>>, T>;
+
+/// A [`ReentrantMutex`] of the lock class `class`, declared with [`lock_class!`](crate::lock_class).
+pub const fn reentrant_mutex<T>(class: &'static Class, value: T) -> ReentrantMutex<T> {
+    ReentrantMutex::from_raw(ReentrantRawMutex::new(class), value)
+}
+
+/// A [`SpinMutex`] of the lock class `class`, declared with [`lock_class!`](crate::lock_class).
+pub const fn spin_mutex<T>(class: &'static Class, value: T) -> SpinMutex<T> {
+    SpinMutex::from_raw(Tracked::new(spin::Mutex::new(()), class), value)
+}
+
+/// A [`SpinRwLock`] of the lock class `class`, declared with [`lock_class!`](crate::lock_class).
+pub const fn spin_rwlock<T>(class: &'static Class, value: T) -> SpinRwLock<T> {
+    SpinRwLock::from_raw(Tracked::new(spin::RwLock::new(()), class), value)
+}
+
+/// Locks `mutex` while another lock of its class is held, see [`Class::nested`].
+pub fn lock_nested<T>(mutex: &SpinMutex<T>) -> SpinMutexGuard<'_, T> {
+    // Safety: the raw lock is only used to take the lock, which the guard then owns
+    unsafe {
+        mutex.raw().lock_nested();
+        mutex.make_guard_unchecked()
+    }
+}
@@ -4,16 +4,20 @@ use alloc::{
     borrow::Cow,
     collections::BTreeSet,
     sync::{Arc, Weak},
+    vec::Vec,
 };
+use blog_os_syscalls::priority::Priority;
 use log::{debug, info, warn};
-use spin::{
-    Lazy, Once,
-    lock_api::{Mutex, RwLock},
-};
+use spin::{Lazy, Once};
+use uuid::Uuid;
 use x86_64::{VirtAddr, registers::control::Cr3};
 
 use crate::{
+    lock_class,
+    lockdep::HeldLocks,
     multitask::{
+        self, SleepKind, SleepingTask,
+        lock::{SpinMutex as Mutex, SpinRwLock as RwLock, lock_nested, spin_mutex, spin_rwlock},
         switching::SwitchData,
         task::{self, Context, TaskControlBlock, free_task},
         task_switch,
@@ -42,7 +46,7 @@ static TASKS: Lazy<Mutex<BTreeSet<Arc<TaskControlBlock<RoundRobinData>>>>> = Laz
     set.insert(Arc::new_cyclic(|w| TaskControlBlock {
         id: uuid_v4(),
         name: "init".into(),
-        context: Mutex::new(Context {
+        context: Context {
             stack_pointer: VirtAddr::zero(),
             cr3: Cr3::read(),
             stack: None,
@@ -52,15 +56,17 @@ static TASKS: Lazy<Mutex<BTreeSet<Arc<TaskControlBlock<RoundRobinData>>>>> = Laz
                 dealloc: None,
                 time: 0,
             },
-        }),
+        }
+        .into_lock(),
+        held_locks: HeldLocks::new(),
     }));
 
-    Mutex::new(set)
+    spin_mutex(lock_class!(), set)
 });
 
 /// The currently running task.
 static CURRENT_TASK: Lazy<RwLock<Arc<TaskControlBlock<RoundRobinData>>>> =
-    Lazy::new(|| RwLock::new(TASKS.lock().first().unwrap().clone()));
+    Lazy::new(|| spin_rwlock(lock_class!(), TASKS.lock().first().unwrap().clone()));
 
 const TIME_LIMIT: usize = 100;
 
@@ -112,14 +118,20 @@ pub fn switch_fn() -> SwitchData<RoundRobinData> {
     }
 }
 
+/// Nothing to finish after a switch, the switch function already did the bookkeeping.
+extern "C" fn after_switch() {}
+
 fn create_cyclic_task<S: Into<Cow<'static, str>>>(
-    entry: extern "C" fn(),
+    entry: extern "C" fn(*mut ()),
+    arg: *mut (),
     name: S,
 ) -> Arc<TaskControlBlock<RoundRobinData>> {
     task::create_cyclic_task(
         entry,
+        arg,
         name,
-        task_exit,
+        multitask::task_exit,
+        after_switch,
         || {
             let _ = TASKS.is_locked();
             let _ = CURRENT_TASK.is_locked();
@@ -134,9 +146,10 @@ fn create_cyclic_task<S: Into<Cow<'static, str>>>(
 }
 
 /// Public task creation function; inserts the task after the current one.
-pub fn create_task(entry: extern "C" fn(), name: &'static str) {
+pub fn create_task(entry: extern "C" fn(*mut ()), arg: *mut (), name: Cow<'static, str>) -> Uuid {
     x86_64::instructions::interrupts::without_interrupts(|| {
-        let tcb = create_cyclic_task(entry, name);
+        let tcb = create_cyclic_task(entry, arg, name);
+        let id = tcb.id;
 
         {
             let mut tasks = TASKS.lock();
@@ -154,7 +167,8 @@ pub fn create_task(entry: extern "C" fn(), name: &'static str) {
         }
 
         info!("Task creation finished");
-    });
+        id
+    })
 }
 
 /// Special task for performing cleanup of dead tasks.
@@ -164,7 +178,7 @@ static TASK_DEALLOC: Once<Arc<TaskControlBlock<RoundRobinData>>> = Once::new();
 pub fn init() {
     info!("Initializing mustitasking");
     x86_64::instructions::interrupts::without_interrupts(|| {
-        let dealloc = create_cyclic_task(task_dealloc, "dealloc");
+        let dealloc = create_cyclic_task(task_dealloc, core::ptr::null_mut(), "dealloc");
         TASKS.lock().insert(dealloc.clone());
         TASK_DEALLOC.call_once(|| dealloc);
         info!("Initialized mustitasking");
@@ -208,7 +222,7 @@ pub extern "C" fn task_exit() -> ! {
 }
 
 /// Task dedicated to freeing task resources.
-extern "C" fn task_dealloc() {
+extern "C" fn task_dealloc(_: *mut ()) {
     loop {
         if let Some(dealloc_ptr) = TASK_DEALLOC.get() {
             let mut dealloc_ptr_lock = dealloc_ptr.context.lock();
@@ -225,7 +239,7 @@ extern "C" fn task_dealloc() {
                 // Redirect tasks that pointed to the now-dead task
                 for t in tasks.iter() {
                     if t != dealloc_ptr {
-                        let mut lock = t.context.lock();
+                        let mut lock = lock_nested(&t.context);
                         if lock
                             .scheduler_data
                             .next_task
@@ -266,3 +280,97 @@ pub fn try_get_current_task() -> Option<Arc<TaskControlBlock<RoundRobinData>>> {
         None
     }
 }
+
+/// Runs the tasks in turn for `TIME_LIMIT` switches each, on the bootstrap processor only.
+///
+/// There are no sleep queues: sleeping tasks stay in the rotation, so sleeping only yields and
+/// callers have to check their wake condition again. Priorities aren't supported.
+pub struct RoundRobin;
+
+impl multitask::Scheduler for RoundRobin {
+    const NAME: &'static str = "round robin";
+    type Data = RoundRobinData;
+    const SMP: bool = false;
+
+    fn init() {
+        init();
+    }
+
+    fn switch_fn() -> SwitchData<RoundRobinData> {
+        switch_fn()
+    }
+
+    extern "C" fn after_switch() {
+        after_switch();
+    }
+
+    /// Every task shares the CPU equally, the priority is ignored
+    fn create_task(
+        entry: extern "C" fn(*mut ()),
+        arg: *mut (),
+        name: Cow<'static, str>,
+        _: Priority,
+    ) -> Uuid {
+        create_task(entry, arg, name)
+    }
+
+    extern "C" fn task_exit() -> ! {
+        task_exit()
+    }
+
+    fn sleep(_: SleepKind) {
+        Self::request_reschedule();
+        task_switch();
+    }
+
+    fn wake(_: &Uuid) {}
+
+    /// Time is counted in switches instead
+    fn tick() {}
+
+    fn request_reschedule() {
+        if let Some(current) = try_get_current_task() {
+            current.context.lock().scheduler_data.time = TIME_LIMIT;
+        }
+    }
+
+    fn try_get_current_task() -> Option<Arc<TaskControlBlock<RoundRobinData>>> {
+        try_get_current_task()
+    }
+
+    fn locking_get_current_task() -> Option<Arc<TaskControlBlock<RoundRobinData>>> {
+        INITIALIZED
+            .load(core::sync::atomic::Ordering::Acquire)
+            .then(get_current_task)
+    }
+
+    fn tasks() -> Vec<(Uuid, Cow<'static, str>)> {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            TASKS
+                .lock()
+                .iter()
+                .map(|t| (t.id, t.name.clone()))
+                .collect()
+        })
+    }
+
+    fn find_task(id: &Uuid) -> Option<Arc<TaskControlBlock<RoundRobinData>>> {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            TASKS.lock().iter().find(|t| t.id == *id).cloned()
+        })
+    }
+
+    fn get_priority(id: &Uuid) -> Option<Priority> {
+        Self::tasks()
+            .iter()
+            .any(|(x, _)| x == id)
+            .then_some(Priority::DEFAULT)
+    }
+
+    fn set_priority(_: &Uuid, _: Priority) -> bool {
+        false
+    }
+
+    /// Tasks never leave the ring to sleep
+    fn for_each_sleeping(_: impl FnMut(&SleepingTask)) {}
+}
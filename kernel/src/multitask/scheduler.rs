@@ -1,51 +1,84 @@
-use core::{
-    num::{NonZeroUsize, Wrapping},
-    sync::atomic::AtomicBool,
-};
+use core::sync::atomic::AtomicBool;
 
 use alloc::{
     borrow::Cow,
     collections::{btree_map::BTreeMap, btree_set::BTreeSet, vec_deque::VecDeque},
-    sync::{Arc, Weak},
+    format,
+    sync::Arc,
+    vec::Vec,
 };
+use blog_os_eevdf::{Entity, Task};
+use blog_os_syscalls::priority::Priority;
 use log::{debug, info};
-use spin::{
-    Once,
-    lock_api::{Mutex, RwLock},
-};
+use spin::Once;
 use uuid::Uuid;
 use x86_64::{VirtAddr, registers::control::Cr3};
 
 use crate::{
+    interrupts::timer,
+    lock_class,
+    lockdep::HeldLocks,
     multitask::{
+        self, SleepKind, SleepingTask,
+        lock::{SpinRwLock as RwLock, spin_rwlock},
         switching::SwitchData,
         task::{Context, TaskControlBlock, create_cyclic_task, free_task},
         task_switch,
     },
     rand::uuid_v4,
+    smp,
 };
 
 #[derive(Debug)]
 pub struct SchedulerData {
     dying: bool,
     sleeping: bool,
-    priority: NonZeroUsize,
-    vruntime: Wrapping<usize>,
-    deadline: Wrapping<usize>,
+    /// Uptime in milliseconds when a bounded sleep started
+    slept_at: Option<u64>,
+    entity: Entity,
+}
+
+impl SchedulerData {
+    fn new(class: Priority) -> Self {
+        Self {
+            dying: false,
+            sleeping: false,
+            slept_at: None,
+            entity: Entity::new(class),
+        }
+    }
+}
+
+impl Task for TaskControlBlock<SchedulerData> {
+    fn with_entity<R>(&self, f: impl FnOnce(&mut Entity) -> R) -> R {
+        f(&mut self.context.lock().scheduler_data.entity)
+    }
 }
 
+/// Run queue of a single CPU.
 struct Scheduler {
     current: RwLock<Arc<TaskControlBlock<SchedulerData>>>,
-    last: RwLock<Weak<TaskControlBlock<SchedulerData>>>,
+    /// The task switched away from, until `after_switch` queues it again.
+    ///
+    /// Its stack pointer is only saved once the switch happened, so other CPUs mustn't see it
+    /// before that.
+    last: RwLock<Option<Arc<TaskControlBlock<SchedulerData>>>>,
     ready: RwLock<BTreeSet<Arc<TaskControlBlock<SchedulerData>>>>,
-    sleeping: RwLock<BTreeMap<uuid::Uuid, Arc<TaskControlBlock<SchedulerData>>>>,
     waking: RwLock<VecDeque<Arc<TaskControlBlock<SchedulerData>>>>,
     wait_task: Arc<TaskControlBlock<SchedulerData>>,
     needs_reschedule: AtomicBool,
 }
 
+/// Sleeping tasks of all CPUs.
+#[derive(Default)]
+struct Sleeping {
+    tasks: BTreeMap<Uuid, Arc<TaskControlBlock<SchedulerData>>>,
+    /// Tasks woken while they were still on their way to sleep
+    pending_wakes: BTreeSet<Uuid>,
+}
+
 
-extern "C" fn wait() {
+extern "C" fn wait(_: *mut ()) {
     loop {
         get_scheduler().needs_reschedule.store(true, core::sync::atomic::Ordering::Release);
         x86_64::instructions::hlt();
@@ -55,161 +88,156 @@ extern "C" fn wait_exit() -> ! {
     panic!("Unreachable")
 }
 
-static SCHED: Once<Scheduler> = Once::new();
+static SCHEDULERS: Once<Vec<Scheduler>> = Once::new();
+static SLEEPING: Once<RwLock<Sleeping>> = Once::new();
 
+fn schedulers<'a>() -> &'a [Scheduler] {
+    SCHEDULERS.call_once(|| (0..smp::cpu_count()).map(Scheduler::new).collect())
+}
+
+/// The scheduler of the running CPU.
 fn get_scheduler<'a>() -> &'a Scheduler {
-    SCHED.call_once(Scheduler::new)
+    &schedulers()[smp::cpu_index()]
 }
 
-const SLICE: Wrapping<usize> = Wrapping(250); // 500 ticks
-// This constant represents 2^(BITS - 1) for a usize.
-const HALF_RANGE: Wrapping<usize> = Wrapping((usize::MAX / 2) + 1);
+fn sleeping_tasks<'a>() -> &'a RwLock<Sleeping> {
+    SLEEPING.call_once(|| spin_rwlock(lock_class!(), Sleeping::default()))
+}
 
 impl Scheduler {
-    fn new() -> Self {
-        info!("Initializing scheduler");
+    fn new(cpu: usize) -> Self {
+        info!("Initializing scheduler for CPU {cpu}");
+        // Stands for whatever the CPU runs before its first switch
+        let name = if cpu == 0 {
+            "init".into()
+        } else {
+            format!("ap{cpu}").into()
+        };
         let init = Arc::new_cyclic(|_| TaskControlBlock {
             id: uuid_v4(),
-            name: "init".into(),
-            context: Mutex::new(Context {
+            name,
+            context: Context {
                 stack_pointer: VirtAddr::zero(),
                 cr3: Cr3::read(),
                 stack: None,
                 process_info: None,
-                scheduler_data: SchedulerData {
-                    dying: false,
-                    sleeping: false,
-                    priority: NonZeroUsize::new(1).unwrap(),
-                    vruntime: core::num::Wrapping(0),
-                    deadline: core::num::Wrapping(0),
-                },
-            }),
+                scheduler_data: SchedulerData::new(Priority::DEFAULT),
+            }
+            .into_lock(),
+            held_locks: HeldLocks::new(),
         });
-        let init_weak = Arc::downgrade(&init);
-        info!("Initialized scheduler");
+        info!("Initialized scheduler for CPU {cpu}");
         Self {
-            current: RwLock::new(init),
-            last: RwLock::new(init_weak),
-            ready: Default::default(),
-            sleeping: Default::default(),
-            waking: Default::default(),
+            current: spin_rwlock(lock_class!(), init),
+            last: spin_rwlock(lock_class!(), None),
+            ready: spin_rwlock(lock_class!(), BTreeSet::new()),
+            waking: spin_rwlock(lock_class!(), VecDeque::new()),
             needs_reschedule: Default::default(),
             wait_task: create_cyclic_task(
             wait,
+            core::ptr::null_mut(),
             "wait",
             wait_exit,
+            after_switch,
             || {},
-            |_| SchedulerData {
-                dying: false,
-                sleeping: false,
-                priority: NonZeroUsize::new(1).unwrap(),
-                vruntime: core::num::Wrapping(0),
-                deadline: core::num::Wrapping(0),
-            },
+            |_| SchedulerData::new(Priority::DEFAULT),
         )
         }
     }
 
-    fn tick(&self) {        
-        let current = self.current.try_read().unwrap().clone();
-        let mut ctx = current.context.try_lock().unwrap();
-        ctx.scheduler_data.vruntime += 1;
-        let vruntime = ctx.scheduler_data.vruntime;
-        let deadline = ctx.scheduler_data.deadline;
+    fn tick(&self) {
+        // The timer may interrupt code holding these locks, skip the tick in that case
+        let Some(current) = self.current.try_read().map(|x| x.clone()) else {
+            return;
+        };
+        let Some(mut ctx) = current.context.try_lock() else {
+            return;
+        };
+        if ctx.scheduler_data.dying || ctx.scheduler_data.sleeping {
+            return;
+        }
+        let deadline_reached = ctx.scheduler_data.entity.tick();
         drop(ctx);
-        // info!("tick2");
-
-        // 2. Implement the modular comparison: Is vruntime >= deadline?
-        // We use wrapping_sub and check if the difference is <= half the maximum value.
-        // If the difference (vruntime - deadline) is small, vruntime is "ahead".
-        // If the difference is large, it means deadline is "ahead" (vruntime wrapped).
-        let difference = vruntime - deadline;
 
-        if difference < HALF_RANGE {
-            // This condition is true if vruntime >= deadline (in a time-based sense)
-            // If vruntime has passed or reached the deadline, a reschedule is needed.
+        if deadline_reached {
             self.needs_reschedule
                 .store(true, core::sync::atomic::Ordering::Relaxed);
         }
-        // info!("tick4");
     }
 
-    fn reschedule(&self) {
-        info!("Reschedule");
-        let mut ready = self.ready.try_write().unwrap();
-
-        let current = self.current.try_read().unwrap().clone();
-        let current_ctx = current.context.try_lock().unwrap();
+    /// Number of runnable tasks, as far as it can be seen without waiting for a lock.
+    fn load(&self) -> usize {
+        let queued = self.ready.try_read().map_or(0, |x| x.len())
+            + self.waking.try_read().map_or(0, |x| x.len());
+        let running = self
+            .current
+            .try_read()
+            .is_some_and(|x| !Arc::ptr_eq(&x, &self.wait_task));
+        queued + usize::from(running)
+    }
 
-        // get the accumulated priority of all ready processes
-        let mut total_priority: NonZeroUsize = current_ctx.scheduler_data.priority;
-        let mut minimum_vruntime: Wrapping<usize> = current_ctx.scheduler_data.vruntime;
-        drop(current_ctx);
-        for t in ready.iter() {
-            let ctx = t.context.try_lock().unwrap();
-            total_priority = total_priority.saturating_add(ctx.scheduler_data.priority.get());
-            minimum_vruntime = if ctx.scheduler_data.vruntime - minimum_vruntime < HALF_RANGE {
-                minimum_vruntime
-            } else {
-                ctx.scheduler_data.vruntime
-            };
-            drop(ctx);
+    /// Takes a ready task from the busiest CPU, if it has at least two tasks more than this one.
+    fn steal(&self) -> Option<Arc<TaskControlBlock<SchedulerData>>> {
+        let own_load = self.load();
+        let busiest = schedulers()
+            .iter()
+            .enumerate()
+            .filter(|(i, x)| smp::is_online(*i) && !core::ptr::eq(*x, self))
+            .map(|(_, x)| x)
+            .max_by_key(|x| x.load())?;
+        if busiest.load() < own_load + 2 {
+            return None;
         }
+        // Don't wait on another CPU that may be rescheduling itself
+        let task = busiest.ready.try_write()?.pop_first()?;
+        debug!("Stole task {} ({})", task.name, task.id);
+        Some(task)
+    }
 
-        let mut waking = self.waking.try_write().unwrap();
-        while let Some(task) = waking.pop_back() {
-            let mut ctx = task.context.try_lock().unwrap();
-            total_priority = total_priority.saturating_add(ctx.scheduler_data.priority.get());
-            ctx.scheduler_data.vruntime = minimum_vruntime;
-            drop(ctx);
-            ready.insert(task);
+    fn reschedule(&self) {
+        debug!("Reschedule");
+        if self.ready.read().is_empty()
+            && self.waking.read().is_empty()
+            && let Some(task) = self.steal()
+        {
+            // Queued like a woken task, so its vruntime fits this CPU
+            self.waking.write().push_back(task);
         }
-        drop(waking);
 
-        // Compute the deadline for each ready process = vruntime + SLICE * (priority / total_priority)
-        let mut current_ctx = current.context.try_lock().unwrap();
-        // let current_vruntime = current_ctx.scheduler_data.vruntime;
-        current_ctx.scheduler_data.deadline = current_ctx.scheduler_data.vruntime
-            + (SLICE * Wrapping(current_ctx.scheduler_data.priority.get()))
-                / Wrapping(total_priority.get());
-        let current_deadline = current_ctx.scheduler_data.deadline;
-        let not_ready = current_ctx.scheduler_data.dying || current_ctx.scheduler_data.sleeping;
+        // Other CPUs take these locks too, so wait for them
+        let mut ready = self.ready.write();
+
+        let current = self.current.read().clone();
+        let is_wait_task = Arc::ptr_eq(&current, &self.wait_task);
+        let current_ctx = current.context.lock();
+        let not_ready =
+            current_ctx.scheduler_data.dying || current_ctx.scheduler_data.sleeping || is_wait_task;
         drop(current_ctx);
-        let mut soonest_deadline = if not_ready {
-            None
-        } else {
-            Some((current, current_deadline))
-        };
-        debug!("Current task: not_ready: {not_ready} // {soonest_deadline:?}");
-        for t in ready.iter() {
-            let mut ctx = t.context.try_lock().unwrap();
-
-            ctx.scheduler_data.deadline = ctx.scheduler_data.vruntime
-                + (SLICE * Wrapping(ctx.scheduler_data.priority.get()))
-                    / Wrapping(total_priority.get());
-            soonest_deadline = Some(soonest_deadline.map_or_else(
-                || (t.clone(), ctx.scheduler_data.deadline),
-                |(a, b)| {
-                    if ctx.scheduler_data.deadline - b < HALF_RANGE {
-                        (t.clone(), ctx.scheduler_data.deadline)
-                    } else {
-                        (a, b)
-                    }
-                },
-            ));
 
-            drop(ctx);
-        }
-        debug!("Soonest deadline is some? {:?}", soonest_deadline.is_some());
+        let mut waking = self.waking.write();
+        blog_os_eevdf::place(
+            (!is_wait_task).then_some(&current),
+            ready.iter(),
+            waking.iter(),
+        );
+        ready.extend(waking.drain(..));
+        drop(waking);
+
+        let soonest = blog_os_eevdf::pick_next((!not_ready).then_some(&current), ready.iter());
+        debug!(
+            "Current task: not_ready: {not_ready} // next: {:?}",
+            soonest.map(|x| &x.name)
+        );
 
         self.needs_reschedule
             .store(false, core::sync::atomic::Ordering::Release);
 
-        let next = soonest_deadline.map(|(a, _)| a).unwrap_or_else(|| self.wait_task.clone());
+        let next = soonest.cloned().unwrap_or_else(|| self.wait_task.clone());
 
         ready.remove(&next);
+        drop(ready);
 
-        let mut current = self.current.try_write().unwrap();
+        let mut current = self.current.write();
         let last = core::mem::replace(&mut *current, next);
         let no_switch = Arc::ptr_eq(&last, &current);
 
@@ -218,64 +246,97 @@ impl Scheduler {
             last.name, last.id, current.name, current.id
         );
         drop(current);
-        let last_weak = Arc::downgrade(&last);
-        *self.last.try_write().unwrap() = last_weak;
-        let ctx = last.context.try_lock().unwrap();
-        let dying = ctx.scheduler_data.dying;
-        let sleeping = ctx.scheduler_data.sleeping;
-        drop(ctx);
 
+        // Queued again by `after_switch`, once its stack pointer is saved
+        if !no_switch {
+            *self.last.write() = Some(last);
+        }
+    }
+
+    /// Queues a task switched away from, after the switch is complete.
+    fn requeue(&self, last: Arc<TaskControlBlock<SchedulerData>>) {
         if Arc::ptr_eq(&last, &self.wait_task) {
-            // Nothing
-        } else if dying || sleeping {
-            self.sleeping.try_write().unwrap().insert(last.id, last);
-        } else if !no_switch {
-            ready.insert(last);
+            return;
         }
 
-        drop(ready);
-    }
+        let mut ctx = last.context.lock();
+        let dying = ctx.scheduler_data.dying;
+        let sleeping = ctx.scheduler_data.sleeping;
 
-    fn create_task<S: Into<Cow<'static, str>>>(&self, entry: extern "C" fn(), name: S) {
-        let task = create_cyclic_task(
-            entry,
-            name,
-            task_exit,
-            || {},
-            |_| SchedulerData {
-                dying: false,
-                sleeping: false,
-                priority: NonZeroUsize::new(1).unwrap(),
-                vruntime: core::num::Wrapping(0),
-                deadline: core::num::Wrapping(0),
-            },
-        );
+        if dying {
+            drop(ctx);
+            sleeping_tasks().write().pending_wakes.remove(&last.id);
+            // the only ref remaining must be my ref
+            if Arc::strong_count(&last) > 1 {
+                panic!("More than one ref to dying task. This shouldnt have happened")
+            }
+            unsafe { free_task(last) };
+        } else if sleeping {
+            let mut sleeping = sleeping_tasks().write();
+            if sleeping.pending_wakes.remove(&last.id) {
+                ctx.scheduler_data.sleeping = false;
+                ctx.scheduler_data.slept_at = None;
+                drop(sleeping);
+                drop(ctx);
+                self.ready.write().insert(last);
+            } else {
+                drop(ctx);
+                sleeping.tasks.insert(last.id, last);
+            }
+        } else {
+            drop(ctx);
+            self.ready.write().insert(last);
+        }
+    }
+}
 
-        self.ready.write().insert(task);
+/// Queues a new or woken task on the least loaded CPU.
+///
+/// A real-time task preempts the normal task running there on its next timer tick.
+fn enqueue(task: Arc<TaskControlBlock<SchedulerData>>) {
+    let realtime = task
+        .context
+        .lock()
+        .scheduler_data
+        .entity
+        .realtime()
+        .is_some();
+    let scheduler = least_loaded();
+    scheduler.waking.write().push_back(task);
+    if realtime {
+        scheduler
+            .needs_reschedule
+            .store(true, core::sync::atomic::Ordering::Release);
     }
 }
 
-pub fn switch_fn() -> SwitchData<SchedulerData> {
+/// The online CPU with the fewest runnable tasks.
+fn least_loaded<'a>() -> &'a Scheduler {
+    schedulers()
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| smp::is_online(*i))
+        .map(|(_, x)| x)
+        .min_by_key(|x| x.load())
+        .unwrap_or_else(get_scheduler)
+}
+
+fn switch_fn() -> SwitchData<SchedulerData> {
     let scheduler = get_scheduler();
+    let current = scheduler.current.read().clone();
     let not_ready = {
-        let current = scheduler.current.try_read().unwrap().clone();
-        let current_ctx = current.context.try_lock().unwrap();
+        let current_ctx = current.context.lock();
         current_ctx.scheduler_data.dying || current_ctx.scheduler_data.sleeping
     };
-    if !not_ready {
-        scheduler.tick();
-    }
     if not_ready
         || scheduler
             .needs_reschedule
             .load(core::sync::atomic::Ordering::Acquire)
     {
-        scheduler.reschedule(); // Last is written always on reschedule
-        let current = scheduler.last.try_read().unwrap().upgrade().unwrap();
-        let next = scheduler.current.try_read().unwrap().clone();
+        scheduler.reschedule();
+        let next = scheduler.current.read().clone();
         SwitchData { current, next }
     } else {
-        let current = scheduler.current.try_read().unwrap().clone();
         SwitchData {
             next: current.clone(),
             current,
@@ -283,76 +344,279 @@ pub fn switch_fn() -> SwitchData<SchedulerData> {
     }
 }
 
-pub fn after_switch() {
-    // Should the last task die?
+/// Runs on the new task after every switch, including its first one.
+extern "C" fn after_switch() {
     let scheduler = get_scheduler();
-    let mut last_lock = scheduler.last.try_write().unwrap();
-    if let Some(last) = last_lock.upgrade() {
-        let ctx = last.context.try_lock().unwrap();
-        let dying = ctx.scheduler_data.dying;
-        drop(ctx);
-
-        if dying {
-            *last_lock = Weak::new();
-            drop(last_lock);
-            drop(scheduler.sleeping.try_write().unwrap().remove(&last.id));
-            // the only ref remaining must be my ref
-            if Arc::strong_count(&last) > 1 {
-                panic!("More than one ref to dying task. This shouldnt have happened")
-            }
-            unsafe { free_task(last) };
-        }
+    let last = scheduler.last.write().take();
+    if let Some(last) = last {
+        scheduler.requeue(last);
     }
 }
 
-pub fn go_to_sleep() {
+fn go_to_sleep(kind: SleepKind) {
     let scheduler = get_scheduler();
-    scheduler
-        .current
-        .read()
-        .context
-        .lock()
-        .scheduler_data
-        .sleeping = true;
+    let current = scheduler.current.read().clone();
+    let mut ctx = current.context.lock();
+    ctx.scheduler_data.sleeping = true;
+    ctx.scheduler_data.slept_at = (kind == SleepKind::Bounded).then(timer::uptime_ms);
+    drop(ctx);
     task_switch();
 }
 
-pub fn wake(id: &Uuid) {
-    let scheduler = get_scheduler();
-    let mut sleeping = scheduler.sleeping.write();
-    let mut waking = scheduler.waking.write();
-    if let Some(task) = sleeping.remove(id) {
-        waking.push_back(task);
+/// Wakes a sleeping task. A task that is about to sleep doesn't go to sleep at all.
+fn wake(id: &Uuid) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut sleeping = sleeping_tasks().write();
+        let Some(task) = sleeping.tasks.remove(id) else {
+            sleeping.pending_wakes.insert(*id);
+            return;
+        };
+        drop(sleeping);
+
+        let mut ctx = task.context.lock();
+        ctx.scheduler_data.sleeping = false;
+        ctx.scheduler_data.slept_at = None;
+        drop(ctx);
+        enqueue(task);
+    });
+}
+
+/// Accounts a timer tick to the current task, flagging a reschedule once its deadline passed.
+fn tick() {
+    if let Some(scheduler) = SCHEDULERS.get() {
+        scheduler[smp::cpu_index()].tick();
+    }
+}
+
+/// Makes the next task switch pick a new task, even if the current deadline didn't pass.
+fn request_reschedule() {
+    get_scheduler()
+        .needs_reschedule
+        .store(true, core::sync::atomic::Ordering::Release);
+}
+
+fn try_get_current_task() -> Option<Arc<TaskControlBlock<SchedulerData>>> {
+    Some(
+        SCHEDULERS.get()?[smp::cpu_index()]
+            .current
+            .try_read()?
+            .clone(),
+    )
+}
+
+fn locking_get_current_task() -> Option<Arc<TaskControlBlock<SchedulerData>>> {
+    Some(SCHEDULERS.get()?[smp::cpu_index()].current.read().clone())
+}
+
+/// Runs `f` on every task in a bounded sleep, holding its context so that it can't be woken
+/// and switched to meanwhile.
+fn for_each_sleeping(mut f: impl FnMut(&SleepingTask)) {
+    let tasks: Vec<_> = x86_64::instructions::interrupts::without_interrupts(|| {
+        sleeping_tasks().read().tasks.values().cloned().collect()
+    });
+    for task in tasks {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let ctx = task.context.lock();
+            if !ctx.scheduler_data.sleeping {
+                return;
+            }
+            if let Some(since_ms) = ctx.scheduler_data.slept_at {
+                f(&SleepingTask {
+                    id: task.id,
+                    name: task.name.clone(),
+                    since_ms,
+                    stack_pointer: ctx.stack_pointer,
+                });
+            }
+        });
     }
 }
 
-pub fn try_get_current_task() -> Option<Arc<TaskControlBlock<SchedulerData>>> {
-    Some(SCHED.get()?.current.try_read()?.clone())
+/// Runs `f` on every task except the per-CPU wait tasks, with interrupts disabled.
+fn for_each_task(mut f: impl FnMut(&Arc<TaskControlBlock<SchedulerData>>)) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for scheduler in schedulers() {
+            let mut visit = |t: &Arc<TaskControlBlock<SchedulerData>>| {
+                if !Arc::ptr_eq(t, &scheduler.wait_task) {
+                    f(t);
+                }
+            };
+            visit(&scheduler.current.read());
+            if let Some(last) = &*scheduler.last.read() {
+                visit(last);
+            }
+            scheduler.ready.read().iter().for_each(&mut visit);
+            scheduler.waking.read().iter().for_each(&mut visit);
+        }
+        sleeping_tasks().read().tasks.values().for_each(f);
+    });
+}
+
+/// IDs and names of all tasks.
+fn tasks() -> Vec<(Uuid, Cow<'static, str>)> {
+    let mut tasks = Vec::new();
+    for_each_task(|t| tasks.push((t.id, t.name.clone())));
+    tasks
 }
 
-pub fn locking_get_current_task() -> Option<Arc<TaskControlBlock<SchedulerData>>> {
-    Some(SCHED.get()?.current.read().clone())
+fn find_task(id: &Uuid) -> Option<Arc<TaskControlBlock<SchedulerData>>> {
+    let mut found = None;
+    for_each_task(|t| {
+        if t.id == *id {
+            found = Some(t.clone());
+        }
+    });
+    found
 }
 
-pub fn init() {
-    get_scheduler(); // Force initialization
+fn get_priority(id: &Uuid) -> Option<Priority> {
+    let mut priority = None;
+    for_each_task(|t| {
+        if t.id == *id {
+            priority = Some(t.context.lock().scheduler_data.entity.class());
+        }
+    });
+    priority
 }
 
-pub extern "C" fn task_exit() -> ! {
+/// Changes the priority of a task, returning whether it exists.
+///
+/// Every CPU reschedules on its next tick, so that a task raised to real-time runs right away.
+fn set_priority(id: &Uuid, priority: Priority) -> bool {
+    let mut found = false;
+    for_each_task(|t| {
+        if t.id == *id {
+            t.context.lock().scheduler_data.entity.set_class(priority);
+            found = true;
+        }
+    });
+    if found {
+        for scheduler in schedulers() {
+            scheduler
+                .needs_reschedule
+                .store(true, core::sync::atomic::Ordering::Release);
+        }
+    }
+    found
+}
+
+fn init() {
+    schedulers(); // Force initialization
+}
+
+extern "C" fn task_exit() -> ! {
     info!("Ending task");
 
     let current = get_scheduler().current.read().clone();
-    current.context.try_lock().unwrap().scheduler_data.dying = true;
+    current.context.lock().scheduler_data.dying = true;
+    drop(current);
 
     info!("Switching out of dying task");
     task_switch();
     unreachable!();
 }
 
-pub fn create_task<S: Into<Cow<'static, str>>>(entry: extern "C" fn(), name: S) {
-    get_scheduler().create_task(entry, name);
+/// Creates a task on the least loaded CPU.
+fn create_task(
+    entry: extern "C" fn(*mut ()),
+    arg: *mut (),
+    name: Cow<'static, str>,
+    priority: Priority,
+) -> Uuid {
+    let task = create_cyclic_task(
+        entry,
+        arg,
+        name,
+        multitask::task_exit,
+        after_switch,
+        || {},
+        |_| SchedulerData::new(priority),
+    );
+    let id = task.id;
+
+    // Queued like a woken task, so its vruntime fits the CPU
+    x86_64::instructions::interrupts::without_interrupts(|| enqueue(task));
 
     info!("Task creation finished");
+    id
+}
+
+/// Earliest eligible virtual deadline first, with a run queue per CPU.
+pub struct Eevdf;
+
+impl multitask::Scheduler for Eevdf {
+    const NAME: &'static str = "eevdf";
+    type Data = SchedulerData;
+    const SMP: bool = true;
+
+    fn init() {
+        init();
+    }
+
+    fn switch_fn() -> SwitchData<SchedulerData> {
+        switch_fn()
+    }
+
+    extern "C" fn after_switch() {
+        after_switch();
+    }
+
+    fn create_task(
+        entry: extern "C" fn(*mut ()),
+        arg: *mut (),
+        name: Cow<'static, str>,
+        priority: Priority,
+    ) -> Uuid {
+        create_task(entry, arg, name, priority)
+    }
+
+    extern "C" fn task_exit() -> ! {
+        task_exit()
+    }
+
+    fn sleep(kind: SleepKind) {
+        go_to_sleep(kind);
+    }
+
+    fn wake(id: &Uuid) {
+        wake(id);
+    }
+
+    fn tick() {
+        tick();
+    }
+
+    fn request_reschedule() {
+        request_reschedule();
+    }
+
+    fn try_get_current_task() -> Option<Arc<TaskControlBlock<SchedulerData>>> {
+        try_get_current_task()
+    }
+
+    fn locking_get_current_task() -> Option<Arc<TaskControlBlock<SchedulerData>>> {
+        locking_get_current_task()
+    }
+
+    fn tasks() -> Vec<(Uuid, Cow<'static, str>)> {
+        tasks()
+    }
+
+    fn find_task(id: &Uuid) -> Option<Arc<TaskControlBlock<SchedulerData>>> {
+        find_task(id)
+    }
+
+    fn get_priority(id: &Uuid) -> Option<Priority> {
+        get_priority(id)
+    }
+
+    fn set_priority(id: &Uuid, priority: Priority) -> bool {
+        set_priority(id, priority)
+    }
+
+    fn for_each_sleeping(f: impl FnMut(&SleepingTask)) {
+        for_each_sleeping(f);
+    }
 }
 
 // pub fn wake(id: &uuid::Uuid) {
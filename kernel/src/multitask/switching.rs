@@ -4,7 +4,12 @@ use alloc::sync::Arc;
 use log::{debug, info};
 use x86_64::{VirtAddr, instructions::interrupts, registers::control::Cr3};
 
-use crate::{multitask::task::TaskControlBlock, setup::KERNEL_INFO};
+use crate::{
+    lockdep,
+    multitask::{lock, task::TaskControlBlock},
+    setup::KERNEL_INFO,
+    watchdog,
+};
 
 /// Naked assembly function that performs the actual register + stack switching.
 #[unsafe(naked)]
@@ -39,8 +44,12 @@ pub struct SwitchData<Data> {
 /// # Safety
 /// Performs a raw context switch between tasks.
 /// Interrupts MUST be disabled.
-unsafe fn task_switch<Data>(switch_fn: fn() -> SwitchData<Data>, after_switch: fn()) -> bool {
+unsafe fn task_switch<Data>(
+    switch_fn: fn() -> SwitchData<Data>,
+    after_switch: extern "C" fn(),
+) -> bool {
     let SwitchData { current, next } = switch_fn();
+    watchdog::touch();
 
     if Arc::ptr_eq(&current, &next) {
         return false;
@@ -51,7 +60,7 @@ unsafe fn task_switch<Data>(switch_fn: fn() -> SwitchData<Data>, after_switch: f
     debug!("Locked current tcb");
 
     debug!("Locking next tcb");
-    let next_tcb = next.context.lock();
+    let next_tcb = lock::lock_nested(&next.context);
     debug!("Locked next tcb");
 
     // Switch page tables (CR3) if needed
@@ -66,11 +75,12 @@ unsafe fn task_switch<Data>(switch_fn: fn() -> SwitchData<Data>, after_switch: f
             Cr3::write(next_frame, next_flags);
         }
 
-        // Update kernel allocator page table
+        // Locking points the kernel allocator at the new page table, the old one can go if
+        // its process is gone
         let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
         let mem = &mut *lock;
         mem.page_table
-            .set_current_page_table(&mut mem.frame_allocator);
+            .release_page_table(cur_cr3.0, &mut mem.frame_allocator);
         drop(lock)
     }
 
@@ -84,6 +94,8 @@ unsafe fn task_switch<Data>(switch_fn: fn() -> SwitchData<Data>, after_switch: f
     drop(next_tcb);
     drop(current_tcb);
 
+    lockdep::switch_to(&current.held_locks, &next.held_locks);
+
     unsafe {
         __switch_asm(cur_sp_ptr, next_sp_ptr);
     }
@@ -96,6 +108,9 @@ unsafe fn task_switch<Data>(switch_fn: fn() -> SwitchData<Data>, after_switch: f
 }
 
 /// Safe wrapper around `task_switch`, ensuring interrupts are disabled.
-pub fn task_switch_safe<Data>(switch_fn: fn() -> SwitchData<Data>, after_switch: fn()) -> bool {
+pub fn task_switch_safe<Data>(
+    switch_fn: fn() -> SwitchData<Data>,
+    after_switch: extern "C" fn(),
+) -> bool {
     interrupts::without_interrupts(|| unsafe { task_switch(switch_fn, after_switch) })
 }
@@ -0,0 +1,166 @@
+//! Locks that put the waiting task to sleep instead of spinning, built on [`WaitQueue`].
+//!
+//! None of them may be waited on from an interrupt handler. [`Semaphore::release`],
+//! [`Condvar::notify_one`] and [`Condvar::notify_all`] can be called from one.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use lock_api::{GuardSend, RawMutex};
+
+use crate::{
+    lockdep::{self, Class, Kind},
+    multitask::wait::WaitQueue,
+};
+
+pub struct SleepingRawMutex {
+    locked: AtomicBool,
+    queue: WaitQueue,
+    class: &'static Class,
+}
+
+impl SleepingRawMutex {
+    pub const fn new(class: &'static Class) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            queue: WaitQueue::new(),
+            class,
+        }
+    }
+
+    fn try_take(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+unsafe impl RawMutex for SleepingRawMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = panic!("sleeping locks have a class, create them with `mutex`");
+
+    type GuardMarker = GuardSend;
+
+    /// Sleeps until the lock is free.
+    fn lock(&self) {
+        lockdep::check(self.class.key(), Kind::Sleep);
+        self.queue.wait_until(|| self.try_take());
+        lockdep::acquire(self.class.key(), Kind::Sleep);
+    }
+
+    fn try_lock(&self) -> bool {
+        let locked = self.try_take();
+        if locked {
+            lockdep::acquire(self.class.key(), Kind::Try);
+        }
+        locked
+    }
+
+    unsafe fn unlock(&self) {
+        lockdep::release(self.class.key());
+        self.locked.store(false, Ordering::Release);
+        self.queue.wake_one();
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+/// A mutex that sleeps while another task holds it.
+pub type Mutex<T> = lock_api::Mutex<SleepingRawMutex, T>;
+pub type MutexGuard<'a, T> = lock_api::MutexGuard<'a, SleepingRawMutex, T>;
+
+/// A [`Mutex`] of the lock class `class`, declared with [`lock_class!`](crate::lock_class).
+pub const fn mutex<T>(class: &'static Class, value: T) -> Mutex<T> {
+    Mutex::from_raw(SleepingRawMutex::new(class), value)
+}
+
+/// A counting semaphore.
+#[derive(Debug, Default)]
+pub struct Semaphore {
+    count: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Takes a unit without sleeping, returning whether there was one.
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Sleeps until a unit is available and takes it.
+    pub fn acquire(&self) {
+        self.queue.wait_until(|| self.try_acquire());
+    }
+
+    /// Gives back a unit, waking a waiting task.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.queue.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+/// A condition variable, used with a [`Mutex`].
+#[derive(Debug, Default)]
+pub struct Condvar {
+    queue: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// A condition variable for events that may never come, see [`WaitQueue::unbounded`].
+    pub const fn unbounded() -> Self {
+        Self {
+            queue: WaitQueue::unbounded(),
+        }
+    }
+
+    /// Releases the lock behind `guard` and sleeps until notified, then takes it again.
+    ///
+    /// The task may also wake up spuriously, so check the condition in a loop, or use
+    /// [`Self::wait_while`].
+    pub fn wait<T>(&self, guard: &mut MutexGuard<'_, T>) {
+        // Registered before unlocking, so that a notify right after isn't missed
+        let waiter = self.queue.prepare();
+        MutexGuard::unlocked(guard, || waiter.sleep());
+    }
+
+    /// Sleeps as long as `condition` holds for the data behind `guard`.
+    pub fn wait_while<T>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) {
+        while condition(&mut *guard) {
+            self.wait(guard);
+        }
+    }
+
+    pub fn notify_one(&self) -> bool {
+        self.queue.wake_one()
+    }
+
+    pub fn notify_all(&self) -> usize {
+        self.queue.wake_all()
+    }
+}
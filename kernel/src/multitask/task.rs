@@ -2,9 +2,8 @@ use alloc::{
     borrow::Cow,
     sync::{Arc, Weak},
 };
-use core::{hash::Hash, ptr};
+use core::{arch::naked_asm, hash::Hash, ptr};
 use log::info;
-use spin::lock_api::Mutex;
 use uuid::Uuid;
 use x86_64::{
     VirtAddr,
@@ -12,7 +11,15 @@ use x86_64::{
     structures::paging::PhysFrame,
 };
 
-use crate::{process::ProcessInfo, rand::uuid_v4, setup::KERNEL_INFO, stack::SlabStack};
+use crate::{
+    lock_class,
+    lockdep::HeldLocks,
+    multitask::lock::{SpinMutex as Mutex, spin_mutex},
+    process::ProcessInfo,
+    rand::uuid_v4,
+    setup::KERNEL_INFO,
+    stack::SlabStack,
+};
 
 /// Optional task ID type.
 pub type TaskId = Option<Uuid>;
@@ -27,6 +34,8 @@ pub struct TaskControlBlock<Data> {
     pub name: Cow<'static, str>,
     /// Saved CPU state (stack pointer, CR3, etc.).
     pub context: Mutex<Context<Data>>,
+    /// Locks the task holds, for the lock dependency checker.
+    pub held_locks: HeldLocks,
 }
 
 // === Ordering + Hashing for BTreeSet ===
@@ -67,11 +76,37 @@ pub struct Context<Data> {
     pub(super) scheduler_data: Data,
 }
 
-/// Creates a new task whose `next_task` points back to itself.
+impl<Data> Context<Data> {
+    /// Puts the context behind its lock, whose class all task contexts share.
+    pub(super) const fn into_lock(self) -> Mutex<Self> {
+        spin_mutex(lock_class!(), self)
+    }
+}
+
+/// First code run by a new task: finishes the switch into it like `task_switch` would, then
+/// jumps to the entry point, which returns into `task_exit`.
+///
+/// Expects the entry point in r12, the after switch hook in r13 and the entry point's argument
+/// in r14.
+#[unsafe(naked)]
+unsafe extern "C" fn task_start() {
+    naked_asm!(
+        // The stack is aligned for a return address, realign for the call
+        "sub rsp, 8",
+        "call r13",
+        "add rsp, 8",
+        "mov rdi, r14",
+        "jmp r12",
+    )
+}
+
+/// Creates a new task whose `next_task` points back to itself, running `entry(arg)`.
 pub(super) fn create_cyclic_task<S: Into<Cow<'static, str>>, Data>(
-    entry: extern "C" fn(),
+    entry: extern "C" fn(*mut ()),
+    arg: *mut (),
     name: S,
     task_exit: extern "C" fn() -> !,
+    after_switch: extern "C" fn(),
     load: impl FnOnce(),
     data: impl FnOnce(&Weak<TaskControlBlock<Data>>) -> Data,
 ) -> Arc<TaskControlBlock<Data>> {
@@ -85,13 +120,13 @@ pub(super) fn create_cyclic_task<S: Into<Cow<'static, str>>, Data>(
     let mut stack_ptr = stack.top().as_mut_ptr::<*const ()>();
 
     let words = [
-        ptr::null(), // rbp
-        ptr::null(), // rbx
-        ptr::null(), // r12
-        ptr::null(), // r13
-        ptr::null(), // r14
-        ptr::null(), // r15
-        entry as *const (),
+        ptr::null(),               // rbp
+        ptr::null(),               // rbx
+        entry as *const (),        // r12
+        after_switch as *const (), // r13
+        arg.cast_const(),          // r14
+        ptr::null(),               // r15
+        task_start as *const (),
         task_exit as *const (),
     ];
 
@@ -101,16 +136,27 @@ pub(super) fn create_cyclic_task<S: Into<Cow<'static, str>>, Data>(
         unsafe { core::ptr::write_volatile(stack_ptr, w) };
     }
 
+    // Not the page table of the creator, which may be a process that exits first
+    let kernel_p4 = KERNEL_INFO
+        .get()
+        .unwrap()
+        .alloc_kinf
+        .lock()
+        .page_table
+        .kernel_p4_frame();
+
     Arc::new_cyclic(|weak_self| TaskControlBlock {
         name,
         id: uuid_v4(),
-        context: Mutex::new(Context {
+        context: Context {
             stack_pointer: VirtAddr::from_ptr(stack_ptr),
-            cr3: Cr3::read(),
+            cr3: (kernel_p4, Cr3::read().1),
             stack: Some(stack),
             process_info: None,
             scheduler_data: data(weak_self),
-        }),
+        }
+        .into_lock(),
+        held_locks: HeldLocks::new(),
     })
 }
 
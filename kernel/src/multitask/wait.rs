@@ -0,0 +1,135 @@
+//! Queues of tasks sleeping until something happens.
+//!
+//! A task registers itself before checking its condition one last time and only then goes to
+//! sleep. A wake in between isn't lost: the scheduler doesn't let a task that was woken on its
+//! way to sleep go to sleep at all. The queue itself is only locked with interrupts disabled,
+//! so interrupt handlers can wake tasks.
+
+use alloc::collections::vec_deque::VecDeque;
+use uuid::Uuid;
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    lock_class,
+    multitask::{
+        self, SleepKind, get_current_task_id,
+        lock::{SpinMutex, spin_mutex},
+    },
+};
+
+#[derive(Debug)]
+pub struct WaitQueue {
+    waiters: SpinMutex<VecDeque<Uuid>>,
+    /// Whether waiters may wait forever, so the watchdog leaves them alone
+    unbounded: bool,
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: spin_mutex(lock_class!(), VecDeque::new()),
+            unbounded: false,
+        }
+    }
+
+    /// A queue for events that may never come, like input, whose waiters aren't hung tasks.
+    pub const fn unbounded() -> Self {
+        let mut queue = Self::new();
+        queue.unbounded = true;
+        queue
+    }
+
+    fn with_waiters<R>(&self, f: impl FnOnce(&mut VecDeque<Uuid>) -> R) -> R {
+        without_interrupts(|| f(&mut self.waiters.lock()))
+    }
+
+    /// Adds the current task to the queue, until the returned [`Waiter`] is dropped.
+    ///
+    /// Check the condition being waited for after this, then [`Waiter::sleep`].
+    pub fn prepare(&self) -> Waiter<'_> {
+        let id = get_current_task_id();
+        if let Some(id) = id {
+            self.with_waiters(|waiters| waiters.push_back(id));
+        }
+        Waiter { queue: self, id }
+    }
+
+    /// Sleeps until `f` returns something.
+    pub fn wait_for<R>(&self, mut f: impl FnMut() -> Option<R>) -> R {
+        loop {
+            if let Some(r) = f() {
+                return r;
+            }
+            let waiter = self.prepare();
+            if let Some(r) = f() {
+                return r;
+            }
+            waiter.sleep();
+        }
+    }
+
+    /// Sleeps until `condition` holds.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        self.wait_for(|| condition().then_some(()));
+    }
+
+    /// Wakes the task waiting the longest, returning whether there was one.
+    pub fn wake_one(&self) -> bool {
+        let id = self.with_waiters(VecDeque::pop_front);
+        if let Some(id) = id {
+            multitask::wake(&id);
+        }
+        id.is_some()
+    }
+
+    /// Wakes every waiting task, returning how many there were.
+    pub fn wake_all(&self) -> usize {
+        let ids = self.with_waiters(core::mem::take);
+        for id in &ids {
+            multitask::wake(id);
+        }
+        ids.len()
+    }
+}
+
+/// The current task's place in a [`WaitQueue`].
+#[must_use]
+pub struct Waiter<'a> {
+    queue: &'a WaitQueue,
+    id: Option<Uuid>,
+}
+
+impl Waiter<'_> {
+    /// Sleeps until woken through the queue, or spuriously.
+    ///
+    /// Before the scheduler runs there is nothing to switch to, so this only spins once.
+    pub fn sleep(&self) {
+        if self.id.is_some() {
+            multitask::go_to_sleep(if self.queue.unbounded {
+                SleepKind::Unbounded
+            } else {
+                SleepKind::Bounded
+            });
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.queue.with_waiters(|waiters| {
+                if let Some(idx) = waiters.iter().position(|x| *x == id) {
+                    waiters.remove(idx);
+                }
+            });
+        }
+    }
+}
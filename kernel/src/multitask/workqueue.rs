@@ -0,0 +1,41 @@
+//! Deferred work that may sleep, run in order by the `kworker` kernel thread.
+
+use alloc::{boxed::Box, collections::vec_deque::VecDeque};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    lock_class,
+    multitask::{
+        kthread,
+        lock::{SpinMutex, spin_mutex},
+        wait::WaitQueue,
+    },
+};
+
+type Work = Box<dyn FnOnce() + Send>;
+
+/// Also filled from tasklets, so only locked with interrupts disabled
+static QUEUE: SpinMutex<VecDeque<Work>> = spin_mutex(lock_class!(), VecDeque::new());
+static QUEUED: WaitQueue = WaitQueue::unbounded();
+
+/// Starts the worker thread.
+pub fn init() {
+    kthread::spawn(worker, "kworker");
+}
+
+/// Queues `work` for the worker thread.
+///
+/// This allocates, so interrupt handlers should leave it to a
+/// [tasklet](crate::interrupts::softirq::Tasklet).
+pub fn queue_work<F: FnOnce() + Send + 'static>(work: F) {
+    let work: Work = Box::new(work);
+    without_interrupts(|| QUEUE.lock().push_back(work));
+    QUEUED.wake_one();
+}
+
+fn worker() {
+    loop {
+        let work = QUEUED.wait_for(|| without_interrupts(|| QUEUE.lock().pop_front()));
+        work();
+    }
+}
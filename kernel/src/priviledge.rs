@@ -28,9 +28,25 @@ unsafe fn jump_to_ring3(
     user_data: SegmentSelector,
     user_code: SegmentSelector,
 ) -> ! {
+    #[cfg(not(feature = "kpti"))]
     use core::arch::asm;
 
     interrupts::disable(); // Interrupts disabled during the switch
+    #[cfg(feature = "kpti")]
+    unsafe {
+        use x86_64::{registers::rflags::RFlags, structures::idt::InterruptStackFrameValue};
+
+        let frame = InterruptStackFrameValue::new(
+            entry,
+            user_code,
+            x86_64::registers::rflags::read() | RFlags::INTERRUPT_FLAG,
+            stack_top,
+            user_data,
+        );
+        // Userspace starts with zeroed registers
+        crate::interrupts::stub::return_to_user(&core::mem::zeroed(), &frame)
+    }
+    #[cfg(not(feature = "kpti"))]
     unsafe {
         asm!(
             "push {user_data}",     // SS
@@ -1,21 +1,22 @@
 use core::{
     mem::ManuallyDrop,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
 };
 
 use alloc::sync::Arc;
 use api_utils::cglue::arc::CArcSome;
-use blog_os_vfs::api::{
-    IOError,
-    file::{File, cglue_file::FileBox},
-    inode::{INode, cglue_inode::INodeBox},
-    path::Path,
+use blog_os_vfs::{
+    INodeRef,
+    api::{
+        IOError,
+        file::{File, cglue_file::FileBox},
+        inode::{INode, cglue_inode::INodeBox},
+        path::{Path, PathBuf},
+    },
 };
 use kernel_utils::{aligned_bytes::AlignedBytes, simple_slotmap::SimpleSlotmap};
 use log::{debug, info, warn};
 use shared_fs::FileType;
-use spin::lock_api::RwLock;
 use thiserror::Error;
 use x86_64::VirtAddr;
 
@@ -23,12 +24,18 @@ use crate::{
     KERNEL_INFO,
     elf::{ElfHeader, ElfLoadError, LoadedProgram, load_user_program},
     fs::VFS,
+    lock_class,
     memory::multi_l4_paging::PageTableToken,
-    multitask::{set_current_process_info, try_get_current_task},
+    multitask::{
+        change_current_process_info,
+        lock::{SpinRwLock, spin_rwlock},
+        set_current_process_info, try_get_current_process_info, try_get_current_task,
+    },
     priviledge::jmp_to_usermode,
     rand::uuid_v4,
 };
 
+pub mod pipe;
 pub mod stdio;
 
 #[derive(Debug, Clone, Default)]
@@ -36,6 +43,28 @@ pub enum ProcessStatus {
     #[default]
     Ok,
     Ending(u64),
+    /// Ended by the kernel
+    Killed(Signal),
+}
+
+impl ProcessStatus {
+    /// The code the process ends with, if it is ending.
+    pub const fn exit_code(&self) -> Option<u64> {
+        match self {
+            Self::Ok => None,
+            Self::Ending(code) => Some(*code),
+            // As shells report processes ended by a signal
+            Self::Killed(signal) => Some(128 + *signal as u64),
+        }
+    }
+}
+
+/// Reasons for the kernel to end a process, numbered like their POSIX signals.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Signal {
+    /// Invalid memory access
+    Segv = 11,
 }
 
 // #[derive(Debug, Clone)]
@@ -71,19 +100,26 @@ pub enum ProcessStatus {
 // }
 
 pub struct OpenFile {
+    inode_ref: Option<INodeRef>,
     inode: Option<CArcSome<INodeBox<'static>>>,
     file: ManuallyDrop<FileBox<'static>>,
 }
 
 impl OpenFile {
-    pub const fn new(inode: CArcSome<INodeBox<'static>>, file: FileBox<'static>) -> Self {
+    pub const fn new(
+        inode_ref: INodeRef,
+        inode: CArcSome<INodeBox<'static>>,
+        file: FileBox<'static>,
+    ) -> Self {
         Self {
+            inode_ref: Some(inode_ref),
             inode: Some(inode),
             file: ManuallyDrop::new(file),
         }
     }
     pub const fn new_no_inode(file: FileBox<'static>) -> Self {
         Self {
+            inode_ref: None,
             inode: None,
             file: ManuallyDrop::new(file),
         }
@@ -92,6 +128,16 @@ impl OpenFile {
     pub const fn inode(&self) -> Option<&CArcSome<INodeBox<'static>>> {
         self.inode.as_ref()
     }
+
+    /// Where [`Self::inode`] lives in the [`VFS`], used to resolve paths relative to this file.
+    pub const fn inode_ref(&self) -> Option<INodeRef> {
+        self.inode_ref
+    }
+
+    /// Puts the file behind its lock, whose class all open files share.
+    pub const fn into_lock(self) -> SpinRwLock<Self> {
+        spin_rwlock(lock_class!(), self)
+    }
 }
 
 impl Deref for OpenFile {
@@ -119,9 +165,13 @@ pub struct ProcessInfo {
     status: ProcessStatus,
     id: uuid::Uuid,
     original: uuid::Uuid,
-    pt_token: Option<Arc<PageTableToken>>,
+    /// Keeps the address space of the process alive, torn down once every clone is gone
+    pt_token: Arc<PageTableToken>,
     // stdout: Stdout,
-    files: Arc<RwLock<SimpleSlotmap<Arc<RwLock<OpenFile>>>>>,
+    files: Arc<SpinRwLock<SimpleSlotmap<Arc<SpinRwLock<OpenFile>>>>>,
+    /// Working directory, where relative paths given with `AT_FDCWD` start. Absolute and
+    /// normalized.
+    cwd: Arc<SpinRwLock<PathBuf>>,
 }
 
 impl core::fmt::Debug for ProcessInfo {
@@ -155,6 +205,7 @@ impl Clone for ProcessInfo {
             original: self.original,
             pt_token: self.pt_token.clone(),
             files: self.files.clone(),
+            cwd: self.cwd.clone(),
         }
     }
 }
@@ -171,29 +222,18 @@ impl Drop for ProcessInfo {
     }
 }
 
-static FIRST_PROC: AtomicBool = AtomicBool::new(true);
-
 impl ProcessInfo {
     pub fn new(prog: &[u8]) -> Result<Self, ElfLoadError> {
         let id = uuid_v4();
-        let token;
-        if FIRST_PROC
-            .compare_exchange(true, false, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            info!("[{id}] Not first proc, creating a new l4 table");
-            debug!(
-                "[{id}] Before CR3: {:?}",
-                x86_64::registers::control::Cr3::read()
-            );
-
-            token = Some(KERNEL_INFO.get().unwrap().create_p4_table_and_switch());
-
-            info!("[{id}] CR3: {:?}", x86_64::registers::control::Cr3::read());
-        } else {
-            info!("[{id}] Creating first proc, not creating a new l4 table");
-            token = None;
-        }
+        info!("[{id}] Creating a new l4 table");
+        debug!(
+            "[{id}] Before CR3: {:?}",
+            x86_64::registers::control::Cr3::read()
+        );
+
+        let token = KERNEL_INFO.get().unwrap().create_p4_table_and_switch();
+
+        info!("[{id}] CR3: {:?}", x86_64::registers::control::Cr3::read());
 
         debug!("[{id}] Loading elf");
         let prog = load_user_program(prog)?;
@@ -205,7 +245,8 @@ impl ProcessInfo {
             id,
             original: id,
             pt_token: token,
-            files: Default::default(),
+            files: Arc::new(spin_rwlock(lock_class!(), SimpleSlotmap::default())),
+            cwd: Arc::new(spin_rwlock(lock_class!(), PathBuf::root())),
         })
     }
 
@@ -257,9 +298,66 @@ impl ProcessInfo {
         self.id
     }
 
-    pub const fn files(&self) -> &Arc<RwLock<SimpleSlotmap<Arc<RwLock<OpenFile>>>>> {
+    pub const fn files(&self) -> &Arc<SpinRwLock<SimpleSlotmap<Arc<SpinRwLock<OpenFile>>>>> {
         &self.files
     }
+
+    pub const fn cwd(&self) -> &Arc<SpinRwLock<PathBuf>> {
+        &self.cwd
+    }
+}
+
+/// Ends the process of the current task with `status` and closes its files. The task itself ends
+/// at its next [`reap_exited`].
+pub fn end_current_process(status: ProcessStatus) {
+    // Closing a file may sleep, which isn't allowed while the task context is locked
+    let files = change_current_process_info(|p| {
+        let Some(pinf) = p else {
+            return SimpleSlotmap::default();
+        };
+        *pinf.status_mut() = status.clone();
+        core::mem::take(&mut *pinf.files().write())
+    });
+    for (fd, file) in files.iter() {
+        let mut lock = file.write();
+        if let Err(e) = lock.flush().and_then(|()| lock.close()) {
+            warn!("Closing file {fd} of an ending process failed: {e:?}");
+        }
+    }
+}
+
+/// Detaches the process of the current task once it exited, and frees its program. Its address
+/// space goes when the task is switched away from for the last time.
+///
+/// Returns whether the process exited, in which case the task has to end.
+pub fn reap_exited() -> bool {
+    let Some(current_pinf) = try_get_current_process_info() else {
+        return false;
+    };
+    let Some(code) = current_pinf.status().exit_code() else {
+        return false;
+    };
+
+    info!("Process ending with code: {code}");
+    change_current_process_info(|p| p.take()); // This process is no longer associated with the task
+    let program = current_pinf.program().clone();
+    drop(current_pinf);
+
+    debug!("Strong count: {}", Arc::strong_count(&program));
+    debug!("Weak count: {}", Arc::weak_count(&program));
+
+    let program = Arc::into_inner(program).expect("No more than one ref");
+    drop(program);
+    true
+}
+
+/// The program of the process running on the current task, if it is not locked.
+pub fn current_program() -> Option<Arc<LoadedProgram>> {
+    let task = try_get_current_task()?;
+    let context = task.context.try_lock()?;
+    let program = context.process_info.as_ref()?.program.clone();
+    drop(context);
+    Some(program)
 }
 
 /// Uses the task stack if possible
@@ -310,9 +408,11 @@ pub fn load(path: &Path) -> Result<ProcessInfo, ExecError> {
     let mut file = inode.open()?;
 
     let mut read = &mut *buf;
+    let mut offset = 0;
 
     while !read.is_empty() {
-        let bytes = file.read(read)?;
+        let bytes = file.pread(read, offset)?;
+        offset += bytes as u64;
         if let Some(r) = read.get_mut(bytes..) {
             read = r;
         } else {
@@ -326,3 +426,221 @@ pub fn load(path: &Path) -> Result<ProcessInfo, ExecError> {
 
     Ok(ProcessInfo::new(&buf)?)
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::{boxed::Box, sync::Arc};
+    use api_utils::cglue;
+    use blog_os_syscalls::priority::Priority;
+    use blog_os_vfs::api::{
+        IOError,
+        file::{File, cglue_file::*},
+        path::PathBuf,
+    };
+    use spin::Once;
+    use x86_64::{
+        VirtAddr,
+        registers::control::Cr3,
+        structures::paging::{PageTableFlags, Translate, mapper::TranslateResult},
+    };
+
+    use super::{
+        OpenFile, Signal, load,
+        pipe::{self, PipeWriter},
+        stdio::{StdIn, StdInData, stderr, stdout},
+    };
+    use crate::{
+        KERNEL_INFO,
+        interrupts::kill_current_process,
+        memory::vma::{Access, FaultError},
+        multitask::{self, Active, Scheduler, set_current_process_info, wait::WaitQueue},
+    };
+
+    /// `path` in the ramfs, which the first call mounts.
+    fn ramfs_path(path: &str) -> PathBuf {
+        static RAMFS: Once = Once::new();
+        RAMFS.call_once(crate::fs::init_ramfs);
+        PathBuf::parse(path)
+    }
+
+    /// The init program.
+    fn init_path() -> PathBuf {
+        ramfs_path("/init")
+    }
+
+    fn is_mapped(addr: VirtAddr) -> bool {
+        KERNEL_INFO
+            .get()
+            .unwrap()
+            .alloc_kinf
+            .lock()
+            .page_table
+            .translate_addr(addr)
+            .is_some()
+    }
+
+    /// Runs the program at `path` on a task of its own until it exits, then waits for the task
+    /// to be torn down, which releases the address space of the process.
+    fn run_to_exit(path: &PathBuf) {
+        extern "C" fn start(arg: *mut ()) {
+            static READABLE: WaitQueue = WaitQueue::unbounded();
+
+            // Safety: `run_to_exit` passes a boxed path, which only this task takes back
+            let path = *unsafe { Box::from_raw(arg.cast::<PathBuf>()) };
+            // Loading switches the task to the address space of the process
+            let process = load(&path).unwrap();
+            drop(path);
+            for file in [
+                cglue::trait_obj!(
+                    StdIn::new(Arc::new(StdInData::default().into_lock()), &READABLE) as File
+                ),
+                cglue::trait_obj!(stdout() as File),
+                cglue::trait_obj!(stderr() as File),
+            ] {
+                process
+                    .files()
+                    .write()
+                    .insert(Arc::new(OpenFile::new_no_inode(file).into_lock()));
+            }
+            process.start();
+        }
+
+        let arg = Box::into_raw(Box::new(path.clone())).cast();
+        let id = Active::create_task(start, arg, "test process".into(), Priority::DEFAULT);
+
+        // The address space goes when the task is switched away from for the last time
+        while multitask::get_priority(&id).is_some() {
+            multitask::task_switch();
+        }
+    }
+
+    fn free_frames() -> u64 {
+        KERNEL_INFO
+            .get()
+            .unwrap()
+            .alloc_kinf
+            .lock()
+            .frame_allocator
+            .free_frames()
+    }
+
+    #[test_case]
+    fn exited_processes_free_their_frames() {
+        // Faults in its code, data and stack, grows its heap and touches it, then exits
+        let path = ramfs_path("/bin/test_prog");
+
+        // The first run may grow the kernel heap and its tables for good
+        run_to_exit(&path);
+        let before = free_frames();
+        for _ in 0..1000 {
+            run_to_exit(&path);
+        }
+        assert_eq!(free_frames(), before);
+    }
+
+    #[test_case]
+    fn killed_writer_closes_its_pipe() {
+        extern "C" fn start(arg: *mut ()) {
+            // Safety: the test passes a boxed writer, which only this task takes back
+            let writer = *unsafe { Box::from_raw(arg.cast::<PipeWriter>()) };
+            let process = load(&init_path()).unwrap();
+            let file = cglue::trait_obj!(writer as File);
+            process
+                .files()
+                .write()
+                .insert(Arc::new(OpenFile::new_no_inode(file).into_lock()));
+            set_current_process_info(process);
+            // As if the process faulted
+            kill_current_process(Signal::Segv);
+        }
+
+        let (mut reader, writer) = pipe::pipe();
+        let arg = Box::into_raw(Box::new(writer)).cast();
+        Active::create_task(start, arg, "killed writer".into(), Priority::DEFAULT);
+
+        // Sleeps until the killed process closes the writing end
+        assert!(matches!(reader.read(&mut [0; 1]), Err(IOError::EOF)));
+    }
+
+    #[test_case]
+    fn user_pages_are_mapped_on_first_touch() {
+        let (kernel_p4, _) = Cr3::read();
+
+        let process = load(&init_path()).unwrap();
+        let prog = process.program().clone();
+        let entry = prog.entry();
+        assert!(!is_mapped(entry));
+        prog.handle_fault(entry, Access::Execute).unwrap();
+        assert!(is_mapped(entry));
+        // Code is not writable
+        assert!(prog.handle_fault(entry, Access::Write).is_err());
+
+        let brk = prog
+            .heap()
+            .lock()
+            .change_brk(&mut prog.address_space().lock(), 4096, false)
+            .unwrap();
+        let heap = brk - 4096u64;
+        assert!(!is_mapped(heap));
+        prog.handle_fault(heap, Access::Write).unwrap();
+        let translated = KERNEL_INFO
+            .get()
+            .unwrap()
+            .alloc_kinf
+            .lock()
+            .page_table
+            .translate(heap);
+        assert!(matches!(
+            translated,
+            TranslateResult::Mapped { flags, .. }
+                if flags.contains(PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+        ));
+        // The heap is data, not code
+        assert!(matches!(
+            prog.handle_fault(heap, Access::Execute),
+            Err(FaultError::Denied(..))
+        ));
+        // Past the break
+        assert!(prog.handle_fault(brk, Access::Read).is_err());
+        drop(prog);
+        drop(process);
+
+        let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
+        let mem = &mut *lock;
+        mem.page_table
+            .switch_and_release(kernel_p4, &mut mem.frame_allocator);
+    }
+
+    #[test_case]
+    fn user_stack_grows_down_to_its_limit() {
+        let (kernel_p4, _) = Cr3::read();
+
+        let process = load(&init_path()).unwrap();
+        let prog = process.program().clone();
+        let stack = *prog.stack();
+
+        // Too far below the stack to be its growth
+        assert!(matches!(
+            prog.handle_fault(stack.top() - 2 * 1024 * 1024u64, Access::Write),
+            Err(FaultError::OutOfArea(_))
+        ));
+
+        // Page by page, like a deep recursion
+        let mut addr = stack.top() - 1u64;
+        while addr >= stack.limit() {
+            prog.handle_fault(addr, Access::Write).unwrap();
+            addr -= 4096u64;
+        }
+        assert!(matches!(
+            prog.handle_fault(addr, Access::Write),
+            Err(FaultError::StackOverflow(_))
+        ));
+        drop(prog);
+        drop(process);
+
+        let mut lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
+        let mem = &mut *lock;
+        mem.page_table
+            .switch_and_release(kernel_p4, &mut mem.frame_allocator);
+    }
+}
@@ -0,0 +1,196 @@
+//! Pipes: a bounded buffer with a reading end and a writing end.
+//!
+//! Reading sleeps until there is data, writing until there is room. Once the writing end is
+//! closed, reads drain what is left and then fail with `EOF`. Once the reading end is closed,
+//! writes fail with `EOF` too.
+
+use alloc::{collections::vec_deque::VecDeque, sync::Arc};
+use blog_os_device::api::DeviceId;
+use blog_os_vfs::api::{
+    IOError,
+    file::{File, SeekMode},
+    inode::FsINodeRef,
+};
+
+use crate::{
+    lock_class,
+    multitask::sync::{Condvar, Mutex, mutex},
+};
+
+/// Bytes a pipe holds before writers have to wait.
+pub const PIPE_CAPACITY: usize = 4096;
+
+#[derive(Debug)]
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    reader_open: bool,
+    writer_open: bool,
+}
+
+struct Pipe {
+    buffer: Mutex<PipeBuffer>,
+    /// Notified when data is written or the writing end is closed
+    readable: Condvar,
+    /// Notified when data is read or the reading end is closed
+    writable: Condvar,
+}
+
+/// Creates a pipe, returning its reading and writing ends.
+pub fn pipe() -> (PipeReader, PipeWriter) {
+    let pipe = Arc::new(Pipe {
+        buffer: mutex(
+            lock_class!(),
+            PipeBuffer {
+                data: VecDeque::with_capacity(PIPE_CAPACITY),
+                reader_open: true,
+                writer_open: true,
+            },
+        ),
+        readable: Condvar::unbounded(),
+        writable: Condvar::unbounded(),
+    });
+
+    (PipeReader { pipe: pipe.clone() }, PipeWriter { pipe })
+}
+
+pub struct PipeReader {
+    pipe: Arc<Pipe>,
+}
+
+pub struct PipeWriter {
+    pipe: Arc<Pipe>,
+}
+
+impl File for PipeReader {
+    fn close(&mut self) -> Result<(), IOError> {
+        self.pipe.buffer.lock().reader_open = false;
+        self.pipe.writable.notify_all();
+        Ok(())
+    }
+
+    /// Sleeps until there is something to read, then reads what is there.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut buffer = self.pipe.buffer.lock();
+        self.pipe
+            .readable
+            .wait_while(&mut buffer, |b| b.data.is_empty() && b.writer_open);
+
+        if buffer.data.is_empty() {
+            return Err(IOError::EOF);
+        }
+
+        let bytes = buf.len().min(buffer.data.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.data.drain(..bytes)) {
+            *dst = src;
+        }
+        drop(buffer);
+
+        self.pipe.writable.notify_all();
+        Ok(bytes)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn mkdir(&mut self, _name: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn mknod(&mut self, _name: &str, _device: DeviceId) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn creat(&mut self, _name: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _size: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn seek(&mut self, _mode: SeekMode, _amount: isize) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn next_direntry(&mut self) -> Result<&str, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+}
+
+impl File for PipeWriter {
+    fn close(&mut self) -> Result<(), IOError> {
+        self.pipe.buffer.lock().writer_open = false;
+        self.pipe.readable.notify_all();
+        Ok(())
+    }
+
+    fn read(&mut self, _buf: &mut [u8]) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    /// Sleeps while the pipe is full, until all of `buf` is written.
+    ///
+    /// If the reading end is closed on the way, returns what was written so far.
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IOError> {
+        let mut written = 0;
+        let mut buffer = self.pipe.buffer.lock();
+        while written < buf.len() {
+            self.pipe.writable.wait_while(&mut buffer, |b| {
+                b.data.len() >= PIPE_CAPACITY && b.reader_open
+            });
+
+            if !buffer.reader_open {
+                break;
+            }
+
+            let bytes = (buf.len() - written).min(PIPE_CAPACITY - buffer.data.len());
+            buffer.data.extend(&buf[written..written + bytes]);
+            written += bytes;
+            self.pipe.readable.notify_all();
+        }
+        drop(buffer);
+
+        if written == 0 && !buf.is_empty() {
+            Err(IOError::EOF)
+        } else {
+            Ok(written)
+        }
+    }
+
+    fn mkdir(&mut self, _name: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn mknod(&mut self, _name: &str, _device: DeviceId) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn creat(&mut self, _name: &str) -> Result<FsINodeRef, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn flush(&mut self) -> Result<(), IOError> {
+        Ok(())
+    }
+
+    fn truncate(&mut self, _size: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn seek(&mut self, _mode: SeekMode, _amount: isize) -> Result<usize, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
+    fn next_direntry(&mut self) -> Result<&str, IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+}
@@ -6,12 +6,22 @@ use blog_os_vfs::api::{
     inode::FsINodeRef,
 };
 use log::{error, info};
-use spin::lock_api::RwLock;
+use shared_fs::ioctl::{IoctlRequest, TERM_GET_MODE, TERM_SET_MODE, TermMode};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use crate::{
+    lock_class,
+    multitask::{
+        lock::{SpinRwLock, spin_rwlock},
+        wait::WaitQueue,
+    },
+};
 
 #[derive(Debug, Default)]
 pub struct StdInData {
     buffer: Vec<u8>,
     eof: bool,
+    mode: TermMode,
 }
 
 impl StdInData {
@@ -22,40 +32,80 @@ impl StdInData {
     pub const fn buffer_mut(&mut self) -> &mut Vec<u8> {
         &mut self.buffer
     }
+
+    pub const fn mode(&self) -> TermMode {
+        self.mode
+    }
+
+    /// Puts the data behind its lock, shared by the [`StdIn`] files reading it.
+    pub const fn into_lock(self) -> SpinRwLock<Self> {
+        spin_rwlock(lock_class!(), self)
+    }
 }
 
 #[derive(Debug)]
 pub struct StdIn {
-    data: Arc<RwLock<StdInData>>,
+    data: Arc<SpinRwLock<StdInData>>,
+    /// Woken whenever input arrives
+    readable: &'static WaitQueue,
 }
 
 impl StdIn {
-    pub const fn new(data: Arc<RwLock<StdInData>>) -> Self {
-        Self { data }
+    pub const fn new(data: Arc<SpinRwLock<StdInData>>, readable: &'static WaitQueue) -> Self {
+        Self { data, readable }
+    }
+
+    /// Input is queued from interrupt handlers, so the data is only locked with them disabled.
+    fn with_data<R>(&self, f: impl FnOnce(&mut StdInData) -> R) -> R {
+        without_interrupts(|| f(&mut self.data.write()))
+    }
+
+    /// Takes what can be read right now, `None` if there is nothing yet.
+    fn try_read(&self, buf: &mut [u8]) -> Option<Result<usize, IOError>> {
+        self.with_data(|data| {
+            if data.eof {
+                return Some(Err(IOError::EOF));
+            }
+
+            let available = if data.mode.contains(TermMode::CANONICAL) {
+                // Only hand out complete lines
+                data.buffer
+                    .iter()
+                    .position(|&b| b == b'\n')
+                    .map_or(0, |newline| newline + 1)
+            } else {
+                data.buffer.len()
+            };
+            if available == 0 {
+                return None;
+            }
+
+            let bytes = buf.len().min(available);
+
+            let next = data.buffer.split_off(bytes);
+            let read = core::mem::replace(&mut data.buffer, next);
+
+            buf[..bytes].copy_from_slice(&read);
+
+            Some(Ok(bytes))
+        })
     }
 }
 
 impl File for StdIn {
     fn close(&mut self) -> Result<(), IOError> {
-        self.data.write().eof = true;
+        self.with_data(|data| data.eof = true);
+        self.readable.wake_all();
         Ok(())
     }
 
+    /// Sleeps until there is input, a whole line in canonical mode.
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, IOError> {
-        if self.data.read().eof {
-            return Err(IOError::EOF);
+        if buf.is_empty() {
+            return Ok(0);
         }
 
-        let mut lock = self.data.write();
-
-        let bytes = buf.len().min(lock.buffer.len());
-
-        let next = lock.buffer.split_off(bytes);
-        let read = core::mem::replace(&mut lock.buffer, next);
-
-        buf[..bytes].copy_from_slice(&read);
-
-        Ok(bytes)
+        self.readable.wait_for(|| self.try_read(buf))
     }
 
     fn write(&mut self, _buf: &[u8]) -> Result<usize, IOError> {
@@ -78,6 +128,10 @@ impl File for StdIn {
         Ok(())
     }
 
+    fn truncate(&mut self, _size: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
     fn seek(&mut self, mode: SeekMode, amount: isize) -> Result<usize, IOError> {
         Err(IOError::OperationNotPermitted)
     }
@@ -85,6 +139,21 @@ impl File for StdIn {
     fn next_direntry(&mut self) -> Result<&str, IOError> {
         Err(IOError::OperationNotPermitted)
     }
+
+    fn ioctl(&mut self, request: IoctlRequest, arg: &mut [u8]) -> Result<u64, IOError> {
+        if TERM_GET_MODE == request {
+            TERM_GET_MODE.set_arg(arg, self.with_data(|data| data.mode));
+        } else if TERM_SET_MODE == request {
+            let mode = TERM_SET_MODE.arg(arg).ok_or(IOError::InvalidArgument)?;
+            self.with_data(|data| data.mode = mode);
+            // Switching out of canonical mode can make partial lines readable
+            self.readable.wake_all();
+        } else {
+            return Err(IOError::NotSupported);
+        }
+
+        Ok(0)
+    }
 }
 
 pub struct Out {
@@ -141,6 +210,10 @@ impl File for Out {
         Ok(())
     }
 
+    fn truncate(&mut self, _size: u64) -> Result<(), IOError> {
+        Err(IOError::OperationNotPermitted)
+    }
+
     fn seek(&mut self, mode: SeekMode, amount: isize) -> Result<usize, IOError> {
         Err(IOError::OperationNotPermitted)
     }
@@ -1,7 +1,12 @@
-use spin::{Lazy, lock_api::Mutex};
+use spin::Lazy;
 use uuid::Uuid;
 use x86_64::instructions::random::RdRand;
 
+use crate::{
+    lock_class,
+    multitask::lock::{SpinMutex, spin_mutex},
+};
+
 fn rdtsc() -> u64 {
     let low: u32;
     let high: u32;
@@ -17,13 +22,18 @@ fn get_entropy_seed() -> u64 {
 
 enum SystemRng {
     RdRand(RdRand),
-    Other(Mutex<Xoroshiro128>),
+    Other(SpinMutex<Xoroshiro128>),
 }
 
 impl SystemRng {
     fn new() -> Self {
         RdRand::new().map_or_else(
-            || Self::Other(Mutex::new(Xoroshiro128::new(get_entropy_seed()))),
+            || {
+                Self::Other(spin_mutex(
+                    lock_class!(),
+                    Xoroshiro128::new(get_entropy_seed()),
+                ))
+            },
             Self::RdRand,
         )
     }
@@ -9,7 +9,7 @@ use qemu_common::KERNEL_START;
 use spin::Once;
 use x86_64::{
     VirtAddr,
-    structures::paging::{FrameDeallocator, Mapper, Size4KiB},
+    structures::paging::{Mapper, Size4KiB},
 };
 
 use crate::{
@@ -20,12 +20,16 @@ use crate::{
     gdt,
     interrupts,
     io,
-    memory::{
-        self, BootInfoFrameAllocator, multi_l4_paging::PageTables, range_alloc::RangeAllocator,
+    lock_class,
+    memory::{self, BuddyFrameAllocator, multi_l4_paging::PageTables, range_alloc::RangeAllocator},
+    multitask::{
+        self,
+        lock::{ReentrantMutex, ReentrantRawMutex, reentrant_mutex},
     },
-    multitask::{self, lock::ReentrantMutex},
+    smp,
     stack::{self, SlabStack, StackAlloc},
     unwind::eh::EhInfo,
+    watchdog,
 };
 
 pub type KernelElfFile = SystemElf<'static>;
@@ -33,7 +37,7 @@ pub type KernelElfFile = SystemElf<'static>;
 pub struct AllocKernelInfo {
     /// The kernel page tables
     pub page_table: PageTables,
-    pub frame_allocator: BootInfoFrameAllocator,
+    pub frame_allocator: BuddyFrameAllocator,
     pub virt_region_allocator: RangeAllocator<u64>,
 }
 
@@ -46,6 +50,18 @@ impl AllocKernelInfo {
     }
 }
 
+/// Lock over [`AllocKernelInfo`], that points the page tables at the address space of the
+/// locking CPU.
+pub struct AllocKernelInfoMutex(ReentrantMutex<AllocKernelInfo>);
+
+impl AllocKernelInfoMutex {
+    pub fn lock(&self) -> lock_api::MutexGuard<'_, ReentrantRawMutex, AllocKernelInfo> {
+        let mut guard = self.0.lock();
+        guard.page_table.sync_current_page_table();
+        guard
+    }
+}
+
 pub struct KernelInfo {
     /// The version of the `bootloader_api` crate. Must match the `bootloader` version.
     pub api_version: ApiVersion,
@@ -56,17 +72,15 @@ pub struct KernelInfo {
     // /// the memory map before passing it to the kernel. Regions marked as usable can be freely
     // /// used by the kernel.
     // pub memory_regions: &'static MemoryRegions,
-    // /// The virtual address at which the mapping of the physical memory starts.
-    // ///
-    // /// Physical addresses can be converted to virtual addresses by adding this offset to them.
-    // ///
-    // /// The mapping of the physical memory allows to access arbitrary physical frames. Accessing
-    // /// frames that are also mapped at other virtual addresses can easily break memory safety and
-    // /// cause undefined behavior. Only frames reported as `USABLE` by the memory map in the `BootInfo`
-    // /// can be safely accessed.
-    // ///
-    // /// Only available if the `map-physical-memory` config option is enabled.
-    // pub physical_memory_offset: VirtAddr,
+    /// The virtual address at which the mapping of the physical memory starts.
+    ///
+    /// Physical addresses can be converted to virtual addresses by adding this offset to them.
+    ///
+    /// The mapping of the physical memory allows to access arbitrary physical frames. Accessing
+    /// frames that are also mapped at other virtual addresses can easily break memory safety and
+    /// cause undefined behavior. Only frames reported as `USABLE` by the memory map in the `BootInfo`
+    /// can be safely accessed.
+    pub physical_memory_offset: VirtAddr,
     // /// The virtual address of the recursively mapped level 4 page table.
     // ///
     // /// Only available if the `map-page-table-recursively` config option is enabled.
@@ -91,7 +105,7 @@ pub struct KernelInfo {
     pub eh_info: Option<EhInfo<'static>>,
     pub addr2line: Option<ReentrantMutex<Context<EndianSlice<'static>>>>,
 
-    pub alloc_kinf: &'static ReentrantMutex<AllocKernelInfo>,
+    pub alloc_kinf: &'static AllocKernelInfoMutex,
     pub stack_alloc: ReentrantMutex<StackAlloc>,
 }
 
@@ -130,7 +144,7 @@ impl KernelInfo {
 }
 
 pub static KERNEL_INFO: Once<KernelInfo> = Once::new();
-static ALLOC_KINF: Once<ReentrantMutex<AllocKernelInfo>> = Once::new();
+static ALLOC_KINF: Once<AllocKernelInfoMutex> = Once::new();
 
 pub fn setup(boot_info: &'static mut bootloader_api::BootInfo) {
     let layout = memory::range_alloc::discover_layout(boot_info);
@@ -142,6 +156,7 @@ pub fn setup(boot_info: &'static mut bootloader_api::BootInfo) {
     io::serial::init();
     io::logger::init();
     interrupts::init_pics();
+    memory::enable_protection();
 
     info!("Kernel offset: {:x}", boot_info.kernel_image_offset);
     info!("Kernel physaddr: {:x}", boot_info.kernel_addr);
@@ -180,7 +195,9 @@ pub fn setup(boot_info: &'static mut bootloader_api::BootInfo) {
             .expect("Physical memory mapped"),
     );
     let page_table = unsafe { memory::init_page_tables(physical_memory_offset) };
-    let frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    let trampoline_frame = smp::reserve_trampoline_frame(&mut boot_info.memory_regions);
+    let frame_allocator =
+        unsafe { BuddyFrameAllocator::init(&boot_info.memory_regions, physical_memory_offset) };
 
     let page_table = PageTables::new(page_table, VirtAddr::new(boot_info.kernel_image_offset));
     info!("Initializing region allocator");
@@ -192,18 +209,19 @@ pub fn setup(boot_info: &'static mut bootloader_api::BootInfo) {
     info!("Initialized region allocator");
 
     let alloc_kinf = ALLOC_KINF.call_once(|| {
-        ReentrantMutex::new(AllocKernelInfo {
-            page_table,
-            frame_allocator,
-            virt_region_allocator,
-        })
+        AllocKernelInfoMutex(reentrant_mutex(
+            lock_class!(),
+            AllocKernelInfo {
+                page_table,
+                frame_allocator,
+                virt_region_allocator,
+            },
+        ))
     });
     info!("Initializing heap");
     allocator::init_heap(alloc_kinf).expect("initialized heap");
     info!("Initialized heap");
 
-    alloc_kinf.lock().frame_allocator.heap_init();
-
     // unmap userspace pages. Should only be the old gdt mapping
     let mut alloc_kinf_lock = alloc_kinf.lock();
     #[allow(clippy::needless_collect)]
@@ -219,7 +237,11 @@ pub fn setup(boot_info: &'static mut bootloader_api::BootInfo) {
         let (frame, flush) = alloc_kinf_lock.page_table.unmap(page).expect("Unmap page");
         flush.flush();
         trace!(event = "unmap_user_pages", subevent = "after", page:?, frame:?; "Unmapped {page:?} from {frame:?}");
-        unsafe { alloc_kinf.lock().frame_allocator.deallocate_frame(frame) };
+        // The bootloader's frames weren't usable in the memory map, so may be below or above all
+        // of those the allocator has state for
+        if !unsafe { alloc_kinf_lock.frame_allocator.add_frame(frame) } {
+            info!(event = "unmap_user_pages", frame:?; "{frame:?} is outside of the frame allocator, leaving it unused");
+        }
     }
     drop(alloc_kinf_lock);
 
@@ -268,17 +290,17 @@ pub fn setup(boot_info: &'static mut bootloader_api::BootInfo) {
                 .inspect_err(|e| warn!("addr2line error: {e:?}"))
                 .ok()
         })
-        .map(ReentrantMutex::new);
+        .map(|context| reentrant_mutex(lock_class!(), context));
 
     let setup_info = KernelInfo {
         kernel_addr: boot_info.kernel_addr,
         api_version: boot_info.api_version,
         // memory_regions: &boot_info.memory_regions,
-        // physical_memory_offset,
+        physical_memory_offset,
         // recursive_index: boot_info.recursive_index.as_ref().copied(),
         rsdp_addr: boot_info.rsdp_addr.as_ref().copied(),
         tls_template: boot_info.tls_template.as_ref().copied(),
-        ramdisk_addr: ReentrantMutex::new(boot_info.ramdisk_addr.as_ref().copied()),
+        ramdisk_addr: reentrant_mutex(lock_class!(), boot_info.ramdisk_addr.as_ref().copied()),
         ramdisk_len: boot_info.ramdisk_len,
         kernel_len: boot_info.kernel_len,
         kernel_image_offset: boot_info.kernel_image_offset,
@@ -286,8 +308,14 @@ pub fn setup(boot_info: &'static mut bootloader_api::BootInfo) {
         eh_info,
         addr2line,
         alloc_kinf,
-        stack_alloc: ReentrantMutex::new(stack_alloc),
+        stack_alloc: reentrant_mutex(lock_class!(), stack_alloc),
     };
     KERNEL_INFO.call_once(|| setup_info);
+    interrupts::init_controller();
+    smp::init_bsp(trampoline_frame);
+    #[cfg(feature = "kpti")]
+    memory::kpti::init();
     multitask::init();
+    watchdog::init();
+    smp::start_application_processors();
 }
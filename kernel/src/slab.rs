@@ -0,0 +1,132 @@
+//! Object caches for the hot kernel structures, in front of the general heap.
+//!
+//! The global allocator serves every allocation with the layout of a cache from that cache,
+//! whoever makes it, so a cache also takes the other objects that share the layout of its
+//! structure. Slabs come from the general heap. With the `slab-poison` feature, freed objects are
+//! poisoned, and reusing one whose poison was overwritten is reported.
+//!
+//! The usage of every cache is reported in `/sys/slab`.
+
+use core::{alloc::Layout, ptr, sync::atomic::AtomicUsize};
+
+use crate::{
+    fs::RamFsLock,
+    lock_class,
+    multitask::{
+        Active, Scheduler,
+        lock::{SpinMutex, SpinRwLock, spin_mutex},
+        task::TaskControlBlock,
+    },
+    process::OpenFile,
+};
+use blog_os_slab::{CacheStats, SlabCache, SlabSource};
+use blog_os_vfs::{api::inode::cglue_inode::INodeBox, dentry::CachedDEntry};
+use log::error;
+use ramfs::inode::{directory::DirectoryINode, regular::RegularINode};
+
+pub static CACHES: [ObjectCache; 6] = [
+    ObjectCache::new(
+        "task",
+        arc_layout::<TaskControlBlock<<Active as Scheduler>::Data>>(),
+    ),
+    ObjectCache::new("open_file", arc_layout::<SpinRwLock<OpenFile>>()),
+    ObjectCache::new("dentry", Layout::new::<CachedDEntry>()),
+    ObjectCache::new("inode", arc_layout::<INodeBox<'static>>()),
+    ObjectCache::new("ramfs_dir", Layout::new::<DirectoryINode<RamFsLock>>()),
+    ObjectCache::new("ramfs_file", Layout::new::<RegularINode<RamFsLock>>()),
+];
+
+/// A named cache of objects of a single layout.
+pub struct ObjectCache {
+    name: &'static str,
+    layout: Layout,
+    inner: SpinMutex<SlabCache>,
+}
+
+impl ObjectCache {
+    const fn new(name: &'static str, layout: Layout) -> Self {
+        Self {
+            name,
+            layout,
+            inner: spin_mutex(
+                lock_class!(),
+                SlabCache::new(name, layout, cfg!(feature = "slab-poison")),
+            ),
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub const fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().stats()
+    }
+
+    /// Hands out an object, null if `source` has no slab left.
+    pub(crate) fn allocate(&self, source: &mut impl SlabSource) -> *mut u8 {
+        let Some(allocation) = self.inner.lock().allocate(source) else {
+            return ptr::null_mut();
+        };
+        if let Some(offset) = allocation.corrupted_at {
+            error!(
+                "slab: {} object {:p} was written at offset {offset} after being freed",
+                self.name, allocation.ptr
+            );
+        }
+        allocation.ptr.as_ptr()
+    }
+
+    /// # Safety
+    /// `ptr` must come from [`Self::allocate`] with the same `source`, and not be freed since.
+    pub(crate) unsafe fn deallocate(&self, ptr: *mut u8, source: &mut impl SlabSource) {
+        let ptr = ptr::NonNull::new(ptr).expect("an object");
+        unsafe { self.inner.lock().deallocate(ptr, source) };
+    }
+}
+
+/// The cache serving allocations of `layout`, if any.
+pub fn cache_for(layout: Layout) -> Option<&'static ObjectCache> {
+    CACHES.iter().find(|cache| cache.layout == layout)
+}
+
+/// Layout of the allocation behind an `Arc<T>`: its two counts, then the value.
+const fn arc_layout<T>() -> Layout {
+    let counts = Layout::new::<[AtomicUsize; 2]>();
+    let value = Layout::new::<T>();
+    let align = if value.align() > counts.align() {
+        value.align()
+    } else {
+        counts.align()
+    };
+    let size = counts.size().next_multiple_of(value.align()) + value.size();
+    match Layout::from_size_align(size.next_multiple_of(align), align) {
+        Ok(layout) => layout,
+        Err(_) => panic!("Arc layout overflows"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::sync::Arc;
+    use core::mem::MaybeUninit;
+
+    use super::{arc_layout, cache_for};
+    use crate::{multitask::lock::SpinRwLock, process::OpenFile};
+
+    #[test_case]
+    fn caches_serve_their_layout() {
+        let cache = cache_for(arc_layout::<SpinRwLock<OpenFile>>()).unwrap();
+        assert_eq!(cache.name(), "open_file");
+
+        let before = cache.stats().active_objects;
+        let file = Arc::new(MaybeUninit::<SpinRwLock<OpenFile>>::uninit());
+        assert_eq!(cache.stats().active_objects, before + 1);
+        drop(file);
+        assert_eq!(cache.stats().active_objects, before);
+    }
+}
@@ -0,0 +1,297 @@
+//! Symmetric multiprocessing: starting the application processors (APs) listed in the MADT and
+//! the per-CPU state they run with.
+//!
+//! Every CPU has its own GDT, TSS and IDT, reached through its [`PerCpu`] area. Kernel mappings
+//! are shared, so removing one flushes the TLB of every CPU before the unmapped frame can be
+//! reused, see [`shootdown_kernel_tlb`].
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use log::{info, warn};
+use spin::Once;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+};
+
+use crate::{
+    acpi, gdt,
+    interrupts::{self, apic, timer},
+    multitask,
+    setup::KERNEL_INFO,
+};
+
+pub mod percpu;
+mod trampoline;
+
+pub use percpu::PerCpu;
+
+/// Highest number of CPUs used, further processors are left alone.
+pub const MAX_CPUS: usize = 64;
+
+/// How long an AP gets to reach the kernel after its startup IPIs
+const AP_START_TIMEOUT_MS: u32 = 100;
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+/// Bit mask of the CPUs running the scheduler
+static ONLINE: AtomicU64 = AtomicU64::new(1);
+static KERNEL_TLB_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// APIC IDs of the APs, in CPU index order starting at 1
+static AP_APIC_IDS: Once<Vec<u8>> = Once::new();
+static TRAMPOLINE_FRAME: Once<PhysFrame> = Once::new();
+/// Control registers of the bootstrap processor, copied by the APs
+static BSP_CONTROL: Once<(Cr0Flags, Cr4Flags, EferFlags)> = Once::new();
+/// Set by an AP once it no longer needs the trampoline
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Number of CPUs the kernel plans to use, including ones that failed to start.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Relaxed)
+}
+
+/// Whether the CPU `index` runs the scheduler.
+pub fn is_online(index: usize) -> bool {
+    index < MAX_CPUS && ONLINE.load(Ordering::Acquire) & (1 << index) != 0
+}
+
+/// Index of the running CPU, 0 being the bootstrap processor.
+pub fn cpu_index() -> usize {
+    percpu::current().map_or(0, PerCpu::index)
+}
+
+/// Flushes a kernel mapping the caller just removed out of the TLB of every CPU, returning once
+/// they all did.
+///
+/// The other CPUs get an NMI, which they take even with interrupts disabled, so waiting for
+/// them can't deadlock on a lock held here.
+pub fn shootdown_kernel_tlb() {
+    let generation = KERNEL_TLB_GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+    sync_tlb();
+    if cpu_count() == 1 {
+        return;
+    }
+    let Some(local_apic) = apic::local_apic() else {
+        return;
+    };
+
+    let me = cpu_index();
+    let others = || {
+        (0..MAX_CPUS)
+            .filter(move |&i| i != me && is_online(i))
+            .filter_map(percpu::by_index)
+    };
+    for cpu in others() {
+        local_apic.send_nmi(cpu.apic_id());
+    }
+    for cpu in others() {
+        while cpu.tlb_generation.load(Ordering::Acquire) < generation {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Flushes the TLB of the running CPU if a kernel mapping was removed since the last flush,
+/// returning whether it did. Run by the NMI of [`shootdown_kernel_tlb`].
+pub fn sync_tlb() -> bool {
+    let Some(cpu) = percpu::current() else {
+        tlb::flush_all();
+        return true;
+    };
+    let generation = KERNEL_TLB_GENERATION.load(Ordering::Acquire);
+    if cpu.tlb_generation.swap(generation, Ordering::AcqRel) == generation {
+        return false;
+    }
+    tlb::flush_all();
+    true
+}
+
+/// Takes a usable frame below 1 MiB out of the memory map, for the AP trampoline.
+///
+/// Must run before the frame allocator is created.
+pub fn reserve_trampoline_frame(regions: &mut MemoryRegions) -> Option<PhysFrame> {
+    const REAL_MODE_END: u64 = 0x10_0000;
+    const PAGE: u64 = 4096;
+
+    let region = regions.iter_mut().find(|r| {
+        let end = r.end & !(PAGE - 1);
+        // Leave the first page alone, real mode can't start there anyway
+        r.kind == MemoryRegionKind::Usable
+            && end <= REAL_MODE_END
+            && end >= r.start.max(PAGE) + PAGE
+    });
+    let Some(region) = region else {
+        warn!("No memory below 1 MiB for the AP trampoline");
+        return None;
+    };
+
+    region.end = (region.end & !(PAGE - 1)) - PAGE;
+    let frame = PhysFrame::containing_address(PhysAddr::new(region.end));
+    info!("Reserved {frame:?} for the AP trampoline");
+    Some(frame)
+}
+
+/// Gives the bootstrap processor its per-CPU area and decides which processors to start.
+pub fn init_bsp(trampoline_frame: Option<PhysFrame>) {
+    let bsp_apic_id = apic::local_apic().map_or(0, apic::LocalApic::id);
+    let percpu = Box::leak(Box::new(PerCpu::new(
+        0,
+        bsp_apic_id,
+        gdt::bsp_gdt(),
+        gdt::bsp_tss(),
+        interrupts::idt(),
+    )));
+    percpu.install();
+
+    if !<multitask::Active as multitask::Scheduler>::SMP {
+        info!("The scheduler runs on one CPU, running on the bootstrap processor only");
+        return;
+    }
+
+    let (Some(_), Some(madt), Some(frame)) = (apic::local_apic(), acpi::madt(), trampoline_frame)
+    else {
+        info!("Running on the bootstrap processor only");
+        return;
+    };
+
+    let ap_apic_ids: Vec<u8> = madt
+        .processors
+        .iter()
+        .filter(|p| p.enabled && p.apic_id != bsp_apic_id)
+        .map(|p| p.apic_id)
+        .take(MAX_CPUS - 1)
+        .collect();
+    if ap_apic_ids.is_empty() {
+        info!("Running on the bootstrap processor only");
+        return;
+    }
+
+    info!("Planning to use {} CPUs", ap_apic_ids.len() + 1);
+    CPU_COUNT.store(ap_apic_ids.len() + 1, Ordering::Relaxed);
+    AP_APIC_IDS.call_once(|| ap_apic_ids);
+    TRAMPOLINE_FRAME.call_once(|| frame);
+}
+
+/// Per-CPU tables and stacks for the AP `index`. They live as long as the kernel.
+fn new_ap_percpu(index: usize, apic_id: u8) -> &'static PerCpu {
+    let kernel_info = KERNEL_INFO.get().unwrap();
+    let esp0 = kernel_info.create_stack().expect("A stack");
+    let ist_df = kernel_info.create_stack().expect("A stack");
+
+    let tss = Box::leak(Box::new(gdt::new_tss(&esp0, &ist_df)));
+    let gdt = Box::leak(Box::new(gdt::new_gdt(tss)));
+    let idt = Box::leak(Box::new(interrupts::idt().clone()));
+    #[cfg(feature = "kpti")]
+    crate::memory::kpti::map_cpu_tables(&gdt.0, tss, idt);
+    Box::leak(Box::new(PerCpu::new(index, apic_id, gdt, tss, idt)))
+}
+
+/// Starts the APs chosen by [`init_bsp`], one at a time.
+///
+/// The schedulers must be initialized, every AP starts scheduling right away.
+pub fn start_application_processors() {
+    let (Some(frame), Some(ap_apic_ids), Some(local_apic)) = (
+        TRAMPOLINE_FRAME.get(),
+        AP_APIC_IDS.get(),
+        apic::local_apic(),
+    ) else {
+        return;
+    };
+    let frame = *frame;
+
+    let (cr3, _) = Cr3::read();
+    if cr3.start_address().as_u64() > u64::from(u32::MAX) {
+        warn!("Kernel page tables at {cr3:?} can't be loaded in real mode, not starting APs");
+        return;
+    }
+    BSP_CONTROL.call_once(|| (Cr0::read(), Cr4::read(), Efer::read()));
+
+    // The trampoline keeps running at its physical address until it reached the kernel
+    let kernel_info = KERNEL_INFO.get().unwrap();
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    {
+        let mut lock = kernel_info.alloc_kinf.lock();
+        let alloc_kinf = &mut *lock;
+        unsafe {
+            alloc_kinf
+                .page_table
+                .map_to(
+                    page,
+                    frame,
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                    &mut alloc_kinf.frame_allocator,
+                )
+                .expect("Identity mapped trampoline")
+                .flush();
+        }
+    }
+    trampoline::install(frame);
+
+    for (i, &apic_id) in ap_apic_ids.iter().enumerate() {
+        let index = i + 1;
+        let percpu = new_ap_percpu(index, apic_id);
+        // Only used until the AP switches to its first task, but never freed
+        let stack = kernel_info.create_stack().expect("A stack");
+
+        trampoline::set_args(
+            frame,
+            trampoline::TrampolineArgs {
+                cr3: cr3.start_address().as_u64(),
+                stack_top: stack.top(),
+                entry: ap_entry,
+                arg: core::ptr::from_ref(percpu) as u64,
+            },
+        );
+        AP_STARTED.store(false, Ordering::Release);
+        local_apic.start_processor(apic_id, (frame.start_address().as_u64() >> 12) as u8);
+
+        let mut waited = 0;
+        while !AP_STARTED.load(Ordering::Acquire) && waited < AP_START_TIMEOUT_MS {
+            timer::pit_wait_ms(1);
+            waited += 1;
+        }
+        if !AP_STARTED.load(Ordering::Acquire) {
+            warn!("CPU {index} (APIC {apic_id}) didn't start");
+        }
+    }
+
+    let (_, flush) = kernel_info
+        .alloc_kinf
+        .lock()
+        .page_table
+        .unmap(page)
+        .expect("Identity mapped trampoline");
+    flush.flush();
+
+    let online = ONLINE.load(Ordering::Acquire).count_ones();
+    info!("{online} of {} CPUs online", cpu_count());
+}
+
+/// Where the APs enter the kernel, on the stack prepared for them.
+extern "C" fn ap_entry(percpu: u64) -> ! {
+    let percpu = unsafe { &*(percpu as *const PerCpu) };
+    percpu.install();
+
+    let &(cr0, cr4, efer) = BSP_CONTROL.get().unwrap();
+    unsafe {
+        Efer::write(efer);
+        Cr4::write(cr4);
+        Cr0::write(cr0);
+    }
+
+    apic::init_application_processor();
+    ONLINE.fetch_or(1 << percpu.index(), Ordering::AcqRel);
+    info!("CPU {} (APIC {}) is up", percpu.index(), percpu.apic_id());
+    AP_STARTED.store(true, Ordering::Release);
+
+    // The bring-up context is done, the scheduler takes over from here
+    x86_64::instructions::interrupts::enable();
+    multitask::task_exit();
+}
@@ -0,0 +1,108 @@
+//! The per-CPU data area, found through the GS base.
+
+use core::{
+    arch::x86_64::__cpuid,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+};
+
+use x86_64::{
+    VirtAddr,
+    registers::model_specific::GsBase,
+    structures::{
+        gdt::GlobalDescriptorTable, idt::InterruptDescriptorTable, tss::TaskStateSegment,
+    },
+};
+
+use crate::gdt::{self, Selectors};
+
+/// Data owned by one CPU. The GS base of every CPU points to its own.
+pub struct PerCpu {
+    /// Index of the CPU, the bootstrap processor being 0
+    index: usize,
+    apic_id: u8,
+    gdt: &'static (GlobalDescriptorTable, Selectors),
+    tss: &'static TaskStateSegment,
+    idt: &'static InterruptDescriptorTable,
+    /// Kernel TLB generation last flushed on this CPU
+    pub(super) tlb_generation: AtomicU64,
+}
+
+/// Set once the bootstrap processor has its per-CPU area
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// Per-CPU areas by APIC ID, to find the area again when the GS base was cleared.
+static BY_APIC_ID: [AtomicPtr<PerCpu>; 256] = [const { AtomicPtr::new(ptr::null_mut()) }; 256];
+
+impl PerCpu {
+    pub const fn new(
+        index: usize,
+        apic_id: u8,
+        gdt: &'static (GlobalDescriptorTable, Selectors),
+        tss: &'static TaskStateSegment,
+        idt: &'static InterruptDescriptorTable,
+    ) -> Self {
+        Self {
+            index,
+            apic_id,
+            gdt,
+            tss,
+            idt,
+            tlb_generation: AtomicU64::new(0),
+        }
+    }
+
+    pub const fn index(&self) -> usize {
+        self.index
+    }
+
+    pub const fn apic_id(&self) -> u8 {
+        self.apic_id
+    }
+
+    pub const fn tss(&self) -> &'static TaskStateSegment {
+        self.tss
+    }
+
+    /// Loads the descriptor tables of this CPU and points the GS base here.
+    ///
+    /// Must run on the CPU this area belongs to.
+    pub(super) fn install(&'static self) {
+        gdt::load(self.gdt);
+        self.idt.load();
+        GsBase::write(VirtAddr::from_ptr(self));
+        BY_APIC_ID[usize::from(self.apic_id)]
+            .store(ptr::from_ref(self).cast_mut(), Ordering::Release);
+        READY.store(true, Ordering::Release);
+    }
+}
+
+/// The per-CPU area of the CPU `index`, once it installed it.
+pub fn by_index(index: usize) -> Option<&'static PerCpu> {
+    BY_APIC_ID.iter().find_map(|percpu| {
+        // Safety: areas are 'static once installed
+        unsafe { percpu.load(Ordering::Acquire).as_ref() }.filter(|x| x.index == index)
+    })
+}
+
+/// The per-CPU area of the running CPU, once it has one.
+pub fn current() -> Option<&'static PerCpu> {
+    if !READY.load(Ordering::Acquire) {
+        return None;
+    }
+
+    let mut base = GsBase::read();
+    if base.is_null() {
+        // Loading a segment into GS from userspace clears the base. Other bases can't be set
+        // from userspace, as long as FSGSBASE stays disabled.
+        let apic_id = (__cpuid(1).ebx >> 24) as usize;
+        let percpu = BY_APIC_ID[apic_id].load(Ordering::Acquire);
+        if percpu.is_null() {
+            return None;
+        }
+        base = VirtAddr::from_ptr(percpu);
+        GsBase::write(base);
+    }
+
+    Some(unsafe { &*base.as_ptr::<PerCpu>() })
+}
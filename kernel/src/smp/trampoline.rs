@@ -0,0 +1,155 @@
+//! Real mode entry point of the application processors.
+//!
+//! A startup IPI starts the processor in real mode at the start of a page below 1 MiB. The
+//! trampoline is copied there, goes straight to long mode with the kernel page tables and
+//! jumps to the kernel on the stack prepared for this processor. The page has to be identity
+//! mapped while it runs.
+
+use core::arch::global_asm;
+
+use x86_64::{PhysAddr, VirtAddr, structures::paging::PhysFrame};
+
+use crate::setup::KERNEL_INFO;
+
+global_asm!(
+    r#"
+    .pushsection .text.ap_trampoline, "ax"
+    .code16
+    .global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    movw %cs, %ax
+    movw %ax, %ds
+
+    # PAE and the kernel page tables, which have to be below 4 GiB
+    movl %cr4, %eax
+    orl $(1 << 5), %eax
+    movl %eax, %cr4
+    movl (ap_trampoline_args - ap_trampoline_start), %eax
+    movl %eax, %cr3
+
+    # Long mode, with no-execute pages
+    movl $0xC0000080, %ecx
+    rdmsr
+    orl $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    lgdtl (ap_trampoline_gdtr - ap_trampoline_start)
+
+    # Protection, write protection and paging at once
+    movl %cr0, %eax
+    orl $0x80010001, %eax
+    movl %eax, %cr0
+
+    ljmpl *(ap_trampoline_far_jump - ap_trampoline_start)
+
+    .code64
+    .global ap_trampoline_long_mode
+ap_trampoline_long_mode:
+    movw $0x10, %ax
+    movw %ax, %ds
+    movw %ax, %es
+    movw %ax, %ss
+    xorw %ax, %ax
+    movw %ax, %fs
+    movw %ax, %gs
+
+    movq (ap_trampoline_args + 8)(%rip), %rsp
+    movq (ap_trampoline_args + 24)(%rip), %rdi
+    movq (ap_trampoline_args + 16)(%rip), %rax
+    xorl %ebp, %ebp
+    # The entry point never returns, this only aligns the stack like a call would
+    pushq $0
+    jmpq *%rax
+
+    .balign 8
+    .global ap_trampoline_gdt
+ap_trampoline_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff
+    .quad 0x00cf92000000ffff
+    .global ap_trampoline_gdtr
+ap_trampoline_gdtr:
+    .word ap_trampoline_gdtr - ap_trampoline_gdt - 1
+    .long 0
+    .global ap_trampoline_far_jump
+ap_trampoline_far_jump:
+    .long 0
+    .word 0x08
+    .balign 8
+    .global ap_trampoline_args
+ap_trampoline_args:
+    .quad 0, 0, 0, 0
+    .global ap_trampoline_end
+ap_trampoline_end:
+    .popsection
+    "#,
+    options(att_syntax)
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_gdt: u8;
+    static ap_trampoline_gdtr: u8;
+    static ap_trampoline_far_jump: u8;
+    static ap_trampoline_args: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Where the application processor goes once it reached long mode.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TrampolineArgs {
+    /// Must be below 4 GiB
+    pub cr3: u64,
+    pub stack_top: VirtAddr,
+    pub entry: extern "C" fn(u64) -> !,
+    /// Passed to `entry`
+    pub arg: u64,
+}
+
+/// Offset of a trampoline label from the start of the trampoline.
+fn offset_of(label: *const u8) -> u64 {
+    label as u64 - (&raw const ap_trampoline_start) as u64
+}
+
+fn frame_ptr(frame: PhysFrame, offset: u64) -> *mut u8 {
+    let phys_offset = KERNEL_INFO.get().unwrap().physical_memory_offset;
+    (phys_offset + frame.start_address().as_u64() + offset).as_mut_ptr()
+}
+
+/// Copies the trampoline to `frame`, which must be below 1 MiB.
+pub fn install(frame: PhysFrame) {
+    let base = frame.start_address();
+    assert!(
+        base < PhysAddr::new(0x10_0000),
+        "The trampoline runs in real mode"
+    );
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            &raw const ap_trampoline_start,
+            frame_ptr(frame, 0),
+            offset_of(&raw const ap_trampoline_end) as usize,
+        );
+
+        // The code doesn't know where it runs, patch in the linear addresses it needs
+        let gdt = base.as_u64() + offset_of(&raw const ap_trampoline_gdt);
+        frame_ptr(frame, offset_of(&raw const ap_trampoline_gdtr) + 2)
+            .cast::<u32>()
+            .write_unaligned(gdt as u32);
+
+        let long_mode = base.as_u64() + offset_of(&raw const ap_trampoline_long_mode);
+        frame_ptr(frame, offset_of(&raw const ap_trampoline_far_jump))
+            .cast::<u32>()
+            .write_unaligned(long_mode as u32);
+    }
+}
+
+/// Sets where the next processor started through the trampoline in `frame` goes.
+pub fn set_args(frame: PhysFrame, args: TrampolineArgs) {
+    let ptr = frame_ptr(frame, offset_of(&raw const ap_trampoline_args));
+    unsafe { ptr.cast::<TrampolineArgs>().write_volatile(args) };
+}
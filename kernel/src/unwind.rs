@@ -1,4 +1,8 @@
-use alloc::{format, string::ToString, vec::Vec};
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
 use gimli::{
     CfaRule, Register, RegisterRule, UnwindContext, UnwindContextStorage, UnwindSection,
     UnwindTableRow, X86_64,
@@ -65,6 +69,9 @@ pub struct Unwinder<'a> {
 
     /// Is it the first iteration?
     is_first: bool,
+
+    /// Whether the steps and errors of the unwinding are logged
+    verbose: bool,
 }
 
 impl<'a> Unwinder<'a> {
@@ -75,6 +82,7 @@ impl<'a> Unwinder<'a> {
             regs: register_set,
             cfa: 0,
             is_first: true,
+            verbose: true,
         }
     }
 
@@ -99,7 +107,9 @@ impl<'a> Unwinder<'a> {
         // println!("Loaded eh_info");
 
         if self.is_first {
-            debug!("IS FIRST");
+            if self.verbose {
+                debug!("IS FIRST");
+            }
             self.is_first = false;
             return Ok(Some(CallFrame {
                 pc,
@@ -118,7 +128,9 @@ impl<'a> Unwinder<'a> {
                 |section, bases, offset| section.cie_from_offset(bases, offset),
             )
             .map_err(|e| {
-                error!("Unwind error: {e}");
+                if self.verbose {
+                    error!("Unwind error: {e}");
+                }
                 UnwinderError::NoUnwindInfo(pc)
             })?;
 
@@ -132,7 +144,9 @@ impl<'a> Unwinder<'a> {
                 pc,
             )
             .map_err(|e| {
-                error!("Unwind error: {e}");
+                if self.verbose {
+                    error!("Unwind error: {e}");
+                }
                 UnwinderError::NoUnwindInfo(pc)
             })?;
         // println!("Gotten row");
@@ -196,7 +210,9 @@ impl<'a> Unwinder<'a> {
         let start = VirtAddr::new(fde.initial_address());
 
         if let Some(i) = IH.get(&start) {
-            debug!("IH: {i:?}");
+            if self.verbose {
+                debug!("IH: {i:?}");
+            }
             let saved_cs_ptr = (self.cfa) as *const u64;
             let lock = KERNEL_INFO.get().unwrap().alloc_kinf.lock();
             if lock
@@ -210,7 +226,9 @@ impl<'a> Unwinder<'a> {
 
             let saved_cs = unsafe { saved_cs_ptr.read() };
 
-            debug!("Saved cs: {saved_cs:x} (CPL: {:x})", saved_cs & 0x3);
+            if self.verbose {
+                debug!("Saved cs: {saved_cs:x} (CPL: {:x})", saved_cs & 0x3);
+            }
 
             let cpl = saved_cs & 0x3;
             if cpl == 3 {
@@ -226,14 +244,18 @@ impl<'a> Unwinder<'a> {
                 }
                 let saved_rsp = unsafe { saved_rsp_ptr.read() };
 
-                debug!("Interrupt return to ring3, saved RSP = {saved_rsp:x}");
+                if self.verbose {
+                    debug!("Interrupt return to ring3, saved RSP = {saved_rsp:x}");
+                }
 
                 self.cfa = saved_rsp;
                 // self.regs.set_stack_ptr(saved_rsp);
                 // let _ = self.regs.set(X86_64::RSP, saved_rsp);
             } else {
                 // TODO is this correct?
-                debug!("Interrupt return to ring0, no saved RSP");
+                if self.verbose {
+                    debug!("Interrupt return to ring0, no saved RSP");
+                }
             }
             drop(lock);
         }
@@ -249,7 +271,9 @@ impl<'a> Unwinder<'a> {
         self.regs.set_stack_ptr(self.cfa);
         // println!("Set regs");
 
-        debug!("PC: 0x{pc:X}; RSP: 0x{:X}", self.cfa);
+        if self.verbose {
+            debug!("PC: 0x{pc:X}; RSP: 0x{:X}", self.cfa);
+        }
 
         Ok(Some(CallFrame { pc, sp: self.cfa }))
     }
@@ -260,7 +284,19 @@ fn single_backtrace_line(frame: CallFrame, unwind: &Unwinder<'_>) -> Result<(),
         .current_unwindable()?
         .ok_or(UnwinderError::NoUnwindInfo(frame.pc))?;
 
-    let (location, addr) = unwindable.find_location(frame.pc);
+    let (loc_str, addr) = location_string(unwindable, frame.pc);
+
+    info!(
+        "Unwind frame: sp: {:#x}; ip: {:#x} (elf: {:#x}) {}",
+        frame.sp, frame.pc, addr, loc_str
+    );
+
+    Ok(())
+}
+
+/// `file:line:column` of `pc`, with its address in the ELF of `unwindable`.
+fn location_string(unwindable: &OrderedUnwindable<'_>, pc: u64) -> (String, u64) {
+    let (location, addr) = unwindable.find_location(pc);
 
     let location = location
         .inspect_err(|e| warn!("No location information: {e}"))
@@ -284,12 +320,50 @@ fn single_backtrace_line(frame: CallFrame, unwind: &Unwinder<'_>) -> Result<(),
         "<unknown>".into()
     };
 
-    info!(
-        "Unwind frame: sp: {:#x}; ip: {:#x} (elf: {:#x}) {}",
-        frame.sp, frame.pc, addr, loc_str
-    );
+    (loc_str, addr)
+}
 
-    Ok(())
+/// Logs the location of the kernel code at `pc`, as a line of a backtrace.
+pub fn log_kernel_frame(pc: u64) {
+    let mut unwind_table = UnwindTable::default();
+    unwind_table.push_ref(KERNEL_INFO.get().unwrap(), "kernel");
+    match unwind_table.get(pc) {
+        Some(unwindable) => {
+            let (loc_str, addr) = location_string(unwindable, pc);
+            info!("  ip: {pc:#x} (elf: {addr:#x}) {loc_str}");
+        }
+        None => info!("  ip: {pc:#x}"),
+    }
+}
+
+/// Fills `pcs` with the instruction pointers of the kernel frames calling this function,
+/// innermost first, and returns how many it found.
+///
+/// Unlike [`backtrace`], nothing is logged, so the allocator can record its callers with it.
+pub fn capture(pcs: &mut [u64]) -> usize {
+    let aprox_pc: u64;
+    let sp: u64;
+    unsafe {
+        core::arch::asm!("
+            lea {pc}, [rip]
+            mov {sp}, rsp
+            ", pc = lateout(reg) aprox_pc, sp = lateout(reg) sp, options(nomem,nostack));
+    }
+    let mut unwind_table = UnwindTable::default();
+    unwind_table.push_ref(KERNEL_INFO.get().unwrap(), "kernel");
+    let mut register_set = RegisterSet::new(aprox_pc);
+    register_set.set_stack_ptr(sp);
+    let mut unwind = Unwinder::new(unwind_table, register_set);
+    unwind.verbose = false;
+
+    // The first frame is this function
+    let frames = core::iter::from_fn(|| unwind.next().ok().flatten()).skip(1);
+    let mut count = 0;
+    for (pc, frame) in pcs.iter_mut().zip(frames) {
+        *pc = frame.pc;
+        count += 1;
+    }
+    count
 }
 
 pub fn backtrace() {
@@ -0,0 +1,238 @@
+//! Watchdog for soft lockups and hung tasks, driven by the timer interrupt.
+//!
+//! Every CPU counts its scheduling points. On each tick, the next online CPU checks that the
+//! count moved. A CPU spinning with interrupts disabled doesn't take its own timer interrupts,
+//! so its observer sends it an NMI. The NMI may have interrupted a lock holder, so it only
+//! records where the CPU was, and the observer reports it on its next tick. With a single CPU,
+//! only lockups that still take interrupts are caught.
+//!
+//! Once a second, the timer also wakes the `khungtaskd` thread, which reports tasks stuck in
+//! a [bounded sleep](multitask::SleepKind::Bounded) for too long, with the backtrace of their
+//! stack.
+//!
+//! Either report panics afterwards if [`set_panic`] was enabled.
+
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use log::{error, info};
+use uuid::Uuid;
+use x86_64::{VirtAddr, structures::idt::InterruptStackFrame};
+
+use crate::{
+    interrupts::{apic, stub::InterruptContext, timer},
+    multitask::{self, SleepingTask, kthread, wait::WaitQueue},
+    smp::{self, MAX_CPUS, percpu},
+    unwind::backtrace_sp_ip,
+};
+
+/// Seconds a CPU may go without scheduling, 0 disabling the check
+static SOFT_LOCKUP_SECS: AtomicU64 = AtomicU64::new(20);
+/// Seconds a task may sleep in a bounded sleep, 0 disabling the check
+static HUNG_TASK_SECS: AtomicU64 = AtomicU64::new(120);
+static PANIC: AtomicBool = AtomicBool::new(false);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Scheduling points of each CPU
+static SCHEDULED: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// Scheduling points of each CPU at the previous check, only written by its observer
+static SEEN: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// Ticks of the observer since the CPU last scheduled
+static STALE_TICKS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// Whether the current lockup of the CPU was reported
+static REPORTED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+/// Set before sending the CPU an NMI, to tell it apart from other NMIs
+static NMI_REQUESTED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+/// Stack and instruction pointers recorded by the NMI, valid once `NMI_RECORDED` is set
+static NMI_SP: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static NMI_IP: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+static NMI_RECORDED: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+static HUNG_CHECK: AtomicBool = AtomicBool::new(false);
+static HUNG_CHECK_QUEUE: WaitQueue = WaitQueue::unbounded();
+
+/// Callee-saved registers `__switch_asm` pushes below its return address
+const SWITCH_SAVED_REGISTERS: u64 = 6;
+
+/// Starts watching, once the scheduler runs.
+pub fn init() {
+    kthread::spawn(khungtaskd, "khungtaskd");
+    ENABLED.store(true, Ordering::Release);
+    info!(
+        "Watchdog started, soft lockup after {}s, hung task after {}s",
+        SOFT_LOCKUP_SECS.load(Ordering::Relaxed),
+        HUNG_TASK_SECS.load(Ordering::Relaxed)
+    );
+}
+
+pub fn set_soft_lockup_timeout(secs: u64) {
+    SOFT_LOCKUP_SECS.store(secs, Ordering::Relaxed);
+}
+
+pub fn set_hung_task_timeout(secs: u64) {
+    HUNG_TASK_SECS.store(secs, Ordering::Relaxed);
+}
+
+/// Whether to panic after reporting a soft lockup or a hung task.
+pub fn set_panic(panic: bool) {
+    PANIC.store(panic, Ordering::Relaxed);
+}
+
+/// Records a scheduling point on the running CPU.
+pub fn touch() {
+    SCHEDULED[smp::cpu_index()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Checks the CPUs the running one observes, `context` being what the tick interrupted.
+pub(crate) fn on_tick(context: &InterruptContext) {
+    if !ENABLED.load(Ordering::Acquire) {
+        return;
+    }
+
+    let me = smp::cpu_index();
+    for cpu in (0..smp::cpu_count()).filter(|&cpu| smp::is_online(cpu)) {
+        if observer(cpu) == me {
+            report_nmi(cpu);
+            check(cpu, me, &context.frame);
+        }
+    }
+
+    if me == 0 && timer::ticks().is_multiple_of(u64::from(timer::frequency().max(1))) {
+        HUNG_CHECK.store(true, Ordering::Release);
+        HUNG_CHECK_QUEUE.wake_one();
+    }
+}
+
+/// Handles an NMI, returning whether it was sent by the watchdog.
+///
+/// Only records where the CPU was: the NMI may have interrupted a holder of the locks logging
+/// and unwinding take.
+pub(crate) fn on_nmi(frame: &InterruptStackFrame) -> bool {
+    let cpu = smp::cpu_index();
+    if !NMI_REQUESTED[cpu].swap(false, Ordering::AcqRel) {
+        return false;
+    }
+    NMI_SP[cpu].store(frame.stack_pointer.as_u64(), Ordering::Relaxed);
+    NMI_IP[cpu].store(frame.instruction_pointer.as_u64(), Ordering::Relaxed);
+    NMI_RECORDED[cpu].store(true, Ordering::Release);
+    true
+}
+
+/// The next online CPU after `cpu`, which may be `cpu` itself.
+fn observer(cpu: usize) -> usize {
+    let count = smp::cpu_count();
+    (1..=count)
+        .map(|i| (cpu + i) % count)
+        .find(|&i| smp::is_online(i))
+        .unwrap_or(cpu)
+}
+
+fn check(cpu: usize, me: usize, frame: &InterruptStackFrame) {
+    let scheduled = SCHEDULED[cpu].load(Ordering::Relaxed);
+    if SEEN[cpu].swap(scheduled, Ordering::Relaxed) != scheduled {
+        STALE_TICKS[cpu].store(0, Ordering::Relaxed);
+        REPORTED[cpu].store(false, Ordering::Relaxed);
+        return;
+    }
+
+    let stale = STALE_TICKS[cpu].fetch_add(1, Ordering::Relaxed) + 1;
+    let limit = SOFT_LOCKUP_SECS.load(Ordering::Relaxed) * u64::from(timer::frequency());
+    if limit == 0 || stale < limit || REPORTED[cpu].swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    if cpu == me {
+        soft_lockup(cpu, frame.stack_pointer, frame.instruction_pointer);
+    } else if let (Some(local_apic), Some(percpu)) = (apic::local_apic(), percpu::by_index(cpu)) {
+        NMI_REQUESTED[cpu].store(true, Ordering::Release);
+        local_apic.send_nmi(percpu.apic_id());
+    } else {
+        error!("watchdog: soft lockup, CPU {cpu} didn't schedule for {limit} ticks");
+        panic_if_enabled();
+    }
+}
+
+/// Reports the soft lockup of `cpu` its NMI recorded, if any.
+fn report_nmi(cpu: usize) {
+    if !NMI_RECORDED[cpu].swap(false, Ordering::Acquire) {
+        return;
+    }
+    let sp = VirtAddr::new(NMI_SP[cpu].load(Ordering::Relaxed));
+    let ip = VirtAddr::new(NMI_IP[cpu].load(Ordering::Relaxed));
+    soft_lockup(cpu, sp, ip);
+}
+
+/// Reports a soft lockup of `cpu`, which was at `ip` with the stack at `sp`.
+///
+/// The stack of another CPU is only unwound while it still hasn't scheduled, as its frames
+/// could be gone otherwise.
+fn soft_lockup(cpu: usize, sp: VirtAddr, ip: VirtAddr) {
+    let local = cpu == smp::cpu_index();
+    // The running task of another CPU can't be looked up without its scheduler's lock
+    let task = local.then(multitask::try_get_current_task).flatten();
+    error!(
+        "watchdog: soft lockup, CPU {cpu} didn't schedule for {}s, running {} at {ip:#x}",
+        SOFT_LOCKUP_SECS.load(Ordering::Relaxed),
+        task.as_ref().map_or("?", |task| &task.name)
+    );
+    drop(task);
+    if local || SCHEDULED[cpu].load(Ordering::Relaxed) == SEEN[cpu].load(Ordering::Relaxed) {
+        backtrace_sp_ip(sp.as_u64(), ip.as_u64());
+    }
+    panic_if_enabled();
+}
+
+fn panic_if_enabled() {
+    if PANIC.load(Ordering::Relaxed) {
+        panic!("watchdog: lockup detected");
+    }
+}
+
+fn khungtaskd() {
+    // Sleeps already reported, by task and start
+    let mut reported: Vec<(Uuid, u64)> = Vec::new();
+    loop {
+        HUNG_CHECK_QUEUE.wait_until(|| HUNG_CHECK.swap(false, Ordering::AcqRel));
+
+        let timeout_ms = HUNG_TASK_SECS.load(Ordering::Relaxed) * 1000;
+        if timeout_ms == 0 {
+            continue;
+        }
+
+        let now = timer::uptime_ms();
+        let mut hung = Vec::new();
+        let mut found = false;
+        multitask::for_each_sleeping(|task| {
+            if now.saturating_sub(task.since_ms) < timeout_ms {
+                return;
+            }
+            let key = (task.id, task.since_ms);
+            hung.push(key);
+            if !reported.contains(&key) {
+                found = true;
+                hung_task(task, now);
+            }
+        });
+        reported = hung;
+
+        if found {
+            panic_if_enabled();
+        }
+    }
+}
+
+/// Reports `task`, whose context is locked so that it stays asleep meanwhile.
+fn hung_task(task: &SleepingTask, now: u64) {
+    error!(
+        "watchdog: task {} ({}) blocked for {}s",
+        task.name,
+        task.id,
+        (now - task.since_ms) / 1000
+    );
+
+    // The switch left the callee-saved registers and then its return address on the stack
+    let return_address = task.stack_pointer + SWITCH_SAVED_REGISTERS * 8;
+    // Safety: the stack belongs to a sleeping task, which can't run or exit meanwhile
+    let ip = unsafe { return_address.as_ptr::<u64>().read() };
+    backtrace_sp_ip(return_address.as_u64() + 8, ip);
+}
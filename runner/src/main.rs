@@ -39,6 +39,9 @@ struct Args {
     ovmf_prebuilt: PathBuf,
     #[arg(long, env = "INITRD_DIR")]
     initrd: Option<PathBuf>,
+    /// Number of CPUs given to QEMU
+    #[arg(default_value_t = 4, long, env = "SMP")]
+    smp: u32,
 }
 
 fn get_env_target_dir() -> Option<PathBuf> {
@@ -229,6 +232,7 @@ fn main() {
                 .arg(format!("if=pflash,format=raw,file={}", ovmf_vars.display()));
         }
         cmd.arg("-m").arg("512M");
+        cmd.arg("-smp").arg(args.smp.to_string());
 
         cmd.arg("-drive")
             .arg(format!("format=raw,file={}", path.display()));
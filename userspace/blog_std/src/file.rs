@@ -2,9 +2,9 @@ use io_error::IOError;
 use path::Path;
 
 use crate::{
-    close, flush,
+    close, flush, ftruncate,
     io::{Read, Write},
-    open, read, write,
+    open, pread, pwrite, read, write,
 };
 
 pub struct File {
@@ -27,6 +27,21 @@ impl File {
     pub fn close(self) {
         drop(self)
     }
+
+    /// Reads at `offset` without moving the file cursor.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize, IOError> {
+        pread(self.fd, buf, offset).map(|x| x as usize)
+    }
+
+    /// Writes at `offset` without moving the file cursor.
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize, IOError> {
+        pwrite(self.fd, buf, offset).map(|x| x as usize)
+    }
+
+    /// Shrinks or extends the file to `size` bytes. New bytes read as zero.
+    pub fn set_len(&self, size: u64) -> Result<(), IOError> {
+        ftruncate(self.fd, size)
+    }
 }
 
 impl Drop for File {
@@ -6,7 +6,7 @@ use path::Path;
 use shared_fs::dirent::DirEntry;
 pub use shared_fs::*;
 
-use crate::{close, next_direntry, open};
+use crate::{close, next_direntry, open, openat, statat};
 
 pub struct DirIter {
     fd: u64,
@@ -17,6 +17,20 @@ impl DirIter {
         let fd = open(path)?;
         Ok(Self { fd })
     }
+
+    /// Opens the directory at `path` relative to this one.
+    pub fn open_at(&self, path: &Path) -> Result<Self, IOError> {
+        let fd = openat(self.fd, path)?;
+        Ok(Self { fd })
+    }
+
+    pub fn stat_at(&self, path: &Path) -> Result<Stat, IOError> {
+        statat(self.fd, path)
+    }
+
+    pub const fn fd(&self) -> u64 {
+        self.fd
+    }
 }
 
 impl Drop for DirIter {
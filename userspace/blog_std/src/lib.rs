@@ -2,15 +2,19 @@
 
 use core::{fmt::Write, mem::MaybeUninit, panic::PanicInfo};
 
-use alloc::string::ToString;
-use blog_os_syscalls::SyscallNumber;
+use alloc::{borrow::Cow, string::ToString, vec::Vec};
+use blog_os_syscalls::{SyscallNumber, priority::Priority};
 use io_error::IOError;
 use num_enum::TryFromPrimitive;
 
 use fs::Stat;
 pub use path;
 use path::Path;
-use shared_fs::dirent::{DirEntry, DirEntryHeader};
+use shared_fs::{
+    dirent::{DirEntry, DirEntryHeader},
+    ioctl::{Ioctl, IoctlArg, IoctlRequest},
+    iovec::IoVec,
+};
 
 extern crate alloc;
 
@@ -51,14 +55,80 @@ pub fn read(fd: u64, buf: &mut [u8]) -> Result<u64, IOError> {
     u64_as_result(unsafe { syscalls::syscall_arg3(SyscallNumber::READ, len, raw, fd) })
 }
 
+pub fn pread(fd: u64, buf: &mut [u8], offset: u64) -> Result<u64, IOError> {
+    let raw = buf.as_ptr() as u64;
+    let len = buf.len() as u64;
+
+    u64_as_result(unsafe { syscalls::syscall_arg4(SyscallNumber::PREAD, offset, len, raw, fd) })
+}
+
+pub fn pwrite(fd: u64, buf: &[u8], offset: u64) -> Result<u64, IOError> {
+    let raw = buf.as_ptr() as u64;
+    let len = buf.len() as u64;
+
+    u64_as_result(unsafe { syscalls::syscall_arg4(SyscallNumber::PWRITE, offset, len, raw, fd) })
+}
+
+pub fn readv(fd: u64, iovs: &[IoVec]) -> Result<u64, IOError> {
+    let raw = iovs.as_ptr() as u64;
+    let count = iovs.len() as u64;
+
+    u64_as_result(unsafe { syscalls::syscall_arg3(SyscallNumber::READV, count, raw, fd) })
+}
+
+pub fn writev(fd: u64, iovs: &[IoVec]) -> Result<u64, IOError> {
+    let raw = iovs.as_ptr() as u64;
+    let count = iovs.len() as u64;
+
+    u64_as_result(unsafe { syscalls::syscall_arg3(SyscallNumber::WRITEV, count, raw, fd) })
+}
+
+/// # Safety
+/// `arg` must point to a buffer of at least `request.size()` bytes
+pub unsafe fn ioctl(fd: u64, request: IoctlRequest, arg: *mut u8) -> Result<u64, IOError> {
+    u64_as_result(unsafe {
+        syscalls::syscall_arg3(SyscallNumber::IOCTL, arg as u64, request.0 as u64, fd)
+    })
+}
+
+pub fn ioctl_read<T: IoctlArg>(fd: u64, request: Ioctl<T>) -> Result<T, IOError> {
+    let mut arg = MaybeUninit::<T>::zeroed();
+    unsafe { ioctl(fd, request.request(), arg.as_mut_ptr().cast())? };
+    Ok(unsafe { arg.assume_init() })
+}
+
+pub fn ioctl_write<T: IoctlArg>(fd: u64, request: Ioctl<T>, mut arg: T) -> Result<u64, IOError> {
+    unsafe { ioctl(fd, request.request(), (&raw mut arg).cast()) }
+}
+
 pub fn brk(offset: i64) -> *mut u8 {
-    (unsafe { syscalls::syscall_arg1(SyscallNumber::BRK, offset as u64) }) as *mut u8
+    brk_with_flags(offset, 0)
+}
+
+/// Moves the program break by `offset` with [`brk` flags](blog_os_syscalls::brk)
+pub fn brk_with_flags(offset: i64, flags: u64) -> *mut u8 {
+    (unsafe { syscalls::syscall_arg2(SyscallNumber::BRK, flags, offset as u64) }) as *mut u8
 }
 
 pub fn yield_syscall() {
     unsafe { syscalls::syscall_arg0(SyscallNumber::YIELD) };
 }
 
+/// Changes the scheduling priority of the calling task.
+///
+/// Processes can't become real-time, asking for it fails with `OperationNotPermitted`.
+pub fn set_priority(priority: Priority) -> Result<(), IOError> {
+    u64_as_result(unsafe {
+        syscalls::syscall_arg1(SyscallNumber::SET_PRIORITY, priority.encode())
+    })?;
+    Ok(())
+}
+
+pub fn get_priority() -> Priority {
+    Priority::decode(unsafe { syscalls::syscall_arg0(SyscallNumber::GET_PRIORITY) })
+        .unwrap_or_default()
+}
+
 pub fn open(path: &Path) -> Result<u64, IOError> {
     let string = path.to_string();
     let bytes = string.as_bytes();
@@ -76,6 +146,15 @@ pub fn flush(fd: u64) -> Result<u64, IOError> {
     u64_as_result(unsafe { syscalls::syscall_arg1(SyscallNumber::FLUSH, fd) })
 }
 
+/// Opens a pipe, returning the fds of its reading and writing ends
+pub fn pipe() -> Result<(u64, u64), IOError> {
+    let mut fds = [0u64; 2];
+    let raw = fds.as_mut_ptr() as u64;
+
+    u64_as_result(unsafe { syscalls::syscall_arg1(SyscallNumber::PIPE, raw) })?;
+    Ok((fds[0], fds[1]))
+}
+
 pub fn stat(path: &Path) -> Result<Stat, IOError> {
     let string = path.to_string();
     let bytes = string.as_bytes();
@@ -100,6 +179,69 @@ pub fn next_direntry(fd: u64, entry: &mut DirEntry) -> Result<(), IOError> {
     Ok(())
 }
 
+pub fn truncate(path: &Path, size: u64) -> Result<(), IOError> {
+    let string = path.to_string();
+    let bytes = string.as_bytes();
+    let raw = bytes.as_ptr() as u64;
+    let len = bytes.len() as u64;
+
+    u64_as_result(unsafe { syscalls::syscall_arg3(SyscallNumber::TRUNCATE, size, len, raw) })?;
+    Ok(())
+}
+
+pub fn ftruncate(fd: u64, size: u64) -> Result<(), IOError> {
+    u64_as_result(unsafe { syscalls::syscall_arg2(SyscallNumber::FTRUNCATE, size, fd) })?;
+    Ok(())
+}
+
+/// Opens `path` relative to the directory `dirfd`, or the working directory with [`fs::AT_FDCWD`]
+pub fn openat(dirfd: u64, path: &Path) -> Result<u64, IOError> {
+    let string = path.to_string();
+    let bytes = string.as_bytes();
+    let raw = bytes.as_ptr() as u64;
+    let len = bytes.len() as u64;
+
+    u64_as_result(unsafe { syscalls::syscall_arg3(SyscallNumber::OPENAT, len, raw, dirfd) })
+}
+
+pub fn statat(dirfd: u64, path: &Path) -> Result<Stat, IOError> {
+    let string = path.to_string();
+    let bytes = string.as_bytes();
+    let raw = bytes.as_ptr() as u64;
+    let len = bytes.len() as u64;
+
+    let mut stat = MaybeUninit::<Stat>::zeroed();
+
+    let ptr = stat.as_mut_ptr();
+
+    u64_as_result(unsafe {
+        syscalls::syscall_arg4(SyscallNumber::STATAT, ptr as u64, len, raw, dirfd)
+    })?;
+
+    Ok(unsafe { stat.assume_init() })
+}
+
+pub fn mkdirat(dirfd: u64, path: &Path) -> Result<(), IOError> {
+    let string = path.to_string();
+    let bytes = string.as_bytes();
+    let raw = bytes.as_ptr() as u64;
+    let len = bytes.len() as u64;
+
+    u64_as_result(unsafe { syscalls::syscall_arg3(SyscallNumber::MKDIRAT, len, raw, dirfd) })?;
+    Ok(())
+}
+
+/// Changes the working directory, where paths relative to [`fs::AT_FDCWD`] start
+pub fn chdir(path: &Path) -> Result<(), IOError> {
+    let string = path.to_string();
+    let bytes = string.as_bytes();
+    let raw = bytes.as_ptr() as u64;
+    let len = bytes.len() as u64;
+
+    u64_as_result(unsafe { syscalls::syscall_arg2(SyscallNumber::CHDIR, len, raw) })?;
+    Ok(())
+}
+
 /// Closes the fd
 pub fn init_driver(fd: u64) -> Result<(), IOError> {
     u64_as_result(unsafe { syscalls::syscall_arg1(SyscallNumber::INIT_DRIVER, fd) })?;
@@ -107,7 +249,10 @@ pub fn init_driver(fd: u64) -> Result<(), IOError> {
 }
 
 pub fn print(s: &str) {
-    let mut buf = s.as_bytes();
+    print_bytes(s.as_bytes());
+}
+
+fn print_bytes(mut buf: &[u8]) {
     while !buf.is_empty() {
         let bytes = write(1, buf).unwrap() as usize;
         // nop(bytes as u64);
@@ -119,6 +264,21 @@ pub fn print(s: &str) {
     }
 }
 
+/// Writes all the buffers to stdout, in a single syscall unless the kernel takes less.
+fn print_vectored(bufs: &[&[u8]]) {
+    let iovs = bufs.iter().map(|b| IoVec::new(b)).collect::<Vec<_>>();
+    let mut written = writev(1, &iovs).unwrap() as usize;
+
+    for buf in bufs {
+        if let Some(rest) = buf.get(written..) {
+            print_bytes(rest);
+            written = 0;
+        } else {
+            written -= buf.len();
+        }
+    }
+}
+
 struct StdoutWriter;
 
 impl core::fmt::Write for StdoutWriter {
@@ -128,8 +288,17 @@ impl core::fmt::Write for StdoutWriter {
     }
 }
 
+fn format(args: core::fmt::Arguments) -> Cow<'static, str> {
+    args.as_str()
+        .map_or_else(|| alloc::fmt::format(args).into(), Cow::Borrowed)
+}
+
 pub fn print_fmt(args: core::fmt::Arguments) {
-    StdoutWriter.write_fmt(args).expect("write");
+    print(&format(args));
+}
+
+pub fn println_fmt(args: core::fmt::Arguments) {
+    print_vectored(&[format(args).as_bytes(), b"\n"]);
 }
 
 #[macro_export]
@@ -140,12 +309,13 @@ macro_rules! print {
 #[macro_export]
 macro_rules! println {
     () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::println_fmt(format_args!($($arg)*)));
 }
 
 // Required panic handler
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{info}");
+    // Do not allocate here, the panic may come from the allocator
+    let _ = writeln!(StdoutWriter, "{info}");
     exit(!0);
 }
@@ -20,5 +20,16 @@ pub extern "C" fn _start() -> ! {
 
     println!("Alloc box: {b}");
 
+    println!("Testing brk");
+    let start = blog_std::brk(0);
+    let grown = blog_std::brk(64 * 1024);
+    if grown as usize != start as usize + 64 * 1024 {
+        println!("brk moved from {start:p} to {grown:p}");
+        blog_std::exit(1);
+    }
+    // The new pages are mapped on first touch
+    unsafe { grown.sub(1).write_volatile(1) };
+    println!("Heap grown to {grown:p}");
+
     blog_std::exit(0);
 }